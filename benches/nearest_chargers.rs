@@ -0,0 +1,136 @@
+//! Benchmarks for the per grid point hot path: finding the chargers near a
+//! point (all of them, or the closest few) and measuring the distance to
+//! them.
+//!
+//! Run with `cargo bench`. Each benchmark runs for about a second and
+//! prints the mean time per iteration.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use ev_charging_gaps::*;
+
+/// Runs `f` repeatedly for about `BENCH_TIME` and prints the mean time per
+/// call.
+fn bench(name: &str, mut f: impl FnMut()) {
+    const BENCH_TIME: Duration = Duration::from_secs(1);
+    // Warm up, and find out roughly how many calls fit in the time
+    let start = Instant::now();
+    let mut warmup = 0u64;
+    while start.elapsed() < BENCH_TIME / 10 {
+        f();
+        warmup += 1;
+    }
+    let iterations = warmup * 10;
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    let per_iteration = start.elapsed() / iterations.try_into().unwrap_or(u32::MAX);
+    println!(
+        "{:<40} {:>12?}/iter ({} iterations)",
+        name, per_iteration, iterations
+    );
+}
+
+/// Trial points spread over the continental US, from the Gulf to Canada and
+/// coast to coast, so some are near chargers and some far from any.
+fn trial_points() -> Vec<TrialPoint> {
+    let mut points = Vec::new();
+    for lat in (25..=49).step_by(3) {
+        for lon in (-124..=-67).step_by(4) {
+            points.push(TrialPoint {
+                latitude: lat as f64 + 0.5,
+                longitude: lon as f64 + 0.5,
+            });
+        }
+    }
+    points
+}
+
+fn main() {
+    let chargers = read_csv(
+        csv::Reader::from_path(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/testdata/chargers.csv"
+        ))
+        .expect("testdata/chargers.csv is missing"),
+        &ChargerFilter::default(),
+    )
+    .unwrap();
+    let points = trial_points();
+    println!(
+        "{} chargers, {} trial points",
+        chargers.chargers_by_id.len(),
+        points.len()
+    );
+
+    for metric in [DistanceMetric::Haversine, DistanceMetric::Geodesic] {
+        bench(
+            &format!("nearest_chargers ({:?}, all points)", metric),
+            || {
+                for point in &points {
+                    black_box(point.nearest_chargers(
+                        black_box(&chargers),
+                        MAX_RANGE_METERS,
+                        metric,
+                    ));
+                }
+            },
+        );
+    }
+
+    for k in [1, 10] {
+        bench(
+            &format!("nearest_chargers_k (k = {}, all points)", k),
+            || {
+                for point in &points {
+                    black_box(point.nearest_chargers_k(
+                        black_box(&chargers),
+                        MAX_RANGE_METERS,
+                        DistanceMetric::Haversine,
+                        k,
+                    ));
+                }
+            },
+        );
+    }
+
+    let sphere = AllChargerLocations::with_backend(
+        chargers.chargers_by_id.values().cloned(),
+        IndexBackend::Sphere,
+    );
+    bench("nearest_chargers (Sphere, all points)", || {
+        for point in &points {
+            black_box(point.nearest_chargers(
+                black_box(&sphere),
+                MAX_RANGE_METERS,
+                DistanceMetric::Haversine,
+            ));
+        }
+    });
+    for k in [1, 10] {
+        bench(
+            &format!("nearest_chargers_k (Sphere, k = {}, all points)", k),
+            || {
+                for point in &points {
+                    black_box(point.nearest_chargers_k(
+                        black_box(&sphere),
+                        MAX_RANGE_METERS,
+                        DistanceMetric::Haversine,
+                        k,
+                    ));
+                }
+            },
+        );
+    }
+
+    let charger = chargers.chargers_by_id.values().next().unwrap();
+    let point = points[points.len() / 2];
+    bench("distance_to", || {
+        black_box(black_box(point).distance_to(black_box(charger)));
+    });
+    bench("distance_to_with (Geodesic)", || {
+        black_box(black_box(point).distance_to_with(black_box(charger), DistanceMetric::Geodesic));
+    });
+}
@@ -0,0 +1,77 @@
+//! Benchmark for reading a nationwide-sized charger CSV: parsing, filtering,
+//! and building the index.
+//!
+//! Run with `cargo bench --bench read_csv`. Prints the mean time per read.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use ev_charging_gaps::*;
+
+/// Rows in the generated CSV, a few times the size of the NREL data.
+const ROWS: u64 = 300_000;
+
+/// Runs `f` repeatedly for about `BENCH_TIME` (at least 3 times) and prints
+/// the mean time per call.
+fn bench(name: &str, mut f: impl FnMut()) {
+    const BENCH_TIME: Duration = Duration::from_secs(3);
+    let start = Instant::now();
+    let mut iterations = 0u32;
+    while iterations < 3 || start.elapsed() < BENCH_TIME {
+        f();
+        iterations += 1;
+    }
+    println!(
+        "{:<40} {:>12?}/iter ({} iterations)",
+        name,
+        start.elapsed() / iterations,
+        iterations
+    );
+}
+
+/// A CSV in the NREL format, with chargers spread over the continental US
+/// on a handful of networks. One row in a thousand repeats an earlier id.
+fn generate_csv() -> String {
+    const NETWORKS: [&str; 5] = [
+        "ChargePoint Network",
+        "Tesla",
+        "Electrify America",
+        "EVgo Network",
+        "Non-Networked",
+    ];
+    let mut csv = String::from(
+        "ID,Latitude,Longitude,EV Network,EV Level2 EVSE Num,EV DC Fast Count,Open Date\n",
+    );
+    for i in 0..ROWS {
+        let id = if i % 1000 == 999 { i / 2 } else { i };
+        // Cheap scrambling, so the points aren't in a line
+        let x = (i.wrapping_mul(2_654_435_761) % 100_000) as f64 / 100_000.0;
+        let y = (i.wrapping_mul(40_503) % 100_000) as f64 / 100_000.0;
+        csv.push_str(&format!(
+            "{},{:.5},{:.5},{},{},{},20{:02}-0{}-15\n",
+            id,
+            25.0 + 24.0 * x,
+            -124.0 + 57.0 * y,
+            NETWORKS[(i % 5) as usize],
+            i % 4,
+            i % 3,
+            10 + i % 13,
+            1 + i % 9
+        ));
+    }
+    csv
+}
+
+fn main() {
+    let csv = generate_csv();
+    println!("{} rows, {} MB", ROWS, csv.len() / 1_000_000);
+    bench("read_csv", || {
+        black_box(
+            read_csv(
+                csv::Reader::from_reader(csv.as_bytes()),
+                &ChargerFilter::default(),
+            )
+            .unwrap(),
+        );
+    });
+}
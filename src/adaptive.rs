@@ -0,0 +1,210 @@
+//! Adaptive grids: checking a coarse grid first, and only checking the
+//! points of a fine grid where the coarse one changes from reachable to
+//! unreachable.
+//!
+//! Most of a large bounding box is either well within reach of chargers or
+//! far from all of them, and a uniform fine grid spends nearly all of its
+//! lookups there. Here each coarse cell is only split in four (recursively,
+//! down to the fine resolution) if its corners disagree; every fine grid
+//! point in a cell whose corners agree gets their answer without being
+//! checked. The result has the same points as a uniform grid at the fine
+//! resolution, so hulls and output work the same way.
+//!
+//! A gap (or a reachable area) small enough to fit between the corners of a
+//! coarse cell without touching any of them is missed, so the coarse
+//! resolution should be smaller than the smallest gap worth finding.
+
+use std::collections::HashMap;
+use std::thread;
+use std::time::Instant;
+
+use geo::algorithm::contains::Contains;
+use geo::MultiPolygon;
+use log::debug;
+
+use crate::routing::RouteProvider;
+use crate::{
+    AllChargerLocations, BoundingBox, GapReport, HullConfig, PointBudget, RangeConfig,
+    ReportConfig, TrialPoint,
+};
+
+/// Grid sizes for `adaptive_find_gaps`, in degrees.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdaptiveResolution {
+    /// Resolution of the grid in the result, and the smallest cells that are
+    /// checked.
+    pub min: f64,
+    /// Size of the cells to start with. It is rounded down to a power of two
+    /// times `min`, so that cells can be split in half evenly.
+    pub max: f64,
+}
+
+impl AdaptiveResolution {
+    /// How many fine grid points across the starting cells are.
+    fn coarse_cells(&self) -> u64 {
+        // A little slack, so that e.g. 0.08 / 0.01 = 7.999... still gives 8
+        let ratio = (self.max / self.min * (1.0 + 1e-9)).max(1.0);
+        1 << (ratio.log2().floor() as u32).min(62)
+    }
+}
+
+/// Checks points of the fine grid (by index from the south west corner of the
+/// bounding box), remembering the answers since neighbouring cells share
+/// corners.
+struct Sampler<'a> {
+    chargers: &'a AllChargerLocations,
+    routes: &'a dyn RouteProvider,
+    config: &'a RangeConfig,
+    budget: Option<&'a PointBudget>,
+    bbox: BoundingBox,
+    resolution: f64,
+    known: HashMap<(u64, u64), bool>,
+    maybe: usize,
+}
+
+impl Sampler<'_> {
+    fn point(&self, lat: u64, lon: u64) -> TrialPoint {
+        // Same as `BoundingBox::generate_grid`
+        TrialPoint {
+            latitude: self.bbox.lat_min + (lat as f64 * self.resolution),
+            longitude: self.bbox.lon_min + (lon as f64 * self.resolution),
+        }
+    }
+
+    fn reachable(&mut self, lat: u64, lon: u64) -> bool {
+        if let Some(&reachable) = self.known.get(&(lat, lon)) {
+            return reachable;
+        }
+        let point = self.point(lat, lon);
+        let (reachable, asked_routes) =
+            self.chargers
+                .point_reachable(&point, self.routes, self.config, self.budget);
+        if asked_routes {
+            self.maybe += 1;
+        }
+        self.known.insert((lat, lon), reachable);
+        reachable
+    }
+}
+
+impl AllChargerLocations {
+    /// Like `find_gaps` with a grid at `resolution.min`, but only checks the
+    /// points near the edges of gaps (see the module docs). The report counts
+    /// every point of the fine grid, including the ones that weren't checked,
+    /// while `maybe` and `routed_points` only count the points looked up. Once
+    /// `budget` is spent, points that need a lookup are unreachable.
+    #[allow(clippy::too_many_arguments)]
+    pub fn adaptive_find_gaps(
+        &self,
+        resolution: AdaptiveResolution,
+        bbox: BoundingBox,
+        routes: &dyn RouteProvider,
+        region: Option<&MultiPolygon<f64>>,
+        config: &RangeConfig,
+        hull: &HullConfig,
+        report: &ReportConfig,
+        budget: Option<&PointBudget>,
+    ) -> GapReport {
+        let _span = tracing::debug_span!(
+            crate::profile::CHUNK_SPAN,
+            lat_min = bbox.lat_min,
+            lon_min = bbox.lon_min
+        )
+        .entered();
+        let start = Instant::now();
+        // Same number of points as `BoundingBox::generate_grid`
+        let lat_points = (bbox.lat_span() / resolution.min) as u64;
+        let lon_points = (bbox.lon_span() / resolution.min) as u64;
+        let coarse = resolution.coarse_cells();
+        let mut sampler = Sampler {
+            chargers: self,
+            routes,
+            config,
+            budget,
+            bbox,
+            resolution: resolution.min,
+            known: HashMap::new(),
+            maybe: 0,
+        };
+        // Cells are (lat, lon, size), and cover the fine grid points from
+        // lat to lat + size (exclusive) and likewise for lon. Their far
+        // corners can be past the edge of the bounding box.
+        let mut cells: Vec<(u64, u64, u64)> = (0..lat_points)
+            .step_by(coarse as usize)
+            .flat_map(|lat| {
+                (0..lon_points)
+                    .step_by(coarse as usize)
+                    .map(move |lon| (lat, lon, coarse))
+            })
+            .collect();
+        let mut labels = vec![false; (lat_points * lon_points) as usize];
+        while let Some((lat, lon, size)) = cells.pop() {
+            let corner = sampler.reachable(lat, lon);
+            let split = size > 1
+                && [(size, 0), (0, size), (size, size)]
+                    .iter()
+                    .any(|&(dlat, dlon)| sampler.reachable(lat + dlat, lon + dlon) != corner);
+            if split {
+                let half = size / 2;
+                for (dlat, dlon) in [(0, 0), (half, 0), (0, half), (half, half)] {
+                    if lat + dlat < lat_points && lon + dlon < lon_points {
+                        cells.push((lat + dlat, lon + dlon, half));
+                    }
+                }
+                continue;
+            }
+            for i in lat..(lat + size).min(lat_points) {
+                for j in lon..(lon + size).min(lon_points) {
+                    // Points on the edge may have been checked for a
+                    // neighbouring cell, and then their answer is better
+                    // than the guess.
+                    labels[(i * lon_points + j) as usize] =
+                        sampler.known.get(&(i, j)).copied().unwrap_or(corner);
+                }
+            }
+        }
+
+        let mut reachable = 0;
+        let mut unreachable_points = Vec::new();
+        let mut reachable_points = Vec::new();
+        for i in 0..lat_points {
+            for j in 0..lon_points {
+                let point = sampler.point(i, j);
+                let lon_lat = point.to_geo_point();
+                if region.is_some_and(|region| !region.contains(&lon_lat)) {
+                    continue;
+                }
+                if labels[(i * lon_points + j) as usize] {
+                    reachable += 1;
+                    if report.emit_reachable {
+                        reachable_points.push(lon_lat);
+                    }
+                } else {
+                    unreachable_points.push(lon_lat);
+                }
+            }
+        }
+        debug!(
+            "{:?} DONE adaptive resolution: {} to {}, Total points: {}, Checked: {}, Reachable: {}, Unreachable: {}, Routed points: {}",
+            thread::current().id(),
+            resolution.max,
+            resolution.min,
+            labels.len(),
+            sampler.known.len(),
+            reachable,
+            unreachable_points.len(),
+            sampler.maybe
+        );
+        GapReport {
+            reachable_points: report.emit_reachable.then_some(reachable_points),
+            ..GapReport::new(
+                unreachable_points,
+                hull,
+                reachable,
+                sampler.maybe,
+                sampler.maybe,
+                start.elapsed(),
+            )
+        }
+    }
+}
@@ -0,0 +1,345 @@
+//! Alpha shapes: the part of the Delaunay triangulation of a set of points
+//! made of triangles whose circumcircle has a radius of at most alpha. Unlike
+//! a concave hull, this can have holes and several separate parts, and it
+//! never intersects itself.
+//!
+//! geo doesn't have a Delaunay triangulation, so this has a simple
+//! Bowyer-Watson one.
+
+use std::collections::HashMap;
+
+use geo::algorithm::contains::Contains;
+use geo::{Coordinate, LineString, MultiPolygon, Polygon};
+
+/// Marks a triangle edge with no triangle on the other side.
+const NONE: usize = usize::MAX;
+
+/// Alpha shape of `points` with radius `alpha` (in the same units as the
+/// points). Larger values fill in more, approaching the convex hull; values
+/// smaller than the spacing of the points give an empty shape.
+pub fn alpha_shape(points: &[geo::Point<f64>], alpha: f64) -> MultiPolygon<f64> {
+    if points.len() < 3 || alpha <= 0.0 {
+        return MultiPolygon(vec![]);
+    }
+    let triangulation = Triangulation::new(points, alpha);
+    let kept: Vec<bool> = triangulation
+        .triangles
+        .iter()
+        .map(|triangle| {
+            triangle.alive
+                && triangle.v.iter().all(|&v| v < points.len())
+                && triangulation.circumradius(triangle) <= alpha
+        })
+        .collect();
+    // Edges of kept triangles that don't border another kept triangle. The
+    // triangles are counterclockwise, so the shape is on the left of each.
+    let mut outgoing: HashMap<usize, Vec<usize>> = HashMap::new();
+    let mut edge_count = 0;
+    for (t, triangle) in triangulation.triangles.iter().enumerate() {
+        if !kept[t] {
+            continue;
+        }
+        for i in 0..3 {
+            let neighbor = triangle.n[i];
+            if neighbor == NONE || !kept[neighbor] {
+                let (from, to) = (triangle.v[(i + 1) % 3], triangle.v[(i + 2) % 3]);
+                outgoing.entry(from).or_default().push(to);
+                edge_count += 1;
+            }
+        }
+    }
+    let rings = trace_rings(points, outgoing, edge_count);
+    let (exteriors, holes): (Vec<_>, Vec<_>) =
+        rings.into_iter().partition(|ring| signed_area(ring) > 0.0);
+    let mut polygons: Vec<Polygon<f64>> = exteriors
+        .into_iter()
+        .map(|ring| Polygon::new(ring, vec![]))
+        .collect();
+    for hole in holes {
+        let inside = hole.0[0];
+        if let Some(polygon) = polygons
+            .iter_mut()
+            .find(|polygon| Polygon::new(polygon.exterior().clone(), vec![]).contains(&inside))
+        {
+            polygon.interiors_push(hole);
+        }
+    }
+    MultiPolygon(polygons)
+}
+
+/// Joins the boundary edges into closed rings. Where two parts of the shape
+/// touch at a single point, each ring takes the edge that keeps it around
+/// its own part: the first one clockwise from the edge it came in on.
+fn trace_rings(
+    points: &[geo::Point<f64>],
+    mut outgoing: HashMap<usize, Vec<usize>>,
+    mut edges_left: usize,
+) -> Vec<LineString<f64>> {
+    let mut rings = Vec::new();
+    let mut starts: Vec<usize> = outgoing.keys().copied().collect();
+    starts.sort_unstable();
+    for start in starts {
+        while outgoing.get(&start).is_some_and(|edges| !edges.is_empty()) {
+            let mut ring = vec![points[start].0];
+            let mut from = start;
+            let mut to = outgoing.get_mut(&start).unwrap().pop().unwrap();
+            edges_left -= 1;
+            while to != start {
+                ring.push(points[to].0);
+                let candidates = match outgoing.get_mut(&to) {
+                    Some(candidates) if !candidates.is_empty() => candidates,
+                    // Can only happen if the triangulation is broken
+                    _ => break,
+                };
+                let back = points[from].0 - points[to].0;
+                let next = (0..candidates.len())
+                    .min_by(|&a, &b| {
+                        let angle = |i: usize| {
+                            clockwise_angle(back, points[candidates[i]].0 - points[to].0)
+                        };
+                        angle(a).total_cmp(&angle(b))
+                    })
+                    .unwrap();
+                from = to;
+                to = candidates.swap_remove(next);
+                edges_left -= 1;
+            }
+            ring.push(points[start].0);
+            if ring.len() >= 4 {
+                rings.push(LineString(ring));
+            }
+        }
+    }
+    debug_assert_eq!(edges_left, 0);
+    rings
+}
+
+/// Angle to turn clockwise from direction `a` to direction `b`, in
+/// `(0, 2π]`.
+fn clockwise_angle(a: Coordinate<f64>, b: Coordinate<f64>) -> f64 {
+    let counterclockwise = (a.x * b.y - a.y * b.x).atan2(a.x * b.x + a.y * b.y);
+    let clockwise = -counterclockwise;
+    if clockwise <= 0.0 {
+        clockwise + 2.0 * std::f64::consts::PI
+    } else {
+        clockwise
+    }
+}
+
+/// Positive if `ring` is counterclockwise.
+fn signed_area(ring: &LineString<f64>) -> f64 {
+    ring.0
+        .windows(2)
+        .map(|w| w[0].x * w[1].y - w[1].x * w[0].y)
+        .sum::<f64>()
+        / 2.0
+}
+
+#[derive(Clone, Debug)]
+struct Triangle {
+    /// Vertices, counterclockwise.
+    v: [usize; 3],
+    /// `n[i]` is the triangle across the edge opposite `v[i]`, or `NONE`.
+    n: [usize; 3],
+    alive: bool,
+}
+
+/// Delaunay triangulation, built by inserting one point at a time into a
+/// triangle big enough to contain all of them (whose corners are the last
+/// three vertices).
+struct Triangulation {
+    vertices: Vec<Coordinate<f64>>,
+    triangles: Vec<Triangle>,
+}
+
+impl Triangulation {
+    /// `scale` is roughly the spacing of the points, and is used to nudge
+    /// them so that no four are exactly on a circle, which grid points
+    /// always are.
+    fn new(points: &[geo::Point<f64>], scale: f64) -> Triangulation {
+        let mut vertices: Vec<Coordinate<f64>> = points
+            .iter()
+            .enumerate()
+            .map(|(i, point)| {
+                let (dx, dy) = jitter(i);
+                Coordinate {
+                    x: point.x() + dx * scale * 1e-6,
+                    y: point.y() + dy * scale * 1e-6,
+                }
+            })
+            .collect();
+        let (mut min_x, mut min_y) = (f64::INFINITY, f64::INFINITY);
+        let (mut max_x, mut max_y) = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+        for vertex in &vertices {
+            min_x = min_x.min(vertex.x);
+            min_y = min_y.min(vertex.y);
+            max_x = max_x.max(vertex.x);
+            max_y = max_y.max(vertex.y);
+        }
+        let size = (max_x - min_x).max(max_y - min_y).max(scale);
+        let (center_x, center_y) = ((min_x + max_x) / 2.0, (min_y + max_y) / 2.0);
+        let n = vertices.len();
+        vertices.push(Coordinate {
+            x: center_x - 100.0 * size,
+            y: center_y - 100.0 * size,
+        });
+        vertices.push(Coordinate {
+            x: center_x + 100.0 * size,
+            y: center_y - 100.0 * size,
+        });
+        vertices.push(Coordinate {
+            x: center_x,
+            y: center_y + 100.0 * size,
+        });
+        let mut triangulation = Triangulation {
+            vertices,
+            triangles: vec![Triangle {
+                v: [n, n + 1, n + 2],
+                n: [NONE; 3],
+                alive: true,
+            }],
+        };
+        // Inserting points in order along x keeps each one close to the
+        // previous one, so finding the triangle it is in is quick.
+        let mut order: Vec<usize> = (0..n).collect();
+        order.sort_by(|&a, &b| {
+            let (a, b) = (triangulation.vertices[a], triangulation.vertices[b]);
+            a.x.total_cmp(&b.x).then(a.y.total_cmp(&b.y))
+        });
+        let mut last = 0;
+        for p in order {
+            last = triangulation.insert(p, last);
+        }
+        triangulation
+    }
+
+    /// Adds vertex `p`, starting the search for the triangle containing it at
+    /// triangle `start`. Returns one of the new triangles.
+    fn insert(&mut self, p: usize, start: usize) -> usize {
+        let point = self.vertices[p];
+        let containing = self.locate(point, start);
+        // The triangles whose circumcircle contains the point form a
+        // connected cavity around it.
+        let mut bad = vec![containing];
+        let mut is_bad: HashMap<usize, bool> = HashMap::from([(containing, true)]);
+        let mut i = 0;
+        while i < bad.len() {
+            for neighbor in self.triangles[bad[i]].n {
+                if neighbor != NONE && !is_bad.contains_key(&neighbor) {
+                    let inside = self.in_circumcircle(&self.triangles[neighbor], point);
+                    is_bad.insert(neighbor, inside);
+                    if inside {
+                        bad.push(neighbor);
+                    }
+                }
+            }
+            i += 1;
+        }
+        // Connect the point to each edge on the boundary of the cavity.
+        let mut by_start = HashMap::new();
+        let mut by_end = HashMap::new();
+        let mut created = Vec::new();
+        for &t in &bad {
+            for i in 0..3 {
+                let triangle = &self.triangles[t];
+                let outside = triangle.n[i];
+                if outside != NONE && is_bad[&outside] {
+                    continue;
+                }
+                let (a, b) = (triangle.v[(i + 1) % 3], triangle.v[(i + 2) % 3]);
+                let new = self.triangles.len();
+                self.triangles.push(Triangle {
+                    v: [a, b, p],
+                    n: [NONE, NONE, outside],
+                    alive: true,
+                });
+                if outside != NONE {
+                    let across = &mut self.triangles[outside];
+                    let j = across.n.iter().position(|&n| n == t).unwrap();
+                    across.n[j] = new;
+                }
+                by_start.insert(a, new);
+                by_end.insert(b, new);
+                created.push(new);
+            }
+        }
+        for &new in &created {
+            let [a, b, _] = self.triangles[new].v;
+            // Across b-p is the new triangle starting at b, across p-a the one
+            // ending at a.
+            self.triangles[new].n[0] = by_start[&b];
+            self.triangles[new].n[1] = by_end[&a];
+        }
+        for t in bad {
+            self.triangles[t].alive = false;
+        }
+        created[0]
+    }
+
+    /// Finds a live triangle containing `point` by walking towards it from
+    /// triangle `start`.
+    fn locate(&self, point: Coordinate<f64>, start: usize) -> usize {
+        let mut t = start;
+        // A walk can't take more steps than there are triangles
+        for _ in 0..self.triangles.len() {
+            let triangle = &self.triangles[t];
+            let outside = (0..3).find(|&i| {
+                let (a, b) = (triangle.v[(i + 1) % 3], triangle.v[(i + 2) % 3]);
+                triangle.n[i] != NONE && orient(self.vertices[a], self.vertices[b], point) < 0.0
+            });
+            match outside {
+                Some(i) => t = triangle.n[i],
+                None => return t,
+            }
+        }
+        // Shouldn't happen, but a linear search always works
+        (0..self.triangles.len())
+            .find(|&t| {
+                let triangle = &self.triangles[t];
+                triangle.alive
+                    && (0..3).all(|i| {
+                        let (a, b) = (triangle.v[(i + 1) % 3], triangle.v[(i + 2) % 3]);
+                        orient(self.vertices[a], self.vertices[b], point) >= 0.0
+                    })
+            })
+            .expect("point is outside the super triangle")
+    }
+
+    fn in_circumcircle(&self, triangle: &Triangle, point: Coordinate<f64>) -> bool {
+        let [a, b, c] = triangle.v.map(|v| self.vertices[v] - point);
+        let det = (a.x * a.x + a.y * a.y) * (b.x * c.y - c.x * b.y)
+            - (b.x * b.x + b.y * b.y) * (a.x * c.y - c.x * a.y)
+            + (c.x * c.x + c.y * c.y) * (a.x * b.y - b.x * a.y);
+        det > 0.0
+    }
+
+    fn circumradius(&self, triangle: &Triangle) -> f64 {
+        let [a, b, c] = triangle.v.map(|v| self.vertices[v]);
+        let side = |p: Coordinate<f64>, q: Coordinate<f64>| (p.x - q.x).hypot(p.y - q.y);
+        let area2 = orient(a, b, c).abs();
+        if area2 == 0.0 {
+            return f64::INFINITY;
+        }
+        side(a, b) * side(b, c) * side(c, a) / (2.0 * area2)
+    }
+}
+
+/// Twice the signed area of triangle `a`, `b`, `c`: positive if they are
+/// counterclockwise.
+fn orient(a: Coordinate<f64>, b: Coordinate<f64>, c: Coordinate<f64>) -> f64 {
+    (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)
+}
+
+/// Deterministic pseudo-random offsets in `[-1, 1)` for point `i`
+/// (SplitMix64).
+fn jitter(i: usize) -> (f64, f64) {
+    let mix = |mut z: u64| {
+        z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+    let unit = |z: u64| (z >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0;
+    let a = mix(i as u64);
+    (unit(a), unit(mix(a)))
+}
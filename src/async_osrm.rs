@@ -0,0 +1,270 @@
+//! Async OSRM lookups, so that many requests can be in flight at once instead
+//! of each one blocking a worker thread while it waits on the network.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures_util::stream::{self, StreamExt};
+use geo::MultiPolygon;
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    network::{random_fraction, retry_after_header},
+    osrm_cache::OsrmCache,
+    AllChargerLocations, BoundingBox, ChargerLocation, CheckResult, GapReport, HullConfig, Json,
+    RangeConfig, RangeMode, ReportConfig, Resolution, RetryPolicy, TrialPoint,
+    DEFAULT_OSRM_PROFILE, MAX_OSRM_CANDIDATES, OSRM_TIMEOUT_SECS,
+};
+
+/// Async counterpart of `OsrmClient`.
+#[derive(Clone, Debug)]
+pub struct AsyncOsrmClient {
+    /// Base url of the OSRM server, without a trailing slash.
+    pub url: String,
+    /// Routing profile in the request paths, see `OsrmClient::profile`.
+    pub profile: String,
+    pub client: reqwest::Client,
+    /// Distances already looked up, if caching is enabled.
+    pub cache: Option<Arc<OsrmCache>>,
+    /// Maximum number of trial points being looked up at the same time.
+    pub max_in_flight: usize,
+    /// How failed requests are retried.
+    pub retry: RetryPolicy,
+    /// Maximum number of closest chargers to look up per point, see
+    /// `MAX_OSRM_CANDIDATES`.
+    pub max_candidates: usize,
+}
+
+impl AsyncOsrmClient {
+    pub fn new(url: &str, max_in_flight: usize) -> AsyncOsrmClient {
+        AsyncOsrmClient {
+            url: url.to_string(),
+            profile: DEFAULT_OSRM_PROFILE.to_string(),
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(OSRM_TIMEOUT_SECS))
+                .build()
+                .expect("failed to build HTTP client"),
+            cache: None,
+            max_in_flight,
+            retry: RetryPolicy::default(),
+            max_candidates: MAX_OSRM_CANDIDATES,
+        }
+    }
+}
+
+impl TrialPoint {
+    /// Async version of `get_osrm_distance`.
+    pub async fn get_osrm_distance_async(
+        &self,
+        osrm: &AsyncOsrmClient,
+        charger: &ChargerLocation,
+    ) -> Option<f64> {
+        let src = (self.longitude, self.latitude);
+        let dst = (charger.longitude, charger.latitude);
+        if let Some(distance) = osrm.cache.as_ref().and_then(|cache| cache.get(src, dst)) {
+            return Some(distance);
+        }
+        let osrm_api_url = format!(
+            "{}/route/v1/{}/{},{};{},{}",
+            osrm.url,
+            osrm.profile,
+            self.longitude,
+            self.latitude,
+            charger.longitude,
+            charger.latitude
+        );
+        let body: Json = get_osrm_json_async(&osrm_api_url, osrm).await?;
+        let distance = body.routes.first()?.distance;
+        if let Some(cache) = &osrm.cache {
+            cache.insert(src, dst, distance);
+        }
+        Some(distance)
+    }
+
+    /// Async driving time in seconds to `charger`. Not cached, since the
+    /// cache only holds distances.
+    pub async fn get_osrm_duration_async(
+        &self,
+        osrm: &AsyncOsrmClient,
+        charger: &ChargerLocation,
+    ) -> Option<f64> {
+        let osrm_api_url = format!(
+            "{}/route/v1/{}/{},{};{},{}",
+            osrm.url,
+            osrm.profile,
+            self.longitude,
+            self.latitude,
+            charger.longitude,
+            charger.latitude
+        );
+        let body: Json = get_osrm_json_async(&osrm_api_url, osrm).await?;
+        Some(body.routes.first()?.duration)
+    }
+
+    /// Whether any of the (at most `osrm.max_candidates`) closest `candidates` is within range by
+    /// road according to `config.mode`. Candidates are tried closest first, stopping at the first one
+    /// that is in range.
+    async fn any_reachable_async(
+        &self,
+        osrm: &AsyncOsrmClient,
+        candidates: Vec<(ChargerLocation, u64)>,
+        config: &RangeConfig,
+    ) -> bool {
+        for (charger, _) in candidates.into_iter().take(osrm.max_candidates) {
+            let in_range = match config.mode {
+                RangeMode::Distance => self
+                    .get_osrm_distance_async(osrm, &charger)
+                    .await
+                    .is_some_and(|distance| distance as u64 <= config.max_range_meters),
+                RangeMode::Duration => self
+                    .get_osrm_duration_async(osrm, &charger)
+                    .await
+                    .is_some_and(|duration| duration as u64 <= config.max_duration_secs),
+            };
+            if in_range {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl AllChargerLocations {
+    /// Async version of `find_gaps`. Maybe-reachable points are looked up
+    /// concurrently, with up to `osrm.max_in_flight` of them at a time.
+    ///
+    /// The unreachable points are reported in grid order, the same as
+    /// `find_gaps`.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn find_gaps_async(
+        &self,
+        resolution: impl Into<Resolution>,
+        bbox: BoundingBox,
+        osrm: &AsyncOsrmClient,
+        region: Option<&MultiPolygon<f64>>,
+        config: &RangeConfig,
+        hull: &HullConfig,
+        report: &ReportConfig,
+    ) -> GapReport {
+        let start = Instant::now();
+        let resolution = resolution.into();
+        let grid = bbox.generate_grid_within(resolution, region);
+        let total = grid.len();
+        let mut unreachable = Vec::new();
+        let mut reachable_points = Vec::new();
+        let mut maybe = Vec::new();
+        for (i, point) in grid.into_iter().enumerate() {
+            match point.check_charger(self, config) {
+                CheckResult::Yes => reachable_points.push((i, point)),
+                CheckResult::No => unreachable.push((i, point)),
+                CheckResult::Maybe { candidates } => maybe.push((i, point, candidates)),
+            }
+        }
+        let maybe_reachable = maybe.len();
+        let looked_up: Vec<_> = stream::iter(maybe)
+            .map(|(i, point, candidates)| async move {
+                let is_reachable = point.any_reachable_async(osrm, candidates, config).await;
+                (i, point, is_reachable)
+            })
+            .buffer_unordered(osrm.max_in_flight.max(1))
+            .collect()
+            .await;
+        for (i, point, is_reachable) in looked_up {
+            if is_reachable {
+                reachable_points.push((i, point));
+            } else {
+                unreachable.push((i, point));
+            }
+        }
+        unreachable.sort_by_key(|(i, _)| *i);
+        reachable_points.sort_by_key(|(i, _)| *i);
+        debug!(
+            "DONE Resolution: {}, Total points: {}, Reachable: {}, Unreachable: {}, Unknown: {}",
+            resolution,
+            total,
+            total - unreachable.len(),
+            unreachable.len(),
+            maybe_reachable
+        );
+        let reachable = total - unreachable.len();
+        let lon_lat = |points: Vec<(usize, TrialPoint)>| {
+            points
+                .into_iter()
+                .map(|(_, point)| point.to_geo_point())
+                .collect()
+        };
+        GapReport {
+            reachable_points: report.emit_reachable.then(|| lon_lat(reachable_points)),
+            ..GapReport::new(
+                lon_lat(unreachable),
+                hull,
+                reachable,
+                maybe_reachable,
+                maybe_reachable,
+                start.elapsed(),
+            )
+        }
+    }
+}
+
+/// Async version of `get_osrm_json`: retries on request errors and on
+/// unreadable responses from a struggling server (HTTP 5xx or 429) according
+/// to `osrm.retry`, waiting out a 429's `Retry-After`, and returns `None` if
+/// the body doesn't parse or every attempt failed.
+async fn get_osrm_json_async<T: DeserializeOwned>(
+    osrm_api_url: &str,
+    osrm: &AsyncOsrmClient,
+) -> Option<T> {
+    let mut retries = 0;
+    loop {
+        let mut retry_after = None;
+        let error = match osrm.client.get(osrm_api_url).send().await {
+            Ok(response) => {
+                let status = response.status();
+                if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                    retry_after = retry_after_header(response.headers());
+                }
+                match response.text().await {
+                    Ok(body) => match serde_json::from_str::<T>(&body) {
+                        Ok(json) => return Some(json),
+                        Err(error)
+                            if status.is_server_error()
+                                || status == reqwest::StatusCode::TOO_MANY_REQUESTS =>
+                        {
+                            format!("{} body error: {}", status, error)
+                        }
+                        // Same as the blocking version, any other body that
+                        // doesn't parse means there is no possible path
+                        // between those points
+                        Err(error) => {
+                            warn!(
+                                "giving up ({}) {} body error: {}\nbody: {}",
+                                retries, status, error, body
+                            );
+                            return None;
+                        }
+                    },
+                    Err(error) => error.to_string(),
+                }
+            }
+            Err(error) => error.to_string(),
+        };
+        if retries >= osrm.retry.max_retries {
+            warn!(
+                "giving up after {} retries, request error: {}",
+                retries, error
+            );
+            return None;
+        }
+        warn!("retrying ({}) request error: {}", retries, error);
+        tokio::time::sleep(
+            osrm.retry
+                .jittered_delay(retries, retry_after, random_fraction()),
+        )
+        .await;
+        retries += 1;
+    }
+}
@@ -0,0 +1,78 @@
+//! Writing output files all at once: to a temporary file next to the real
+//! one, renamed over it only once the write has succeeded. A run that fails
+//! or is interrupted part way through writing leaves whatever was there
+//! before, rather than a truncated file that some readers take for whole.
+
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// Calls `write` with a temporary path in the same directory as `path`,
+/// with the same extension, then renames what it wrote to `path`. Files
+/// written next to it with the `sidecars` extensions (e.g. a shapefile's
+/// `shx` and `dbf`) are renamed along with it. If `write` fails, the
+/// temporary files are removed and `path` is left alone.
+///
+/// The directory is created if it doesn't exist yet.
+pub(crate) fn write_atomically(
+    path: &Path,
+    sidecars: &[&str],
+    write: impl FnOnce(&Path) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let partial = partial_path(path);
+    let extensions: Vec<_> = path
+        .extension()
+        .into_iter()
+        .chain(sidecars.iter().map(|extension| extension.as_ref()))
+        .collect();
+    let result = write(&partial).and_then(|()| {
+        for extension in &extensions {
+            fs::rename(
+                partial.with_extension(extension),
+                path.with_extension(extension),
+            )?;
+        }
+        if extensions.is_empty() {
+            fs::rename(&partial, path)?;
+        }
+        Ok(())
+    });
+    if result.is_err() {
+        for extension in &extensions {
+            let _ = fs::remove_file(partial.with_extension(extension));
+        }
+        let _ = fs::remove_file(&partial);
+    }
+    result
+}
+
+/// Like `write_atomically` for a single file, written through a buffer.
+pub(crate) fn write_file_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    write_atomically(path, &[], |partial| {
+        let mut writer = BufWriter::new(File::create(partial)?);
+        write(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    })
+}
+
+/// `dir/gaps.geojson` is written as `dir/.gaps-partial.geojson`, hidden and
+/// with the same extension, so that sidecar files line up.
+fn partial_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut name = format!(".{}-partial", stem);
+    if let Some(extension) = path.extension() {
+        name.push('.');
+        name.push_str(&extension.to_string_lossy());
+    }
+    path.with_file_name(name)
+}
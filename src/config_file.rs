@@ -0,0 +1,236 @@
+//! Reading command line options from a TOML file (`--config`), so that the
+//! settings of a study can be kept with its results.
+//!
+//! Options are all top level keys, so this only handles the subset of TOML
+//! they need: `key = value` pairs with strings, numbers, booleans and arrays
+//! of those, and comments. Tables are an error.
+
+use std::fmt;
+
+/// A value in a config file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigValue {
+    String(String),
+    /// Integers and floats, kept as written (less any `_` separators) since
+    /// they are passed on as command line arguments.
+    Number(String),
+    Bool(bool),
+    Array(Vec<ConfigValue>),
+}
+
+impl fmt::Display for ConfigValue {
+    /// The value as it would be written on the command line. Arrays are
+    /// joined with commas.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigValue::String(s) | ConfigValue::Number(s) => write!(f, "{}", s),
+            ConfigValue::Bool(b) => write!(f, "{}", b),
+            ConfigValue::Array(values) => {
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// What kind of command line option a config key sets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OptionKind {
+    /// An option without a value, like `--refresh`. Set with `true`, or a
+    /// number for options that can be repeated, like `verbose = 2`.
+    Flag,
+    /// An option with a value, like `--resolution 0.05`. An array passes the
+    /// option once per element.
+    Value,
+}
+
+/// Turns `key = value` pairs into command line arguments, to be parsed along
+/// with (and before) the actual ones. Keys are option names without the
+/// leading `--`, with `_` or `-` between words; `kind` says what kind of
+/// option a name is, or `None` if there is no such option.
+pub fn config_args(
+    pairs: &[(String, ConfigValue)],
+    kind: impl Fn(&str) -> Option<OptionKind>,
+) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    for (key, value) in pairs {
+        let long = key.replace('_', "-");
+        match (kind(&long), value) {
+            (None, _) => return Err(format!("unknown option {}", key)),
+            (Some(OptionKind::Flag), ConfigValue::Bool(set)) => {
+                if *set {
+                    args.push(format!("--{}", long));
+                }
+            }
+            (Some(OptionKind::Flag), ConfigValue::Number(count)) => {
+                let count: usize = count
+                    .parse()
+                    .map_err(|_| format!("{} must be true, false or a count", key))?;
+                args.extend((0..count).map(|_| format!("--{}", long)));
+            }
+            (Some(OptionKind::Flag), _) => {
+                return Err(format!("{} must be true, false or a count", key))
+            }
+            (Some(OptionKind::Value), ConfigValue::Array(values)) => {
+                args.extend(values.iter().map(|value| format!("--{}={}", long, value)))
+            }
+            (Some(OptionKind::Value), value) => args.push(format!("--{}={}", long, value)),
+        }
+    }
+    Ok(args)
+}
+
+/// Parses the `key = value` pairs of a config file, in the order they are
+/// written. Errors say which line is wrong.
+pub fn parse_config(text: &str) -> Result<Vec<(String, ConfigValue)>, String> {
+    let mut pairs: Vec<(String, ConfigValue)> = Vec::new();
+    let mut lines = text.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+        let line_number = i + 1;
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') {
+            return Err(format!(
+                "line {}: tables aren't supported, options go at the top level",
+                line_number
+            ));
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected key = value", line_number))?;
+        let key = key.trim().trim_matches('"');
+        if key.is_empty() {
+            return Err(format!("line {}: missing key", line_number));
+        }
+        // Arrays can be split over several lines
+        let mut value = value.trim().to_string();
+        while value.starts_with('[') && !brackets_balanced(&value) {
+            let (_, next) = lines
+                .next()
+                .ok_or_else(|| format!("line {}: unclosed array", line_number))?;
+            value.push(' ');
+            value.push_str(strip_comment(next).trim());
+        }
+        let (parsed, rest) =
+            parse_value(&value).map_err(|error| format!("line {}: {}", line_number, error))?;
+        if !rest.trim().is_empty() {
+            return Err(format!(
+                "line {}: unexpected {:?} after value",
+                line_number,
+                rest.trim()
+            ));
+        }
+        if pairs.iter().any(|(existing, _)| existing == key) {
+            return Err(format!("line {}: {} is set twice", line_number, key));
+        }
+        pairs.push((key.to_string(), parsed));
+    }
+    Ok(pairs)
+}
+
+/// `line` up to a `#` that isn't inside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+fn brackets_balanced(value: &str) -> bool {
+    let mut depth = 0;
+    let mut quote = None;
+    for c in value.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') => depth -= 1,
+            _ => {}
+        }
+    }
+    depth <= 0
+}
+
+/// Parses the value at the start of `text`, returning it and the rest.
+fn parse_value(text: &str) -> Result<(ConfigValue, &str), String> {
+    let text = text.trim_start();
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((ConfigValue::String(value), &rest[i + 1..])),
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    other => return Err(format!("unsupported escape \\{}", other.unwrap_or(' '))),
+                },
+                c => value.push(c),
+            }
+        }
+        return Err("unclosed string".to_string());
+    }
+    if let Some(rest) = text.strip_prefix('\'') {
+        let end = rest.find('\'').ok_or("unclosed string")?;
+        return Ok((
+            ConfigValue::String(rest[..end].to_string()),
+            &rest[end + 1..],
+        ));
+    }
+    if let Some(mut rest) = text.strip_prefix('[') {
+        let mut values = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((ConfigValue::Array(values), after));
+            }
+            let (value, after) = parse_value(rest)?;
+            values.push(value);
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') {
+                return Err("expected , or ] in array".to_string());
+            }
+        }
+    }
+    // Numbers and booleans run until a delimiter
+    let end = text
+        .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+        .unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+    let value = match word {
+        "true" => ConfigValue::Bool(true),
+        "false" => ConfigValue::Bool(false),
+        "" => return Err("missing value".to_string()),
+        word => {
+            let number = word.replace('_', "");
+            if number.parse::<f64>().is_err() {
+                return Err(format!("{:?} is not a string, number or boolean", word));
+            }
+            ConfigValue::Number(number)
+        }
+    };
+    Ok((value, rest))
+}
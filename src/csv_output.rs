@@ -0,0 +1,43 @@
+//! CSV output of the unreachable points, one row each with the closest they
+//! came to a charger (see `UnreachablePoint`), for looking at in a
+//! spreadsheet.
+
+use std::{error::Error, path::Path};
+
+use crate::{atomic::write_file_atomically, UnreachablePoint};
+
+/// Column names of `write_unreachable_csv`.
+pub const UNREACHABLE_CSV_HEADER: [&str; 6] = [
+    "lat",
+    "lon",
+    "nearest_charger_id",
+    "straight_line_meters",
+    "driving_meters",
+    "driving_secs",
+];
+
+/// Writes `points` to `path` as CSV with `UNREACHABLE_CSV_HEADER`. Values
+/// that aren't known, like the driving distance of a point that didn't need
+/// a lookup, are left empty.
+pub fn write_unreachable_csv(
+    path: &str,
+    points: &[UnreachablePoint],
+) -> Result<(), Box<dyn Error>> {
+    write_file_atomically(Path::new(path), |writer| {
+        let mut csv = csv::Writer::from_writer(writer);
+        csv.write_record(UNREACHABLE_CSV_HEADER)?;
+        let text = |value: Option<String>| value.unwrap_or_default();
+        for point in points {
+            csv.write_record([
+                point.point.latitude.to_string(),
+                point.point.longitude.to_string(),
+                text(point.nearest_charger.clone()),
+                text(point.straight_line_meters.map(|meters| meters.to_string())),
+                text(point.driving_meters.map(|meters| format!("{:.0}", meters))),
+                text(point.driving_secs.map(|secs| format!("{:.0}", secs))),
+            ])?;
+        }
+        csv.flush()?;
+        Ok(())
+    })
+}
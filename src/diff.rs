@@ -0,0 +1,81 @@
+//! Comparing the gaps of two charger snapshots, e.g. last month's NREL data
+//! and this month's, to see which gaps new chargers have closed and where
+//! new gaps have opened up (chargers closing, or moving).
+//!
+//! Both snapshots have to be searched with the same bounding box, resolution
+//! and chunks, so that they check the same grid points. The difference is
+//! then taken point by point, and the hulls are drawn around the points that
+//! changed, rather than by intersecting the two sets of hulls: hulls only
+//! approximate the points, so differences between them would mostly be
+//! slivers along edges that didn't really move.
+
+use std::collections::HashSet;
+
+use geo::MultiPolygon;
+
+use crate::{gap_area_km2, gap_hulls, GapReport, HullConfig};
+
+/// Gaps that changed between two snapshots, see `diff_gaps`.
+#[derive(Clone, Debug)]
+pub struct GapDiff {
+    /// Grid points that were in a gap with the old chargers, but are in
+    /// range of the new ones.
+    pub closed_points: Vec<geo::Point<f64>>,
+    /// Hulls around `closed_points`, see `gap_hulls`.
+    pub closed: MultiPolygon<f64>,
+    /// Grid points that were in range of the old chargers, but are in a gap
+    /// with the new ones.
+    pub opened_points: Vec<geo::Point<f64>>,
+    /// Hulls around `opened_points`.
+    pub opened: MultiPolygon<f64>,
+}
+
+impl GapDiff {
+    /// Area of the closed gaps in square kilometers, see `gap_area_km2`.
+    pub fn closed_area_km2(&self) -> f64 {
+        self.closed.iter().map(gap_area_km2).sum()
+    }
+
+    /// Area of the new gaps in square kilometers.
+    pub fn opened_area_km2(&self) -> f64 {
+        self.opened.iter().map(gap_area_km2).sum()
+    }
+}
+
+/// Which of the unreachable points of `old` aren't unreachable in `new`, and
+/// the other way round, with hulls drawn around each using `hull`. The
+/// reports can be for chunks or for the whole area, as long as both cover
+/// the same grid.
+pub fn diff_gaps(old: &[GapReport], new: &[GapReport], hull: &HullConfig) -> GapDiff {
+    let old_points = unreachable_points(old);
+    let new_points = unreachable_points(new);
+    let old_keys: HashSet<_> = old_points.iter().map(key).collect();
+    let new_keys: HashSet<_> = new_points.iter().map(key).collect();
+    let closed_points: Vec<_> = old_points
+        .into_iter()
+        .filter(|point| !new_keys.contains(&key(point)))
+        .collect();
+    let opened_points: Vec<_> = new_points
+        .into_iter()
+        .filter(|point| !old_keys.contains(&key(point)))
+        .collect();
+    GapDiff {
+        closed: gap_hulls(&closed_points, hull),
+        closed_points,
+        opened: gap_hulls(&opened_points, hull),
+        opened_points,
+    }
+}
+
+fn unreachable_points(reports: &[GapReport]) -> Vec<geo::Point<f64>> {
+    reports
+        .iter()
+        .flat_map(|report| report.unreachable_points.iter().copied())
+        .collect()
+}
+
+/// Grid points of the same grid are computed the same way in both runs, so
+/// they can be compared exactly.
+fn key(point: &geo::Point<f64>) -> (u64, u64) {
+    (point.x().to_bits(), point.y().to_bits())
+}
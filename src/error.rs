@@ -0,0 +1,85 @@
+use std::{error::Error, fmt};
+
+/// Everything that can go wrong while loading charger data or looking up
+/// routes.
+#[derive(Debug)]
+pub enum GapError {
+    /// The charger CSV couldn't be read or parsed.
+    Csv(csv::Error),
+    /// Charger data from the NREL JSON API couldn't be parsed.
+    Json(serde_json::Error),
+    /// A request to an external API failed.
+    #[cfg(feature = "network")]
+    Http(reqwest::Error),
+    /// Reading or writing a local file failed.
+    Io(std::io::Error),
+    /// OSRM didn't return any route between two points.
+    NoRoutes,
+    /// NREL answered with an error (e.g. an invalid API key, or too many
+    /// requests) instead of charger data. `message` is what it said, as far
+    /// as it could be made out.
+    NrelApi { status: u16, message: String },
+    /// The charger data didn't contain any usable chargers.
+    EmptyData,
+    /// The settings of a run don't make sense, e.g. an empty bounding box.
+    InvalidConfig(String),
+}
+
+impl fmt::Display for GapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GapError::Csv(error) => write!(f, "failed to read charger CSV: {}", error),
+            GapError::Json(error) => write!(f, "failed to read charger JSON: {}", error),
+            #[cfg(feature = "network")]
+            GapError::Http(error) => write!(f, "HTTP request failed: {}", error),
+            GapError::Io(error) => write!(f, "I/O error: {}", error),
+            GapError::NoRoutes => write!(f, "no route found"),
+            GapError::NrelApi { status, message } => {
+                write!(f, "NREL API error (HTTP {}): {}", status, message)
+            }
+            GapError::EmptyData => write!(f, "no chargers left after reading and filtering data"),
+            GapError::InvalidConfig(reason) => write!(f, "invalid configuration: {}", reason),
+        }
+    }
+}
+
+impl Error for GapError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GapError::Csv(error) => Some(error),
+            GapError::Json(error) => Some(error),
+            #[cfg(feature = "network")]
+            GapError::Http(error) => Some(error),
+            GapError::Io(error) => Some(error),
+            GapError::NoRoutes
+            | GapError::NrelApi { .. }
+            | GapError::EmptyData
+            | GapError::InvalidConfig(_) => None,
+        }
+    }
+}
+
+impl From<csv::Error> for GapError {
+    fn from(error: csv::Error) -> Self {
+        GapError::Csv(error)
+    }
+}
+
+impl From<serde_json::Error> for GapError {
+    fn from(error: serde_json::Error) -> Self {
+        GapError::Json(error)
+    }
+}
+
+#[cfg(feature = "network")]
+impl From<reqwest::Error> for GapError {
+    fn from(error: reqwest::Error) -> Self {
+        GapError::Http(error)
+    }
+}
+
+impl From<std::io::Error> for GapError {
+    fn from(error: std::io::Error) -> Self {
+        GapError::Io(error)
+    }
+}
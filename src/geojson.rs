@@ -0,0 +1,299 @@
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+};
+
+use geo::{Coordinate, LineString, MultiPolygon, Polygon};
+use serde_json::{json, Value};
+
+use crate::{
+    atomic::write_file_atomically, diff::GapDiff, AllChargerLocations, ChunkGap, GapReport,
+};
+
+/// Reads a GeoJSON file and collects every polygon in it into a single
+/// `MultiPolygon`, with x = longitude and y = latitude as in the GeoJSON spec.
+pub fn read_region_geojson(path: &str) -> Result<MultiPolygon<f64>, Box<dyn Error>> {
+    let contents = std::fs::read_to_string(path)?;
+    parse_region_geojson(&contents)
+}
+
+/// Parses a GeoJSON document (a bare geometry, a `Feature`, or a
+/// `FeatureCollection`) into a `MultiPolygon`. Non-polygon geometries are
+/// ignored, but it is an error if there are no polygons at all.
+pub fn parse_region_geojson(contents: &str) -> Result<MultiPolygon<f64>, Box<dyn Error>> {
+    let json: Value = serde_json::from_str(contents)?;
+    let mut polygons = Vec::new();
+    collect_polygons(&json, &mut polygons)?;
+    if polygons.is_empty() {
+        return Err("GeoJSON region does not contain any Polygon or MultiPolygon geometry".into());
+    }
+    Ok(MultiPolygon(polygons))
+}
+
+fn collect_polygons(json: &Value, polygons: &mut Vec<Polygon<f64>>) -> Result<(), Box<dyn Error>> {
+    match json["type"].as_str() {
+        Some("FeatureCollection") => {
+            for feature in json["features"].as_array().into_iter().flatten() {
+                collect_polygons(feature, polygons)?;
+            }
+        }
+        Some("Feature") => collect_polygons(&json["geometry"], polygons)?,
+        Some("GeometryCollection") => {
+            for geometry in json["geometries"].as_array().into_iter().flatten() {
+                collect_polygons(geometry, polygons)?;
+            }
+        }
+        Some("Polygon") => polygons.push(parse_polygon(&json["coordinates"])?),
+        Some("MultiPolygon") => {
+            for polygon in json["coordinates"].as_array().into_iter().flatten() {
+                polygons.push(parse_polygon(polygon)?);
+            }
+        }
+        // Points, lines etc. don't describe an area, so they can't be a region
+        _ => {}
+    }
+    Ok(())
+}
+
+fn parse_polygon(coordinates: &Value) -> Result<Polygon<f64>, Box<dyn Error>> {
+    let mut rings = coordinates
+        .as_array()
+        .ok_or("Polygon coordinates must be an array of rings")?
+        .iter()
+        .map(parse_ring)
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter();
+    let exterior = rings.next().ok_or("Polygon must have an exterior ring")?;
+    Ok(Polygon::new(exterior, rings.collect()))
+}
+
+fn parse_ring(ring: &Value) -> Result<LineString<f64>, Box<dyn Error>> {
+    ring.as_array()
+        .ok_or("Polygon ring must be an array of positions")?
+        .iter()
+        .map(
+            |position| match (position[0].as_f64(), position[1].as_f64()) {
+                (Some(x), Some(y)) => Ok(Coordinate { x, y }),
+                _ => Err("GeoJSON position must be [longitude, latitude]".into()),
+            },
+        )
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()
+        .map(LineString)
+}
+
+/// Builds a `FeatureCollection` with one `MultiPolygon` feature holding the
+/// gap polygons of each chunk, like `chunk_gaps_feature_collection`, with
+/// `reachable` set to false.
+///
+/// If there are any `reachable` points, they are one more `MultiPoint`
+/// feature with a null chunk and `reachable` set to true.
+pub fn gaps_feature_collection(gaps: &[ChunkGap], reachable: &[geo::Point<f64>]) -> Value {
+    let gap_features = gaps.iter().map(|gap| {
+        let mut feature = gap_feature(
+            gap.chunk_id,
+            gap.point_count,
+            &gap.polygons,
+            gap.area_km2,
+            gap.priority_score,
+        );
+        feature["properties"]["reachable"] = Value::Bool(false);
+        feature
+    });
+    let reachable_features = (!reachable.is_empty()).then(|| reachable_feature(reachable));
+    json!({
+        "type": "FeatureCollection",
+        "features": gap_features.chain(reachable_features).collect::<Vec<_>>(),
+    })
+}
+
+/// Writes the gaps as a GeoJSON `FeatureCollection`, see
+/// `gaps_feature_collection`.
+pub fn write_gaps_geojson(
+    path: &str,
+    gaps: &[ChunkGap],
+    reachable: &[geo::Point<f64>],
+) -> Result<(), Box<dyn Error>> {
+    write_file_atomically(Path::new(path), |writer| {
+        Ok(serde_json::to_writer(
+            writer,
+            &gaps_feature_collection(gaps, reachable),
+        )?)
+    })
+}
+
+/// Builds a `FeatureCollection` with one `MultiPolygon` feature holding the
+/// gaps of a single chunk, tagged with the chunk index, unreachable point
+/// count, area and (if known) priority score.
+pub fn chunk_gaps_feature_collection(chunk: usize, report: &GapReport) -> Value {
+    json!({
+        "type": "FeatureCollection",
+        "features": [gap_feature(
+            chunk,
+            report.unreachable,
+            &report.polygons,
+            report.area_km2,
+            report.priority_score,
+        )],
+    })
+}
+
+fn gap_feature(
+    chunk: usize,
+    point_count: usize,
+    polygons: &MultiPolygon<f64>,
+    area_km2: f64,
+    priority_score: Option<f64>,
+) -> Value {
+    let coordinates: Vec<_> = polygons.iter().map(polygon_coordinates).collect();
+    json!({
+        "type": "Feature",
+        "properties": {
+            "chunk": chunk,
+            "point_count": point_count,
+            "area_km2": area_km2,
+            "priority_score": priority_score,
+        },
+        "geometry": {
+            "type": "MultiPolygon",
+            "coordinates": coordinates,
+        },
+    })
+}
+
+/// Writes the gaps of one chunk to `chunk_{chunk}.geojson` in `dir`, see
+/// `chunk_gaps_feature_collection`, and returns its path. The file is written
+/// under another name and then renamed, so anything watching `dir` never
+/// sees half of it.
+pub fn write_chunk_gaps_geojson(
+    dir: &Path,
+    chunk: usize,
+    report: &GapReport,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let path = dir.join(format!("chunk_{}.geojson", chunk));
+    write_file_atomically(&path, |writer| {
+        Ok(serde_json::to_writer(
+            writer,
+            &chunk_gaps_feature_collection(chunk, report),
+        )?)
+    })?;
+    Ok(path)
+}
+
+/// Builds a `FeatureCollection` with one `Point` feature per charger, in id
+/// order, with its id, network, charging levels and opening date (null if
+/// unknown) as properties.
+pub fn chargers_feature_collection(chargers: &AllChargerLocations) -> Value {
+    let mut ids: Vec<_> = chargers.chargers_by_id.keys().copied().collect();
+    ids.sort_unstable();
+    let features: Vec<_> = ids
+        .iter()
+        .map(|id| {
+            let charger = &chargers.chargers_by_id[id];
+            json!({
+                "type": "Feature",
+                "properties": {
+                    "id": charger.station_id(),
+                    "network": charger.network(),
+                    "level2": charger.levels().level2,
+                    "dc_fast": charger.levels().dc_fast,
+                    "opened": charger.open_date().map(|date| date.to_string()),
+                },
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": [charger.longitude(), charger.latitude()],
+                },
+            })
+        })
+        .collect();
+    json!({
+        "type": "FeatureCollection",
+        "features": features,
+    })
+}
+
+/// Writes the chargers as a GeoJSON `FeatureCollection`, see
+/// `chargers_feature_collection`.
+pub fn write_chargers_geojson(
+    path: &str,
+    chargers: &AllChargerLocations,
+) -> Result<(), Box<dyn Error>> {
+    write_file_atomically(Path::new(path), |writer| {
+        Ok(serde_json::to_writer(
+            writer,
+            &chargers_feature_collection(chargers),
+        )?)
+    })
+}
+
+/// Builds a `FeatureCollection` with two `MultiPolygon` features: the gaps
+/// that have closed between two snapshots (`"change": "closed"`) and the
+/// ones that have opened up (`"change": "new"`), each with its point count
+/// and area.
+pub fn gap_diff_feature_collection(diff: &GapDiff) -> Value {
+    let feature = |change: &str, polygons: &MultiPolygon<f64>, points: usize, area_km2: f64| {
+        let coordinates: Vec<_> = polygons.iter().map(polygon_coordinates).collect();
+        json!({
+            "type": "Feature",
+            "properties": {
+                "change": change,
+                "point_count": points,
+                "area_km2": area_km2,
+            },
+            "geometry": {
+                "type": "MultiPolygon",
+                "coordinates": coordinates,
+            },
+        })
+    };
+    json!({
+        "type": "FeatureCollection",
+        "features": [
+            feature(
+                "closed",
+                &diff.closed,
+                diff.closed_points.len(),
+                diff.closed_area_km2(),
+            ),
+            feature(
+                "new",
+                &diff.opened,
+                diff.opened_points.len(),
+                diff.opened_area_km2(),
+            ),
+        ],
+    })
+}
+
+/// Writes the changes between two snapshots as a GeoJSON
+/// `FeatureCollection`, see `gap_diff_feature_collection`.
+pub fn write_gap_diff_geojson(path: &str, diff: &GapDiff) -> Result<(), Box<dyn Error>> {
+    write_file_atomically(Path::new(path), |writer| {
+        Ok(serde_json::to_writer(
+            writer,
+            &gap_diff_feature_collection(diff),
+        )?)
+    })
+}
+
+fn polygon_coordinates(polygon: &Polygon<f64>) -> Vec<Vec<[f64; 2]>> {
+    std::iter::once(polygon.exterior())
+        .chain(polygon.interiors())
+        .map(|ring| ring.0.iter().map(|c| [c.x, c.y]).collect())
+        .collect()
+}
+
+fn reachable_feature(points: &[geo::Point<f64>]) -> Value {
+    let coordinates: Vec<[f64; 2]> = points.iter().map(|p| [p.x(), p.y()]).collect();
+    json!({
+        "type": "Feature",
+        "properties": {
+            "chunk": null,
+            "point_count": points.len(),
+            "reachable": true,
+        },
+        "geometry": {
+            "type": "MultiPoint",
+            "coordinates": coordinates,
+        },
+    })
+}
@@ -0,0 +1,329 @@
+//! Minimal gzip (RFC 1952) decompression, enough to read gzipped charger
+//! CSVs without pulling in a compression library. Modeled on zlib's `puff.c`:
+//! simple rather than fast, and it works on whole buffers, with no streaming
+//! `Read` adapter (which a library like `flate2` would give).
+
+use std::io::{Error, ErrorKind, Result};
+
+/// First two bytes of every gzip member.
+pub const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Maximum number of bits in a deflate Huffman code.
+const MAX_BITS: usize = 15;
+
+/// Base lengths and extra bits for length codes 257..285.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+/// Base offsets and extra bits for distance codes 0..29.
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order in which code length code lengths are stored in a dynamic block.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Whether `data` starts like a gzip file.
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&GZIP_MAGIC)
+}
+
+/// Decompresses a complete gzip file, including files made of several
+/// concatenated gzip members.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut input = Bits::new(data);
+    loop {
+        let start = output.len();
+        read_header(&mut input)?;
+        inflate(&mut input, &mut output)?;
+        // The trailer is byte aligned
+        input.align();
+        let crc = input.bytes_u32()?;
+        let size = input.bytes_u32()?;
+        if crc != crc32(&output[start..]) || size != (output.len() - start) as u32 {
+            return Err(invalid("gzip checksum mismatch"));
+        }
+        if !is_gzip(input.remaining()) {
+            return Ok(output);
+        }
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn read_header(input: &mut Bits) -> Result<()> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+    let header = input.take(10)?;
+    if !is_gzip(header) || header[2] != 8 {
+        return Err(invalid("not a deflate-compressed gzip file"));
+    }
+    let flags = header[3];
+    if flags & FEXTRA != 0 {
+        let length = input.take(2)?;
+        input.take(u16::from_le_bytes([length[0], length[1]]) as usize)?;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            // Zero-terminated string
+            while input.take(1)?[0] != 0 {}
+        }
+    }
+    if flags & FHCRC != 0 {
+        input.take(2)?;
+    }
+    Ok(())
+}
+
+/// Reads deflate (RFC 1951) blocks up to and including the last one.
+fn inflate(input: &mut Bits, output: &mut Vec<u8>) -> Result<()> {
+    loop {
+        let last = input.bits(1)? == 1;
+        match input.bits(2)? {
+            0 => stored_block(input, output)?,
+            1 => {
+                let (lengths, distances) = fixed_codes();
+                codes_block(input, output, &lengths, &distances)?
+            }
+            2 => {
+                let (lengths, distances) = dynamic_codes(input)?;
+                codes_block(input, output, &lengths, &distances)?
+            }
+            _ => return Err(invalid("invalid deflate block type")),
+        }
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+fn stored_block(input: &mut Bits, output: &mut Vec<u8>) -> Result<()> {
+    input.align();
+    let header = input.take(4)?;
+    let length = u16::from_le_bytes([header[0], header[1]]);
+    let complement = u16::from_le_bytes([header[2], header[3]]);
+    if length != !complement {
+        return Err(invalid("corrupt stored deflate block"));
+    }
+    output.extend_from_slice(input.take(length as usize)?);
+    Ok(())
+}
+
+fn codes_block(
+    input: &mut Bits,
+    output: &mut Vec<u8>,
+    lengths: &Huffman,
+    distances: &Huffman,
+) -> Result<()> {
+    loop {
+        let symbol = lengths.decode(input)?;
+        match symbol {
+            0..=255 => output.push(symbol as u8),
+            256 => return Ok(()),
+            257..=285 => {
+                let index = symbol as usize - 257;
+                let length =
+                    LENGTH_BASE[index] as usize + input.bits(LENGTH_EXTRA[index] as u32)? as usize;
+                let index = distances.decode(input)? as usize;
+                if index >= DIST_BASE.len() {
+                    return Err(invalid("invalid deflate distance code"));
+                }
+                let distance =
+                    DIST_BASE[index] as usize + input.bits(DIST_EXTRA[index] as u32)? as usize;
+                if distance > output.len() {
+                    return Err(invalid("deflate distance too far back"));
+                }
+                // Copied a byte at a time, since the source and destination
+                // can overlap.
+                let start = output.len() - distance;
+                for i in 0..length {
+                    output.push(output[start + i]);
+                }
+            }
+            _ => return Err(invalid("invalid deflate length code")),
+        }
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(input: &mut Bits) -> Result<(Huffman, Huffman)> {
+    let length_count = input.bits(5)? as usize + 257;
+    let distance_count = input.bits(5)? as usize + 1;
+    let code_count = input.bits(4)? as usize + 4;
+    let mut code_lengths = [0; 19];
+    for &i in &CODE_LENGTH_ORDER[..code_count] {
+        code_lengths[i] = input.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths);
+    let mut lengths = Vec::with_capacity(length_count + distance_count);
+    while lengths.len() < length_count + distance_count {
+        let symbol = code_lengths.decode(input)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths
+                    .last()
+                    .ok_or_else(|| invalid("deflate repeat with no previous length"))?;
+                (previous, 3 + input.bits(2)?)
+            }
+            17 => (0, 3 + input.bits(3)?),
+            _ => (0, 11 + input.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(value, repeat as usize));
+    }
+    if lengths.len() > length_count + distance_count {
+        return Err(invalid("deflate code lengths overrun"));
+    }
+    let (literal_lengths, distance_lengths) = lengths.split_at(length_count);
+    Ok((
+        Huffman::new(literal_lengths),
+        Huffman::new(distance_lengths),
+    ))
+}
+
+/// Canonical Huffman code, stored as the number of codes of each length and
+/// the symbols ordered by code.
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0; MAX_BITS + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0; MAX_BITS + 1];
+        for bits in 1..MAX_BITS {
+            offsets[bits + 1] = offsets[bits] + counts[bits];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    /// Reads one symbol, a bit at a time.
+    fn decode(&self, input: &mut Bits) -> Result<u16> {
+        // First code of the current length, and index of its symbol
+        let mut code = 0;
+        let mut first = 0;
+        let mut index = 0;
+        for bits in 1..=MAX_BITS {
+            code |= input.bits(1)? as i32;
+            let count = self.counts[bits] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("invalid deflate Huffman code"))
+    }
+}
+
+/// Reads a byte slice a bit at a time, least significant bit first.
+struct Bits<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl<'a> Bits<'a> {
+    fn new(data: &'a [u8]) -> Bits<'a> {
+        Bits {
+            data,
+            position: 0,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    fn bits(&mut self, needed: u32) -> Result<u32> {
+        while self.count < needed {
+            let byte = *self
+                .data
+                .get(self.position)
+                .ok_or_else(|| invalid("unexpected end of gzip data"))?;
+            self.position += 1;
+            self.buffer |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1u64 << needed) - 1) as u32;
+        self.buffer >>= needed;
+        self.count -= needed;
+        Ok(value)
+    }
+
+    /// Drops the bits left in the current byte.
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+
+    /// Takes the next `n` whole bytes. Only valid when aligned.
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + n)
+            .ok_or_else(|| invalid("unexpected end of gzip data"))?;
+        self.position += n;
+        Ok(bytes)
+    }
+
+    fn bytes_u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn remaining(&self) -> &'a [u8] {
+        &self.data[self.position..]
+    }
+}
+
+/// CRC-32 (IEEE) as used in the gzip trailer.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
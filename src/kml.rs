@@ -0,0 +1,81 @@
+//! KML output, for opening gaps in Google Earth without any GIS tools. The
+//! document is simple enough to write by hand rather than pulling in the
+//! `kml` crate.
+
+use std::{error::Error, fmt::Write as _, io::Write, path::Path};
+
+use geo::{LineString, Polygon};
+
+use crate::{atomic::write_file_atomically, gap_area_km2, ChunkGap};
+
+/// Style shared by every gap: a translucent red fill with a solid red
+/// outline. KML colors are `aabbggrr`.
+const GAP_STYLE: &str = r#"    <Style id="gap">
+      <LineStyle><color>ff0000ff</color><width>2</width></LineStyle>
+      <PolyStyle><color>660000ff</color></PolyStyle>
+    </Style>
+"#;
+
+/// KML document with one `Placemark` per gap polygon, named after its chunk
+/// and area, e.g. "Gap chunk 3 (1234.5 km²)". Gaps with a priority score
+/// have it in the description.
+pub fn gaps_kml(gaps: &[ChunkGap]) -> String {
+    let mut kml = String::from(concat!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+        "\n",
+        r#"<kml xmlns="http://www.opengis.net/kml/2.2">"#,
+        "\n  <Document>\n    <name>EV charging gaps</name>\n",
+    ));
+    kml.push_str(GAP_STYLE);
+    for gap in gaps {
+        for polygon in &gap.polygons {
+            write_placemark(&mut kml, gap, polygon);
+        }
+    }
+    kml.push_str("  </Document>\n</kml>\n");
+    kml
+}
+
+fn write_placemark(kml: &mut String, gap: &ChunkGap, polygon: &Polygon<f64>) {
+    kml.push_str("    <Placemark>\n");
+    writeln!(
+        kml,
+        "      <name>Gap chunk {} ({:.1} km²)</name>",
+        gap.chunk_id,
+        gap_area_km2(polygon)
+    )
+    .unwrap();
+    if let Some(priority) = gap.priority_score {
+        writeln!(
+            kml,
+            "      <description>Population of the gaps in this chunk: {:.0}</description>",
+            priority
+        )
+        .unwrap();
+    }
+    kml.push_str("      <styleUrl>#gap</styleUrl>\n      <Polygon>\n");
+    write_ring(kml, "outerBoundaryIs", polygon.exterior());
+    for interior in polygon.interiors() {
+        write_ring(kml, "innerBoundaryIs", interior);
+    }
+    kml.push_str("      </Polygon>\n    </Placemark>\n");
+}
+
+/// A polygon boundary, with `lon,lat` coordinates as KML wants them.
+fn write_ring(kml: &mut String, boundary: &str, ring: &LineString<f64>) {
+    write!(kml, "        <{}><LinearRing><coordinates>", boundary).unwrap();
+    for (i, c) in ring.0.iter().enumerate() {
+        if i > 0 {
+            kml.push(' ');
+        }
+        write!(kml, "{},{}", c.x, c.y).unwrap();
+    }
+    writeln!(kml, "</coordinates></LinearRing></{}>", boundary).unwrap();
+}
+
+/// Writes `gaps` to `path` as KML, see `gaps_kml`.
+pub fn write_gaps_kml(path: &str, gaps: &[ChunkGap]) -> Result<(), Box<dyn Error>> {
+    write_file_atomically(Path::new(path), |writer| {
+        Ok(writer.write_all(gaps_kml(gaps).as_bytes())?)
+    })
+}
@@ -1,24 +1,121 @@
 use core::f64;
-use std::{collections::HashMap, error::Error, thread, time::Duration};
+use std::{
+    collections::{BinaryHeap, HashMap, HashSet},
+    fmt,
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
+use chrono::NaiveDate;
 use csv::Reader;
-use quadtree_f32::{Item, ItemId, Point, QuadTree, Rect};
-use reqwest;
-use reqwest::blocking::Client;
-use serde::Deserialize;
+pub use error::GapError;
+use geo::algorithm::{
+    bounding_rect::BoundingRect, chamberlain_duquette_area::ChamberlainDuquetteArea,
+    closest_point::ClosestPoint, concave_hull::ConcaveHull, contains::Contains,
+    convex_hull::ConvexHull, euclidean_distance::EuclideanDistance,
+    geodesic_distance::GeodesicDistance, haversine_distance::HaversineDistance, simplify::Simplify,
+};
+use geo::MultiPolygon;
+use log::{debug, info, trace, warn};
+use parallel::*;
+use population::Population;
+use routing::{MemoizedRoutes, RouteProvider};
+use rstar::{primitives::PointWithData, RTree, RTreeObject, AABB};
+use serde::{Deserialize, Serialize};
+use spill::{PointBuffer, Points, SpillConfig};
 use std::f64::consts::PI;
 
-/// Assumed EV's max range in meters.
+/// Default assumed EV's max range in meters.
 pub const MAX_RANGE_METERS: u64 = 400_000;
-/// Ratio between the linear distance and driving distance at which we assume
-/// point is reachable, intended to avoid API lookups for chargers that are
-/// obviously reachable.
+/// Default ratio between the linear distance and driving distance at which we
+/// assume point is reachable, intended to avoid API lookups for chargers that
+/// are obviously reachable.
 pub const CROW_FLIES_RATIO: f64 = 0.1;
+/// Default longest drive to a charger, in seconds, when range is measured in
+/// driving time (`RangeMode::Duration`).
+pub const MAX_DURATION_SECS: u64 = 4 * 60 * 60;
 pub const EARTH_RADIUS_METERS: f64 = 6_371_000.0;
-pub const DEFAULT_OSRM_URL: &'static str = "https://router.project-osrm.org";
+pub const DEFAULT_OSRM_URL: &str = "https://router.project-osrm.org";
+/// Most routing lookups a run may need before the command line tool refuses
+/// to send them to the public OSRM server at `DEFAULT_OSRM_URL` without
+/// confirmation. The server is a demo, not meant for batch jobs, and
+/// throttles clients that send it too many requests.
+pub const PUBLIC_OSRM_MAX_LOOKUPS: usize = 10_000;
+/// OSRM routing profile used unless another is given. The public server
+/// only has this one.
+pub const DEFAULT_OSRM_PROFILE: &str = "driving";
+/// Default number of closest chargers (by straight-line distance) whose
+/// driving distance is looked up for a maybe-reachable point.
+///
+/// Driving distance is never shorter than the straight-line distance, so the
+/// closest chargers are the most likely to be in range, and a point for
+/// which none of the first 50 are reachable by road is very unlikely to
+/// reach any further one. All candidates go in a single table request, so
+/// the limit mostly bounds the URL length; the public OSRM server rejects
+/// tables with more than 100 locations.
+pub const MAX_OSRM_CANDIDATES: usize = 50;
+/// Default number of times a failed OSRM request is retried before giving up.
+pub const OSRM_MAX_RETRIES: u32 = 10;
+/// Default concavity of the hull drawn around each gap's unreachable points.
+pub const DEFAULT_CONCAVITY: f64 = 2.0;
+/// Default alpha shape radius, in grid cells (multiplied by the resolution).
+pub const ALPHA_CELLS: f64 = 1.0;
+/// Default distance, in grid cells, within which unreachable points belong to
+/// the same gap. A bit more than the diagonal of a cell, so that points
+/// touching at a corner are still one gap.
+pub const CLUSTER_EPS_CELLS: f64 = 1.5;
+/// Default timeout for a single OSRM request, in seconds.
+pub const OSRM_TIMEOUT_SECS: u64 = 30;
+/// Default most grid points a run may have, see
+/// `BoundingBox::check_grid_points`. The whole US at 0.001° is about 1.4
+/// billion, far more than fit in memory.
+pub const MAX_GRID_POINTS: u64 = 50_000_000;
+/// Fewer grid points than this are probably a mistyped resolution, e.g.
+/// 1.0 for 0.01, and are warned about.
+pub const MIN_GRID_POINTS: u64 = 10;
 
-#[cfg(test)]
+pub mod adaptive;
+pub mod alpha_shape;
+#[cfg(feature = "async")]
+pub mod async_osrm;
+mod atomic;
+pub mod config_file;
+pub mod csv_output;
+pub mod diff;
+mod error;
+pub mod geojson;
+mod gzip;
+pub mod kml;
+pub mod multi_hop;
+#[cfg(feature = "network")]
+mod network;
+#[cfg(feature = "network")]
+pub mod nrel_cache;
+#[cfg(feature = "network")]
+pub mod osrm_cache;
+mod parallel;
+pub mod population;
+pub mod profile;
+#[cfg(feature = "network")]
+pub mod rate_limit;
+pub mod routing;
+pub mod shapefile_output;
+pub mod spill;
+#[cfg(all(test, feature = "network"))]
 mod tests;
+pub mod wkt;
+
+#[cfg(feature = "network")]
+pub use network::*;
+pub use routing::RouteLookup;
 
 /// CsvRow includes all information we need about chargers
 /// that is parsed out from CSV row
@@ -28,175 +125,2152 @@ pub struct CsvRow {
     latitude: f64,
     #[serde(rename = "Longitude")]
     longitude: f64,
+    /// Kept as text: NREL's ids are numbers, but nothing depends on it.
     #[serde(rename = "ID")]
-    id: u64,
+    id: String,
     #[serde(rename = "EV Network")]
     network: String,
+    #[serde(rename = "EV Level2 EVSE Num", default)]
+    level2_count: Option<u32>,
+    #[serde(rename = "EV DC Fast Count", default)]
+    dc_fast_count: Option<u32>,
+    /// Kept as text so that a malformed date doesn't drop the row, see
+    /// `parse_open_date`.
+    #[serde(rename = "Open Date", default)]
+    open_date: Option<String>,
+    /// "public" or "private".
+    #[serde(rename = "Access Code", default)]
+    access_code: Option<String>,
+    /// Access as shown on the NREL station locator, e.g. "Public - Credit
+    /// card at all times". Only used when there's no `Access Code`.
+    #[serde(rename = "Groups With Access Code", default)]
+    groups_with_access: Option<String>,
+}
+
+impl CsvRow {
+    /// Whether anyone can use the station. Rows that say neither way are
+    /// taken to be public, like those of a download with `access=public`.
+    fn is_public(&self) -> bool {
+        let access = self
+            .access_code
+            .as_deref()
+            .filter(|code| !code.trim().is_empty())
+            .or(self.groups_with_access.as_deref())
+            .unwrap_or("public");
+        !access.trim().to_lowercase().starts_with("private")
+    }
+}
+
+/// Parses an NREL `Open Date`, e.g. "2021-12-31". The older US style
+/// "12/31/2021" is accepted too.
+pub fn parse_open_date(date: &str) -> Option<NaiveDate> {
+    let date = date.trim();
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .or_else(|_| NaiveDate::parse_from_str(date, "%m/%d/%Y"))
+        .ok()
+}
+
+/// All operations done on ChargerLocations type
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChargerLocation {
+    latitude: f64,
+    longitude: f64,
+    /// Numbered in the order the chargers were read, see `id`.
+    id: u64,
+    station_id: String,
+    /// Shared between all chargers on the same network, see `read_csv`.
+    network: Arc<str>,
+    levels: ChargingLevels,
+    /// When the station opened, if known.
+    open_date: Option<NaiveDate>,
+    /// Whether anyone can use the charger, rather than only e.g. a fleet or
+    /// a dealership's customers.
+    public: bool,
+}
+
+impl ChargerLocation {
+    /// A public charger with no network or charging levels and an unknown
+    /// open date, to fill in with the `with_` methods, e.g. to build an
+    /// `AllChargerLocations` with `from_chargers`.
+    pub fn new(station_id: impl Into<String>, latitude: f64, longitude: f64) -> ChargerLocation {
+        ChargerLocation {
+            latitude,
+            longitude,
+            id: 0,
+            station_id: station_id.into(),
+            network: "".into(),
+            levels: ChargingLevels::default(),
+            open_date: None,
+            public: true,
+        }
+    }
+
+    pub fn with_network(self, network: &str) -> ChargerLocation {
+        ChargerLocation {
+            network: network.into(),
+            ..self
+        }
+    }
+
+    pub fn with_levels(self, levels: ChargingLevels) -> ChargerLocation {
+        ChargerLocation { levels, ..self }
+    }
+
+    pub fn with_open_date(self, open_date: NaiveDate) -> ChargerLocation {
+        ChargerLocation {
+            open_date: Some(open_date),
+            ..self
+        }
+    }
+
+    pub fn with_public(self, public: bool) -> ChargerLocation {
+        ChargerLocation { public, ..self }
+    }
+
+    pub fn latitude(&self) -> f64 {
+        self.latitude
+    }
+
+    pub fn longitude(&self) -> f64 {
+        self.longitude
+    }
+
+    /// Identifies the charger among those it was read with, e.g. in
+    /// `AllChargerLocations::chargers_by_id`. This isn't the NREL id, see
+    /// `station_id`.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// NREL station id, as text. It has always been a number so far.
+    pub fn station_id(&self) -> &str {
+        &self.station_id
+    }
+
+    /// The `EV Network` the charger belongs to, e.g. "Electrify America".
+    pub fn network(&self) -> &str {
+        &self.network
+    }
+
+    pub fn levels(&self) -> ChargingLevels {
+        self.levels
+    }
+
+    pub fn open_date(&self) -> Option<NaiveDate> {
+        self.open_date
+    }
+
+    pub fn is_public(&self) -> bool {
+        self.public
+    }
+
+    /// Where the charger is, as x = longitude, y = latitude like GeoJSON.
+    pub fn to_geo_point(&self) -> geo::Point<f64> {
+        geo::Point::new(self.longitude, self.latitude)
+    }
+}
+
+impl fmt::Display for ChargerLocation {
+    /// `lat, lon (id 123, Network)`, ready to paste into a map search.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}, {} (id {}",
+            self.latitude, self.longitude, self.station_id
+        )?;
+        if !self.network.is_empty() {
+            write!(f, ", {}", self.network)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Which kinds of charging a charger offers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChargingLevels {
+    pub level2: bool,
+    pub dc_fast: bool,
+}
+
+/// Which kinds of chargers to include in the analysis.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChargingLevel {
+    /// Only chargers with Level 2 ports
+    L2,
+    /// Only chargers with DC fast ports
+    DcFast,
+    /// Every charger
+    All,
+}
+
+impl ChargingLevel {
+    pub fn matches(self, levels: ChargingLevels) -> bool {
+        match self {
+            ChargingLevel::L2 => levels.level2,
+            ChargingLevel::DcFast => levels.dc_fast,
+            ChargingLevel::All => true,
+        }
+    }
+
+    /// Value of the NREL API's `ev_charging_level` query parameter that
+    /// downloads these chargers.
+    pub fn nrel_query_value(self) -> &'static str {
+        match self {
+            ChargingLevel::L2 => "2",
+            ChargingLevel::DcFast => "dc_fast",
+            ChargingLevel::All => "2,dc_fast",
+        }
+    }
+}
+
+impl FromStr for ChargingLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "l2" => Ok(ChargingLevel::L2),
+            "dcfast" => Ok(ChargingLevel::DcFast),
+            "all" => Ok(ChargingLevel::All),
+            _ => Err(format!(
+                "unknown charging level {:?}, expected l2, dcfast or all",
+                s
+            )),
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TrialPoint {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl TrialPoint {
+    /// The point as x = longitude, y = latitude, the way gap geometry and
+    /// GeoJSON have it.
+    pub fn to_geo_point(&self) -> geo::Point<f64> {
+        geo::Point::new(self.longitude, self.latitude)
+    }
+}
+
+impl fmt::Display for TrialPoint {
+    /// `lat, lon`, ready to paste into a map search.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}, {}", self.latitude, self.longitude)
+    }
+}
+
+/// A charger's entry in the spatial index, positioned at `[lat, lon]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexedCharger {
+    pub position: [f64; 2],
+    pub id: u64,
+}
+
+impl RTreeObject for IndexedCharger {
+    type Envelope = AABB<[f64; 2]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        AABB::from_point(self.position)
+    }
+}
+
+/// A charger's entry in the `IndexBackend::Sphere` index: the charger id at
+/// its position on the unit sphere, see `unit_sphere`.
+pub type SphereCharger = PointWithData<u64, [f64; 3]>;
+
+/// How `AllChargerLocations` finds the chargers near a point (for
+/// `TrialPoint::nearest_chargers`), chosen when it is built.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IndexBackend {
+    /// Chargers in a box of latitude and longitude around the point, padded
+    /// so that it holds every charger in range (see
+    /// `TrialPoint::search_envelope`). The ones in the corners of the box
+    /// are measured and then thrown away.
+    #[default]
+    Envelope,
+    /// Nearest neighbor search on the unit sphere. The straight line between
+    /// two points on a sphere gets longer with the great circle distance
+    /// between them, so the tree finds the chargers in range directly, and
+    /// closest (by haversine distance) first.
+    Sphere,
+}
+
+/// See `AllChargerLocations::summary`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChargerSummary {
+    pub charger_count: usize,
+    /// The smallest box holding every charger, `None` if there are none. A
+    /// single charger gives a box with no area.
+    pub bbox: Option<BoundingBox>,
+}
+
+#[derive(Clone)]
+pub struct AllChargerLocations {
+    /// Chargers indexed by their (full precision) coordinates. Built with
+    /// every backend, since it is also used for the bounding box of the data
+    /// and for `charger_components`.
+    pub index: RTree<IndexedCharger>,
+    /// Chargers indexed by their position on the unit sphere, with
+    /// `IndexBackend::Sphere`.
+    pub sphere_index: Option<RTree<SphereCharger>>,
+    pub chargers_by_id: HashMap<u64, ChargerLocation>,
+}
+
+impl AllChargerLocations {
+    /// Indexes `chargers` by location. If several chargers have the same id,
+    /// only the last one is kept, with a warning. Chargers made with
+    /// `ChargerLocation::new` all have the same id until they are numbered
+    /// by `from_chargers`.
+    ///
+    /// The index is built from the deduplicated chargers afterwards, so every
+    /// entry in it refers to a charger in `chargers_by_id`.
+    pub fn new(chargers: impl IntoIterator<Item = ChargerLocation>) -> AllChargerLocations {
+        let mut chargers: Vec<_> = chargers.into_iter().collect();
+        // The sort is stable, so after reversing, the last of each id comes
+        // first and is the one that `dedup_by` keeps
+        chargers.reverse();
+        chargers.par_sort_by_key(|charger| charger.id);
+        let mut duplicates = 0;
+        chargers.dedup_by(|charger, kept| {
+            let duplicate = charger.id == kept.id;
+            if duplicate {
+                debug!("replaced charger with duplicate id {}", charger.station_id);
+                duplicates += 1;
+            }
+            duplicate
+        });
+        if duplicates > 0 {
+            warn!(
+                "{} chargers had the same id as another one and were replaced by it",
+                duplicates
+            );
+        }
+        let positions = chargers
+            .par_iter()
+            .map(|charger| IndexedCharger {
+                position: [charger.latitude, charger.longitude],
+                id: charger.id,
+            })
+            .collect();
+        let (index, chargers_by_id) = join(
+            || RTree::bulk_load(positions),
+            || {
+                chargers
+                    .into_par_iter()
+                    .map(|charger| (charger.id, charger))
+                    .collect()
+            },
+        );
+        AllChargerLocations {
+            index,
+            sphere_index: None,
+            chargers_by_id,
+        }
+    }
+
+    /// The charger with NREL station id `station_id`, if it was kept.
+    pub fn by_station_id(&self, station_id: &str) -> Option<&ChargerLocation> {
+        self.chargers_by_id
+            .values()
+            .find(|charger| charger.station_id == station_id)
+    }
+
+    /// Indexes `chargers` like `read_csv` does, numbering them (see
+    /// `ChargerLocation::id`) in the order their station ids first appear.
+    /// As with `new`, only the last of several chargers with the same
+    /// station id is kept.
+    pub fn from_chargers(
+        chargers: impl IntoIterator<Item = ChargerLocation>,
+    ) -> AllChargerLocations {
+        let mut chargers: Vec<_> = chargers.into_iter().collect();
+        let ids = station_numbers(chargers.iter().map(|charger| charger.station_id.as_str()));
+        let ids: Vec<_> = chargers
+            .iter()
+            .map(|charger| ids[charger.station_id.as_str()])
+            .collect();
+        for (charger, id) in chargers.iter_mut().zip(ids) {
+            charger.id = id;
+        }
+        AllChargerLocations::new(chargers)
+    }
+
+    /// Like `new`, finding nearby chargers with `backend`.
+    pub fn with_backend(
+        chargers: impl IntoIterator<Item = ChargerLocation>,
+        backend: IndexBackend,
+    ) -> AllChargerLocations {
+        let mut locations = AllChargerLocations::new(chargers);
+        if backend == IndexBackend::Sphere {
+            let positions = locations
+                .chargers_by_id
+                .values()
+                .map(|charger| {
+                    SphereCharger::new(charger.id, unit_sphere(charger.latitude, charger.longitude))
+                })
+                .collect();
+            locations.sphere_index = Some(RTree::bulk_load(positions));
+        }
+        locations
+    }
+
+    /// Which backend finds nearby chargers.
+    pub fn backend(&self) -> IndexBackend {
+        match self.sphere_index {
+            Some(_) => IndexBackend::Sphere,
+            None => IndexBackend::Envelope,
+        }
+    }
+
+    /// How many chargers there are, and the box around them, e.g. to check
+    /// that the right data was loaded.
+    pub fn summary(&self) -> ChargerSummary {
+        // Index positions are [lat, lon]
+        let bbox = (self.index.size() > 0).then(|| {
+            let envelope = self.index.root().envelope();
+            let (lower, upper) = (envelope.lower(), envelope.upper());
+            BoundingBox {
+                lat_min: lower[0],
+                lat_max: upper[0],
+                lon_min: lower[1],
+                lon_max: upper[1],
+            }
+        });
+        ChargerSummary {
+            charger_count: self.chargers_by_id.len(),
+            bbox,
+        }
+    }
+
+    /// Ids of the chargers that might be within `max_range_meters` of
+    /// `point`: every one that is, and maybe some that aren't.
+    fn candidates<'a>(
+        &'a self,
+        point: &TrialPoint,
+        max_range_meters: u64,
+    ) -> Box<dyn Iterator<Item = u64> + 'a> {
+        match &self.sphere_index {
+            Some(sphere_index) => Box::new(
+                sphere_index
+                    .locate_within_distance(
+                        unit_sphere(point.latitude, point.longitude),
+                        sphere_chord_2(max_range_meters),
+                    )
+                    .map(|indexed| indexed.data),
+            ),
+            None => {
+                let bbox = point.search_envelope(max_range_meters);
+                trace!("bounding box {:?} to {:?}", bbox.lower(), bbox.upper());
+                Box::new(
+                    self.index
+                        .locate_in_envelope(&bbox)
+                        .map(|indexed| indexed.id),
+                )
+            }
+        }
+    }
+}
+
+/// Extra distance searched around a point beyond the range, for the error in
+/// the approximations of the indexes and the earth being an ellipsoid rather
+/// than a sphere.
+const SEARCH_PADDING_METERS: f64 = 25_000.0;
+
+/// Position of `(lat, lon)` on the unit sphere, as `[x, y, z]`.
+pub fn unit_sphere(lat: f64, lon: f64) -> [f64; 3] {
+    let (lat, lon) = (lat.to_radians(), lon.to_radians());
+    [lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin()]
+}
+
+/// Squared length of the straight line between two points on the unit sphere
+/// that are `max_range_meters` (plus padding) apart on the earth's surface.
+fn sphere_chord_2(max_range_meters: u64) -> f64 {
+    let angle = (max_range_meters as f64 + SEARCH_PADDING_METERS) / EARTH_RADIUS_METERS;
+    // Anything further than halfway round the earth is in range
+    let chord = 2.0 * (angle.min(PI) / 2.0).sin();
+    chord * chord
+}
+
+/// Parameters describing the vehicle whose reachability we are checking.
+#[derive(Clone, Copy, Debug)]
+pub struct RangeConfig {
+    /// EV's max range in meters.
+    pub max_range_meters: u64,
+    /// Fraction of the max range within which a charger is close enough (in a
+    /// straight line) to be assumed reachable without asking OSRM. 0.0 always
+    /// asks OSRM; 1.0 never does for chargers within range.
+    pub crow_flies_ratio: f64,
+    /// How straight-line distances to chargers are calculated.
+    pub metric: DistanceMetric,
+    /// Whether a charger is in range by driving distance or driving time.
+    pub mode: RangeMode,
+    /// Longest drive to a charger, in seconds, in `RangeMode::Duration`.
+    /// Chargers still have to be within `max_range_meters` in a straight line
+    /// to be considered at all.
+    pub max_duration_secs: u64,
+}
+
+impl Default for RangeConfig {
+    fn default() -> Self {
+        RangeConfig {
+            max_range_meters: MAX_RANGE_METERS,
+            crow_flies_ratio: CROW_FLIES_RATIO,
+            metric: DistanceMetric::Haversine,
+            mode: RangeMode::Distance,
+            max_duration_secs: MAX_DURATION_SECS,
+        }
+    }
+}
+
+impl RangeConfig {
+    /// The same config with `max_range_meters` scaled by `factor`, between
+    /// 0.0 and 1.0: e.g. 0.7 for a car that only gets 70% of its rated range
+    /// in the cold. Every range check (the straight line, the crow flies
+    /// shortcut and the driving distance) uses the derated range. Driving
+    /// time limits aren't changed.
+    pub fn derated(self, factor: f64) -> RangeConfig {
+        assert!(
+            (0.0..=1.0).contains(&factor),
+            "derating must be between 0 and 1"
+        );
+        RangeConfig {
+            max_range_meters: (self.max_range_meters as f64 * factor).round() as u64,
+            ..self
+        }
+    }
+}
+
+/// How the unreachable points of a gap are turned into a polygon.
+#[derive(Clone, Debug)]
+pub struct HullConfig {
+    /// How the polygon around each gap is drawn.
+    pub algorithm: HullAlgorithm,
+    /// Concavity of the hull, must be positive. Lower values wrap the points
+    /// more tightly (with more vertices); higher values approach the convex
+    /// hull. What counts as tight depends on the grid resolution.
+    pub concavity: f64,
+    /// Radius of the alpha shape in degrees, for `HullAlgorithm::Alpha`.
+    /// Should be at least the grid resolution, or neighbouring points won't
+    /// be joined up.
+    pub alpha: f64,
+    /// Unreachable points within this many degrees of each other are the
+    /// same gap, and each gap gets its own hull (see `cluster_points`). 0
+    /// puts all of the points in one hull.
+    pub cluster_eps: f64,
+}
+
+impl Default for HullConfig {
+    fn default() -> Self {
+        HullConfig {
+            algorithm: HullAlgorithm::Concave,
+            concavity: DEFAULT_CONCAVITY,
+            alpha: 0.01,
+            cluster_eps: 0.0,
+        }
+    }
+}
+
+/// What a `GapReport` keeps besides the gaps and the point counts.
+#[derive(Clone, Debug, Default)]
+pub struct ReportConfig {
+    /// Also keep the reachable points, in `GapReport::reachable_points`, e.g.
+    /// to draw a coverage map. Off by default, since most grid points are
+    /// reachable and they're otherwise only counted.
+    pub emit_reachable: bool,
+    /// Also keep what is known about each unreachable point, in
+    /// `GapReport::unreachable_details`, e.g. for `csv_output`. Off by
+    /// default: it takes finding each point's nearest charger, and keeping
+    /// every driving distance looked up while a chunk is searched (see
+    /// `MemoizedRoutes`). With engines that look up one charger at a time,
+    /// that also means looking up every candidate of the reachable points,
+    /// rather than stopping at the first in range.
+    pub emit_unreachable_details: bool,
+    /// Write the unreachable points of a chunk to disk once there are more
+    /// than `SpillConfig::threshold` of them, and draw its hulls from there
+    /// (see `spill::spilled_hulls`), for grids too big to keep every point
+    /// in memory. The report then has no `unreachable_points`, see
+    /// `GapReport::spilled`. Only `find_gaps_with_progress` spills.
+    pub spill: Option<SpillConfig>,
+}
+
+/// Algorithms for drawing a polygon around the unreachable points of a gap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HullAlgorithm {
+    /// Smallest convex polygon containing all of the points. Simple and
+    /// stable, but covers reachable land in any bays of the gap.
+    Convex,
+    /// Concave hull with `HullConfig::concavity`. Follows the points more
+    /// closely, but is always a single polygon without holes.
+    Concave,
+    /// Alpha shape with `HullConfig::alpha` (see `alpha_shape`). Can have
+    /// holes and several parts, e.g. around a reachable town in the middle
+    /// of a gap.
+    Alpha,
+}
+
+impl FromStr for HullAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "convex" => Ok(HullAlgorithm::Convex),
+            "concave" => Ok(HullAlgorithm::Concave),
+            "alpha" => Ok(HullAlgorithm::Alpha),
+            _ => Err(format!(
+                "unknown hull algorithm {:?}, expected convex, concave or alpha",
+                s
+            )),
+        }
+    }
+}
+
+/// Ways of calculating the straight-line distance between two points.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DistanceMetric {
+    /// Great-circle distance on a spherical earth. Fast, but can be off by
+    /// up to ~0.5% since the earth isn't a sphere.
+    Haversine,
+    /// Distance on the WGS84 ellipsoid (Karney's algorithm). Accurate to a
+    /// few nanometers, but slower.
+    Geodesic,
+}
+
+impl FromStr for DistanceMetric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "haversine" => Ok(DistanceMetric::Haversine),
+            "geodesic" => Ok(DistanceMetric::Geodesic),
+            _ => Err(format!(
+                "unknown distance metric {:?}, expected haversine or geodesic",
+                s
+            )),
+        }
+    }
+}
+
+/// Units that a distance can be given in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DistanceUnit {
+    Meters,
+    Kilometers,
+    Miles,
+}
+
+/// Meters in an international mile.
+pub const METERS_PER_MILE: f64 = 1609.344;
+
+impl DistanceUnit {
+    /// `distance` in this unit, in meters.
+    pub fn to_meters(self, distance: f64) -> f64 {
+        match self {
+            DistanceUnit::Meters => distance,
+            DistanceUnit::Kilometers => distance * 1000.0,
+            DistanceUnit::Miles => distance * METERS_PER_MILE,
+        }
+    }
+
+    /// Short name of the unit, as accepted by `from_str`: `m`, `km` or `mi`.
+    pub fn abbreviation(self) -> &'static str {
+        match self {
+            DistanceUnit::Meters => "m",
+            DistanceUnit::Kilometers => "km",
+            DistanceUnit::Miles => "mi",
+        }
+    }
+}
+
+impl FromStr for DistanceUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "meters" | "m" => Ok(DistanceUnit::Meters),
+            "km" | "kilometers" => Ok(DistanceUnit::Kilometers),
+            "miles" | "mi" => Ok(DistanceUnit::Miles),
+            _ => Err(format!(
+                "unknown distance unit {:?}, expected meters, km or miles",
+                s
+            )),
+        }
+    }
+}
+
+/// What a charger has to be within to count as reachable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RangeMode {
+    /// Driving distance, compared against `RangeConfig::max_range_meters`.
+    Distance,
+    /// Driving time, compared against `RangeConfig::max_duration_secs`. The
+    /// same distance can take very different times on an interstate and on
+    /// mountain roads.
+    Duration,
+}
+
+impl FromStr for RangeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "distance" => Ok(RangeMode::Distance),
+            "duration" => Ok(RangeMode::Duration),
+            _ => Err(format!(
+                "unknown range mode {:?}, expected distance or duration",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoundingBox {
+    pub lat_min: f64,
+    pub lat_max: f64,
+    pub lon_min: f64,
+    pub lon_max: f64,
+}
+
+/// What `find_gaps` found in one bounding box.
+#[derive(Clone, Debug)]
+pub struct GapReport {
+    /// Hulls around each separate gap, see `gap_hulls`.
+    pub polygons: geo::MultiPolygon<f64>,
+    /// Total area of `polygons` in square kilometers, see `gap_area_km2`.
+    pub area_km2: f64,
+    /// The unreachable grid points themselves, in grid order. Empty if they
+    /// were `spilled`.
+    pub unreachable_points: Vec<geo::Point<f64>>,
+    /// The reachable grid points, in grid order, if
+    /// `ReportConfig::emit_reachable` was set. Same x = longitude,
+    /// y = latitude convention as `unreachable_points`.
+    pub reachable_points: Option<Vec<geo::Point<f64>>>,
+    /// What is known about each unreachable point, in grid order, if
+    /// `ReportConfig::emit_unreachable_details` was set. Only
+    /// `find_gaps_with_progress` fills these in, not the adaptive grid.
+    pub unreachable_details: Option<Vec<UnreachablePoint>>,
+    /// Whether the unreachable points went to disk while searching (see
+    /// `ReportConfig::spill`) and were dropped once the hulls were drawn.
+    /// `unreachable_points` is then empty, but `unreachable` still counts
+    /// them.
+    pub spilled: bool,
+    /// Number of grid points within range of a charger.
+    pub reachable: usize,
+    /// Number of grid points not within range of any charger.
+    pub unreachable: usize,
+    /// Number of grid points that needed an OSRM lookup to decide. These are
+    /// also counted as either reachable or unreachable.
+    pub maybe: usize,
+    /// Number of grid points looked up with the routing engine. Each takes
+    /// one or more requests, depending on the engine and how many candidate
+    /// chargers it tries, or none if the answer was cached.
+    pub routed_points: usize,
+    /// How long the search took.
+    pub elapsed: Duration,
+    /// Number of people living in `polygons`, if population data was given
+    /// (see `with_population`). Gaps where more people live are a higher
+    /// priority to fill than bigger but emptier ones.
+    pub priority_score: Option<f64>,
+}
+
+impl GapReport {
+    pub(crate) fn new(
+        unreachable_points: Vec<geo::Point<f64>>,
+        hull: &HullConfig,
+        reachable: usize,
+        maybe: usize,
+        routed_points: usize,
+        elapsed: Duration,
+    ) -> GapReport {
+        let polygons = {
+            let _span = tracing::debug_span!("gap_hulls").entered();
+            gap_hulls(&unreachable_points, hull)
+        };
+        GapReport {
+            area_km2: polygons.iter().map(gap_area_km2).sum(),
+            polygons,
+            unreachable: unreachable_points.len(),
+            unreachable_points,
+            reachable_points: None,
+            unreachable_details: None,
+            spilled: false,
+            reachable,
+            maybe,
+            routed_points,
+            elapsed,
+            priority_score: None,
+        }
+    }
+
+    /// Sets `priority_score` to the population inside the gaps.
+    pub fn with_population(self, population: &Population) -> GapReport {
+        GapReport {
+            priority_score: Some(population.within(&self.polygons)),
+            ..self
+        }
+    }
+
+    /// Total number of grid points checked.
+    pub fn total(&self) -> usize {
+        self.reachable + self.unreachable
+    }
+}
+
+/// Combines the reports of all the chunks into one, as if the whole area had
+/// been searched as a single chunk. Hulls are drawn again around all of the
+/// unreachable points, so a gap that straddles chunks becomes one polygon
+/// instead of several cut off along the chunk edges. The merged report has
+/// no `priority_score` (see `GapReport::with_population`), and its `elapsed`
+/// is that of the slowest chunk, since chunks are searched in parallel.
+///
+/// Reports that `spilled` their points keep the hulls they have, which are
+/// added to the merged report as they are.
+pub fn merge_reports(reports: Vec<GapReport>, hull: &HullConfig) -> GapReport {
+    let mut unreachable_points = Vec::new();
+    let mut spilled = Vec::new();
+    let mut spilled_points = 0;
+    let mut reachable_points: Option<Vec<_>> = None;
+    let mut unreachable_details: Option<Vec<_>> = None;
+    let (mut reachable, mut maybe, mut routed_points) = (0, 0, 0);
+    let mut elapsed = Duration::ZERO;
+    for report in reports {
+        if report.spilled {
+            spilled.extend(report.polygons);
+            spilled_points += report.unreachable;
+        }
+        unreachable_points.extend(report.unreachable_points);
+        if let Some(points) = report.reachable_points {
+            reachable_points.get_or_insert_with(Vec::new).extend(points);
+        }
+        if let Some(details) = report.unreachable_details {
+            unreachable_details
+                .get_or_insert_with(Vec::new)
+                .extend(details);
+        }
+        reachable += report.reachable;
+        maybe += report.maybe;
+        routed_points += report.routed_points;
+        elapsed = elapsed.max(report.elapsed);
+    }
+    let merged = GapReport::new(
+        unreachable_points,
+        hull,
+        reachable,
+        maybe,
+        routed_points,
+        elapsed,
+    );
+    if spilled_points == 0 {
+        return GapReport {
+            reachable_points,
+            unreachable_details,
+            ..merged
+        };
+    }
+    let mut polygons = merged.polygons;
+    polygons.0.extend(spilled);
+    GapReport {
+        area_km2: polygons.iter().map(gap_area_km2).sum(),
+        polygons,
+        unreachable: merged.unreachable + spilled_points,
+        reachable_points,
+        unreachable_details,
+        spilled: true,
+        ..merged
+    }
+}
+
+/// An unreachable grid point, with the closest it came to a charger, see
+/// `ReportConfig::emit_unreachable_details`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnreachablePoint {
+    pub point: TrialPoint,
+    /// Station id of the nearest charger in a straight line, if there are
+    /// any chargers at all.
+    pub nearest_charger: Option<String>,
+    /// Distance to that charger in meters, by `RangeConfig::metric`.
+    pub straight_line_meters: Option<u64>,
+    /// Shortest driving distance found to a charger in range in a straight
+    /// line, if any were looked up (in `RangeMode::Distance`).
+    pub driving_meters: Option<f64>,
+    /// Shortest driving time found, in seconds, in `RangeMode::Duration`.
+    pub driving_secs: Option<f64>,
+}
+
+/// The gaps found in one chunk, as written to the output.
+#[derive(Clone, Debug)]
+pub struct ChunkGap {
+    pub chunk_id: usize,
+    /// Number of unreachable grid points.
+    pub point_count: usize,
+    pub polygons: MultiPolygon<f64>,
+    pub area_km2: f64,
+    /// See `GapReport::priority_score`.
+    pub priority_score: Option<f64>,
+}
+
+/// How far `AllChargerLocations::find_gaps` has got through its grid.
+/// Points that needed a routing lookup (`maybe`) are also counted as
+/// reachable or unreachable.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Progress {
+    /// Grid points checked so far.
+    pub processed: usize,
+    /// Grid points to check in all.
+    pub total: usize,
+    pub reachable: usize,
+    pub unreachable: usize,
+    pub maybe: usize,
+}
+
+/// How many grid points `find_gaps` checks between progress reports.
+pub const PROGRESS_INTERVAL: usize = 1_000;
+
+/// Most grid points a run may look up with the routing engine, shared
+/// between its chunks (see `GapConfig::budget`). Each point is a single
+/// OSRM table request, but up to a request per candidate charger with
+/// engines that look them up one at a time. Once it's spent, points that would need a
+/// lookup are taken to be unreachable, so the gaps come out too big rather
+/// than too small: good enough for a preview.
+#[derive(Debug)]
+pub struct PointBudget {
+    limit: usize,
+    used: AtomicUsize,
+    exhausted: AtomicBool,
+}
+
+impl PointBudget {
+    pub fn new(limit: usize) -> PointBudget {
+        PointBudget {
+            limit,
+            used: AtomicUsize::new(0),
+            exhausted: AtomicBool::new(false),
+        }
+    }
+
+    /// Takes one point from the budget, if there are any left. Logs the
+    /// first time there aren't.
+    pub fn try_spend(&self) -> bool {
+        if self.used.fetch_add(1, Ordering::Relaxed) < self.limit {
+            return true;
+        }
+        if !self.exhausted.swap(true, Ordering::Relaxed) {
+            warn!(
+                "Looked up all {} routed points, treating the remaining maybe reachable points as unreachable",
+                self.limit
+            );
+        }
+        false
+    }
+
+    /// Points looked up so far.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed).min(self.limit)
+    }
+
+    /// Whether any point was taken to be unreachable for lack of budget.
+    pub fn exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::Relaxed)
+    }
+}
+
+/// Checks only part of the grid, for a quick rough look at where the gaps
+/// are: a pseudo-random `fraction` of the points, picked by `seed`. The same
+/// seed always picks the same points, however the area is split into
+/// chunks, and the hulls come out coarser than with the whole grid.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    /// Share of the grid points to check, between 0.0 and 1.0.
+    pub fraction: f64,
+    pub seed: u64,
+}
+
+impl Sample {
+    /// Whether `point` is one of the points checked.
+    pub fn keeps(&self, point: &TrialPoint) -> bool {
+        let hash = splitmix64(
+            self.seed ^ point.latitude.to_bits() ^ point.longitude.to_bits().rotate_left(32),
+        );
+        // The top 53 bits, as a number in [0, 1)
+        ((hash >> 11) as f64 / (1u64 << 53) as f64) < self.fraction
+    }
+}
+
+/// Mixes the bits of `x`, see https://prng.di.unimi.it/splitmix64.c.
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Grid points counted by `AllChargerLocations::estimate_gaps`. Unlike in
+/// `GapReport`, `maybe` points aren't counted as reachable or unreachable,
+/// since that isn't known yet.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GridEstimate {
+    pub points: usize,
+    /// Points with a charger close enough to be assumed reachable.
+    pub reachable: usize,
+    /// Points with no charger in range at all.
+    pub unreachable: usize,
+    /// Points that would need a routing lookup.
+    pub maybe: usize,
+}
+
+impl std::ops::Add for GridEstimate {
+    type Output = GridEstimate;
+
+    fn add(self, other: GridEstimate) -> GridEstimate {
+        GridEstimate {
+            points: self.points + other.points,
+            reachable: self.reachable + other.reachable,
+            unreachable: self.unreachable + other.unreachable,
+            maybe: self.maybe + other.maybe,
+        }
+    }
+}
+
+/// Totals over all the chunks of a run, for comparing runs (e.g. after the
+/// charger data is refreshed). Written as JSON by `--summary-path`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RunSummary {
+    /// Grid resolution north to south, in degrees.
+    pub resolution: f64,
+    /// Grid resolution east to west, in degrees. The same as `resolution`
+    /// unless they were set separately.
+    pub resolution_lon: f64,
+    pub total_points: usize,
+    pub reachable: usize,
+    pub unreachable: usize,
+    /// Points that needed a routing lookup, see `GapReport::maybe`.
+    pub maybe: usize,
+    /// See `GapReport::routed_points`.
+    pub routed_points: usize,
+    /// Wall clock time for all of the chunks, which run in parallel, so this
+    /// is less than the sum of their `GapReport::elapsed`.
+    pub elapsed_secs: f64,
+    /// Total area of the gaps in square kilometers.
+    pub gap_area_km2: f64,
+}
+
+impl RunSummary {
+    pub fn new(
+        reports: &[GapReport],
+        resolution: impl Into<Resolution>,
+        elapsed: Duration,
+    ) -> RunSummary {
+        let resolution = resolution.into();
+        RunSummary {
+            resolution: resolution.lat,
+            resolution_lon: resolution.lon,
+            total_points: reports.iter().map(GapReport::total).sum(),
+            reachable: reports.iter().map(|r| r.reachable).sum(),
+            unreachable: reports.iter().map(|r| r.unreachable).sum(),
+            maybe: reports.iter().map(|r| r.maybe).sum(),
+            routed_points: reports.iter().map(|r| r.routed_points).sum(),
+            elapsed_secs: elapsed.as_secs_f64(),
+            gap_area_km2: reports.iter().map(|r| r.area_km2).sum(),
+        }
+    }
+
+    /// Writes the summary to `path` as a JSON object.
+    pub fn write(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        atomic::write_file_atomically(Path::new(path), |writer| {
+            Ok(serde_json::to_writer_pretty(writer, self)?)
+        })
+    }
+}
+
+/// How many chunks per CPU `run_gap_analysis` splits the search area into by
+/// default. Chunks near the coast or in cities take much less time than
+/// others, so having many more chunks than threads keeps them all busy.
+pub const CHUNKS_PER_CPU: usize = 16;
+
+/// What `run_gap_analysis` searches, and how. Start from `GapConfig::new`
+/// and set whatever else differs from the defaults.
+#[derive(Clone)]
+pub struct GapConfig<'a> {
+    pub chargers: &'a AllChargerLocations,
+    /// Where driving distances come from, e.g. an `OsrmClient`.
+    pub routes: &'a dyn RouteProvider,
+    /// Area to search.
+    pub bbox: BoundingBox,
+    /// Grid resolution in degrees. With `max_resolution`, the finest one,
+    /// which then has to be square.
+    pub resolution: Resolution,
+    /// Start from cells this many degrees across and only split the ones
+    /// that aren't all reachable or all unreachable (see `adaptive`). `None`
+    /// checks every point of the grid.
+    pub max_resolution: Option<f64>,
+    /// Only grid points inside this region are checked.
+    pub region: Option<&'a MultiPolygon<f64>>,
+    pub range: RangeConfig,
+    pub hull: HullConfig,
+    /// What each chunk's report keeps besides the gaps.
+    pub report: ReportConfig,
+    /// Population data to set each report's `priority_score` from.
+    pub population: Option<&'a Population>,
+    /// About how many chunks to split `bbox` into, see
+    /// `BoundingBox::grid_shape`. Chunks are searched in parallel.
+    pub chunks: usize,
+    /// Called with the chunk number and its `Progress` as it goes (every
+    /// `PROGRESS_INTERVAL` points, only at the end with `max_resolution`),
+    /// and once more when the chunk is done. Progress is logged otherwise.
+    pub progress: Option<&'a ChunkProgressFn<'a>>,
+    /// Most grid points to look up with the routing engine across all of the
+    /// chunks. `None` looks up as many as it takes.
+    pub budget: Option<&'a PointBudget>,
+    /// Directory to write each chunk's gaps to as soon as it's done, as
+    /// `chunk_{id}.geojson` (see `geojson::write_chunk_gaps_geojson`), to look
+    /// at during a long run or keep if it dies. It has to exist already.
+    pub partial_dir: Option<&'a Path>,
+    /// Stops searching once set, e.g. from a Ctrl-C handler, so that the
+    /// reports only cover the points checked by then. Chunks that haven't
+    /// started yet come back empty. Not checked with `max_resolution`.
+    pub cancel: Option<&'a AtomicBool>,
+    /// Only check some of the grid points. Not used with `max_resolution`.
+    pub sample: Option<Sample>,
+}
+
+/// Callback for `GapConfig::progress`, with the chunk number.
+pub type ChunkProgressFn<'a> = dyn Fn(usize, &Progress) + Sync + 'a;
+
+impl<'a> GapConfig<'a> {
+    /// Searches `bbox` for gaps between `chargers` at the default resolution
+    /// of 0.01 degrees, with the default range and hulls, in
+    /// `CHUNKS_PER_CPU` chunks per CPU.
+    pub fn new(
+        chargers: &'a AllChargerLocations,
+        routes: &'a dyn RouteProvider,
+        bbox: BoundingBox,
+    ) -> GapConfig<'a> {
+        GapConfig {
+            chargers,
+            routes,
+            bbox,
+            resolution: Resolution::square(0.01),
+            max_resolution: None,
+            region: None,
+            range: RangeConfig::default(),
+            hull: HullConfig::default(),
+            report: ReportConfig::default(),
+            population: None,
+            chunks: num_cpus::get() * CHUNKS_PER_CPU,
+            progress: None,
+            budget: None,
+            partial_dir: None,
+            cancel: None,
+            sample: None,
+        }
+    }
+
+    /// Searches one chunk.
+    fn chunk_report(&self, chunk: usize, bbox: BoundingBox) -> Result<GapReport, GapError> {
+        let report = match (self.max_resolution, self.progress) {
+            (Some(max_resolution), _) => self.chargers.adaptive_find_gaps(
+                adaptive::AdaptiveResolution {
+                    min: self.resolution.lat,
+                    max: max_resolution,
+                },
+                bbox,
+                self.routes,
+                self.region,
+                &self.range,
+                &self.hull,
+                &self.report,
+                self.budget,
+            ),
+            (None, Some(progress)) => self.chargers.find_gaps_with_progress(
+                self.resolution,
+                bbox,
+                self.routes,
+                self.region,
+                self.sample,
+                &self.range,
+                &self.hull,
+                &self.report,
+                self.budget,
+                self.cancel,
+                &|chunk_progress| progress(chunk, chunk_progress),
+            )?,
+            (None, None) => self.chargers.find_gaps_with_progress(
+                self.resolution,
+                bbox,
+                self.routes,
+                self.region,
+                self.sample,
+                &self.range,
+                &self.hull,
+                &self.report,
+                self.budget,
+                self.cancel,
+                &log_progress,
+            )?,
+        };
+        if let Some(progress) = self.progress {
+            progress(
+                chunk,
+                &Progress {
+                    processed: report.total(),
+                    total: report.total(),
+                    reachable: report.reachable,
+                    unreachable: report.unreachable,
+                    maybe: report.maybe,
+                },
+            );
+        }
+        Ok(match self.population {
+            Some(population) => report.with_population(population),
+            None => report,
+        })
+    }
+}
+
+/// Splits `config.bbox` into chunks and finds the gaps in each of them in
+/// parallel. The reports are in chunk order, which is also their chunk id in
+/// the output, e.g. `ChunkGap::chunk_id`.
+pub fn run_gap_analysis(config: GapConfig) -> Result<Vec<GapReport>, GapError> {
+    config.bbox.validate().map_err(GapError::InvalidConfig)?;
+    let resolution = config.resolution;
+    if [resolution.lat, resolution.lon]
+        .iter()
+        .any(|degrees| degrees.is_nan() || *degrees <= 0.0)
+    {
+        return Err(GapError::InvalidConfig(format!(
+            "resolution {} is not positive",
+            resolution
+        )));
+    }
+    if let Some(max_resolution) = config.max_resolution {
+        if !resolution.is_square() {
+            return Err(GapError::InvalidConfig(
+                "the adaptive grid (max_resolution) needs a square resolution".to_string(),
+            ));
+        }
+        if max_resolution < resolution.lat {
+            return Err(GapError::InvalidConfig(
+                "max_resolution must be at least resolution".to_string(),
+            ));
+        }
+    }
+    if config.chunks == 0 {
+        return Err(GapError::InvalidConfig(
+            "there must be at least 1 chunk".to_string(),
+        ));
+    }
+    let (rows, cols) = config.bbox.grid_shape(config.chunks);
+    let chunks = config.bbox.chunkify_grid(rows, cols);
+    let chunk_count = chunks.len();
+    debug!("splitting into {} x {} chunks", rows, cols);
+    let completed = AtomicUsize::new(0);
+    chunks
+        .into_par_iter()
+        .enumerate()
+        .map(|(chunk, bbox)| {
+            let report = config.chunk_report(chunk, bbox)?;
+            let cancelled = config
+                .cancel
+                .is_some_and(|cancel| cancel.load(Ordering::Relaxed));
+            // A cancelled chunk's gaps are only as far as it got
+            if let Some(dir) = config.partial_dir.filter(|_| !cancelled) {
+                // Partial results are a convenience, not worth stopping for
+                if let Err(error) = geojson::write_chunk_gaps_geojson(dir, chunk, &report) {
+                    warn!("Couldn't write the gaps of chunk {}: {}", chunk, error);
+                }
+            }
+            info!(
+                "Completed chunk {}/{} in {:?}: reachable: {}, unreachable: {}, maybe reachable: {}, routed points: {}",
+                completed.fetch_add(1, Ordering::Relaxed) + 1,
+                chunk_count,
+                report.elapsed,
+                report.reachable,
+                report.unreachable,
+                report.maybe,
+                report.routed_points
+            );
+            Ok(report)
+        })
+        .collect()
+}
+
+/// Hull around a set of unreachable points, using the algorithm from `hull`.
+/// An alpha shape can have several parts, which don't fit in one polygon, so
+/// `HullAlgorithm::Alpha` gets the convex hull here; `gap_hulls` has the
+/// actual alpha shape.
+///
+/// There's no meaningful hull around fewer than three points, so those cases
+/// get a polygon with zero area instead:
+/// - no points: a polygon with an empty exterior,
+/// - one point: an exterior ring collapsed to that point,
+/// - two points: an exterior ring that goes to the other point and back,
+///   i.e. the line between them.
+pub fn gap_hull(points: &[geo::Point<f64>], hull: &HullConfig) -> geo::Polygon<f64> {
+    match points {
+        [] => geo::Polygon::new(geo::LineString(vec![]), vec![]),
+        [a] => geo::Polygon::new(geo::LineString(vec![a.0, a.0]), vec![]),
+        [a, b] => geo::Polygon::new(geo::LineString(vec![a.0, b.0, a.0]), vec![]),
+        _ => match hull.algorithm {
+            HullAlgorithm::Concave => geo::MultiPoint(points.to_vec()).concave_hull(hull.concavity),
+            HullAlgorithm::Convex | HullAlgorithm::Alpha => {
+                geo::MultiPoint(points.to_vec()).convex_hull()
+            }
+        },
+    }
+}
+
+/// Hulls around each cluster of unreachable points (see `cluster_points` and
+/// `gap_hull`), so that separate gaps don't get wrapped into one polygon that
+/// covers the reachable area between them. With `HullAlgorithm::Alpha` a
+/// cluster can give several polygons; a cluster too thin to have an alpha
+/// shape at all (e.g. a single row of points) gets `gap_hull` instead.
+pub fn gap_hulls(points: &[geo::Point<f64>], hull: &HullConfig) -> MultiPolygon<f64> {
+    MultiPolygon(
+        cluster_points(points, hull.cluster_eps)
+            .iter()
+            .flat_map(|cluster| {
+                let shape = match hull.algorithm {
+                    HullAlgorithm::Alpha => alpha_shape::alpha_shape(cluster, hull.alpha).0,
+                    HullAlgorithm::Convex | HullAlgorithm::Concave => vec![],
+                };
+                if shape.is_empty() {
+                    vec![gap_hull(cluster, hull)]
+                } else {
+                    shape
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Simplifies gap polygons with Douglas-Peucker, dropping vertices that are
+/// within `epsilon` degrees of the line through their neighbours. Hulls
+/// around fine grids have long runs of nearly collinear vertices, which make
+/// the output much bigger without changing its shape. An `epsilon` of 0 (or
+/// less) leaves the polygons as they are.
+pub fn simplify_gaps(polygons: &MultiPolygon<f64>, epsilon: f64) -> MultiPolygon<f64> {
+    if epsilon <= 0.0 {
+        return polygons.clone();
+    }
+    polygons.simplify(&epsilon)
+}
+
+/// Splits `points` into clusters of points that are connected through points
+/// at most `eps` apart. This is DBSCAN with a minimum cluster size of one:
+/// gaps can be as small as a single grid point, so there is no noise.
+///
+/// Points stay in their original order within each cluster, and clusters are
+/// ordered by their first point. An `eps` of 0 (or less) returns all the
+/// points as a single cluster.
+pub fn cluster_points(points: &[geo::Point<f64>], eps: f64) -> Vec<Vec<geo::Point<f64>>> {
+    if points.is_empty() {
+        return Vec::new();
+    }
+    if eps <= 0.0 {
+        return vec![points.to_vec()];
+    }
+    // Bucket the points into eps sized cells, so only the neighboring cells
+    // have to be searched.
+    let cell = |p: &geo::Point<f64>| ((p.x() / eps).floor() as i64, (p.y() / eps).floor() as i64);
+    let mut cells: HashMap<(i64, i64), Vec<usize>> = HashMap::new();
+    for (i, point) in points.iter().enumerate() {
+        cells.entry(cell(point)).or_default().push(i);
+    }
+    let mut visited = vec![false; points.len()];
+    let mut clusters = Vec::new();
+    for start in 0..points.len() {
+        if visited[start] {
+            continue;
+        }
+        visited[start] = true;
+        let mut members = vec![start];
+        let mut stack = vec![start];
+        while let Some(i) = stack.pop() {
+            let (x, y) = cell(&points[i]);
+            for dx in -1..=1 {
+                for dy in -1..=1 {
+                    for &j in cells.get(&(x + dx, y + dy)).into_iter().flatten() {
+                        if !visited[j] && points[i].euclidean_distance(&points[j]) <= eps {
+                            visited[j] = true;
+                            members.push(j);
+                            stack.push(j);
+                        }
+                    }
+                }
+            }
+        }
+        members.sort_unstable();
+        clusters.push(members.into_iter().map(|i| points[i]).collect());
+    }
+    clusters
 }
 
-/// All operations done on ChargerLocations type
-#[derive(Clone, Debug, PartialEq)]
-pub struct ChargerLocation {
-    latitude: f64,
-    longitude: f64,
-    id: u64,
-}
-#[derive(Clone, Copy)]
-pub struct TrialPoint {
-    pub latitude: f64,
-    pub longitude: f64,
+/// Area of a gap polygon on the earth's surface, in square kilometers.
+///
+/// Uses Chamberlain and Duquette's spherical approximation, which is within a
+/// fraction of a percent of the true ellipsoidal area.
+pub fn gap_area_km2(polygon: &geo::Polygon<f64>) -> f64 {
+    polygon.chamberlain_duquette_unsigned_area() / 1_000_000.0
 }
 
-#[derive(Clone)]
-pub struct AllChargerLocations {
-    pub quadtree: QuadTree,
-    pub chargers_by_id: HashMap<ItemId, ChargerLocation>,
+/// Where a point is relative to a layer of gap polygons, see
+/// `TrialPoint::locate_in_gaps`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GapLocation {
+    /// Index of the polygon the point is in, if it's in a gap.
+    pub inside: Option<usize>,
+    /// Index of the polygon with the nearest boundary, or the one the point
+    /// is in.
+    pub nearest: usize,
+    /// Distance in meters from the point to the boundary of `nearest`: how
+    /// far into the gap it is, or how far from the gap.
+    pub boundary_meters: f64,
 }
 
-#[derive(Deserialize, Debug)]
-pub struct Json {
-    pub routes: Vec<Route>,
-}
+impl TrialPoint {
+    /// Which of `gaps` (e.g. a gap layer read back with `read_region`) this
+    /// point is in, if any, and how far it is from the edge. `None` if there
+    /// are no gaps at all.
+    pub fn locate_in_gaps(&self, gaps: &MultiPolygon<f64>) -> Option<GapLocation> {
+        let point = self.to_geo_point();
+        let boundary_meters: Vec<f64> = gaps
+            .iter()
+            .map(|polygon| self.boundary_distance(polygon))
+            .collect();
+        let inside = gaps.iter().position(|polygon| polygon.contains(&point));
+        let nearest = inside.or_else(|| {
+            (0..boundary_meters.len())
+                .min_by(|&a, &b| boundary_meters[a].total_cmp(&boundary_meters[b]))
+        })?;
+        Some(GapLocation {
+            inside,
+            nearest,
+            boundary_meters: boundary_meters[nearest],
+        })
+    }
 
-#[derive(Deserialize, Debug)]
-pub struct Route {
-    pub distance: f64,
+    /// Haversine distance in meters to the nearest point on the boundary of
+    /// `polygon`. Longitudes are scaled by the cosine of this point's
+    /// latitude to find that point, so that a degree counts the same either
+    /// way nearby.
+    fn boundary_distance(&self, polygon: &geo::Polygon<f64>) -> f64 {
+        let scale = self.latitude.to_radians().cos().max(1e-9);
+        let scaled = |c: &geo::Coordinate<f64>| geo::Coordinate {
+            x: c.x * scale,
+            y: c.y,
+        };
+        let point = geo::Point::new(self.longitude * scale, self.latitude);
+        std::iter::once(polygon.exterior())
+            .chain(polygon.interiors())
+            .filter_map(|ring| {
+                let ring: geo::LineString<f64> = ring.0.iter().map(scaled).collect();
+                match ring.closest_point(&point) {
+                    geo::Closest::Intersection(closest) | geo::Closest::SinglePoint(closest) => {
+                        let closest = geo::Point::new(closest.x() / scale, closest.y());
+                        Some(self.to_geo_point().haversine_distance(&closest))
+                    }
+                    geo::Closest::Indeterminate => None,
+                }
+            })
+            .fold(f64::INFINITY, f64::min)
+    }
 }
-#[derive(Debug)]
-pub struct BoundingBox {
-    pub lat_min: f64,
-    pub lat_max: f64,
-    pub lon_min: f64,
-    pub lon_max: f64,
+
+/// How `find_gaps` reports its progress: at debug level.
+fn log_progress(progress: &Progress) {
+    debug!(
+        "{:?} {}/{}: reachable: {}, unreachable: {}, maybe reachable: {}",
+        thread::current().id(),
+        progress.processed,
+        progress.total,
+        progress.reachable,
+        progress.unreachable,
+        progress.maybe
+    )
 }
 
 impl AllChargerLocations {
+    /// Whether `point` is in range of a charger, and whether finding out took
+    /// asking `routes` (when `check_charger` can't tell by itself). Points
+    /// that would need asking once `budget` is spent are unreachable.
+    pub(crate) fn point_reachable(
+        &self,
+        point: &TrialPoint,
+        routes: &dyn RouteProvider,
+        config: &RangeConfig,
+        budget: Option<&PointBudget>,
+    ) -> (bool, bool) {
+        match point.check_charger(self, config) {
+            CheckResult::Yes => (true, false),
+            CheckResult::No => (false, false),
+            CheckResult::Maybe { .. } if budget.is_some_and(|budget| !budget.try_spend()) => {
+                (false, false)
+            }
+            CheckResult::Maybe { candidates } => {
+                // Ask the routing engine whether any of the closest
+                // candidates is in range by road.
+                let candidates: Vec<_> = candidates
+                    .into_iter()
+                    .take(routes.max_candidates())
+                    .map(|(charger, _)| charger)
+                    .collect();
+                let _span = tracing::trace_span!("routing").entered();
+                (routes.any_in_range(point, &candidates, config), true)
+            }
+        }
+    }
+
+    /// What is known about `point`, which `point_reachable` found to be
+    /// unreachable, asking `routes` if it did. With a `MemoizedRoutes`, the
+    /// driving distances it looked up don't get looked up again.
+    pub(crate) fn unreachable_point(
+        &self,
+        point: &TrialPoint,
+        routes: &dyn RouteProvider,
+        config: &RangeConfig,
+        asked_routes: bool,
+    ) -> UnreachablePoint {
+        let nearest = point
+            .nearest_chargers_k(self, config.max_range_meters, config.metric, 1)
+            .pop()
+            .or_else(|| self.nearest_out_of_range(point, config.max_range_meters, config.metric));
+        let (mut driving_meters, mut driving_secs) = (None, None);
+        if asked_routes {
+            let candidates: Vec<_> = point
+                .nearest_chargers(self, config.max_range_meters, config.metric)
+                .into_iter()
+                .take(routes.max_candidates())
+                .map(|(charger, _)| charger)
+                .collect();
+            let shortest = |costs: Vec<Option<f64>>| costs.into_iter().flatten().reduce(f64::min);
+            match config.mode {
+                RangeMode::Distance => {
+                    driving_meters = shortest(routes.driving_distances(point, &candidates))
+                }
+                RangeMode::Duration => {
+                    driving_secs = shortest(routes.driving_durations(point, &candidates))
+                }
+            }
+        }
+        UnreachablePoint {
+            point: *point,
+            straight_line_meters: nearest.as_ref().map(|(_, distance)| *distance),
+            nearest_charger: nearest.map(|(charger, _)| charger.station_id),
+            driving_meters,
+            driving_secs,
+        }
+    }
+
+    /// The charger nearest to `point` further than `max_range_meters`,
+    /// searching twice as far each time, up to halfway around the earth.
+    /// Chargers found in the corners of a search box may be further than
+    /// ones just outside it, so only those within the range count.
+    fn nearest_out_of_range(
+        &self,
+        point: &TrialPoint,
+        max_range_meters: u64,
+        metric: DistanceMetric,
+    ) -> Option<(ChargerLocation, u64)> {
+        const HALF_CIRCUMFERENCE_METERS: u64 = 20_040_000;
+        let mut range = max_range_meters.max(1);
+        while range < HALF_CIRCUMFERENCE_METERS {
+            range = (range * 2).min(HALF_CIRCUMFERENCE_METERS);
+            let nearest = point.nearest_chargers_k(self, range, metric, 1).pop();
+            if nearest.as_ref().is_some_and(|(_, distance)| {
+                *distance <= range || range == HALF_CIRCUMFERENCE_METERS
+            }) {
+                return nearest;
+            }
+        }
+        None
+    }
+
+    /// Counts how the points of the grid `find_gaps` would check are decided
+    /// by `check_charger` alone, without asking a routing engine: an
+    /// estimate of how long a run will take, since every `maybe` point needs
+    /// a lookup.
+    pub fn estimate_gaps(
+        &self,
+        resolution: impl Into<Resolution>,
+        bbox: BoundingBox,
+        region: Option<&MultiPolygon<f64>>,
+        config: &RangeConfig,
+    ) -> GridEstimate {
+        let mut estimate = GridEstimate::default();
+        for point in bbox.generate_grid_within(resolution, region) {
+            estimate.points += 1;
+            match point.check_charger(self, config) {
+                CheckResult::Yes => estimate.reachable += 1,
+                CheckResult::No => estimate.unreachable += 1,
+                CheckResult::Maybe { .. } => estimate.maybe += 1,
+            }
+        }
+        estimate
+    }
+
     pub fn find_gaps(
         &self,
-        resolution: f64,
+        resolution: impl Into<Resolution>,
+        bbox: BoundingBox,
+        routes: &dyn RouteProvider,
+        region: Option<&MultiPolygon<f64>>,
+        config: &RangeConfig,
+        hull: &HullConfig,
+    ) -> GapReport {
+        self.find_gaps_with_progress(
+            resolution,
+            bbox,
+            routes,
+            region,
+            None,
+            config,
+            hull,
+            &ReportConfig::default(),
+            None,
+            None,
+            &log_progress,
+        )
+        .expect("find_gaps doesn't spill, which is all that can fail")
+    }
+
+    /// Like `find_gaps`, but calls `progress` every `PROGRESS_INTERVAL`
+    /// points, starting with the first one, instead of logging how far it
+    /// has got, and stops asking `routes` once `budget` is spent. With a
+    /// `sample`, only some of the grid points are checked. The report also
+    /// keeps whatever `report` asks for, which `find_gaps` never does.
+    ///
+    /// Fails only if `report.spill` is set and the points can't be written
+    /// to or read back from disk.
+    ///
+    /// Once `cancel` is set, the rest of the grid is skipped and the report
+    /// only covers the points checked before then.
+    #[allow(clippy::too_many_arguments)]
+    pub fn find_gaps_with_progress(
+        &self,
+        resolution: impl Into<Resolution>,
         bbox: BoundingBox,
-        osrm_url: &str,
-        client: Client,
-    ) -> Vec<geo::Point<f64>> {
-        let grid = bbox.generate_grid(resolution);
+        routes: &dyn RouteProvider,
+        region: Option<&MultiPolygon<f64>>,
+        sample: Option<Sample>,
+        config: &RangeConfig,
+        hull: &HullConfig,
+        report: &ReportConfig,
+        budget: Option<&PointBudget>,
+        cancel: Option<&AtomicBool>,
+        progress: &dyn Fn(&Progress),
+    ) -> Result<GapReport, GapError> {
+        let resolution = resolution.into();
+        let span = tracing::debug_span!(
+            profile::CHUNK_SPAN,
+            lat_min = bbox.lat_min,
+            lon_min = bbox.lon_min
+        );
+        let _entered = span.enter();
+        let grid = {
+            let _span = tracing::debug_span!("generate_grid").entered();
+            let mut grid = bbox.generate_grid_within(resolution, region);
+            if let Some(sample) = sample {
+                grid.retain(|point| sample.keeps(point));
+            }
+            grid
+        };
+        // Points are checked on other threads, which need to be told where
+        // their spans go.
+        let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
         let total = grid.len();
         let thread = thread::current().id();
-        println!("{:?} generated grid (length: {})", thread, total);
+        debug!("{:?} generated grid (length: {})", thread, total);
         let mut reachable = 0;
         let mut unreachable = 0;
         let mut maybe_reachable = 0;
-        // let mut api_call_counter = 0;
-        // let start = std::time::Instant::now();
-        let mut not_reachable_points = Vec::new();
-        for (i, point) in grid.into_iter().enumerate() {
-            assert!(bbox.contains_point(point));
-            let result = point.check_charger(&self);
-            match result {
-                CheckResult::Yes => {
-                    reachable += 1;
+        let mut routed_points = 0;
+        let start = Instant::now();
+        let mut not_reachable_points = PointBuffer::new(report.spill.clone());
+        let mut reachable_points = Vec::new();
+        let mut unreachable_details = Vec::new();
+        // The details of unreachable points reuse their driving distances
+        let memoized;
+        let routes = if report.emit_unreachable_details {
+            memoized = MemoizedRoutes::new(routes);
+            &memoized
+        } else {
+            routes
+        };
+        let cancelled = || cancel.is_some_and(|cancel| cancel.load(Ordering::Relaxed));
+        // Points are checked in parallel a block at a time, and the results
+        // added up in grid order, so that the report and the progress events
+        // are the same as if they had been checked one by one.
+        'blocks: for (block_number, block) in grid.chunks(PROGRESS_INTERVAL).enumerate() {
+            let results: Vec<Option<(bool, bool, Option<UnreachablePoint>)>> = block
+                .par_iter()
+                .map(|point| {
+                    assert!(bbox.contains_point(*point));
+                    // Skip the rest of the block once cancelled
+                    (!cancelled()).then(|| {
+                        tracing::dispatcher::with_default(&dispatch, || {
+                            let _span =
+                                tracing::trace_span!(parent: &span, "check_point").entered();
+                            let (reachable, asked_routes) =
+                                self.point_reachable(point, routes, config, budget);
+                            let details =
+                                (!reachable && report.emit_unreachable_details).then(|| {
+                                    self.unreachable_point(point, routes, config, asked_routes)
+                                });
+                            (reachable, asked_routes, details)
+                        })
+                    })
+                })
+                .collect();
+            for (j, (point, result)) in block.iter().zip(results).enumerate() {
+                let i = block_number * PROGRESS_INTERVAL + j;
+                let (is_reachable, asked_routes, details) = match result {
+                    Some(result) if !cancelled() => result,
+                    _ => {
+                        debug!("{:?} cancelled after {} of {} points", thread, i, total);
+                        break 'blocks;
+                    }
+                };
+                if asked_routes {
+                    maybe_reachable += 1;
+                    routed_points += 1;
                 }
-                CheckResult::No => {
+                let lon_lat = point.to_geo_point();
+                if is_reachable {
+                    reachable += 1;
+                    if report.emit_reachable {
+                        reachable_points.push(lon_lat);
+                    }
+                } else {
                     unreachable += 1;
-                    let geo_point = geo::Point::new(point.longitude, point.latitude);
-                    not_reachable_points.push(geo_point);
+                    not_reachable_points.push(lon_lat)?;
+                    unreachable_details.extend(details);
                 }
-                CheckResult::Maybe { candidates } => {
-                    maybe_reachable += 1;
-                    // Find the distance between points and chargers that are maybe reachable
-                    // Where candidates is a vector of ChargerLocations
-                    let mut is_reachable = false;
-                    let mut tried_chargers = 0;
-                    for (charger, _) in candidates {
-                        if let Some(distance) = point.get_osrm_distance(osrm_url, &client, &charger)
-                        {
-                            // api_call_counter += 1;
-                            if distance as u64 <= MAX_RANGE_METERS {
-                                reachable += 1;
-                                is_reachable = true;
-                                break;
-                            }
-                            tried_chargers += 1;
-                            if tried_chargers == 50 {
-                                break;
-                            }
-                        }
-                    }
-                    if is_reachable == false {
-                        unreachable += 1;
-                        let geo_point = geo::Point::new(point.longitude, point.latitude);
-                        not_reachable_points.push(geo_point);
-                    }
+                if j == 0 {
+                    progress(&Progress {
+                        processed: i + 1,
+                        total,
+                        reachable,
+                        unreachable,
+                        maybe: maybe_reachable,
+                    });
                 }
             }
-            // if i % 1_000 == 0 {
-            //     println!("{:?} {}: {:?}", thread, i, start.elapsed());
-            //     println!("{:?} reachable: {}", thread, reachable);
-            //     println!("{:?} unreachable: {}", thread, unreachable);
-            //     println!("{:?} maybe reachable: {}", thread, maybe_reachable);
-            //     println!("{:?} api call count: {}", thread, api_call_counter);
-            //     api_call_counter = 0;
-            // }
-        }
-        println!(
-            "{:?} DONE Resolution: {}\n\nTotal points: {}\nReachable: {}\nUnreachable: {}\nUnknown: {}",
-            thread, resolution, total, reachable, unreachable, maybe_reachable
+        }
+        debug!(
+            "{:?} DONE Resolution: {}, Total points: {}, Reachable: {}, Unreachable: {}, Unknown: {}, Routed points: {}",
+            thread, resolution, total, reachable, unreachable, maybe_reachable, routed_points
+        );
+        let reachable_points = report.emit_reachable.then_some(reachable_points);
+        let unreachable_details = report
+            .emit_unreachable_details
+            .then_some(unreachable_details);
+        let file = match not_reachable_points.finish()? {
+            Points::InMemory(points) => {
+                return Ok(GapReport {
+                    reachable_points,
+                    unreachable_details,
+                    ..GapReport::new(
+                        points,
+                        hull,
+                        reachable,
+                        maybe_reachable,
+                        routed_points,
+                        start.elapsed(),
+                    )
+                })
+            }
+            Points::Spilled(file) => file,
+        };
+        debug!(
+            "{:?} drawing hulls around {} spilled points",
+            thread,
+            file.len()
         );
-        not_reachable_points
+        let threshold = report.spill.as_ref().map_or(0, |spill| spill.threshold);
+        let polygons = {
+            let _span = tracing::debug_span!("gap_hulls").entered();
+            spill::spilled_hulls(&file, hull, threshold)?
+        };
+        Ok(GapReport {
+            area_km2: polygons.iter().map(gap_area_km2).sum(),
+            polygons,
+            unreachable: file.len(),
+            reachable_points,
+            unreachable_details,
+            spilled: true,
+            ..GapReport::new(
+                Vec::new(),
+                hull,
+                reachable,
+                maybe_reachable,
+                routed_points,
+                start.elapsed(),
+            )
+        })
+    }
+}
+
+/// Which EV networks to keep when reading charger data. Networks are matched
+/// case-insensitively as substrings of the `EV Network` column.
+#[derive(Clone, Debug)]
+pub struct NetworkFilter {
+    /// If non-empty, only chargers on one of these networks are kept.
+    pub include: Vec<String>,
+    /// Chargers on any of these networks are dropped.
+    pub exclude: Vec<String>,
+}
+
+impl Default for NetworkFilter {
+    fn default() -> Self {
+        // We are interested in the gaps in non-Tesla charging infrastructure
+        NetworkFilter {
+            include: Vec::new(),
+            exclude: vec!["Tesla".to_string()],
+        }
+    }
+}
+
+/// Which chargers to keep when reading charger data.
+#[derive(Clone, Debug)]
+pub struct ChargerFilter {
+    pub networks: NetworkFilter,
+    pub level: ChargingLevel,
+    /// Only keep chargers that had opened by this date, to see what coverage
+    /// looked like then. Chargers without a valid open date are assumed to
+    /// be older and kept.
+    pub as_of: Option<NaiveDate>,
+    /// Leave out private chargers, like the NREL API's `access=public`.
+    pub public_only: bool,
+}
+
+impl Default for ChargerFilter {
+    fn default() -> Self {
+        ChargerFilter {
+            networks: NetworkFilter::default(),
+            level: ChargingLevel::All,
+            as_of: None,
+            public_only: false,
+        }
+    }
+}
+
+impl NetworkFilter {
+    /// Keeps the networks in `include`, if any, without those in `exclude`.
+    /// Empty names are ignored, so `exclude: Some(vec!["".into()])` excludes
+    /// nothing. Without `exclude`, Tesla is left out as in `default`, unless
+    /// networks to include are given: those are kept, even Tesla.
+    pub fn new(include: Vec<String>, exclude: Option<Vec<String>>) -> NetworkFilter {
+        let names = |names: Vec<String>| -> Vec<String> {
+            names.into_iter().filter(|name| !name.is_empty()).collect()
+        };
+        let include = names(include);
+        let exclude = match exclude {
+            Some(exclude) => names(exclude),
+            None if include.is_empty() => NetworkFilter::default().exclude,
+            None => Vec::new(),
+        };
+        NetworkFilter { include, exclude }
+    }
+
+    pub fn matches(&self, network: &str) -> bool {
+        let network = network.to_lowercase();
+        let matches_any = |names: &[String]| {
+            names
+                .iter()
+                .filter(|name| !name.is_empty())
+                .any(|name| network.contains(&name.to_lowercase()))
+        };
+        (self.include.is_empty() || matches_any(&self.include)) && !matches_any(&self.exclude)
+    }
+}
+
+/// Reads every polygon in a shapefile (if `path` ends in `.shp`) or a GeoJSON
+/// file (otherwise) into a single `MultiPolygon`, e.g. to use as a land mask.
+pub fn read_region(path: &str) -> Result<MultiPolygon<f64>, Box<dyn std::error::Error>> {
+    let is_shapefile = std::path::Path::new(path)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("shp"));
+    if !is_shapefile {
+        return geojson::read_region_geojson(path);
+    }
+    let polygons: Vec<geo::Polygon<f64>> =
+        shapefile::read_shapes_as::<_, shapefile::Polygon>(path)?
+            .into_iter()
+            .flat_map(MultiPolygon::from)
+            .collect();
+    if polygons.is_empty() {
+        return Err("shapefile region does not contain any polygons".into());
+    }
+    Ok(MultiPolygon(polygons))
+}
+
+/// Writes the chargers as points to a shapefile (if `path` ends in `.shp`)
+/// or a GeoJSON file (otherwise), e.g. to check which ones a filter kept.
+pub fn write_chargers(
+    path: &str,
+    chargers: &AllChargerLocations,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let is_shapefile = std::path::Path::new(path)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("shp"));
+    if is_shapefile {
+        shapefile_output::write_chargers(path, chargers)
+    } else {
+        geojson::write_chargers_geojson(path, chargers)
     }
 }
 
-pub fn download_source_data(nrel_api_key: &str) -> Result<AllChargerLocations, Box<dyn Error>> {
-    let url = format!("https://developer.nrel.gov/api/alt-fuel-stations/v1.csv?access=public&api_key={}&cards_accepted=all&cng_fill_type=all&cng_psi=all&cng_vehicle_class=all&country=all&download=true&e85_has_blender_pump=false&ev_charging_level=2%2Cdc_fast&ev_connector_type=all&ev_network=all&fuel_type=ELEC&hy_is_retail=true&limit=all&lng_vehicle_class=all&lpg_include_secondary=false&offset=0&owner_type=all&state=all&status=E&utf8_bom=true", nrel_api_key);
-    let body = reqwest::blocking::get(url)?.text()?;
-    let reader = Reader::from_reader(body.as_bytes());
-    read_csv(reader)
+/// Reads charger data from a CSV file, or from stdin if the path is `-`. The
+/// data may be gzipped (detected from the contents, not the extension).
+///
+/// Plain CSVs are parsed as they are read, but a gzipped one is not
+/// streamed: `gzip` only decompresses whole buffers, so the compressed file
+/// and then the whole decompressed CSV are held in memory before parsing
+/// starts. For a large export that can be several times the size of the
+/// file; decompress it first (or pipe `gunzip -c` into `-`) to avoid that.
+pub fn read_from_file(
+    path_to_csv: &str,
+    filter: &ChargerFilter,
+) -> Result<AllChargerLocations, GapError> {
+    let mut input: Box<dyn BufRead> = if path_to_csv == "-" {
+        Box::new(std::io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(File::open(path_to_csv)?))
+    };
+    if gzip::is_gzip(input.fill_buf()?) {
+        let mut compressed = Vec::new();
+        input.read_to_end(&mut compressed)?;
+        let csv = gzip::decompress(&compressed)?;
+        return read_csv(Reader::from_reader(csv.as_slice()), filter);
+    }
+    read_csv(Reader::from_reader(input), filter)
 }
 
-pub fn read_from_file(path_to_csv: &str) -> Result<AllChargerLocations, Box<dyn Error>> {
-    let reader = csv::Reader::from_path(path_to_csv)?;
-    read_csv(reader)
+/// Whether a row's coordinates could be a real charger. Besides being in range,
+/// (0, 0) is rejected since it is a common placeholder for missing data, and
+/// is in the middle of the Atlantic anyway.
+fn has_valid_coordinates(row: &CsvRow) -> bool {
+    (-90.0..=90.0).contains(&row.latitude)
+        && (-180.0..=180.0).contains(&row.longitude)
+        && (row.latitude, row.longitude) != (0.0, 0.0)
 }
 
-pub fn read_csv<R>(mut reader: csv::Reader<R>) -> Result<AllChargerLocations, Box<dyn Error>>
+pub fn read_csv<R>(
+    mut reader: csv::Reader<R>,
+    filter: &ChargerFilter,
+) -> Result<AllChargerLocations, GapError>
 where
     R: std::io::Read,
 {
-    let mut chargers_by_id = HashMap::new();
-    let rows = reader
-        .deserialize()
-        .filter_map(|row: Result<CsvRow, _>| row.ok())
-        // We are interested in the gaps in non-Tesla charging infrastructure
-        // TODO: might be interesting to make that a command line argument so
-        // we can see gaps in other networks
-        .filter(|row| !row.network.contains("Tesla"))
-        .map(|location| {
-            let id = ItemId(location.id as usize);
-            let point = Item::Point(Point {
-                x: location.latitude as f32,
-                y: location.longitude as f32,
-            });
-            // Because we don't need to copy the network strings all the time, just use ChargerLocation type
-            // so we convert csv row into ChargerLocation
-            chargers_by_id.insert(
-                id,
-                ChargerLocation {
-                    latitude: location.latitude,
-                    longitude: location.longitude,
-                    id: location.id,
+    // Parsing has to be done in order, but filtering the rows and building
+    // the index can be done in parallel, which matters for nationwide data.
+    let rows: Vec<CsvRow> = reader.deserialize().filter_map(Result::ok).collect();
+    chargers_from_rows(rows, filter)
+}
+
+/// One station in the NREL JSON API's response, with a few fields the CSV
+/// download doesn't have in a usable form. See
+/// https://developer.nrel.gov/docs/transportation/alt-fuel-stations-v1/all/
+/// for what they mean.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct NrelStation {
+    /// A number in the API's responses, but read as text like the CSV's.
+    #[serde(deserialize_with = "number_or_string")]
+    pub id: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    #[serde(default)]
+    pub ev_network: Option<String>,
+    #[serde(default)]
+    pub ev_level2_evse_num: Option<u32>,
+    #[serde(default)]
+    pub ev_dc_fast_num: Option<u32>,
+    #[serde(default)]
+    pub open_date: Option<String>,
+    /// "public" or "private".
+    #[serde(default)]
+    pub access_code: Option<String>,
+    /// Opening hours and other restrictions on access, as free text.
+    #[serde(default)]
+    pub access_days_time: Option<String>,
+    /// What charging costs, as free text.
+    #[serde(default)]
+    pub ev_pricing: Option<String>,
+}
+
+impl From<NrelStation> for CsvRow {
+    fn from(station: NrelStation) -> CsvRow {
+        CsvRow {
+            latitude: station.latitude,
+            longitude: station.longitude,
+            id: station.id,
+            network: station.ev_network.unwrap_or_default(),
+            level2_count: station.ev_level2_evse_num,
+            dc_fast_count: station.ev_dc_fast_num,
+            open_date: station.open_date,
+            access_code: station.access_code,
+            groups_with_access: None,
+        }
+    }
+}
+
+/// A JSON number or string, as a string.
+fn number_or_string<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrString {
+        Number(serde_json::Number),
+        String(String),
+    }
+    Ok(match NumberOrString::deserialize(deserializer)? {
+        NumberOrString::Number(number) => number.to_string(),
+        NumberOrString::String(string) => string,
+    })
+}
+
+/// The parts of an NREL JSON API response that are used. Errors have no
+/// `fuel_stations`.
+#[derive(Deserialize)]
+struct NrelResponse {
+    fuel_stations: Option<Vec<serde_json::Value>>,
+}
+
+/// Parses the stations in an NREL JSON API response, keeping the chargers
+/// that pass `filter` like `read_csv` does. A response without stations is
+/// an error from the API, and fails with `GapError::NrelApi`.
+pub fn read_nrel_json(body: &str, filter: &ChargerFilter) -> Result<AllChargerLocations, GapError> {
+    let response: NrelResponse = serde_json::from_str(body)?;
+    let stations = response.fuel_stations.ok_or_else(|| GapError::NrelApi {
+        status: 200,
+        message: nrel_error_message(body),
+    })?;
+    // Like unparseable CSV rows, stations without the basic fields are
+    // skipped rather than failing the whole download
+    let rows: Vec<CsvRow> = stations
+        .into_iter()
+        .filter_map(|station| serde_json::from_value::<NrelStation>(station).ok())
+        .map(CsvRow::from)
+        .collect();
+    chargers_from_rows(rows, filter)
+}
+
+/// Longest part of an error response from NREL that isn't JSON (e.g. an
+/// HTML page) to show, in characters.
+const NREL_ERROR_MAX_CHARS: usize = 200;
+
+/// The message in an error response from NREL (the api.data.gov gateway in
+/// front of it answers `{"error": {"code": ..., "message": ...}}`), or the
+/// start of the body if it isn't in that form.
+pub(crate) fn nrel_error_message(body: &str) -> String {
+    let json: serde_json::Value = serde_json::from_str(body).unwrap_or_default();
+    let error = &json["error"];
+    let message = error["message"]
+        .as_str()
+        .or_else(|| error.as_str())
+        .or_else(|| json["errors"][0].as_str())
+        .or_else(|| json["message"].as_str());
+    match (error["code"].as_str(), message) {
+        (Some(code), Some(message)) => format!("{}: {}", code, message),
+        (None, Some(message)) => message.to_string(),
+        _ => {
+            let body: String = body.trim().chars().take(NREL_ERROR_MAX_CHARS).collect();
+            if body.is_empty() {
+                "empty response".to_string()
+            } else {
+                body
+            }
+        }
+    }
+}
+
+/// Numbers station ids in the order they first appear, so that chargers
+/// with the same station id get the same `ChargerLocation::id`.
+fn station_numbers<'a>(station_ids: impl Iterator<Item = &'a str>) -> HashMap<&'a str, u64> {
+    let mut ids = HashMap::new();
+    for station_id in station_ids {
+        let next = ids.len() as u64;
+        ids.entry(station_id).or_insert(next);
+    }
+    ids
+}
+
+/// The chargers in `rows` that have valid coordinates and pass `filter`.
+fn chargers_from_rows(
+    rows: Vec<CsvRow>,
+    filter: &ChargerFilter,
+) -> Result<AllChargerLocations, GapError> {
+    let invalid_coordinates = rows
+        .par_iter()
+        .filter(|row| !has_valid_coordinates(row))
+        .count();
+    // There are only a few dozen networks, so chargers share one copy of each
+    // name instead of each owning a String.
+    let mut networks: HashMap<&str, Arc<str>> = HashMap::new();
+    for row in &rows {
+        networks
+            .entry(&row.network)
+            .or_insert_with(|| row.network.as_str().into());
+    }
+    let ids = station_numbers(rows.iter().map(|row| row.id.as_str()));
+    // Collecting keeps the rows in order, so that the last of several
+    // chargers with the same id still wins in `AllChargerLocations::new`
+    let mut chargers: Vec<ChargerLocation> = rows
+        .par_iter()
+        .filter(|row| has_valid_coordinates(row) && filter.networks.matches(&row.network))
+        .filter(|row| !filter.public_only || row.is_public())
+        .map(|row| {
+            let open_date = row.open_date.as_deref().and_then(parse_open_date);
+            if let Some(date) = row.open_date.as_deref().filter(|date| !date.is_empty()) {
+                if open_date.is_none() {
+                    debug!("charger {} has an invalid open date {:?}", row.id, date);
+                }
+            }
+            ChargerLocation {
+                latitude: row.latitude,
+                longitude: row.longitude,
+                id: ids[row.id.as_str()],
+                station_id: row.id.clone(),
+                network: networks[row.network.as_str()].clone(),
+                levels: ChargingLevels {
+                    level2: row.level2_count.unwrap_or(0) > 0,
+                    dc_fast: row.dc_fast_count.unwrap_or(0) > 0,
                 },
-            );
-            (id, point)
-        });
-    let quadtree = QuadTree::new(rows);
-    println!(
-        "tree = {:?}; len = {}",
-        quadtree.bbox(),
-        chargers_by_id.len()
+                open_date,
+                public: row.is_public(),
+            }
+        })
+        .filter(|charger| filter.level.matches(charger.levels))
+        .collect();
+    let undated = chargers
+        .par_iter()
+        .filter(|charger| charger.open_date.is_none())
+        .count();
+    if let Some(as_of) = filter.as_of {
+        chargers.retain(|charger| charger.open_date.is_none_or(|date| date <= as_of));
+    }
+    if invalid_coordinates > 0 {
+        warn!(
+            "dropped {} chargers with invalid coordinates",
+            invalid_coordinates
+        );
+    }
+    if let (Some(as_of), true) = (filter.as_of, undated > 0) {
+        info!(
+            "kept {} chargers with a missing or invalid open date as of {}",
+            undated, as_of
+        );
+    }
+    let chargers = AllChargerLocations::new(chargers);
+    if chargers.chargers_by_id.is_empty() {
+        return Err(GapError::EmptyData);
+    }
+    let bbox = chargers.index.root().envelope();
+    info!(
+        "loaded {} chargers, bounding box {:?} to {:?}",
+        chargers.chargers_by_id.len(),
+        bbox.lower(),
+        bbox.upper()
     );
-    Ok(AllChargerLocations {
-        quadtree,
-        chargers_by_id,
-    })
+    Ok(chargers)
 }
 
 pub enum CheckResult {
@@ -208,75 +2282,69 @@ pub enum CheckResult {
 }
 
 impl TrialPoint {
-    pub fn check_charger(&self, chargers: &AllChargerLocations) -> CheckResult {
-        let nearest_chargers = self.nearest_chargers(chargers);
-
-        // If there are no chargers within MAX_RANGE_METERS, the list will be empty;
-        // this point cannot be reachable based on driving distance if all crow-flies
-        // distances are greater.
-        if nearest_chargers.is_empty() {
-            return CheckResult::No;
-        }
-
-        // Because the list of chargers is sorted, the first element is
-        // always the closest, so we can use it for checking if the point
-        // is trivially reachable.
-        let nearest_charger_distance = nearest_chargers[0].1;
+    pub fn check_charger(
+        &self,
+        chargers: &AllChargerLocations,
+        config: &RangeConfig,
+    ) -> CheckResult {
+        // Only the closest charger matters for the quick checks, so the
+        // full sorted list is only built if they can't decide.
+        let nearest_charger_distance = match self
+            .nearest_chargers_k(chargers, config.max_range_meters, config.metric, 1)
+            .first()
+        {
+            Some((_, distance)) => *distance,
+            // If there are no chargers within the max range, the list will be empty;
+            // this point cannot be reachable based on driving distance if all crow-flies
+            // distances are greater.
+            None => return CheckResult::No,
+        };
 
         // If the nearest charger is really close, this point *definitely* has a
-        // reachable charger.
-        if nearest_charger_distance < (MAX_RANGE_METERS as f64 * CROW_FLIES_RATIO) as u64 {
+        // reachable charger. There is no straight-line distance that
+        // guarantees a short enough drive though, so in duration mode every
+        // point within range needs a lookup.
+        if config.mode == RangeMode::Distance
+            && nearest_charger_distance
+                < (config.max_range_meters as f64 * config.crow_flies_ratio) as u64
+        {
             CheckResult::Yes
         } else {
             // We need to use the OSRM API to find out whether a charger is reachable.
             CheckResult::Maybe {
-                candidates: nearest_chargers,
+                candidates: self.nearest_chargers(chargers, config.max_range_meters, config.metric),
             }
         }
     }
 
-    /// Returns the distance in kilometers from this `TrialPoint` to the given `charger`.
+    /// Returns the Haversine distance in meters from this `TrialPoint` to the
+    /// given `charger`.
     pub fn distance_to(&self, charger: &ChargerLocation) -> f64 {
-        // Calculate the distance using the Haversine formula
-
-        let lat1 = self.latitude * (PI / 180.);
-        let lat2 = charger.latitude * (PI / 180.);
-
-        let delta_lat = (self.latitude - charger.latitude) * (PI / 180.);
-        let delta_lon = (self.longitude - charger.longitude) * (PI / 180.);
-        let a = (delta_lat / 2.0).sin().powi(2)
-            + lat1.cos() * (lat2.cos()) * (delta_lon / 2.0).sin().powi(2);
-        let c = 2.0 * a.sqrt().atan2((1.0 - a).sqrt());
+        self.distance_to_with(charger, DistanceMetric::Haversine)
+    }
 
-        EARTH_RADIUS_METERS * c
+    /// Returns the distance in meters from this `TrialPoint` to the given
+    /// `charger`, calculated with `metric`.
+    pub fn distance_to_with(&self, charger: &ChargerLocation, metric: DistanceMetric) -> f64 {
+        let point = self.to_geo_point();
+        let charger = charger.to_geo_point();
+        match metric {
+            DistanceMetric::Haversine => point.haversine_distance(&charger),
+            DistanceMetric::Geodesic => point.geodesic_distance(&charger),
+        }
     }
 
-    pub fn nearest_chargers(&self, chargers: &AllChargerLocations) -> Vec<(ChargerLocation, u64)> {
-        // QuadTree uses 2 dimensional geometry, so we add padding to the bounding box to
-        // ensure we get all possible relevant points since this is an approximation
-        const PADDED_MAX_RANGE_METERS: f64 = MAX_RANGE_METERS as f64 + 25_000.0;
-        let (max_x, max_y) =
-            add_meters_to_coords(PADDED_MAX_RANGE_METERS, (self.latitude, self.longitude));
-        let (min_x, min_y) =
-            add_meters_to_coords(-PADDED_MAX_RANGE_METERS, (self.latitude, self.longitude));
-        let bbox = Rect {
-            max_x: max_x as f32,
-            max_y: max_y as f32,
-            min_x: min_x as f32,
-            min_y: min_y as f32,
-        };
-        // println!(
-        //     "bounding box {:?} at {:?} has dimensions {} x {}",
-        //     bbox,
-        //     bbox.get_center(),
-        //     bbox.get_width(),
-        //     bbox.get_height()
-        // );
-        let ids = chargers.quadtree.get_ids_that_overlap(&bbox);
-        let mut chargers_distances = Vec::with_capacity(ids.len());
-        for id in ids {
+    pub fn nearest_chargers(
+        &self,
+        chargers: &AllChargerLocations,
+        max_range_meters: u64,
+        metric: DistanceMetric,
+    ) -> Vec<(ChargerLocation, u64)> {
+        let _span = tracing::trace_span!("nearest_chargers").entered();
+        let mut chargers_distances = Vec::new();
+        for id in chargers.candidates(self, max_range_meters) {
             if let Some(charger) = chargers.chargers_by_id.get(&id) {
-                let distance = self.distance_to(&charger) as u64;
+                let distance = self.distance_to_with(charger, metric) as u64;
                 chargers_distances.push((charger.clone(), distance));
             }
         }
@@ -284,67 +2352,275 @@ impl TrialPoint {
         chargers_distances
     }
 
-    pub fn get_osrm_distance(
+    /// The `k` closest of `nearest_chargers`, closest first, without sorting
+    /// all of them: a max-heap keeps the closest `k` seen so far, so this
+    /// takes O(n log k) rather than O(n log n) in dense areas, and only those
+    /// `k` chargers are cloned.
+    pub fn nearest_chargers_k(
         &self,
-        osrm_url: &str,
-        client: &Client,
-        charger: &ChargerLocation,
-    ) -> Option<f64> {
-        return None;
-        let osrm_api_url = format!(
-            "{}/route/v1/driving/{},{};{},{}",
-            osrm_url, self.longitude, self.latitude, charger.longitude, charger.latitude
-        );
-        let mut retries = 0;
-        let body = loop {
-            match client
-                .get(osrm_api_url.clone())
-                .send()
-                .and_then(|rsp| rsp.text())
-            {
-                Ok(body) => match serde_json::from_str::<Json>(&body) {
-                    Ok(json) => break json,
-                    // If we get a response back (the request succeeded) but the response doesn't have
-                    // valid response json, we assume there is no possible path between those pts
-                    Err(error) => {
-                        println!(
-                            "{:?} retrying ({}) body error: {}\nbody: {}",
-                            thread::current().id(),
-                            retries,
-                            error,
-                            body,
-                        );
-                        return None;
-                    }
-                },
-                Err(error) => println!(
-                    "{:?} retrying ({}) request error: {}",
-                    thread::current().id(),
-                    retries,
-                    error
-                ),
-            };
-            retries += 1;
-            let sleep = if retries > 60 { 60 } else { retries };
-            thread::sleep(Duration::from_secs(sleep));
-        };
-        let distance = body.routes[0].distance;
-        Some(distance)
+        chargers: &AllChargerLocations,
+        max_range_meters: u64,
+        metric: DistanceMetric,
+        k: usize,
+    ) -> Vec<(ChargerLocation, u64)> {
+        let _span = tracing::trace_span!("nearest_chargers").entered();
+        if k == 0 {
+            return Vec::new();
+        }
+        // The sphere index finds chargers closest by haversine distance first,
+        // so the first k are the ones
+        if let (Some(sphere_index), DistanceMetric::Haversine) = (&chargers.sphere_index, metric) {
+            let max_chord_2 = sphere_chord_2(max_range_meters);
+            return sphere_index
+                .nearest_neighbor_iter_with_distance_2(&unit_sphere(self.latitude, self.longitude))
+                .take_while(|(_, chord_2)| *chord_2 <= max_chord_2)
+                .take(k)
+                .map(|(indexed, _)| {
+                    let charger = &chargers.chargers_by_id[&indexed.data];
+                    (charger.clone(), self.distance_to(charger) as u64)
+                })
+                .collect();
+        }
+        // (distance, id), with the furthest of the closest k on top
+        let mut closest = BinaryHeap::with_capacity(k + 1);
+        for id in chargers.candidates(self, max_range_meters) {
+            if let Some(charger) = chargers.chargers_by_id.get(&id) {
+                let distance = self.distance_to_with(charger, metric) as u64;
+                if closest.len() < k {
+                    closest.push((distance, id));
+                } else if closest
+                    .peek()
+                    .is_some_and(|&(furthest, _)| distance < furthest)
+                {
+                    closest.pop();
+                    closest.push((distance, id));
+                }
+            }
+        }
+        closest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|(distance, id)| (chargers.chargers_by_id[&id].clone(), distance))
+            .collect()
+    }
+
+    /// Part of the charger index that holds every charger within
+    /// `max_range_meters` of this point (and some that aren't).
+    pub(crate) fn search_envelope(&self, max_range_meters: u64) -> AABB<[f64; 2]> {
+        // The index uses 2 dimensional geometry, so we add padding to the bounding box to
+        // ensure we get all possible relevant points since this is an approximation
+        let padded_max_range_meters = max_range_meters as f64 + SEARCH_PADDING_METERS;
+        let (max_x, max_y) =
+            add_meters_to_coords(padded_max_range_meters, (self.latitude, self.longitude));
+        let (min_x, min_y) =
+            add_meters_to_coords(-padded_max_range_meters, (self.latitude, self.longitude));
+        AABB::from_corners([min_x, min_y], [max_x, max_y])
+    }
+}
+
+/// Checks that `url` is an absolute http(s) URL that can be used as the base of
+/// API requests, and returns it without a trailing slash so paths can be
+/// appended with `format!("{}/route/...", url)`.
+pub fn parse_base_url(url: &str) -> Result<String, String> {
+    let parsed = url::Url::parse(url).map_err(|e| format!("invalid URL {:?}: {}", url, e))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!(
+            "invalid URL {:?}: scheme must be http or https, not {:?}",
+            url,
+            parsed.scheme()
+        ));
+    }
+    if parsed.host_str().is_none() {
+        return Err(format!("invalid URL {:?}: missing host", url));
+    }
+    if parsed.query().is_some() || parsed.fragment().is_some() {
+        return Err(format!(
+            "invalid URL {:?}: base URL can't have a query string or fragment",
+            url
+        ));
+    }
+    Ok(parsed.as_str().trim_end_matches('/').to_string())
+}
+
+/// Grid resolution, in degrees, for grid points `km` apart on the ground.
+///
+/// A degree of latitude is about 111 km everywhere, so this is exact north to
+/// south. Grid points are the same number of degrees apart east to west,
+/// which is `cos(latitude)` times less on the ground: about 0.77 times `km`
+/// at 40° north.
+pub fn resolution_from_km(km: f64) -> f64 {
+    (km * 1000.0 / EARTH_RADIUS_METERS) * (180.0 / PI)
+}
+
+/// Spacing of the grid points, in degrees, north to south (`lat`) and east
+/// to west (`lon`). A plain number is the same in both directions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Resolution {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl Resolution {
+    /// The same number of degrees in both directions. Cells are narrower on
+    /// the ground than they are tall, by `cos(latitude)`.
+    pub fn square(degrees: f64) -> Resolution {
+        Resolution {
+            lat: degrees,
+            lon: degrees,
+        }
+    }
+
+    /// `lat` degrees north to south, and as far east to west on the ground at
+    /// `latitude`, e.g. the middle of the area being searched, so that cells
+    /// there are about square on the ground.
+    pub fn ground_square(lat: f64, latitude: f64) -> Resolution {
+        Resolution {
+            lat,
+            lon: lat / latitude.to_radians().cos(),
+        }
+    }
+
+    /// Whether both directions have the same spacing in degrees.
+    pub fn is_square(&self) -> bool {
+        self.lat == self.lon
+    }
+
+    /// The larger of the two spacings, e.g. to tell neighbouring grid points
+    /// apart from ones further away.
+    pub fn max(&self) -> f64 {
+        self.lat.max(self.lon)
+    }
+}
+
+impl From<f64> for Resolution {
+    fn from(degrees: f64) -> Self {
+        Resolution::square(degrees)
+    }
+}
+
+impl fmt::Display for Resolution {
+    /// One number if square, otherwise `lat x lon`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_square() {
+            write!(f, "{}", self.lat)
+        } else {
+            write!(f, "{} x {}", self.lat, self.lon)
+        }
     }
 }
 
+/// Corner of a box reaching `meters` north and east of `(lat, lon)` (south
+/// and west if negative), as `(lat, lon)`.
+///
+/// This is a local flat-earth approximation on a spherical earth, good to
+/// within a few percent for a few hundred kilometers, which is why
+/// `search_envelope` pads it. A degree of longitude is narrowest on the
+/// poleward side of the box, so the longitude offset is for that latitude,
+/// and the box covers every point within `meters` of the centre there too.
+/// Latitudes are clamped to ±90°, and a box that reaches a pole (or is wider
+/// than the whole earth) covers all longitudes, -180° to 180°. Boxes aren't
+/// wrapped around the antimeridian.
 pub fn add_meters_to_coords(meters: f64, (lat, lon): (f64, f64)) -> (f64, f64) {
-    let degrees_lat = lat + (meters / EARTH_RADIUS_METERS) * (180.0 / PI);
-    let degrees_lon =
-        lon + (meters / EARTH_RADIUS_METERS) * (180.0 / PI) / (lat * PI / 180.0).cos();
+    let delta_lat = (meters / EARTH_RADIUS_METERS) * (180.0 / PI);
+    let degrees_lat = (lat + delta_lat).clamp(-90.0, 90.0);
+    let poleward_lat = lat.abs() + delta_lat.abs();
+    let delta_lon = delta_lat / (poleward_lat * PI / 180.0).cos();
+    let degrees_lon = if poleward_lat >= 90.0 || delta_lon.abs() >= 180.0 {
+        180.0_f64.copysign(meters)
+    } else {
+        lon + delta_lon
+    };
     (degrees_lat, degrees_lon)
 }
 
+/// Bounding box of the continental United States.
+pub const CONTINENTAL_US: BoundingBox = BoundingBox {
+    lat_min: 24.5243,
+    lon_min: -124.7580,
+    lat_max: 49.1756,
+    lon_max: -66.9472,
+};
+
+impl FromStr for BoundingBox {
+    type Err = String;
+
+    /// Parses `"lat_min,lon_min,lat_max,lon_max"` and validates the result.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid bounding box {:?}: {}", s, e))?;
+        let bbox = match values[..] {
+            [lat_min, lon_min, lat_max, lon_max] => BoundingBox {
+                lat_min,
+                lon_min,
+                lat_max,
+                lon_max,
+            },
+            _ => {
+                return Err(format!(
+                    "invalid bounding box {:?}: expected lat_min,lon_min,lat_max,lon_max",
+                    s
+                ))
+            }
+        };
+        bbox.validate()?;
+        Ok(bbox)
+    }
+}
+
 impl BoundingBox {
-    pub fn generate_grid(&self, resolution: f64) -> Vec<TrialPoint> {
-        let number_lat_pts = ((self.width()) / resolution) as u64;
-        let number_lon_pts = ((self.height()) / resolution) as u64;
-        println!(
+    /// Checks that the coordinates are legal and the minimums are less than
+    /// the maximums. Boxes that cross the antimeridian are rejected: the
+    /// charger index doesn't wrap around it, so chargers just across it
+    /// wouldn't be found.
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, lat) in [("lat_min", self.lat_min), ("lat_max", self.lat_max)] {
+            if !(-90.0..=90.0).contains(&lat) {
+                return Err(format!("{} {} is not between -90 and 90", name, lat));
+            }
+        }
+        for (name, lon) in [("lon_min", self.lon_min), ("lon_max", self.lon_max)] {
+            if !(-180.0..=180.0).contains(&lon) {
+                return Err(format!("{} {} is not between -180 and 180", name, lon));
+            }
+        }
+        if self.lat_min >= self.lat_max {
+            return Err(format!(
+                "lat_min {} must be less than lat_max {}",
+                self.lat_min, self.lat_max
+            ));
+        }
+        if self.crosses_antimeridian() {
+            return Err(format!(
+                "lon_min {} is east of lon_max {}, so the box crosses the antimeridian (180°), which isn't supported; search the parts on either side of it separately",
+                self.lon_min, self.lon_max
+            ));
+        }
+        if self.lon_min >= self.lon_max {
+            return Err(format!(
+                "lon_min {} must be less than lon_max {}",
+                self.lon_min, self.lon_max
+            ));
+        }
+        Ok(())
+    }
+
+    /// Smallest bounding box that contains the whole region.
+    pub fn from_region(region: &MultiPolygon<f64>) -> Option<BoundingBox> {
+        let rect = region.bounding_rect()?;
+        Some(BoundingBox {
+            lat_min: rect.min().y,
+            lat_max: rect.max().y,
+            lon_min: rect.min().x,
+            lon_max: rect.max().x,
+        })
+    }
+    pub fn generate_grid(&self, resolution: impl Into<Resolution>) -> Vec<TrialPoint> {
+        let resolution = resolution.into();
+        let (number_lat_pts, number_lon_pts) = self.grid_dimensions(resolution);
+        debug!(
             "{:?} generating {} x {} grid",
             thread::current().id(),
             number_lat_pts,
@@ -353,8 +2629,8 @@ impl BoundingBox {
         let mut grid = Vec::with_capacity((number_lat_pts * number_lon_pts) as usize);
         for lat in 0..number_lat_pts {
             for lon in 0..number_lon_pts {
-                let latitude = self.lat_min + (lat as f64 * resolution);
-                let longitude = self.lon_min + (lon as f64 * resolution);
+                let latitude = self.lat_min + (lat as f64 * resolution.lat);
+                let longitude = self.lon_min + (lon as f64 * resolution.lon);
                 grid.push(TrialPoint {
                     latitude,
                     longitude,
@@ -363,40 +2639,217 @@ impl BoundingBox {
         }
         grid
     }
-    pub fn width(&self) -> f64 {
+    /// Number of grid points north to south and east to west in
+    /// `generate_grid`.
+    fn grid_dimensions(&self, resolution: Resolution) -> (u64, u64) {
+        (
+            (self.lat_span() / resolution.lat) as u64,
+            (self.lon_span() / resolution.lon) as u64,
+        )
+    }
+    /// Number of points `generate_grid` makes at `resolution`.
+    pub fn grid_point_count(&self, resolution: impl Into<Resolution>) -> u64 {
+        let (lat_points, lon_points) = self.grid_dimensions(resolution.into());
+        lat_points.saturating_mul(lon_points)
+    }
+    /// Checks that `resolution` gives a grid that fits in memory, at most
+    /// `max_points` points (e.g. `MAX_GRID_POINTS`), and isn't empty.
+    /// Returns how many there are, and warns if it's so few
+    /// (`MIN_GRID_POINTS`) that the resolution looks like a typo.
+    pub fn check_grid_points(
+        &self,
+        resolution: impl Into<Resolution>,
+        max_points: u64,
+    ) -> Result<u64, String> {
+        let resolution = resolution.into();
+        let points = self.grid_point_count(resolution);
+        if points > max_points {
+            return Err(format!(
+                "resolution {} gives {} grid points, more than the limit of {}",
+                resolution, points, max_points
+            ));
+        }
+        if points == 0 {
+            return Err(format!(
+                "resolution {} is coarser than the bounding box, so there are no grid points",
+                resolution
+            ));
+        }
+        if points < MIN_GRID_POINTS {
+            warn!(
+                "resolution {} gives only {} grid points, is it too coarse?",
+                resolution, points
+            );
+        }
+        Ok(points)
+    }
+    /// Like `generate_grid`, but only keeps the points inside `region`, if
+    /// there is one.
+    pub fn generate_grid_within(
+        &self,
+        resolution: impl Into<Resolution>,
+        region: Option<&MultiPolygon<f64>>,
+    ) -> Vec<TrialPoint> {
+        let mut grid = self.generate_grid(resolution);
+        // When clipping to a region, points outside of it aren't part of the
+        // analysis at all, so drop them before doing any work on them.
+        if let Some(region) = region {
+            grid.retain(|point| region.contains(&point.to_geo_point()));
+        }
+        grid
+    }
+    /// North-south extent of the box, in degrees of latitude.
+    pub fn lat_span(&self) -> f64 {
         // By taking the absolute value, this works in both hemispheres
         (self.lat_min - self.lat_max).abs()
     }
-    pub fn height(&self) -> f64 {
+    /// East-west extent of the box, in degrees of longitude.
+    pub fn lon_span(&self) -> f64 {
         (self.lon_min - self.lon_max).abs()
     }
+    /// Whether the box would go east from `lon_min` across the antimeridian
+    /// to `lon_max`, e.g. around the Aleutian Islands: that's what a
+    /// `lon_min` greater than `lon_max` means. `validate` rejects such boxes.
+    pub fn crosses_antimeridian(&self) -> bool {
+        self.lon_min > self.lon_max
+    }
+    #[deprecated(note = "this is the latitude span, use `lat_span`")]
+    pub fn width(&self) -> f64 {
+        self.lat_span()
+    }
+    #[deprecated(note = "this is the longitude span, use `lon_span`")]
+    pub fn height(&self) -> f64 {
+        self.lon_span()
+    }
+    /// Splits the bounding box into `chunks` latitude bands of equal height,
+    /// from south to north. The bands don't overlap, and together they cover
+    /// exactly the original box.
     pub fn chunkify(self, chunks: usize) -> Vec<BoundingBox> {
-        let mut chunks_vec = Vec::new();
-        let sqrt_chunks = (chunks as f64).sqrt();
-        let num_width_intervals = sqrt_chunks.floor();
-        let num_height_intervals = sqrt_chunks.ceil();
-        let width_interval = self.width() / sqrt_chunks.floor();
-        let height_interval = self.height() / sqrt_chunks.ceil();
-        for n_w in 0..num_width_intervals as usize {
-            for n_h in 0..num_height_intervals as usize {
-                let current_width_interval = n_w as f64 * width_interval;
-                let current_height_interval = n_h as f64 * height_interval;
-                let chunk = BoundingBox {
-                    lat_min: self.lat_min - current_width_interval,
-                    lat_max: self.lat_max - (current_width_interval + width_interval),
-                    lon_min: self.lon_min - current_height_interval,
-                    lon_max: self.lon_max - (current_height_interval + height_interval),
-                };
-                chunks_vec.push(chunk);
-            }
-        }
-        assert!(!chunks_vec.is_empty());
-        chunks_vec
+        self.chunkify_grid(chunks, 1)
+    }
+    /// Splits the bounding box into a grid of `rows` latitude bands by `cols`
+    /// longitude bands, row by row from the south west corner. The tiles are
+    /// all the same size in degrees, so they have the same number of grid
+    /// points, and unlike with `chunkify` they don't get thin slivers when
+    /// there are many of them. Like `chunkify`'s bands, they don't overlap and
+    /// together cover exactly the original box.
+    pub fn chunkify_grid(self, rows: usize, cols: usize) -> Vec<BoundingBox> {
+        assert!(rows > 0 && cols > 0);
+        let lat_edges = split_evenly(self.lat_min, self.lat_max, rows);
+        let lon_edges = split_evenly(self.lon_min, self.lon_max, cols);
+        lat_edges
+            .windows(2)
+            .flat_map(|lat| {
+                lon_edges.windows(2).map(move |lon| BoundingBox {
+                    lat_min: lat[0],
+                    lat_max: lat[1],
+                    lon_min: lon[0],
+                    lon_max: lon[1],
+                })
+            })
+            .collect()
+    }
+    /// The number of rows and columns for about `tiles` tiles of
+    /// `chunkify_grid` that are roughly square, with at least `tiles` in
+    /// total.
+    pub fn grid_shape(&self, tiles: usize) -> (usize, usize) {
+        assert!(tiles > 0);
+        let aspect = self.lon_span() / self.lat_span();
+        let cols = if aspect.is_finite() {
+            ((tiles as f64 * aspect).sqrt().round() as usize).clamp(1, tiles)
+        } else {
+            1
+        };
+        (tiles.div_ceil(cols), cols)
     }
     pub fn contains_point(&self, point: TrialPoint) -> bool {
         point.latitude >= self.lat_min
             && point.latitude <= self.lat_max
             && point.longitude >= self.lon_min
-            && point.longitude <= self.lat_max
+            && point.longitude <= self.lon_max
+    }
+}
+
+/// `n + 1` evenly spaced edges from `min` to `max`, for splitting that range
+/// into `n` parts. The outer edges are `min` and `max` themselves rather than
+/// computed, so floating point error can't make the parts fall short of (or
+/// run past) the range.
+fn split_evenly(min: f64, max: f64, n: usize) -> Vec<f64> {
+    let interval = (max - min) / n as f64;
+    (0..=n)
+        .map(|i| match i {
+            0 => min,
+            i if i == n => max,
+            i => min + i as f64 * interval,
+        })
+        .collect()
+}
+
+/// Merges gaps that are separated by less than `distance` (in degrees) by
+/// applying a morphological close to the unreachable grid points: every gap
+/// point is dilated by `distance`, then the result is eroded by the same
+/// amount. Thin reachable slivers between two gaps get filled in, while gaps
+/// that are further apart than `distance` stay separate.
+///
+/// The close is done on a lattice with spacing `resolution`, anchored at the
+/// south-west-most gap point, rather than by buffering the gap polygons, so
+/// `distance` only counts in whole grid steps. The original points are
+/// returned untouched, followed by the lattice points that the close filled
+/// in, sorted west to east and then south to north so that the same points
+/// always give the same hull.
+pub fn close_gaps(
+    points: Vec<geo::Point<f64>>,
+    resolution: impl Into<Resolution>,
+    distance: f64,
+) -> Vec<geo::Point<f64>> {
+    let resolution = resolution.into();
+    if distance <= 0.0 || points.is_empty() {
+        return points;
+    }
+    let origin_x = points.iter().map(|p| p.x()).fold(f64::INFINITY, f64::min);
+    let origin_y = points.iter().map(|p| p.y()).fold(f64::INFINITY, f64::min);
+    let to_cell = |p: &geo::Point<f64>| {
+        (
+            ((p.x() - origin_x) / resolution.lon).round() as i64,
+            ((p.y() - origin_y) / resolution.lat).round() as i64,
+        )
+    };
+    // Structuring element: all lattice offsets within `distance` of the origin
+    let (reach_x, reach_y) = (
+        (distance / resolution.lon).floor() as i64,
+        (distance / resolution.lat).floor() as i64,
+    );
+    let mut disk = Vec::new();
+    for dx in -reach_x..=reach_x {
+        for dy in -reach_y..=reach_y {
+            let (x, y) = (dx as f64 * resolution.lon, dy as f64 * resolution.lat);
+            if x * x + y * y <= distance * distance {
+                disk.push((dx, dy));
+            }
+        }
     }
+    let gaps: HashSet<(i64, i64)> = points.iter().map(to_cell).collect();
+    let dilated: HashSet<(i64, i64)> = gaps
+        .iter()
+        .flat_map(|&(x, y)| disk.iter().map(move |&(dx, dy)| (x + dx, y + dy)))
+        .collect();
+    let mut filled: Vec<_> = dilated
+        .iter()
+        .copied()
+        .filter(|&(x, y)| {
+            !gaps.contains(&(x, y))
+                && disk
+                    .iter()
+                    .all(|&(dx, dy)| dilated.contains(&(x + dx, y + dy)))
+        })
+        .collect();
+    filled.sort_unstable();
+    let mut closed = points;
+    closed.extend(filled.into_iter().map(|(x, y)| {
+        geo::Point::new(
+            origin_x + x as f64 * resolution.lon,
+            origin_y + y as f64 * resolution.lat,
+        )
+    }));
+    closed
 }
@@ -1,98 +1,1521 @@
-use clap::Parser;
-use rayon::prelude::*;
-use reqwest::blocking::Client;
-use shapefile::dbase;
-use shapefile::Multipoint;
+use chrono::NaiveDate;
+use clap::{ArgEnum, CommandFactory, Parser, Subcommand, ValueSource};
+use geo::algorithm::coords_iter::CoordsIter;
+use log::{info, warn};
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
+use serde::Serialize;
 use std::error::Error;
-use std::sync::{
-    atomic::{AtomicUsize, Ordering::Relaxed},
-    Arc,
-};
-use std::time::Instant;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use ev_charging_gaps::config_file::{config_args, parse_config, OptionKind};
+use ev_charging_gaps::csv_output::write_unreachable_csv;
+use ev_charging_gaps::diff::{diff_gaps, GapDiff};
+use ev_charging_gaps::geojson::{read_region_geojson, write_gap_diff_geojson, write_gaps_geojson};
+use ev_charging_gaps::kml::write_gaps_kml;
+use ev_charging_gaps::multi_hop::parse_anchors;
+use ev_charging_gaps::nrel_cache::NrelCache;
+use ev_charging_gaps::osrm_cache::OsrmCache;
+use ev_charging_gaps::population::Population;
+use ev_charging_gaps::profile::PhaseTimer;
+use ev_charging_gaps::rate_limit::RateLimiter;
+use ev_charging_gaps::routing::{
+    GraphHopperClient, MemoizedRoutes, RouteProvider, ValhallaClient, CONCURRENT_LOOKUPS,
+};
+use ev_charging_gaps::shapefile_output::write_gaps;
+use ev_charging_gaps::spill::{SpillConfig, DEFAULT_SPILL_THRESHOLD};
+use ev_charging_gaps::wkt::write_gaps_wkt;
 use ev_charging_gaps::*;
 
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
-    /// Path to charger csv file
+    #[clap(subcommand)]
+    command: Option<Command>,
+    /// TOML file of options, e.g. `resolution = 0.05` or
+    /// `exclude_network = ["Tesla"]`, so a run's settings can be kept
+    ///
+    /// Keys are the long option names, with - or _ between words. Options
+    /// given on the command line take precedence over the file.
+    #[clap(long)]
+    config: Option<String>,
+    /// Path to charger csv file, optionally gzipped, or - to read it from
+    /// stdin
     ///
     /// If this is not provided, need API key to download
-    /// charger location data
+    /// charger location data. A gzipped file is decompressed into memory
+    /// in full before it is read, so for very large files it takes less
+    /// memory to pipe `gunzip -c` into --path -.
     #[clap(short, long, required_unless_present = "nrel-api-key")]
     path: Option<String>,
     /// Grid resolution, in degrees.
     #[clap(short, long, default_value_t = 0.01)]
     resolution: f64,
+    /// Grid resolution as a distance on the ground, in km, instead of
+    /// degrees
+    ///
+    /// Converted to degrees of latitude (1° is about 111 km). Grid points are
+    /// closer together east to west, by cos(latitude).
+    #[clap(long, conflicts_with = "resolution", parse(try_from_str = parse_positive))]
+    resolution_km: Option<f64>,
+    /// Grid resolution north to south, in degrees [default: --resolution]
+    #[clap(long, parse(try_from_str = parse_positive))]
+    resolution_lat: Option<f64>,
+    /// Grid resolution east to west, in degrees [default: --resolution]
+    #[clap(long, parse(try_from_str = parse_positive))]
+    resolution_lon: Option<f64>,
+    /// Space grid points further apart east to west, by 1 / cos(latitude) in
+    /// the middle of the area, so that grid cells are about square on the
+    /// ground there instead of in degrees
+    #[clap(long, conflicts_with = "resolution-lon")]
+    square_cells: bool,
+    /// Check an adaptive grid, starting with cells this big (in degrees) and
+    /// only checking the points in between near the edges of gaps
+    ///
+    /// Much faster than a uniform grid at the same --min-resolution, but
+    /// gaps smaller than these cells can be missed.
+    #[clap(long, parse(try_from_str = parse_positive))]
+    max_resolution: Option<f64>,
+    /// Refuse to run with a grid of more points than this
+    ///
+    /// A resolution that's too fine for the area would otherwise run out of
+    /// memory.
+    #[clap(long, default_value_t = MAX_GRID_POINTS)]
+    max_grid_points: u64,
+    /// Finest resolution of the adaptive grid, in degrees [default:
+    /// --resolution]
+    #[clap(long, requires = "max-resolution", parse(try_from_str = parse_positive))]
+    min_resolution: Option<f64>,
     /// Base url of OSRM server, default is public API
-    #[clap(short, long, default_value = DEFAULT_OSRM_URL)]
+    ///
+    /// With another --routing-engine, the base url of that server instead.
+    #[clap(short, long, default_value = DEFAULT_OSRM_URL, parse(try_from_str = parse_base_url))]
     osrm_url: String,
+    /// OSRM routing profile, e.g. car or bike on servers that have them
+    ///
+    /// Use a separate --osrm-cache for each profile, since cached distances
+    /// aren't kept apart by profile.
+    #[clap(long, default_value = DEFAULT_OSRM_PROFILE, parse(try_from_str = parse_osrm_profile))]
+    osrm_profile: String,
+    /// Which routing engine --osrm-url points to
+    ///
+    /// Valhalla and GraphHopper are asked about one charger per request, so
+    /// they make more requests than OSRM.
+    #[clap(long, arg_enum, default_value = "osrm")]
+    routing_engine: RoutingEngine,
+    /// Number of times to retry a failed OSRM request before giving up on
+    /// that pair of points
+    ///
+    /// Retries back off exponentially, from 1 second up to 60 seconds, less
+    /// a random part so that threads don't all retry at once. A server
+    /// answering 429 Too Many Requests with a Retry-After is waited out, for
+    /// up to 60 seconds.
+    #[clap(long, default_value_t = OSRM_MAX_RETRIES)]
+    osrm_max_retries: u32,
+    /// Most requests per second to send to the routing server, across all
+    /// threads
+    ///
+    /// The public OSRM server allows about 1 request per second. Retries
+    /// count too. Not limited if not set.
+    #[clap(long, parse(try_from_str = parse_positive))]
+    osrm_max_rps: Option<f64>,
+    /// Number of closest chargers whose driving distance is looked up for a
+    /// point that might be in range
+    ///
+    /// All of them go in one OSRM table request; the public OSRM server
+    /// accepts at most 99.
+    #[clap(long, default_value_t = MAX_OSRM_CANDIDATES)]
+    max_osrm_candidates: usize,
+    /// Number of chargers looked up at the same time for one point, with
+    /// --routing-engine valhalla or graphhopper
+    ///
+    /// The first one found in range stops the rest. OSRM looks up all of a
+    /// point's chargers in one request instead.
+    #[clap(long, default_value_t = CONCURRENT_LOOKUPS, parse(try_from_str = parse_nonzero))]
+    concurrent_lookups: usize,
+    /// Number of threads to search with, one per CPU if not set
+    ///
+    /// Fewer leave room for other work on a shared machine, and send fewer
+    /// requests to the routing server at once.
+    #[clap(long, parse(try_from_str = parse_nonzero))]
+    threads: Option<usize>,
+    /// Number of chunks to split the area into, 16 per thread if not set
+    ///
+    /// Chunks are searched in parallel and some take much longer than
+    /// others, so more chunks than threads keeps them all busy.
+    #[clap(long, parse(try_from_str = parse_nonzero))]
+    chunks: Option<usize>,
+    /// Seconds to wait for a single OSRM request before treating it as failed
+    ///
+    /// Timed out requests are retried like any other failure, so a lookup
+    /// against an unresponsive server can take up to
+    /// (--osrm-max-retries + 1) * --osrm-timeout-secs, plus the backoff
+    /// between retries, before giving up.
+    #[clap(long, default_value_t = OSRM_TIMEOUT_SECS)]
+    osrm_timeout_secs: u64,
+    /// Points further than this many meters from a road have no route
+    ///
+    /// OSRM otherwise snaps a point in a field or out at sea to the nearest
+    /// road, however far away, and routes from there. Only used with
+    /// --routing-engine osrm.
+    #[clap(long, parse(try_from_str = parse_positive))]
+    osrm_max_snap_meters: Option<f64>,
     /// API key for the downloading NREL charger data
     ///
     /// Only needed if path is not set
     #[clap(long, env = "NREL_API_KEY", required_unless_present = "path")]
     nrel_api_key: Option<String>,
+    /// Format to download the charger data from NREL in: csv or json
+    ///
+    /// The JSON API avoids CSV quoting problems, and is cached separately
+    /// with --cache-dir. Only used without --path.
+    #[clap(long, default_value = "csv")]
+    source_format: NrelFormat,
+    /// Comma-separated statuses of the stations to download from NREL: E
+    /// (open), P (planned) and/or T (temporarily unavailable)
+    #[clap(long, default_value = "E", conflicts_with = "path")]
+    status: String,
+    /// Two letter code of the only state to download from NREL, or all
+    #[clap(long, default_value = "all", conflicts_with = "path")]
+    state: String,
+    /// Directory to keep a copy of the downloaded NREL data in, so later runs
+    /// don't need to download it again
+    #[clap(long)]
+    cache_dir: Option<String>,
+    /// Download the NREL data again once the copy in --cache-dir is this
+    /// many days old
+    #[clap(long, default_value_t = 7, requires = "cache-dir")]
+    cache_max_age_days: u64,
+    /// Download the NREL data even if --cache-dir has a recent copy
+    #[clap(long, requires = "cache-dir")]
+    refresh: bool,
+    /// Southern edge of the analysis bounding box, in degrees
+    #[clap(long, default_value_t = CONTINENTAL_US.lat_min, allow_hyphen_values = true)]
+    lat_min: f64,
+    /// Northern edge of the analysis bounding box, in degrees
+    #[clap(long, default_value_t = CONTINENTAL_US.lat_max, allow_hyphen_values = true)]
+    lat_max: f64,
+    /// Western edge of the analysis bounding box, in degrees
+    #[clap(long, default_value_t = CONTINENTAL_US.lon_min, allow_hyphen_values = true)]
+    lon_min: f64,
+    /// Eastern edge of the analysis bounding box, in degrees
+    #[clap(long, default_value_t = CONTINENTAL_US.lon_max, allow_hyphen_values = true)]
+    lon_max: f64,
+    /// Analysis bounding box as "lat_min,lon_min,lat_max,lon_max"
+    ///
+    /// Alternative to setting --lat-min, --lat-max, --lon-min and --lon-max
+    /// individually.
+    #[clap(long, allow_hyphen_values = true, conflicts_with_all = &["lat-min", "lat-max", "lon-min", "lon-max"])]
+    bbox: Option<BoundingBox>,
+    /// Path to a GeoJSON file whose polygon(s) define the study area
+    ///
+    /// The analysis bounding box is derived from the extent of the polygons
+    #[clap(long, conflicts_with_all = &["bbox", "lat-min", "lat-max", "lon-min", "lon-max"])]
+    region_geojson: Option<String>,
+    /// Only analyze grid points inside the `--region-geojson` polygons,
+    /// rather than everything in their bounding box
+    #[clap(long, requires = "region-geojson")]
+    clip: bool,
+    /// Path to a GeoJSON file whose polygon(s) are the study area, e.g. a
+    /// metro area or a state
+    ///
+    /// Same as --region-geojson with --clip: grid points are laid out over
+    /// the polygons' bounding box, and only the ones inside them are checked.
+    #[clap(long, conflicts_with_all = &["region-geojson", "bbox", "lat-min", "lat-max", "lon-min", "lon-max", "mask"])]
+    region: Option<String>,
+    /// Path to a GeoJSON file or shapefile of land (e.g. US states); grid
+    /// points outside of its polygons are skipped
+    ///
+    /// Keeps ocean points, which are always unreachable, out of the gaps.
+    /// To restrict the analysis to the --region-geojson polygons instead, use
+    /// --clip.
+    #[clap(long, conflicts_with = "clip")]
+    mask: Option<String>,
+    /// Merge gaps separated by less than this distance, in degrees
+    ///
+    /// Near-touching gaps are joined by a morphological close (dilate by this
+    /// distance, then erode back). The close works on the grid points, not
+    /// on the gap polygons, so the distance is rounded down to whole grid
+    /// steps (--resolution): less than one step merges nothing. 0 disables
+    /// merging.
+    #[clap(long, default_value_t = 0.0)]
+    merge_gap_distance: f64,
+    /// Write the gaps of the whole area as one layer instead of one per chunk
+    ///
+    /// Hulls are drawn around all of the unreachable points together, so gaps
+    /// aren't cut along the chunk edges.
+    #[clap(long)]
+    merge_chunks: bool,
+    /// Assumed EV's max range, in meters
+    #[clap(long, default_value_t = MAX_RANGE_METERS)]
+    max_range_meters: u64,
+    /// Assumed EV's max range, in --range-unit
+    ///
+    /// Alternative to --max-range-meters, e.g. --max-range 250
+    /// --range-unit miles. Repeat it or give a comma-separated list to
+    /// compare several ranges in one run, e.g. --max-range 300,400,500
+    /// --range-unit km: each range is written to its own output, named after
+    /// it (e.g. gaps_300km.geojson), and each route is only looked up once.
+    #[clap(
+        long,
+        conflicts_with = "max-range-meters",
+        use_value_delimiter = true,
+        parse(try_from_str = parse_positive)
+    )]
+    max_range: Vec<f64>,
+    /// Unit of --max-range: meters, km or miles (1 mile = 1609.344 meters)
+    #[clap(long, default_value = "meters")]
+    range_unit: DistanceUnit,
+    /// Fraction of the max range the car really gets, between 0.0 and 1.0,
+    /// e.g. 0.7 in cold weather
+    #[clap(long, default_value_t = 1.0, parse(try_from_str = parse_ratio))]
+    range_derate: f64,
+    /// Fraction of the max range within which a charger is assumed reachable
+    /// without an OSRM lookup, between 0.0 and 1.0
+    ///
+    /// Lower values make more OSRM calls but are more accurate.
+    #[clap(long, default_value_t = CROW_FLIES_RATIO, parse(try_from_str = parse_ratio))]
+    crow_flies_ratio: f64,
+    /// How to calculate straight-line distances: haversine or geodesic
+    ///
+    /// Geodesic is more accurate at continental scale but slower.
+    #[clap(long, default_value = "haversine")]
+    distance_metric: DistanceMetric,
+    /// Whether chargers have to be within range by driving distance or by
+    /// driving time: distance or duration
+    ///
+    /// In duration mode every point with a charger within --max-range-meters
+    /// in a straight line is looked up in OSRM, since no straight-line
+    /// distance guarantees a short enough drive.
+    #[clap(long, default_value = "distance")]
+    range_mode: RangeMode,
+    /// Longest drive to a charger with --range-mode duration, in seconds
+    #[clap(long, default_value_t = MAX_DURATION_SECS)]
+    max_duration_secs: u64,
+    /// Allow trips that stop at chargers on the way
+    ///
+    /// Only chargers that can be reached from --origin by hopping between
+    /// chargers, each within --max-range-meters of the last in a straight
+    /// line, are used; a point is reachable if it is in range of one of them.
+    #[clap(long)]
+    multi_hop: bool,
+    /// Path to a GeoJSON file or shapefile of where --multi-hop trips start
+    ///
+    /// Defaults to wherever the largest group of connected chargers is.
+    #[clap(long, requires = "multi-hop")]
+    origin: Option<String>,
+    /// Where --multi-hop trips start, e.g. major cities, instead of --origin:
+    /// "lat,lon;lat,lon;..." or a file with a lat,lon pair per line
+    ///
+    /// Points in range of an anchor, or of a charger that can be reached
+    /// from one, are reachable.
+    #[clap(long, requires = "multi-hop", conflicts_with = "origin")]
+    anchors: Option<String>,
+    /// Comma-separated list of EV networks to leave out of the analysis
+    ///
+    /// Matched case-insensitively against the NREL "EV Network" column.
+    /// Defaults to Tesla, unless --include-network is given; pass "" to
+    /// exclude nothing.
+    #[clap(long, use_value_delimiter = true)]
+    exclude_network: Option<Vec<String>>,
+    /// Comma-separated list of EV networks to restrict the analysis to
+    ///
+    /// If not set, all networks that aren't excluded are used. Networks
+    /// given here are kept even if they are only excluded by default.
+    #[clap(long, use_value_delimiter = true)]
+    include_network: Vec<String>,
+    /// How to draw the polygon around each gap: convex, concave or alpha
+    ///
+    /// convex is the smallest convex polygon around the unreachable points.
+    /// alpha follows them like concave, but can also have holes, e.g. around
+    /// a reachable town inside a gap.
+    #[clap(long, default_value = "concave")]
+    hull: HullAlgorithm,
+    /// Concavity of the hull drawn around each gap, must be positive
+    ///
+    /// Lower values wrap the unreachable points more tightly; higher values
+    /// approach their convex hull. Finer grids usually need lower values.
+    #[clap(long, default_value_t = DEFAULT_CONCAVITY, parse(try_from_str = parse_positive))]
+    concavity: f64,
+    /// Radius of the alpha shape for --hull alpha, in degrees
+    /// [default: --resolution]
+    ///
+    /// Neighbouring unreachable points are only joined up if it's at least
+    /// the resolution; larger values smooth over small bays and holes.
+    #[clap(long, parse(try_from_str = parse_positive))]
+    alpha: Option<f64>,
+    /// Unreachable points within this distance of each other, in degrees,
+    /// are one gap with its own hull [default: 1.5 × --resolution]
+    ///
+    /// Keeps separate gaps from being wrapped in one polygon that covers the
+    /// reachable area between them. 0 draws one hull per chunk.
+    #[clap(long)]
+    cluster_eps: Option<f64>,
+    /// Simplify the gap polygons, dropping vertices within this many degrees
+    /// of a straight line, to make the output smaller
+    ///
+    /// Hulls around fine grids have many nearly collinear vertices. Applies to
+    /// every output format, GeoJSON included. 0 turns simplification off.
+    #[clap(long, default_value_t = 0.0)]
+    simplify_epsilon: f64,
+    /// Leave out chargers that opened after this date (YYYY-MM-DD), to see
+    /// what the gaps looked like then
+    ///
+    /// Chargers without a valid open date are kept.
+    #[clap(long)]
+    as_of: Option<NaiveDate>,
+    /// Leave out private chargers, e.g. fleet or dealership-only ones
+    ///
+    /// Downloads from NREL only have public chargers already; this is for
+    /// --path files downloaded with other settings.
+    #[clap(long)]
+    public_only: bool,
+    /// Which chargers to use: l2 (Level 2), dcfast (DC fast) or all
+    #[clap(long, default_value = "all")]
+    level: ChargingLevel,
+    /// Output file format
+    #[clap(long, arg_enum, default_value = "shapefile")]
+    format: OutputFormat,
+    /// Also write the reachable grid points, e.g. for a coverage map
+    ///
+    /// Only supported with --format geojson, where they are one more
+    /// MultiPoint feature with "reachable": true.
+    #[clap(long)]
+    emit_reachable: bool,
+    /// Also write the chargers used in the analysis, after filtering, to
+    /// this path as points
+    ///
+    /// Written as a shapefile if the path ends in .shp, otherwise as
+    /// GeoJSON. Useful to check what the network and level filters kept
+    /// before starting a long run.
+    #[clap(long)]
+    emit_chargers: Option<String>,
+    /// Also write the unreachable grid points to this path as CSV, each
+    /// with its nearest charger
+    ///
+    /// Columns: lat, lon, nearest_charger_id, straight_line_meters, and the
+    /// shortest route found if any were looked up, in driving_meters (or
+    /// driving_secs with --range-mode duration). Not supported with
+    /// --max-resolution.
+    #[clap(long, conflicts_with = "max-resolution")]
+    emit_unreachable_csv: Option<String>,
+    /// Path to write the gaps to
+    ///
+    /// Defaults to output/test_shapefile3.shp for shapefiles,
+    /// output/gaps.geojson for GeoJSON, output/gaps.wkt for WKT and
+    /// output/gaps.kml for KML.
+    #[clap(long)]
+    output: Option<String>,
+    /// Directory to write each chunk's gaps to as soon as it's done, e.g.
+    /// partial
+    ///
+    /// Each chunk is written as chunk_{id}.geojson, so finished chunks can
+    /// be looked at while the rest are still being searched, and are kept
+    /// if the run dies.
+    #[clap(long)]
+    partial_dir: Option<String>,
+    /// Directory to write unreachable points to during very large runs,
+    /// instead of keeping them all in memory
+    ///
+    /// A chunk only writes its points out once it has more than
+    /// --spill-threshold of them. Its gaps are then drawn from the file
+    /// --spill-threshold points at a time, rounded up to whole rows of the
+    /// grid, so a gap can be cut in two between rows the way gaps are cut at
+    /// chunk edges. The files are removed once the gaps are drawn. Not used
+    /// with --max-resolution or --merge-gap-distance, which need all of the
+    /// points, nor by the diff command.
+    #[clap(long, conflicts_with = "max-resolution")]
+    spill_dir: Option<String>,
+    /// Number of unreachable points per chunk to keep in memory before
+    /// writing them to --spill-dir
+    #[clap(long, default_value_t = DEFAULT_SPILL_THRESHOLD, requires = "spill-dir", parse(try_from_str = parse_nonzero))]
+    spill_threshold: usize,
+    /// Also write totals over the whole run (point counts, routed points, time
+    /// and gap area) to this path as JSON, e.g. to compare runs
+    #[clap(long)]
+    summary_path: Option<String>,
+    /// CSV of gridded population, with lat, lon and population columns, to
+    /// rank gaps by how many people live in them
+    ///
+    /// Adds a "priority" attribute (the population inside the gap) to
+    /// shapefile output, and sorts gaps by it instead of by area.
+    #[clap(long)]
+    population: Option<String>,
+    /// Only count the grid points, and how many of them would need a routing
+    /// lookup, then exit without looking anything up or writing any output
+    ///
+    /// Counts the uniform grid at --resolution, even with --max-resolution.
+    #[clap(long)]
+    dry_run: bool,
+    /// Send a large run's lookups to the public OSRM server anyway
+    ///
+    /// Without this, runs that are estimated to need more than 10,000
+    /// lookups stop before starting if --osrm-url isn't set. The public
+    /// server is a demo that throttles heavy users; self-host OSRM instead,
+    /// see http://project-osrm.org.
+    #[clap(long, alias = "force")]
+    yes_i_accept_public_osrm: bool,
+    /// Stop asking the routing engine after this many grid points, for a
+    /// quick preview
+    ///
+    /// Each point is one request to OSRM, and up to one per candidate
+    /// charger (--max-osrm-candidates) to Valhalla or GraphHopper. The limit is
+    /// shared by all chunks. Once it's reached, points that would need a
+    /// lookup are taken to be unreachable, so the gaps found are too big
+    /// rather than too small.
+    #[clap(long, alias = "max-api-calls", parse(try_from_str = parse_nonzero))]
+    max_routed_points: Option<usize>,
+    /// Log how long each chunk spent generating its grid, looking up nearby
+    /// chargers, waiting on the routing engine and drawing hulls
+    ///
+    /// Times on several threads at once are added up, so they can be longer
+    /// than the run.
+    #[clap(long)]
+    profile: bool,
+    /// Only check this fraction of the grid points, picked at random, for a
+    /// quick rough preview of where the gaps are
+    #[clap(long, conflicts_with = "max-resolution", parse(try_from_str = parse_ratio))]
+    sample: Option<f64>,
+    /// Seed picking the --sample points; the same seed picks the same points
+    #[clap(long, default_value_t = 0, requires = "sample")]
+    seed: u64,
+    /// Path to a file caching OSRM distances between runs
+    ///
+    /// Only used with --routing-engine osrm.
+    ///
+    /// Created if it doesn't exist. Coordinates are rounded to
+    /// 5 decimal places when looking up cached distances.
+    #[clap(long)]
+    osrm_cache: Option<String>,
+    /// Report progress as newline-delimited JSON on stderr, e.g. for a GUI,
+    /// instead of in the log
+    ///
+    /// Each line is {"chunk", "processed", "total", "reachable",
+    /// "unreachable", "maybe"}, every 1000 grid points of a chunk (only at
+    /// the end with --max-resolution) and when it's done. Any other lines
+    /// on stderr are log messages.
+    #[clap(long)]
+    progress_json: bool,
+    /// Print more detail about progress; repeat for even more
+    #[clap(short, long, parse(from_occurrences), conflicts_with = "quiet")]
+    verbose: usize,
+    /// Only print warnings; repeat to only print errors
+    #[clap(short, long, parse(from_occurrences))]
+    quiet: usize,
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let args = Args::parse();
-    // Bounding box of continental United States
-    let lat_min = 24.5243;
-    let lon_min = -124.7580;
-    let lat_max = 49.1756;
-    let lon_max = -66.9472;
-    let client = Client::new();
-
-    let charger_locations = match args.path {
-        Some(path) => read_from_file(&path),
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check whether a single point is within range of a charger, instead of
+    /// mapping the gaps in a whole region
+    ///
+    /// Uses the same charger data, OSRM and range options as a full run.
+    Query {
+        /// Latitude of the point, in degrees
+        #[clap(long, allow_hyphen_values = true)]
+        lat: f64,
+        /// Longitude of the point, in degrees
+        #[clap(long, allow_hyphen_values = true)]
+        lon: f64,
+        /// Check the point against a gap layer from an earlier run instead,
+        /// a shapefile (.shp) or GeoJSON file of gap polygons
+        ///
+        /// No charger data or routing engine is needed then.
+        #[clap(long)]
+        gaps: Option<String>,
+    },
+    /// Compare the gaps of two snapshots of the charger data, e.g. last
+    /// month's and this month's, instead of mapping the gaps of one
+    ///
+    /// Both are searched with the same options as a full run, and the gaps
+    /// that have closed and the ones that have opened up are written to
+    /// --output (default output/gap_diff.geojson) as GeoJSON, whatever
+    /// --format is.
+    Diff {
+        /// Path to the older charger csv file
+        old: String,
+        /// Path to the newer charger csv file
+        new: String,
+    },
+}
+
+/// Logs messages to stderr, without pulling in a full logging framework.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        // Dependencies (reqwest, hyper...) are chatty, so only show their
+        // warnings and errors.
+        let ours = metadata.target().starts_with("ev_charging_gaps");
+        metadata.level() <= log::max_level() && (ours || metadata.level() <= log::Level::Warn)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Parses the command line, adding the options from the `--config` file that
+/// aren't set on the command line (or through their environment variable).
+fn parse_args() -> Result<Args, Box<dyn Error>> {
+    let cli: Vec<String> = std::env::args().collect();
+    let command = Args::command();
+    // Errors (including --help) are left to the full parse below
+    let matches = match command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&cli)
+    {
+        Ok(matches) => matches,
+        Err(_) => return Ok(Args::parse_from(cli)),
+    };
+    let path = match matches.value_of("config") {
+        Some(path) => path,
+        None => return Ok(Args::parse_from(cli)),
+    };
+    let text = fs::read_to_string(path)
+        .map_err(|error| format!("Couldn't read --config {}: {}", path, error))?;
+    let pairs = parse_config(&text).map_err(|error| format!("{}: {}", path, error))?;
+    let option = |long: &str| {
+        command.get_arguments().find(|arg| {
+            arg.get_long() == Some(long) && !["config", "help", "version"].contains(&long)
+        })
+    };
+    let explicit = |long: &str| {
+        option(long).is_some_and(|arg| {
+            matches.occurrences_of(arg.get_id()) > 0
+                || matches.value_source(arg.get_id()) == Some(ValueSource::EnvVariable)
+        })
+    };
+    let pairs: Vec<_> = pairs
+        .into_iter()
+        .filter(|(key, _)| !explicit(&key.replace('_', "-")))
+        .collect();
+    let file_args = config_args(&pairs, |long| {
+        option(long).map(|arg| match arg.is_takes_value_set() {
+            true => OptionKind::Value,
+            false => OptionKind::Flag,
+        })
+    })
+    .map_err(|error| format!("{}: {}", path, error))?;
+    // After the program name, so the command line comes last
+    let mut args = cli;
+    let at = args.len().min(1);
+    args.splice(at..at, file_args);
+    Ok(Args::parse_from(args))
+}
+
+/// Log level for the given number of `-v` and `-q` flags, starting at info.
+fn log_level(verbose: usize, quiet: usize) -> log::LevelFilter {
+    match verbose as isize - quiet as isize {
+        i if i <= -2 => log::LevelFilter::Error,
+        -1 => log::LevelFilter::Warn,
+        0 => log::LevelFilter::Info,
+        1 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum RoutingEngine {
+    Osrm,
+    Valhalla,
+    Graphhopper,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+enum OutputFormat {
+    Shapefile,
+    Geojson,
+    /// One line per chunk: the chunk id, a tab and the gaps as WKT, for
+    /// loading into PostGIS with `COPY`.
+    Wkt,
+    /// One placemark per gap, for opening in Google Earth.
+    Kml,
+}
+
+impl OutputFormat {
+    fn default_path(self) -> &'static str {
+        match self {
+            OutputFormat::Shapefile => "output/test_shapefile3.shp",
+            OutputFormat::Geojson => "output/gaps.geojson",
+            OutputFormat::Wkt => "output/gaps.wkt",
+            OutputFormat::Kml => "output/gaps.kml",
+        }
+    }
+}
+
+/// Parses a number between 0.0 and 1.0 inclusive.
+fn parse_ratio(s: &str) -> Result<f64, String> {
+    let ratio: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if (0.0..=1.0).contains(&ratio) {
+        Ok(ratio)
+    } else {
+        Err(format!("{} is not between 0.0 and 1.0", ratio))
+    }
+}
+
+/// Parses a number greater than 0.0.
+fn parse_positive(s: &str) -> Result<f64, String> {
+    let value: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if value > 0.0 {
+        Ok(value)
+    } else {
+        Err(format!("{} is not positive", value))
+    }
+}
+
+/// Parses a whole number greater than zero.
+fn parse_nonzero(s: &str) -> Result<usize, String> {
+    match s.parse().map_err(|e| format!("{}", e))? {
+        0 => Err("must be at least 1".to_string()),
+        value => Ok(value),
+    }
+}
+
+/// Parses an OSRM profile name, see `is_valid_osrm_profile`.
+fn parse_osrm_profile(s: &str) -> Result<String, String> {
+    if is_valid_osrm_profile(s) {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "{:?} is not a profile name (letters, digits, - and _)",
+            s
+        ))
+    }
+}
+
+/// Routing engine client configured from the command line. Only OSRM uses
+/// `cache`.
+fn route_provider(
+    args: &Args,
+    cache: Option<Arc<OsrmCache>>,
+) -> Result<Box<dyn RouteProvider>, Box<dyn Error>> {
+    let url = args.osrm_url.clone();
+    if args.routing_engine != RoutingEngine::Osrm && url == DEFAULT_OSRM_URL {
+        return Err(format!(
+            "--osrm-url is required with --routing-engine {:?}",
+            args.routing_engine
+        )
+        .into());
+    }
+    let client = http_client(Duration::from_secs(args.osrm_timeout_secs))?;
+    let retry = RetryPolicy {
+        max_retries: args.osrm_max_retries,
+        ..RetryPolicy::default()
+    };
+    let max_candidates = args.max_osrm_candidates;
+    // One limiter shared by every worker, so the limit is on the total rate
+    let rate_limit = args.osrm_max_rps.map(|rps| Arc::new(RateLimiter::new(rps)));
+    Ok(match args.routing_engine {
+        RoutingEngine::Osrm => Box::new(OsrmClient {
+            url,
+            profile: args.osrm_profile.clone(),
+            client,
+            cache,
+            retry,
+            max_candidates,
+            rate_limit,
+            max_snap_meters: args.osrm_max_snap_meters,
+        }),
+        RoutingEngine::Valhalla => Box::new(ValhallaClient {
+            url,
+            client,
+            retry,
+            max_candidates,
+            concurrent_lookups: args.concurrent_lookups,
+            rate_limit,
+        }),
+        RoutingEngine::Graphhopper => Box::new(GraphHopperClient {
+            url,
+            client,
+            retry,
+            max_candidates,
+            concurrent_lookups: args.concurrent_lookups,
+            rate_limit,
+        }),
+    })
+}
+
+/// Reads the charger data from --path, or downloads it from NREL. With
+/// --multi-hop, only the chargers that can be reached from --origin (or
+/// --anchors) are kept.
+fn load_chargers(args: &Args, config: &RangeConfig) -> Result<AllChargerLocations, Box<dyn Error>> {
+    let filter = charger_filter(args);
+    let nrel_cache = args.cache_dir.as_ref().map(|dir| NrelCache {
+        dir: dir.into(),
+        max_age: Duration::from_secs(args.cache_max_age_days * 24 * 60 * 60),
+        refresh: args.refresh,
+    });
+    let chargers = match &args.path {
+        Some(path) => read_from_file(path, &filter),
         None => download_source_data(
-            &args
-                .nrel_api_key
-                .expect("If there was no path provided, there should be a NREL API key"),
+            args.nrel_api_key
+                .as_deref()
+                .ok_or("--path or --nrel-api-key is required")?,
+            &NrelQuery {
+                status: args.status.clone(),
+                state: args.state.clone(),
+                level: args.level,
+                format: args.source_format,
+                ..NrelQuery::default()
+            },
+            &filter,
+            nrel_cache.as_ref(),
         ),
     }?;
-    let cpus = dbg!(num_cpus::get() * 16);
-    let bounding_box = BoundingBox {
-        lat_min,
-        lon_min,
-        lat_max,
-        lon_max,
-    };
-    let chunks = bounding_box.chunkify(cpus);
-    let completed = Arc::new(AtomicUsize::new(0));
-    let start = Instant::now();
-    let polygons: Vec<_> = chunks
-        .into_par_iter()
-        .map_with(
-            (charger_locations, args.osrm_url, completed),
-            |(charger_locations, osrm_url, completed), c| {
-                let start = Instant::now();
-                let polygon =
-                    charger_locations.find_gaps(args.resolution, c, &osrm_url, client.clone());
-                println!(
-                    "Completed chunk {}/{} in {:?}",
-                    completed.fetch_add(1, Relaxed),
-                    cpus,
-                    start.elapsed()
+    reachable_chargers(args, chargers, config)
+}
+
+/// Which chargers to read, from the network and level options.
+fn charger_filter(args: &Args) -> ChargerFilter {
+    ChargerFilter {
+        networks: NetworkFilter::new(args.include_network.clone(), args.exclude_network.clone()),
+        level: args.level,
+        as_of: args.as_of,
+        public_only: args.public_only,
+    }
+}
+
+/// With --multi-hop, only the chargers that can be reached from --origin or
+/// --anchors; otherwise all of them.
+fn reachable_chargers(
+    args: &Args,
+    chargers: AllChargerLocations,
+    config: &RangeConfig,
+) -> Result<AllChargerLocations, Box<dyn Error>> {
+    if !args.multi_hop {
+        return Ok(chargers);
+    }
+    if let Some(anchors) = &args.anchors {
+        let anchors = match fs::read_to_string(anchors) {
+            Ok(text) => parse_anchors(&text),
+            Err(_) => parse_anchors(anchors),
+        }?;
+        return Ok(chargers.reachable_from_anchors(&anchors, config));
+    }
+    let origin = args.origin.as_deref().map(read_region).transpose()?;
+    Ok(chargers.reachable_from(origin.as_ref(), config))
+}
+
+/// Writes a `--progress-json` event to stderr, as one line.
+fn print_progress_json(chunk: usize, progress: &Progress) {
+    #[derive(Serialize)]
+    struct ChunkProgress<'a> {
+        chunk: usize,
+        #[serde(flatten)]
+        progress: &'a Progress,
+    }
+    let event = ChunkProgress { chunk, progress };
+    eprintln!(
+        "{}",
+        serde_json::to_string(&event).expect("progress serializes")
+    );
+}
+
+/// Prints what `--dry-run` found out about the grid.
+fn dry_run_report(resolution: Resolution, args: &Args, estimate: GridEstimate) {
+    let percent = |n: usize| 100.0 * n as f64 / estimate.points.max(1) as f64;
+    println!(
+        "{} grid points at resolution {}",
+        estimate.points, resolution
+    );
+    println!(
+        "{} ({:.1}%) have a charger close enough to be reachable",
+        estimate.reachable,
+        percent(estimate.reachable)
+    );
+    println!(
+        "{} ({:.1}%) have no charger in range",
+        estimate.unreachable,
+        percent(estimate.unreachable)
+    );
+    println!(
+        "{} ({:.1}%) need a routing lookup",
+        estimate.maybe,
+        percent(estimate.maybe)
+    );
+    match args.routing_engine {
+        // One table request covers all of a point's candidates
+        RoutingEngine::Osrm => println!("Estimated API calls: {}", estimate.maybe),
+        RoutingEngine::Valhalla | RoutingEngine::Graphhopper => println!(
+            "Estimated API calls: {} to {} (one per candidate charger, up to {} per point)",
+            estimate.maybe,
+            estimate.maybe * args.max_osrm_candidates,
+            args.max_osrm_candidates
+        ),
+    }
+}
+
+/// Stops runs that would send more than `PUBLIC_OSRM_MAX_LOOKUPS` lookups to
+/// the public OSRM server, unless `accepted` (--yes-i-accept-public-osrm).
+/// Either way, the warning goes straight to stderr, so that it is seen
+/// whatever the log level.
+fn check_public_osrm_load(lookups: usize, accepted: bool) -> Result<(), Box<dyn Error>> {
+    if lookups <= PUBLIC_OSRM_MAX_LOOKUPS {
+        return Ok(());
+    }
+    eprintln!(
+        "WARNING: this run needs about {} routing lookups, and --osrm-url is the public \
+         OSRM demo server ({}), which isn't meant for batch jobs and throttles heavy \
+         users. Please run your own OSRM server (see http://project-osrm.org) and pass \
+         its --osrm-url, or use a coarser --resolution or smaller area.",
+        lookups, DEFAULT_OSRM_URL
+    );
+    if accepted {
+        return Ok(());
+    }
+    Err(format!(
+        "refusing to send {} lookups to the public OSRM server; pass \
+         --yes-i-accept-public-osrm to do it anyway",
+        lookups
+    )
+    .into())
+}
+
+/// The gaps in each chunk that has any, highest priority first if there is
+/// population data, otherwise largest first.
+fn chunk_gaps(
+    reports: Vec<GapReport>,
+    resolution: Resolution,
+    args: &Args,
+    hull_config: &HullConfig,
+    population: Option<&Population>,
+) -> Vec<ChunkGap> {
+    let mut gaps: Vec<_> = reports
+        .into_iter()
+        .enumerate()
+        .filter(|(_, report)| report.unreachable > 0)
+        .map(|(chunk_id, report)| {
+            // Merging is done within each chunk, since each chunk is
+            // its own record anyway.
+            if args.merge_gap_distance > 0.0 {
+                let points = close_gaps(
+                    report.unreachable_points.clone(),
+                    resolution,
+                    args.merge_gap_distance,
                 );
-                polygon
-            },
-        )
+                let polygons = gap_hulls(&points, hull_config);
+                ChunkGap {
+                    chunk_id,
+                    point_count: report.unreachable,
+                    area_km2: polygons.iter().map(gap_area_km2).sum(),
+                    priority_score: population.map(|population| population.within(&polygons)),
+                    polygons,
+                }
+            } else {
+                ChunkGap {
+                    chunk_id,
+                    point_count: report.unreachable,
+                    polygons: report.polygons,
+                    area_km2: report.area_km2,
+                    priority_score: report.priority_score,
+                }
+            }
+        })
         .collect();
-    println!("Completed all chunks in {:?}", start.elapsed());
-    let table_info = dbase::TableWriterBuilder::new()
-        .add_logical_field(dbase::FieldName::try_from("has_charger").unwrap());
-    let mut writer = shapefile::Writer::from_path("output/test_shapefile3.shp", table_info)?;
-    let mut record = dbase::Record::default();
-    record.insert(
-        "has_charger".to_owned(),
-        dbase::FieldValue::Logical(Some(false)),
+    if args.simplify_epsilon > 0.0 {
+        let before: usize = gaps.iter().map(|gap| gap.polygons.coords_count()).sum();
+        for gap in &mut gaps {
+            gap.polygons = simplify_gaps(&gap.polygons, args.simplify_epsilon);
+            gap.area_km2 = gap.polygons.iter().map(gap_area_km2).sum();
+            gap.priority_score = population.map(|population| population.within(&gap.polygons));
+        }
+        let after: usize = gaps.iter().map(|gap| gap.polygons.coords_count()).sum();
+        info!(
+            "Simplified gap polygons from {} to {} vertices",
+            before, after
+        );
+    }
+    if population.is_some() {
+        let score = |gap: &ChunkGap| gap.priority_score.unwrap_or_default();
+        gaps.sort_by(|a, b| score(b).total_cmp(&score(a)));
+        if let Some(gap) = gaps.first() {
+            info!(
+                "Highest priority gap: {:.0} people in {:.0} km² in chunk {}",
+                gap.priority_score.unwrap_or_default(),
+                gap.area_km2,
+                gap.chunk_id
+            );
+        }
+    } else {
+        // Largest gaps first
+        gaps.sort_by(|a, b| b.area_km2.total_cmp(&a.area_km2));
+        if let Some(gap) = gaps.first() {
+            info!(
+                "Largest gap: {:.0} km² in chunk {}",
+                gap.area_km2, gap.chunk_id
+            );
+        }
+    }
+    gaps
+}
+
+/// Prints whether `point` is in one of `gaps`, for `query --gaps`.
+fn query_gaps(point: TrialPoint, gaps: &geo::MultiPolygon<f64>) {
+    match point.locate_in_gaps(gaps) {
+        Some(GapLocation {
+            inside: Some(gap),
+            boundary_meters,
+            ..
+        }) => println!(
+            "In gap {}, {:.1} km from its edge",
+            gap,
+            boundary_meters / 1000.0
+        ),
+        Some(GapLocation {
+            nearest,
+            boundary_meters,
+            ..
+        }) => println!(
+            "Not in a gap; the nearest one is gap {}, {:.1} km away",
+            nearest,
+            boundary_meters / 1000.0
+        ),
+        None => println!("Not in a gap; there are no gaps in the layer"),
+    }
+}
+
+/// Prints whether `point` is within range of a charger, and how that was
+/// decided.
+fn query(
+    point: TrialPoint,
+    chargers: &AllChargerLocations,
+    routes: &dyn RouteProvider,
+    config: &RangeConfig,
+) {
+    let max_range_km = config.max_range_meters as f64 / 1000.0;
+    match point
+        .nearest_chargers(chargers, config.max_range_meters, config.metric)
+        .first()
+    {
+        Some((charger, distance)) => println!(
+            "Nearest charger: {} ({}) at {}, {} ({:.1} km in a straight line)",
+            charger.station_id(),
+            charger.network(),
+            charger.latitude(),
+            charger.longitude(),
+            *distance as f64 / 1000.0
+        ),
+        None => println!("No charger within {:.0} km", max_range_km),
+    }
+    let reachable = match point.check_charger(chargers, config) {
+        CheckResult::Yes => {
+            println!("Close enough to assume it is reachable without asking OSRM");
+            true
+        }
+        CheckResult::No => false,
+        CheckResult::Maybe { candidates } => {
+            let candidates: Vec<_> = candidates
+                .into_iter()
+                .take(routes.max_candidates())
+                .map(|(charger, _)| charger)
+                .collect();
+            let by_road = match config.mode {
+                RangeMode::Distance => routes.driving_distances(&point, &candidates),
+                RangeMode::Duration => routes.driving_durations(&point, &candidates),
+            };
+            let closest_by_road = by_road
+                .into_iter()
+                .zip(&candidates)
+                .filter_map(|(cost, charger)| Some((cost?, charger)))
+                .min_by(|a, b| a.0.total_cmp(&b.0));
+            match closest_by_road {
+                Some((cost, charger)) => {
+                    let (driving, in_range) = match config.mode {
+                        RangeMode::Distance => (
+                            format!("{:.1} km", cost / 1000.0),
+                            cost as u64 <= config.max_range_meters,
+                        ),
+                        RangeMode::Duration => (
+                            format!("{:.0} min", cost / 60.0),
+                            cost as u64 <= config.max_duration_secs,
+                        ),
+                    };
+                    println!(
+                        "Closest charger by road: {} ({}) at {}, {} ({} driving)",
+                        charger.station_id(),
+                        charger.network(),
+                        charger.latitude(),
+                        charger.longitude(),
+                        driving
+                    );
+                    in_range
+                }
+                None => {
+                    println!(
+                        "No route to any of the {} closest chargers",
+                        candidates.len()
+                    );
+                    false
+                }
+            }
+        }
+    };
+    let verdict = if reachable {
+        "Reachable"
+    } else {
+        "Unreachable"
+    };
+    match config.mode {
+        RangeMode::Distance => println!("{} with a {:.0} km range", verdict, max_range_km),
+        RangeMode::Duration => println!(
+            "{} within {:.0} min of driving",
+            verdict,
+            config.max_duration_secs as f64 / 60.0
+        ),
+    }
+}
+
+/// Set by the Ctrl-C handler, see `catch_interrupts`.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Makes the first Ctrl-C stop the search and write the gaps found so far
+/// rather than losing them. A second one exits straight away as usual.
+#[cfg(unix)]
+fn catch_interrupts() {
+    extern "C" fn on_interrupt(_: libc::c_int) {
+        // Only async-signal-safe calls in here
+        INTERRUPTED.store(true, Ordering::Relaxed);
+        unsafe { libc::signal(libc::SIGINT, libc::SIG_DFL) };
+    }
+    let handler: extern "C" fn(libc::c_int) = on_interrupt;
+    unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+}
+
+#[cfg(not(unix))]
+fn catch_interrupts() {}
+
+/// Reads a charger snapshot for `diff`, with the same filters as a full
+/// run.
+fn load_snapshot(
+    path: &str,
+    args: &Args,
+    config: &RangeConfig,
+) -> Result<AllChargerLocations, Box<dyn Error>> {
+    let chargers = read_from_file(path, &charger_filter(args))?;
+    let chargers = reachable_chargers(args, chargers, config)?;
+    info!(
+        "Read {} chargers from {}",
+        chargers.chargers_by_id.len(),
+        path
+    );
+    Ok(chargers)
+}
+
+/// Writes the gaps that changed between two snapshots.
+fn write_diff(diff: &GapDiff, args: &Args) -> Result<(), Box<dyn Error>> {
+    info!(
+        "Closed gaps: {:.0} km² ({} points), new gaps: {:.0} km² ({} points)",
+        diff.closed_area_km2(),
+        diff.closed_points.len(),
+        diff.opened_area_km2(),
+        diff.opened_points.len()
     );
-    let mut points = Vec::new();
-    for mut p in polygons {
-        points.append(&mut p);
+    let output = args.output.as_deref().unwrap_or("output/gap_diff.geojson");
+    write_gap_diff_geojson(output, diff)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = parse_args()?;
+    if let Some(km) = args.resolution_km {
+        args.resolution = resolution_from_km(km);
+    }
+    if let Some(region) = args.region.take() {
+        args.region_geojson = Some(region);
+        args.clip = true;
+    }
+    let ranges = ranges(&args);
+    if ranges.len() > 1 && (args.command.is_some() || args.multi_hop || args.partial_dir.is_some())
+    {
+        return Err(
+            "several --max-range values can't be used with a command, --multi-hop or --partial-dir"
+                .into(),
+        );
+    }
+    if args.emit_unreachable_csv.is_some() && args.command.is_some() {
+        return Err("--emit-unreachable-csv can't be used with a command".into());
+    }
+    // Everything up to the search itself uses the longest range
+    args.max_range_meters = ranges[0].1;
+    // The adaptive grid has the same points as a uniform one at its finest
+    // resolution, so everything after checking the points uses that
+    if let Some(min_resolution) = args.min_resolution {
+        args.resolution = min_resolution;
+    }
+    if args.max_resolution.is_some_and(|max| max < args.resolution) {
+        return Err("--max-resolution must be at least --min-resolution".into());
+    }
+    log::set_logger(&StderrLogger)?;
+    log::set_max_level(log_level(args.verbose, args.quiet));
+    let range_config = RangeConfig {
+        max_range_meters: args.max_range_meters,
+        crow_flies_ratio: args.crow_flies_ratio,
+        metric: args.distance_metric,
+        mode: args.range_mode,
+        max_duration_secs: args.max_duration_secs,
+    }
+    .derated(args.range_derate);
+    if let Some(Command::Query { lat, lon, gaps }) = &args.command {
+        let point = TrialPoint {
+            latitude: *lat,
+            longitude: *lon,
+        };
+        if let Some(gaps) = gaps {
+            query_gaps(point, &read_region(gaps)?);
+            return Ok(());
+        }
+        let routes = route_provider(&args, None)?;
+        let chargers = load_chargers(&args, &range_config)?;
+        query(point, &chargers, routes.as_ref(), &range_config);
+        return Ok(());
+    }
+    if args.spill_dir.is_some() && args.merge_gap_distance > 0.0 {
+        return Err("--merge-gap-distance can't be used with --spill-dir".into());
+    }
+    if args.emit_reachable && !matches!(args.format, OutputFormat::Geojson) {
+        return Err("--emit-reachable is only supported with --format geojson".into());
+    }
+    let region = args
+        .region_geojson
+        .as_deref()
+        .map(read_region_geojson)
+        .transpose()?;
+    let bounding_box = match &region {
+        Some(region) => BoundingBox::from_region(region).ok_or("GeoJSON region is empty")?,
+        None => {
+            let bbox = args.bbox.unwrap_or(BoundingBox {
+                lat_min: args.lat_min,
+                lon_min: args.lon_min,
+                lat_max: args.lat_max,
+                lon_max: args.lon_max,
+            });
+            bbox.validate()?;
+            bbox
+        }
+    };
+    let resolution_lat = args.resolution_lat.unwrap_or(args.resolution);
+    let resolution = if args.square_cells {
+        let middle = (bounding_box.lat_min + bounding_box.lat_max) / 2.0;
+        Resolution::ground_square(resolution_lat, middle)
+    } else {
+        Resolution {
+            lat: resolution_lat,
+            lon: args.resolution_lon.unwrap_or(args.resolution),
+        }
+    };
+    if args.max_resolution.is_some() && !resolution.is_square() {
+        return Err("--max-resolution needs the same resolution in both directions".into());
+    }
+    bounding_box
+        .check_grid_points(resolution, args.max_grid_points)
+        .map_err(|error| format!("{} (--max-grid-points)", error))?;
+    let osrm_cache = args
+        .osrm_cache
+        .as_deref()
+        .map(OsrmCache::load)
+        .transpose()?
+        .map(Arc::new);
+    let routes = route_provider(&args, osrm_cache.clone())?;
+    let pool = args
+        .threads
+        .map(|threads| ThreadPoolBuilder::new().num_threads(threads).build())
+        .transpose()?;
+    let threads = pool
+        .as_ref()
+        .map_or_else(num_cpus::get, ThreadPool::current_num_threads);
+    let chunks = args.chunks.unwrap_or(threads * CHUNKS_PER_CPU);
+    let mask = args.mask.as_deref().map(read_region).transpose()?;
+    let population = args
+        .population
+        .as_deref()
+        .map(Population::read)
+        .transpose()?;
+    let clip_region = if args.clip {
+        region.as_ref()
+    } else {
+        mask.as_ref()
+    };
+    if let Some(dir) = &args.partial_dir {
+        fs::create_dir_all(dir)?;
+    }
+    if let Some(dir) = &args.spill_dir {
+        fs::create_dir_all(dir)?;
+    }
+    let hull_config = HullConfig {
+        algorithm: args.hull,
+        concavity: args.concavity,
+        alpha: args.alpha.unwrap_or(ALPHA_CELLS * resolution.max()),
+        cluster_eps: args
+            .cluster_eps
+            .unwrap_or(CLUSTER_EPS_CELLS * resolution.max()),
+    };
+    if let Some(Command::Diff { old, new }) = &args.command {
+        let old = load_snapshot(old, &args, &range_config)?;
+        let new = load_snapshot(new, &args, &range_config)?;
+        let config = GapConfig {
+            resolution,
+            max_resolution: args.max_resolution,
+            region: clip_region,
+            range: range_config,
+            hull: hull_config.clone(),
+            chunks,
+            ..GapConfig::new(&old, routes.as_ref(), bounding_box)
+        };
+        let old_reports = in_pool(pool.as_ref(), || run_gap_analysis(config.clone()))?;
+        let new_reports = in_pool(pool.as_ref(), || {
+            run_gap_analysis(GapConfig {
+                chargers: &new,
+                ..config
+            })
+        })?;
+        if let Some(cache) = &osrm_cache {
+            cache.flush()?;
+        }
+        return write_diff(&diff_gaps(&old_reports, &new_reports, &hull_config), &args);
+    }
+    let charger_locations = load_chargers(&args, &range_config)?;
+    if let Some(path) = &args.emit_chargers {
+        write_chargers(path, &charger_locations)?;
+        info!(
+            "Wrote {} chargers to {}",
+            charger_locations.chargers_by_id.len(),
+            path
+        );
+    }
+    let estimate = || {
+        let (rows, cols) = bounding_box.grid_shape(chunks);
+        in_pool(pool.as_ref(), || {
+            bounding_box
+                .chunkify_grid(rows, cols)
+                .into_par_iter()
+                .map(|c| charger_locations.estimate_gaps(resolution, c, clip_region, &range_config))
+                .reduce(GridEstimate::default, |a, b| a + b)
+        })
+    };
+    if args.dry_run {
+        dry_run_report(resolution, &args, estimate());
+        return Ok(());
+    }
+    if args.routing_engine == RoutingEngine::Osrm && args.osrm_url == DEFAULT_OSRM_URL {
+        let maybe = estimate().maybe;
+        let sampled = match args.sample {
+            Some(fraction) => (maybe as f64 * fraction).ceil() as usize,
+            None => maybe,
+        };
+        let lookups = sampled.min(args.max_routed_points.unwrap_or(usize::MAX));
+        check_public_osrm_load(lookups, args.yes_i_accept_public_osrm)?;
+    }
+    let budget = args.max_routed_points.map(PointBudget::new);
+    // The adaptive grid can't stop part way
+    if args.max_resolution.is_none() {
+        catch_interrupts();
+    }
+    let timer = args.profile.then(PhaseTimer::new);
+    if let Some(timer) = &timer {
+        tracing::subscriber::set_global_default(timer.clone())?;
+    }
+    let progress = |chunk, progress: &Progress| print_progress_json(chunk, progress);
+    // Routes looked up for one range are remembered for the next
+    let memoized = (ranges.len() > 1).then(|| MemoizedRoutes::new(routes.as_ref()));
+    let routes = match &memoized {
+        Some(memoized) => memoized as &dyn RouteProvider,
+        None => routes.as_ref(),
+    };
+    for (name, max_range_meters) in ranges {
+        if INTERRUPTED.load(Ordering::Relaxed) {
+            break;
+        }
+        let range_config = RangeConfig {
+            max_range_meters,
+            ..range_config
+        }
+        .derated(args.range_derate);
+        if let Some(name) = &name {
+            info!("Searching for gaps with a {} range", name);
+        }
+        let start = Instant::now();
+        let config = GapConfig {
+            resolution,
+            max_resolution: args.max_resolution,
+            region: clip_region,
+            range: range_config,
+            hull: hull_config.clone(),
+            report: ReportConfig {
+                emit_reachable: args.emit_reachable,
+                emit_unreachable_details: args.emit_unreachable_csv.is_some(),
+                spill: args.spill_dir.as_ref().map(|dir| SpillConfig {
+                    dir: dir.into(),
+                    threshold: args.spill_threshold,
+                }),
+            },
+            population: population.as_ref(),
+            chunks,
+            progress: match args.progress_json {
+                true => Some(&progress),
+                false => None,
+            },
+            budget: budget.as_ref(),
+            partial_dir: args.partial_dir.as_deref().map(Path::new),
+            cancel: Some(&INTERRUPTED),
+            sample: args.sample.map(|fraction| Sample {
+                fraction,
+                seed: args.seed,
+            }),
+            ..GapConfig::new(&charger_locations, routes, bounding_box)
+        };
+        let reports = in_pool(pool.as_ref(), || run_gap_analysis(config))?;
+        if INTERRUPTED.load(Ordering::Relaxed) {
+            warn!(
+                "Interrupted, writing the gaps found so far; the rest of the area wasn't searched"
+            );
+        }
+        if budget.as_ref().is_some_and(PointBudget::exhausted) {
+            warn!(
+                "Ran out of routed points (--max-routed-points), so the gaps are larger than they really are"
+            );
+        }
+        let summary = RunSummary::new(&reports, resolution, start.elapsed());
+        info!(
+            "Completed all chunks in {:.1}s: Resolution: {}, Total points: {}, Reachable: {}, Unreachable: {}, Unknown: {}, Routed points: {}, Gap area: {:.0} km²",
+            summary.elapsed_secs,
+            summary.resolution,
+            summary.total_points,
+            summary.reachable,
+            summary.unreachable,
+            summary.maybe,
+            summary.routed_points,
+            summary.gap_area_km2
+        );
+        if let Some(path) = &args.summary_path {
+            summary.write(&range_path(path, name.as_deref()))?;
+        }
+        if let Some(cache) = &osrm_cache {
+            cache.flush()?;
+        }
+        if let Some(path) = &args.emit_unreachable_csv {
+            let points: Vec<_> = reports
+                .iter()
+                .flat_map(|report| report.unreachable_details.iter().flatten().cloned())
+                .collect();
+            write_unreachable_csv(&range_path(path, name.as_deref()), &points)?;
+        }
+        let reports = if args.merge_chunks {
+            let merged = merge_reports(reports, &hull_config);
+            vec![match &population {
+                Some(population) => merged.with_population(population),
+                None => merged,
+            }]
+        } else {
+            reports
+        };
+        let output = args
+            .output
+            .as_deref()
+            .unwrap_or_else(|| args.format.default_path());
+        write_reports(
+            &range_path(output, name.as_deref()),
+            reports,
+            resolution,
+            &args,
+            &hull_config,
+            population.as_ref(),
+        )?;
+    }
+    if let Some(timer) = &timer {
+        info!("Time spent in all chunks: {}", timer.total());
     }
-    let converted_multipoint = Multipoint::from(geo::MultiPoint(points));
-    writer.write_shape_and_record(&converted_multipoint, &record)?;
     Ok(())
 }
+
+/// Runs `f` on the --threads pool if there is one, otherwise on rayon's
+/// global pool, with a thread per CPU.
+fn in_pool<R: Send>(pool: Option<&ThreadPool>, f: impl FnOnce() -> R + Send) -> R {
+    match pool {
+        Some(pool) => pool.install(f),
+        None => f(),
+    }
+}
+
+/// Each --max-range in meters, longest first, with its name for the outputs
+/// (e.g. `300km`) if there are several, otherwise just --max-range-meters.
+fn ranges(args: &Args) -> Vec<(Option<String>, u64)> {
+    let meters = |max_range: f64| args.range_unit.to_meters(max_range).round() as u64;
+    let mut max_ranges = args.max_range.clone();
+    max_ranges.sort_by(|a, b| b.total_cmp(a));
+    max_ranges.dedup();
+    match max_ranges.as_slice() {
+        [] => vec![(None, args.max_range_meters)],
+        [max_range] => vec![(None, meters(*max_range))],
+        max_ranges => max_ranges
+            .iter()
+            .map(|&max_range| {
+                let name = format!("{}{}", max_range, args.range_unit.abbreviation());
+                (Some(name), meters(max_range))
+            })
+            .collect(),
+    }
+}
+
+/// `path` with `_{name}` added to the file name, e.g. `output/gaps.geojson`
+/// becomes `output/gaps_300km.geojson`.
+fn range_path(path: &str, name: Option<&str>) -> String {
+    let Some(name) = name else {
+        return path.to_string();
+    };
+    let path = Path::new(path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(extension) => format!("{}_{}.{}", stem, name, extension.to_string_lossy()),
+        None => format!("{}_{}", stem, name),
+    };
+    path.with_file_name(file_name)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Writes the gaps of a run to `output` in --format.
+fn write_reports(
+    output: &str,
+    reports: Vec<GapReport>,
+    resolution: Resolution,
+    args: &Args,
+    hull_config: &HullConfig,
+    population: Option<&Population>,
+) -> Result<(), Box<dyn Error>> {
+    match args.format {
+        OutputFormat::Shapefile => write_gaps(
+            output,
+            &chunk_gaps(reports, resolution, args, hull_config, population),
+        ),
+        OutputFormat::Geojson => {
+            let reachable_points: Vec<_> = reports
+                .iter()
+                .flat_map(|report| report.reachable_points.iter().flatten().copied())
+                .collect();
+            write_gaps_geojson(
+                output,
+                &chunk_gaps(reports, resolution, args, hull_config, population),
+                &reachable_points,
+            )
+        }
+        OutputFormat::Wkt => {
+            let gaps: Vec<_> = chunk_gaps(reports, resolution, args, hull_config, population)
+                .into_iter()
+                .map(|gap| (gap.chunk_id, gap.polygons))
+                .collect();
+            write_gaps_wkt(output, &gaps)
+        }
+        OutputFormat::Kml => write_gaps_kml(
+            output,
+            &chunk_gaps(reports, resolution, args, hull_config, population),
+        ),
+    }
+}
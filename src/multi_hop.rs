@@ -0,0 +1,219 @@
+//! Multi-hop reachability. Real trips stop at chargers on the way, so a
+//! remote point can be reached as long as there is a chain of chargers from
+//! where the trip starts, each within range of the last, that ends within
+//! range of the point.
+
+use std::collections::HashMap;
+
+use geo::algorithm::contains::Contains;
+use geo::MultiPolygon;
+use log::{info, warn};
+
+use crate::{AllChargerLocations, ChargerLocation, ChargingLevels, RangeConfig, TrialPoint};
+
+impl AllChargerLocations {
+    /// Groups the chargers into components whose chargers can all be reached
+    /// from each other by hops of at most `config.max_range_meters`, as
+    /// charger ids.
+    ///
+    /// Hops are measured in a straight line (with `config.metric`) rather
+    /// than by road, since looking up every pair of chargers in OSRM would
+    /// take far too long. This is optimistic: a hop that is in range as the
+    /// crow flies might not be by road.
+    pub fn charger_components(&self, config: &RangeConfig) -> Vec<Vec<u64>> {
+        let mut ids: Vec<u64> = self.chargers_by_id.keys().copied().collect();
+        ids.sort_unstable();
+        let index_of: HashMap<u64, usize> =
+            ids.iter().enumerate().map(|(i, &id)| (id, i)).collect();
+        let mut sets = DisjointSets::new(ids.len());
+        for (i, id) in ids.iter().enumerate() {
+            let charger = &self.chargers_by_id[id];
+            let point = TrialPoint {
+                latitude: charger.latitude,
+                longitude: charger.longitude,
+            };
+            for indexed in self
+                .index
+                .locate_in_envelope(&point.search_envelope(config.max_range_meters))
+            {
+                let j = index_of[&indexed.id];
+                // Each pair only needs checking once, and not at all if the
+                // two are already connected through other chargers.
+                if j <= i || sets.find(i) == sets.find(j) {
+                    continue;
+                }
+                let distance =
+                    point.distance_to_with(&self.chargers_by_id[&indexed.id], config.metric);
+                if distance as u64 <= config.max_range_meters {
+                    sets.union(i, j);
+                }
+            }
+        }
+        let mut components: HashMap<usize, Vec<u64>> = HashMap::new();
+        for (i, &id) in ids.iter().enumerate() {
+            components.entry(sets.find(i)).or_default().push(id);
+        }
+        let mut components: Vec<_> = components.into_values().collect();
+        // Largest first, then by id so the order doesn't depend on hashing
+        components.sort_by(|a, b| b.len().cmp(&a.len()).then(a[0].cmp(&b[0])));
+        components
+    }
+
+    /// The chargers that can be reached from `origin` by hopping between
+    /// chargers, as described in `charger_components`. These are the
+    /// components with at least one charger inside `origin`, or the largest
+    /// component if there is no origin.
+    ///
+    /// A point is then reachable with multiple hops if it is reachable from
+    /// one of the returned chargers, so they can be used with `find_gaps`
+    /// like any other chargers.
+    pub fn reachable_from(
+        &self,
+        origin: Option<&MultiPolygon<f64>>,
+        config: &RangeConfig,
+    ) -> AllChargerLocations {
+        let components = self.charger_components(config);
+        let component_count = components.len();
+        let kept: Vec<u64> = match origin {
+            Some(origin) => components
+                .into_iter()
+                .filter(|component| {
+                    component.iter().any(|id| {
+                        let charger = &self.chargers_by_id[id];
+                        origin.contains(&charger.to_geo_point())
+                    })
+                })
+                .flatten()
+                .collect(),
+            None => components.into_iter().next().unwrap_or_default(),
+        };
+        if kept.is_empty() {
+            warn!("no chargers can be reached from the origin");
+        }
+        info!(
+            "{} of {} chargers (in {} components) can be reached with multiple hops",
+            kept.len(),
+            self.chargers_by_id.len(),
+            component_count
+        );
+        AllChargerLocations::with_backend(
+            kept.iter().map(|id| self.chargers_by_id[id].clone()),
+            self.backend(),
+        )
+    }
+
+    /// The chargers that can be reached by hopping between chargers from any
+    /// of `anchors`, e.g. major cities where trips start, plus the anchors
+    /// themselves, since trips start there with a full battery. Anchors get
+    /// ids counting down from `ANCHOR_ID_MAX`.
+    ///
+    /// As with `reachable_from`, a point is then reachable from an anchor if
+    /// it is in range of one of the returned chargers. The first hop, from an
+    /// anchor to a charger, is measured in a straight line like the others.
+    pub fn reachable_from_anchors(
+        &self,
+        anchors: &[TrialPoint],
+        config: &RangeConfig,
+    ) -> AllChargerLocations {
+        let components = self.charger_components(config);
+        let component_count = components.len();
+        let kept: Vec<u64> = components
+            .into_iter()
+            .filter(|component| {
+                component.iter().any(|id| {
+                    let charger = &self.chargers_by_id[id];
+                    anchors.iter().any(|anchor| {
+                        anchor.distance_to_with(charger, config.metric) as u64
+                            <= config.max_range_meters
+                    })
+                })
+            })
+            .flatten()
+            .collect();
+        info!(
+            "{} of {} chargers (in {} components) can be reached from {} anchors",
+            kept.len(),
+            self.chargers_by_id.len(),
+            component_count,
+            anchors.len()
+        );
+        let anchor_chargers = anchors
+            .iter()
+            .enumerate()
+            .map(|(i, anchor)| ChargerLocation {
+                latitude: anchor.latitude,
+                longitude: anchor.longitude,
+                id: ANCHOR_ID_MAX - i as u64,
+                station_id: format!("anchor {}", i + 1),
+                network: "anchor".into(),
+                levels: ChargingLevels::default(),
+                open_date: None,
+                public: true,
+            });
+        AllChargerLocations::with_backend(
+            kept.iter()
+                .map(|id| self.chargers_by_id[id].clone())
+                .chain(anchor_chargers),
+            self.backend(),
+        )
+    }
+}
+
+/// Id of the first anchor in `reachable_from_anchors`, far above any NREL
+/// station id.
+pub const ANCHOR_ID_MAX: u64 = u64::MAX;
+
+/// Parses anchor points as `lat,lon` pairs separated by `;` or new lines,
+/// e.g. `"40.71,-74.01; 41.88,-87.63"` or a file with one pair per line.
+/// Blank lines and lines starting with `#` are skipped.
+pub fn parse_anchors(text: &str) -> Result<Vec<TrialPoint>, String> {
+    text.split([';', '\n'])
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty() && !pair.starts_with('#'))
+        .map(|pair| {
+            let parse = |value: &str| value.trim().parse::<f64>().ok();
+            match pair.split_once(',') {
+                Some((lat, lon)) => match (parse(lat), parse(lon)) {
+                    (Some(latitude), Some(longitude))
+                        if (-90.0..=90.0).contains(&latitude)
+                            && (-180.0..=180.0).contains(&longitude) =>
+                    {
+                        Ok(TrialPoint {
+                            latitude,
+                            longitude,
+                        })
+                    }
+                    _ => Err(format!("invalid anchor {:?}", pair)),
+                },
+                None => Err(format!("anchor {:?} is not lat,lon", pair)),
+            }
+        })
+        .collect()
+}
+
+/// Union-find over `0..n`.
+struct DisjointSets {
+    parents: Vec<usize>,
+}
+
+impl DisjointSets {
+    fn new(n: usize) -> DisjointSets {
+        DisjointSets {
+            parents: (0..n).collect(),
+        }
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parents[i] != i {
+            // Path halving keeps the trees shallow
+            self.parents[i] = self.parents[self.parents[i]];
+            i = self.parents[i];
+        }
+        i
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        self.parents[a] = b;
+    }
+}
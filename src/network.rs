@@ -0,0 +1,665 @@
+//! Everything that talks to a server: routing lookups against OSRM (with
+//! retries and rate limiting) and downloading charger data from NREL.
+//!
+//! Only built with the `network` feature, so that the reachability logic
+//! can be used without a network or threads, e.g. compiled to WebAssembly.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use csv::Reader;
+use log::{debug, warn};
+use reqwest::blocking::Client;
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::nrel_cache::NrelCache;
+use crate::osrm_cache::OsrmCache;
+use crate::rate_limit::RateLimiter;
+use crate::routing::RouteLookup;
+use crate::{
+    nrel_error_message, read_csv, read_nrel_json, AllChargerLocations, ChargerFilter,
+    ChargerLocation, ChargingLevel, GapError, TrialPoint, DEFAULT_OSRM_PROFILE,
+    MAX_OSRM_CANDIDATES, OSRM_MAX_RETRIES, OSRM_TIMEOUT_SECS,
+};
+
+/// Response from the OSRM route service.
+#[derive(Deserialize, Debug)]
+pub struct Json {
+    /// "Ok", or what went wrong, e.g. "NoRoute".
+    pub code: String,
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    /// Where the coordinates were snapped to, source first.
+    #[serde(default)]
+    pub waypoints: Vec<Waypoint>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Route {
+    /// Driving distance in meters.
+    pub distance: f64,
+    /// Driving time in seconds.
+    pub duration: f64,
+}
+
+/// Response from the OSRM table service. `distances[i][j]` is the driving
+/// distance in meters from source `i` to destination `j`, and `durations[i][j]`
+/// the driving time in seconds, or `None` if there is no route between them.
+/// Only the annotations that were asked for are filled in.
+#[derive(Deserialize, Debug)]
+pub struct TableJson {
+    /// "Ok", or what went wrong, e.g. "NoTable".
+    pub code: String,
+    #[serde(default)]
+    pub distances: Vec<Vec<Option<f64>>>,
+    #[serde(default)]
+    pub durations: Vec<Vec<Option<f64>>>,
+    /// Where the sources were snapped to.
+    #[serde(default)]
+    pub sources: Vec<Waypoint>,
+}
+
+/// A coordinate of an OSRM request, snapped to the nearest road.
+#[derive(Deserialize, Debug)]
+pub struct Waypoint {
+    /// How far the coordinate was moved to get onto the road, in meters.
+    pub distance: f64,
+}
+/// OSRM response codes meaning that there is no route between the points, as
+/// opposed to a bad request or a problem with the server. `NoSegment` means a
+/// point is too far from any road to start from.
+pub const OSRM_NO_ROUTE_CODES: [&str; 3] = ["NoRoute", "NoSegment", "NoTable"];
+
+/// Connection to an OSRM server, shared by all the lookups in a run.
+#[derive(Clone, Debug)]
+pub struct OsrmClient {
+    /// Base url of the OSRM server, without a trailing slash.
+    pub url: String,
+    /// Routing profile in the request paths, `DEFAULT_OSRM_PROFILE` unless
+    /// the server has others. Should pass `is_valid_osrm_profile`.
+    pub profile: String,
+    pub client: Client,
+    /// Distances already looked up, if caching is enabled.
+    pub cache: Option<Arc<OsrmCache>>,
+    /// How failed requests are retried.
+    pub retry: RetryPolicy,
+    /// Maximum number of closest chargers to look up per point, see
+    /// `MAX_OSRM_CANDIDATES`.
+    pub max_candidates: usize,
+    /// Limit on the rate of requests to the server, if any. It is shared by
+    /// every clone of the client.
+    pub rate_limit: Option<Arc<RateLimiter>>,
+    /// Furthest a trial point may be from a road, in meters. OSRM snaps
+    /// points to the nearest road however far away it is, so a point in a
+    /// field or out at sea gets the distance from somewhere else. Points
+    /// further away than this have no route. `None` allows any distance.
+    pub max_snap_meters: Option<f64>,
+}
+
+impl OsrmClient {
+    /// Client for the OSRM server at `url`, with the default request timeout
+    /// and retry policy.
+    pub fn new(url: &str) -> OsrmClient {
+        OsrmClient {
+            url: url.to_string(),
+            profile: DEFAULT_OSRM_PROFILE.to_string(),
+            client: http_client(Duration::from_secs(OSRM_TIMEOUT_SECS))
+                .expect("failed to build HTTP client"),
+            cache: None,
+            retry: RetryPolicy::default(),
+            max_candidates: MAX_OSRM_CANDIDATES,
+            rate_limit: None,
+            max_snap_meters: None,
+        }
+    }
+
+    /// The `radiuses` parameter for a request with the trial point first,
+    /// then `chargers` other coordinates, if snapping is limited. Chargers
+    /// are at stations, which are next to a road anyway.
+    fn radiuses(&self, chargers: usize) -> Option<String> {
+        let max_snap_meters = self.max_snap_meters?;
+        let mut radiuses = max_snap_meters.to_string();
+        for _ in 0..chargers {
+            radiuses.push_str(";unlimited");
+        }
+        Some(radiuses)
+    }
+
+    /// Whether the trial point had to be moved further than
+    /// `max_snap_meters` to get onto a road. The server should already have
+    /// refused because of `radiuses`, but not every version does.
+    fn snapped_too_far(&self, source: Option<&Waypoint>) -> bool {
+        match (self.max_snap_meters, source) {
+            (Some(max_snap_meters), Some(source)) => source.distance > max_snap_meters,
+            _ => false,
+        }
+    }
+}
+
+/// Whether `profile` can go in an OSRM request path as is: a non-empty name
+/// of ASCII letters, digits, `-` and `_`, like `driving` or `car`. Anything
+/// else could change the meaning of the URL.
+pub fn is_valid_osrm_profile(profile: &str) -> bool {
+    !profile.is_empty()
+        && profile
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Blocking HTTP client whose requests fail after `timeout`, so a stalled
+/// connection counts as a failed attempt instead of blocking forever.
+pub fn http_client(timeout: Duration) -> reqwest::Result<Client> {
+    Client::builder().timeout(timeout).build()
+}
+
+/// How often, and how patiently, to retry OSRM requests that fail (as
+/// opposed to ones that succeed but find no route).
+///
+/// Retries back off exponentially: the n-th retry waits up to
+/// `base_delay * 2^n`, capped at `max_delay`, or as long as the server asks
+/// with `Retry-After` up to the same cap (see `jittered_delay`). Requests that time out count
+/// as failures, so in the worst case a single lookup takes `max_retries + 1`
+/// times the client's timeout, plus the delays.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt before giving up.
+    pub max_retries: u32,
+    /// Delay before the first retry.
+    pub base_delay: Duration,
+    /// Longest delay between two retries, even if the server asks for a
+    /// longer one.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: OSRM_MAX_RETRIES,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Longest wait before retry number `retry` (starting at 0).
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry);
+        self.base_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+
+    /// How long to actually wait before retry number `retry`: `delay` less
+    /// up to half of it, depending on `random` (between 0 and 1), so that
+    /// workers that failed together don't all retry together too. If the
+    /// server said how long to wait (`retry_after`), that long plus up to
+    /// half of `base_delay` instead. Either way it's at most `max_delay` (plus
+    /// the jitter), so a server asking for hours doesn't stall a worker that
+    /// long.
+    pub fn jittered_delay(
+        &self,
+        retry: u32,
+        retry_after: Option<Duration>,
+        random: f64,
+    ) -> Duration {
+        match retry_after {
+            Some(retry_after) => {
+                retry_after.min(self.max_delay) + self.base_delay.mul_f64(random / 2.0)
+            }
+            None => self.delay(retry).mul_f64(1.0 - random / 2.0),
+        }
+    }
+}
+
+/// A number between 0 and 1 that is different every time, for
+/// `RetryPolicy::jittered_delay`. The standard library's hash maps are
+/// seeded randomly, which is random enough to spread out retries.
+pub(crate) fn random_fraction() -> f64 {
+    let bits = RandomState::new().hash_one(Instant::now());
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// The wait asked for by a response's `Retry-After` header, if it has one
+/// that can be read.
+pub(crate) fn retry_after_header(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    headers
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_retry_after(value, SystemTime::now()))
+}
+
+/// The wait asked for by a `Retry-After` header at `now`: a number of
+/// seconds, or an HTTP date (e.g. "Wed, 21 Oct 2015 07:28:00 GMT"). Dates
+/// in the past mean not waiting at all.
+pub(crate) fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let at = UNIX_EPOCH + Duration::from_secs(date.timestamp().try_into().ok()?);
+    Some(at.duration_since(now).unwrap_or_default())
+}
+
+pub const NREL_STATIONS_URL: &str = "https://developer.nrel.gov/api/alt-fuel-stations/v1.csv";
+pub const NREL_STATIONS_JSON_URL: &str = "https://developer.nrel.gov/api/alt-fuel-stations/v1.json";
+
+/// Which of NREL's formats to download the stations in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NrelFormat {
+    /// The CSV download, as read by `read_csv`.
+    #[default]
+    Csv,
+    /// The JSON API, as read by `read_nrel_json`. It has a few more fields
+    /// (see `NrelStation`), and no CSV quoting to go wrong.
+    Json,
+}
+
+impl FromStr for NrelFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(NrelFormat::Csv),
+            "json" => Ok(NrelFormat::Json),
+            _ => Err(format!("unknown format {:?}, expected csv or json", s)),
+        }
+    }
+}
+
+impl NrelFormat {
+    /// File extension of a download in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            NrelFormat::Csv => "csv",
+            NrelFormat::Json => "json",
+        }
+    }
+
+    /// Reads a download in this format, see `read_csv` and `read_nrel_json`.
+    pub fn read(self, body: &str, filter: &ChargerFilter) -> Result<AllChargerLocations, GapError> {
+        match self {
+            NrelFormat::Csv => read_csv(Reader::from_reader(body.as_bytes()), filter),
+            NrelFormat::Json => read_nrel_json(body, filter),
+        }
+    }
+}
+
+/// Which stations to download from NREL. The defaults download every open
+/// EV charger in the database.
+///
+/// These are applied by the NREL API, unlike `ChargerFilter`, which is
+/// applied to the downloaded (or cached) data.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NrelQuery {
+    /// Comma-separated station statuses: `E` (open), `P` (planned) and/or
+    /// `T` (temporarily unavailable).
+    pub status: String,
+    /// `US`, `CA` or `all`.
+    pub country: String,
+    /// Two letter state or province code, or `all`.
+    pub state: String,
+    pub level: ChargingLevel,
+    /// Comma-separated NREL network ids, or `all`.
+    pub network: String,
+    pub format: NrelFormat,
+}
+
+impl Default for NrelQuery {
+    fn default() -> Self {
+        NrelQuery {
+            status: "E".to_string(),
+            country: "all".to_string(),
+            state: "all".to_string(),
+            level: ChargingLevel::All,
+            network: "all".to_string(),
+            format: NrelFormat::Csv,
+        }
+    }
+}
+
+impl NrelQuery {
+    /// URL of the download for this query.
+    pub fn url(&self, nrel_api_key: &str) -> url::Url {
+        let mut url = url::Url::parse_with_params(
+            match self.format {
+                NrelFormat::Csv => NREL_STATIONS_URL,
+                NrelFormat::Json => NREL_STATIONS_JSON_URL,
+            },
+            [
+                ("access", "public"),
+                ("api_key", nrel_api_key),
+                ("country", &self.country),
+                ("ev_charging_level", self.level.nrel_query_value()),
+                ("ev_connector_type", "all"),
+                ("ev_network", &self.network),
+                ("fuel_type", "ELEC"),
+                ("limit", "all"),
+                ("owner_type", "all"),
+                ("state", &self.state),
+                ("status", &self.status),
+            ],
+        )
+        .expect("NREL_STATIONS_URL is a valid URL");
+        if self.format == NrelFormat::Csv {
+            url.query_pairs_mut()
+                .append_pair("download", "true")
+                .append_pair("utf8_bom", "true");
+        }
+        url
+    }
+}
+
+/// Downloads the charger data for `query` from NREL, or reads it from `cache`
+/// if it has a recent enough copy.
+pub fn download_source_data(
+    nrel_api_key: &str,
+    query: &NrelQuery,
+    filter: &ChargerFilter,
+    cache: Option<&NrelCache>,
+) -> Result<AllChargerLocations, GapError> {
+    if let Some(body) = cache.and_then(|cache| cache.load(query)) {
+        return query.format.read(&body, filter);
+    }
+    let body = fetch_nrel(query.url(nrel_api_key), query.format)?;
+    // Checked before caching, so that an error in the API's answer isn't
+    // kept as if it were data
+    let chargers = query.format.read(&body, filter);
+    if let (Some(cache), Ok(_) | Err(GapError::EmptyData)) = (cache, &chargers) {
+        cache.store(query, &body)?;
+    }
+    chargers
+}
+
+/// Downloads charger data from NREL in `format`, failing with
+/// `GapError::NrelApi` if the answer is an error instead: a non-2xx status,
+/// an HTML page, or for CSV a JSON body, which the CSV reader would otherwise
+/// read as no chargers at all.
+pub(crate) fn fetch_nrel(url: url::Url, format: NrelFormat) -> Result<String, GapError> {
+    let response = reqwest::blocking::get(url)?;
+    let status = response.status();
+    let body = response.text()?;
+    let start = body.trim_start_matches('\u{feff}').trim_start();
+    let wrong_format = match format {
+        NrelFormat::Csv => start.starts_with('{') || start.starts_with('<'),
+        NrelFormat::Json => start.starts_with('<'),
+    };
+    if !status.is_success() || wrong_format {
+        return Err(GapError::NrelApi {
+            status: status.as_u16(),
+            message: nrel_error_message(&body),
+        });
+    }
+    Ok(body)
+}
+
+impl TrialPoint {
+    /// Looks up the driving distance from this point to `charger`, telling
+    /// apart OSRM answering that there is no route from the lookup failing.
+    pub fn get_osrm_distance(&self, osrm: &OsrmClient, charger: &ChargerLocation) -> RouteLookup {
+        let _span = tracing::trace_span!("get_osrm_distance").entered();
+        let src = (self.longitude, self.latitude);
+        let dst = (charger.longitude, charger.latitude);
+        if let Some(distance) = osrm.cache.as_ref().and_then(|cache| cache.get(src, dst)) {
+            return RouteLookup::Reachable(distance);
+        }
+        let mut osrm_api_url = format!(
+            "{}/route/v1/{}/{},{};{},{}",
+            osrm.url,
+            osrm.profile,
+            self.longitude,
+            self.latitude,
+            charger.longitude,
+            charger.latitude
+        );
+        if let Some(radiuses) = osrm.radiuses(1) {
+            osrm_api_url.push_str(&format!("?radiuses={}", radiuses));
+        }
+        let body: Json = match get_osrm_json(&osrm_api_url, osrm) {
+            Some(body) => body,
+            None => return RouteLookup::Error,
+        };
+        if osrm.snapped_too_far(body.waypoints.first()) {
+            debug!("{} is too far from a road to route from", self);
+            return RouteLookup::NoRoute;
+        }
+        match (body.code.as_str(), body.routes.first()) {
+            ("Ok", Some(route)) => {
+                if let Some(cache) = &osrm.cache {
+                    cache.insert(src, dst, route.distance);
+                }
+                RouteLookup::Reachable(route.distance)
+            }
+            ("Ok", None) => RouteLookup::NoRoute,
+            (code, _) if OSRM_NO_ROUTE_CODES.contains(&code) => RouteLookup::NoRoute,
+            (code, _) => {
+                warn!("OSRM error {} for {}", code, osrm_api_url);
+                RouteLookup::Error
+            }
+        }
+    }
+
+    /// Looks up the driving distance in meters from this point to each of the
+    /// `candidates`, in the same order, using a single request to the OSRM
+    /// table service. If the request fails, every lookup is an `Error`.
+    ///
+    /// Distances that are already in the cache aren't requested again.
+    pub fn get_osrm_table_distances(
+        &self,
+        osrm: &OsrmClient,
+        candidates: &[ChargerLocation],
+    ) -> Vec<RouteLookup> {
+        let src = (self.longitude, self.latitude);
+        let cached: Vec<Option<f64>> = match &osrm.cache {
+            Some(cache) => candidates
+                .iter()
+                .map(|charger| cache.get(src, (charger.longitude, charger.latitude)))
+                .collect(),
+            None => vec![None; candidates.len()],
+        };
+        let mut distances: Vec<RouteLookup> = cached
+            .iter()
+            .map(|distance| distance.map_or(RouteLookup::Error, RouteLookup::Reachable))
+            .collect();
+        let uncached: Vec<usize> = (0..candidates.len())
+            .filter(|&i| cached[i].is_none())
+            .collect();
+        if uncached.is_empty() {
+            return distances;
+        }
+        let osrm_api_url =
+            self.osrm_table_url(osrm, uncached.iter().map(|&i| &candidates[i]), "distance");
+        let row = self.osrm_table_row(osrm, &osrm_api_url, uncached.len(), |table| table.distances);
+        for (&candidate, distance) in uncached.iter().zip(row) {
+            distances[candidate] = distance;
+            if let (Some(cache), RouteLookup::Reachable(distance)) = (&osrm.cache, distance) {
+                let charger = &candidates[candidate];
+                cache.insert(src, (charger.longitude, charger.latitude), distance);
+            }
+        }
+        distances
+    }
+
+    /// Looks up the driving time in seconds from this point to each of the
+    /// `candidates`, like `get_osrm_table_distances`. The cache only holds
+    /// distances, so durations are always requested.
+    pub fn get_osrm_table_durations(
+        &self,
+        osrm: &OsrmClient,
+        candidates: &[ChargerLocation],
+    ) -> Vec<RouteLookup> {
+        if candidates.is_empty() {
+            return Vec::new();
+        }
+        let osrm_api_url = self.osrm_table_url(osrm, candidates.iter(), "duration");
+        self.osrm_table_row(osrm, &osrm_api_url, candidates.len(), |table| {
+            table.durations
+        })
+    }
+
+    /// Requests the table at `osrm_api_url`, with this point as the only
+    /// source, and returns the `count` lookups in the row that `rows` picks
+    /// out of it. A point too far from a road has no route to anywhere; a
+    /// failed request, or one with the wrong number of lookups, is an
+    /// `Error` for all of them.
+    fn osrm_table_row(
+        &self,
+        osrm: &OsrmClient,
+        osrm_api_url: &str,
+        count: usize,
+        rows: impl FnOnce(TableJson) -> Vec<Vec<Option<f64>>>,
+    ) -> Vec<RouteLookup> {
+        let Some(table) = get_osrm_json::<TableJson>(osrm_api_url, osrm) else {
+            return vec![RouteLookup::Error; count];
+        };
+        if OSRM_NO_ROUTE_CODES.contains(&table.code.as_str()) {
+            return vec![RouteLookup::NoRoute; count];
+        }
+        if table.code != "Ok" {
+            warn!("OSRM error {} for {}", table.code, osrm_api_url);
+            return vec![RouteLookup::Error; count];
+        }
+        if !self.snapped_near_road(osrm, &table) {
+            return vec![RouteLookup::NoRoute; count];
+        }
+        match rows(table).into_iter().next() {
+            Some(row) if row.len() == count => row
+                .into_iter()
+                .map(|value| value.map_or(RouteLookup::NoRoute, RouteLookup::Reachable))
+                .collect(),
+            _ => {
+                warn!("OSRM table without {} entries for {}", count, osrm_api_url);
+                vec![RouteLookup::Error; count]
+            }
+        }
+    }
+
+    /// Whether this point, the source of `table`, is close enough to a road
+    /// (see `OsrmClient::max_snap_meters`). Otherwise no charger is in reach.
+    fn snapped_near_road(&self, osrm: &OsrmClient, table: &TableJson) -> bool {
+        if osrm.snapped_too_far(table.sources.first()) {
+            debug!("{} is too far from a road to route from", self);
+            return false;
+        }
+        true
+    }
+
+    /// URL of an OSRM table request from this point to each of `chargers`.
+    fn osrm_table_url<'a>(
+        &self,
+        osrm: &OsrmClient,
+        chargers: impl Iterator<Item = &'a ChargerLocation>,
+        annotations: &str,
+    ) -> String {
+        // The trial point is coordinate 0 and the only source; the chargers
+        // are coordinates 1..=n and the destinations.
+        let mut coordinates = format!("{},{}", self.longitude, self.latitude);
+        let mut destinations = Vec::new();
+        for (i, charger) in chargers.enumerate() {
+            coordinates.push_str(&format!(";{},{}", charger.longitude, charger.latitude));
+            destinations.push((i + 1).to_string());
+        }
+        let mut url = format!(
+            "{}/table/v1/{}/{}?sources=0&destinations={}&annotations={}",
+            osrm.url,
+            osrm.profile,
+            coordinates,
+            destinations.join(";"),
+            annotations
+        );
+        if let Some(radiuses) = osrm.radiuses(destinations.len()) {
+            url.push_str(&format!("&radiuses={}", radiuses));
+        }
+        url
+    }
+}
+
+/// Sends a GET request to the OSRM API, retrying on request errors according
+/// to `osrm.retry` and waiting for `osrm.rate_limit`, and parses the response
+/// body as `T`. Returns `None` if the body doesn't parse or every attempt
+/// failed.
+fn get_osrm_json<T: DeserializeOwned>(osrm_api_url: &str, osrm: &OsrmClient) -> Option<T> {
+    request_json(
+        || osrm.client.get(osrm_api_url),
+        &osrm.retry,
+        osrm.rate_limit.as_deref(),
+    )
+}
+
+/// Sends the request built by `request`, retrying on request errors and on
+/// unreadable responses from a struggling server (HTTP 5xx or 429)
+/// according to `retry`, and parses the response body as `T`. A 429's
+/// `Retry-After` is waited out before the next attempt. Every attempt,
+/// including retries, waits for `rate_limit` first. Returns `None` if the
+/// body doesn't parse or every attempt failed.
+pub(crate) fn request_json<T: DeserializeOwned>(
+    request: impl Fn() -> reqwest::blocking::RequestBuilder,
+    retry: &RetryPolicy,
+    rate_limit: Option<&RateLimiter>,
+) -> Option<T> {
+    let mut retries = 0;
+    loop {
+        if let Some(rate_limit) = rate_limit {
+            rate_limit.acquire();
+        }
+        let mut retry_after = None;
+        let error = match request().send() {
+            Ok(response) => {
+                let status = response.status();
+                if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                    retry_after = retry_after_header(response.headers());
+                }
+                match response.text() {
+                    Ok(body) => match serde_json::from_str::<T>(&body) {
+                        Ok(json) => return Some(json),
+                        Err(error)
+                            if status.is_server_error()
+                                || status == reqwest::StatusCode::TOO_MANY_REQUESTS =>
+                        {
+                            format!("{} body error: {}", status, error)
+                        }
+                        // Any other response that isn't what was asked for
+                        // won't be any different next time
+                        Err(error) => {
+                            warn!(
+                                "{:?} giving up ({}) {} body error: {}\nbody: {}",
+                                thread::current().id(),
+                                retries,
+                                status,
+                                error,
+                                body,
+                            );
+                            return None;
+                        }
+                    },
+                    Err(error) => error.to_string(),
+                }
+            }
+            Err(error) => error.to_string(),
+        };
+        if retries >= retry.max_retries {
+            warn!(
+                "{:?} giving up after {} retries, request error: {}",
+                thread::current().id(),
+                retries,
+                error
+            );
+            return None;
+        }
+        warn!(
+            "{:?} retrying ({}) request error: {}",
+            thread::current().id(),
+            retries,
+            error
+        );
+        thread::sleep(retry.jittered_delay(retries, retry_after, random_fraction()));
+        retries += 1;
+    }
+}
@@ -0,0 +1,108 @@
+use log::{info, warn};
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use crate::{ChargingLevel, NrelQuery};
+
+/// Default age after which the cached NREL download is fetched again.
+pub const NREL_CACHE_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Local copy of the charger CSV downloaded from NREL, so that it doesn't
+/// have to be fetched again on every run.
+#[derive(Clone, Debug)]
+pub struct NrelCache {
+    /// Directory the CSV is stored in. Created if it doesn't exist.
+    pub dir: PathBuf,
+    /// Cached data older than this is downloaded again.
+    pub max_age: Duration,
+    /// Ignore any cached data and always download (and cache) it again.
+    pub refresh: bool,
+}
+
+impl NrelCache {
+    pub fn new(dir: impl Into<PathBuf>) -> NrelCache {
+        NrelCache {
+            dir: dir.into(),
+            max_age: NREL_CACHE_MAX_AGE,
+            refresh: false,
+        }
+    }
+
+    /// Where the download for `query` is stored. Each query is a different
+    /// download, so they're cached separately: the default query is
+    /// `nrel.csv`, and anything else is added to the name, e.g.
+    /// `nrel-l2-state_ca.csv`. JSON downloads end in `.json` instead.
+    pub fn path(&self, query: &NrelQuery) -> PathBuf {
+        let defaults = NrelQuery::default();
+        let mut name = "nrel".to_string();
+        match query.level {
+            ChargingLevel::All => {}
+            ChargingLevel::L2 => name.push_str("-l2"),
+            ChargingLevel::DcFast => name.push_str("-dcfast"),
+        }
+        for (key, value, default) in [
+            ("status", &query.status, &defaults.status),
+            ("country", &query.country, &defaults.country),
+            ("state", &query.state, &defaults.state),
+            ("network", &query.network, &defaults.network),
+        ] {
+            if value != default {
+                let value: String = value
+                    .chars()
+                    .map(|c| {
+                        if c.is_ascii_alphanumeric() {
+                            c.to_ascii_lowercase()
+                        } else {
+                            '_'
+                        }
+                    })
+                    .collect();
+                name.push_str(&format!("-{}_{}", key, value));
+            }
+        }
+        self.dir.join(name + "." + query.format.extension())
+    }
+
+    /// The cached CSV for `query`, if there is one that is recent enough to
+    /// use.
+    pub fn load(&self, query: &NrelQuery) -> Option<String> {
+        if self.refresh {
+            return None;
+        }
+        let path = self.path(query);
+        let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+        if age > self.max_age {
+            info!(
+                "cached NREL data {:?} is {:?} old, downloading again",
+                path, age
+            );
+            return None;
+        }
+        match std::fs::read_to_string(&path) {
+            Ok(body) => {
+                info!("using cached NREL data {:?}", path);
+                Some(body)
+            }
+            Err(error) => {
+                warn!("failed to read cached NREL data {:?}: {}", path, error);
+                None
+            }
+        }
+    }
+
+    /// Stores a freshly downloaded CSV for `query`.
+    pub fn store(&self, query: &NrelQuery, body: &str) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.path(query);
+        // Same as the OSRM cache, write then rename so a crash can't leave a
+        // truncated CSV behind that looks fresh.
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, body)?;
+        std::fs::rename(&tmp_path, &path)
+    }
+}
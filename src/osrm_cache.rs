@@ -0,0 +1,133 @@
+use log::warn;
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+/// Number of decimal places coordinates are rounded to when used as a cache
+/// key. 5 decimal places is roughly 1m, so grid points that differ only by
+/// floating point noise share an entry.
+pub const CACHE_KEY_DECIMALS: i32 = 5;
+/// The cache is written back to disk every time this many new entries have
+/// been added, so a crash loses at most this many lookups.
+pub const CACHE_FLUSH_INTERVAL: usize = 1_000;
+
+/// Rounded `(src_lon, src_lat, dst_lon, dst_lat)`, in units of
+/// 10^-`CACHE_KEY_DECIMALS` degrees.
+type CacheKey = (i64, i64, i64, i64);
+
+/// File-backed cache of OSRM driving distances, keyed by source and
+/// destination coordinates. Safe to share between threads.
+#[derive(Debug)]
+pub struct OsrmCache {
+    path: PathBuf,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    distances: HashMap<CacheKey, f64>,
+    unsaved: usize,
+}
+
+impl OsrmCache {
+    /// Loads the cache from `path`, or starts an empty one if the file doesn't
+    /// exist yet.
+    pub fn load(path: impl Into<PathBuf>) -> Result<OsrmCache, Box<dyn Error>> {
+        let path = path.into();
+        let mut distances = HashMap::new();
+        if path.exists() {
+            // Stored as a list of [src_lon, src_lat, dst_lon, dst_lat, distance]
+            let entries: Vec<[f64; 5]> =
+                serde_json::from_reader(BufReader::new(File::open(&path)?))?;
+            for [src_lon, src_lat, dst_lon, dst_lat, distance] in entries {
+                distances.insert(cache_key((src_lon, src_lat), (dst_lon, dst_lat)), distance);
+            }
+        }
+        Ok(OsrmCache {
+            path,
+            state: Mutex::new(CacheState {
+                distances,
+                unsaved: 0,
+            }),
+        })
+    }
+
+    /// Number of cached distances.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().distances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Cached driving distance in meters between two `(lon, lat)` points.
+    pub fn get(&self, src: (f64, f64), dst: (f64, f64)) -> Option<f64> {
+        let state = self.state.lock().unwrap();
+        state.distances.get(&cache_key(src, dst)).copied()
+    }
+
+    /// Records a driving distance, writing the cache to disk if enough new
+    /// entries have built up.
+    pub fn insert(&self, src: (f64, f64), dst: (f64, f64), distance: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.distances.insert(cache_key(src, dst), distance);
+        state.unsaved += 1;
+        if state.unsaved >= CACHE_FLUSH_INTERVAL {
+            if let Err(error) = self.save(&mut state) {
+                warn!("failed to write OSRM cache {:?}: {}", self.path, error);
+            }
+        }
+    }
+
+    /// Writes any new entries to disk.
+    pub fn flush(&self) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.lock().unwrap();
+        if state.unsaved > 0 {
+            self.save(&mut state)?;
+        }
+        Ok(())
+    }
+
+    fn save(&self, state: &mut CacheState) -> Result<(), Box<dyn Error>> {
+        let scale = 10f64.powi(CACHE_KEY_DECIMALS);
+        let entries: Vec<[f64; 5]> = state
+            .distances
+            .iter()
+            .map(|(&(src_lon, src_lat, dst_lon, dst_lat), &distance)| {
+                [
+                    src_lon as f64 / scale,
+                    src_lat as f64 / scale,
+                    dst_lon as f64 / scale,
+                    dst_lat as f64 / scale,
+                    distance,
+                ]
+            })
+            .collect();
+        // Write to a temporary file and rename it over the old cache, so a
+        // crash mid-write can't corrupt what was already there.
+        let tmp_path = self.path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, &entries)?;
+        writer.flush()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        state.unsaved = 0;
+        Ok(())
+    }
+}
+
+fn cache_key((src_lon, src_lat): (f64, f64), (dst_lon, dst_lat): (f64, f64)) -> CacheKey {
+    let scale = 10f64.powi(CACHE_KEY_DECIMALS);
+    let round = |degrees: f64| (degrees * scale).round() as i64;
+    (
+        round(src_lon),
+        round(src_lat),
+        round(dst_lon),
+        round(dst_lat),
+    )
+}
@@ -0,0 +1,40 @@
+//! The parallel iterators used by the core, from rayon when there are
+//! threads to run them on (with the `network` feature), and otherwise the
+//! same calls on plain iterators, e.g. in WebAssembly.
+
+#[cfg(feature = "rayon")]
+pub(crate) use rayon::{join, prelude::*};
+
+#[cfg(not(feature = "rayon"))]
+pub(crate) use sequential::*;
+
+#[cfg(not(feature = "rayon"))]
+mod sequential {
+    pub(crate) fn join<A, B>(a: impl FnOnce() -> A, b: impl FnOnce() -> B) -> (A, B) {
+        (a(), b())
+    }
+
+    pub(crate) trait ParallelSlice<T> {
+        fn par_iter(&self) -> std::slice::Iter<'_, T>;
+        /// Stable, like rayon's.
+        fn par_sort_by_key<K: Ord>(&mut self, key: impl FnMut(&T) -> K);
+    }
+
+    impl<T> ParallelSlice<T> for [T] {
+        fn par_iter(&self) -> std::slice::Iter<'_, T> {
+            self.iter()
+        }
+
+        fn par_sort_by_key<K: Ord>(&mut self, key: impl FnMut(&T) -> K) {
+            self.sort_by_key(key)
+        }
+    }
+
+    pub(crate) trait IntoParallelIterator: IntoIterator + Sized {
+        fn into_par_iter(self) -> Self::IntoIter {
+            self.into_iter()
+        }
+    }
+
+    impl<T> IntoParallelIterator for Vec<T> {}
+}
@@ -0,0 +1,88 @@
+//! Gridded population, for ranking gaps by how many people live in them
+//! rather than by area alone.
+
+use std::fs::File;
+use std::io::Read;
+
+use geo::algorithm::{bounding_rect::BoundingRect, contains::Contains};
+use geo::MultiPolygon;
+use rstar::{RTree, RTreeObject, AABB};
+use serde::Deserialize;
+
+use crate::GapError;
+
+/// One row of a population CSV: the population of the grid cell centered on
+/// a point.
+#[derive(Debug, Deserialize)]
+struct PopulationRow {
+    #[serde(alias = "latitude", alias = "Latitude")]
+    lat: f64,
+    #[serde(alias = "longitude", alias = "Longitude")]
+    lon: f64,
+    #[serde(alias = "Population")]
+    population: f64,
+}
+
+/// A population cell's entry in the index, positioned at `[lat, lon]` like
+/// the charger index.
+#[derive(Clone, Debug, PartialEq)]
+struct PopulationCell {
+    position: [f64; 2],
+    population: f64,
+}
+
+impl RTreeObject for PopulationCell {
+    type Envelope = AABB<[f64; 2]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        AABB::from_point(self.position)
+    }
+}
+
+/// Population counts at points, indexed by location.
+pub struct Population {
+    index: RTree<PopulationCell>,
+}
+
+impl Population {
+    /// Reads a CSV with `lat`, `lon` and `population` columns (`latitude`
+    /// and `longitude` work too), e.g. the centers of a population raster's
+    /// cells. Rows that don't parse are skipped.
+    pub fn from_csv<R: Read>(reader: csv::Reader<R>) -> Population {
+        let cells = reader
+            .into_deserialize()
+            .filter_map(|row: Result<PopulationRow, _>| row.ok())
+            .map(|row| PopulationCell {
+                position: [row.lat, row.lon],
+                population: row.population,
+            })
+            .collect();
+        Population {
+            index: RTree::bulk_load(cells),
+        }
+    }
+
+    pub fn read(path: &str) -> Result<Population, GapError> {
+        Ok(Population::from_csv(csv::Reader::from_reader(File::open(
+            path,
+        )?)))
+    }
+
+    /// Total population of the points inside `polygons` (x = longitude,
+    /// y = latitude). Points where polygons overlap are only counted once.
+    pub fn within(&self, polygons: &MultiPolygon<f64>) -> f64 {
+        let bounds = match polygons.bounding_rect() {
+            Some(bounds) => bounds,
+            None => return 0.0,
+        };
+        let envelope = AABB::from_corners(
+            [bounds.min().y, bounds.min().x],
+            [bounds.max().y, bounds.max().x],
+        );
+        self.index
+            .locate_in_envelope(&envelope)
+            .filter(|cell| polygons.contains(&geo::Point::new(cell.position[1], cell.position[0])))
+            .map(|cell| cell.population)
+            .sum()
+    }
+}
@@ -0,0 +1,246 @@
+//! Where a run spends its time. The search is instrumented with `tracing`
+//! spans: `find_gaps` for each chunk, and inside it `generate_grid`,
+//! `check_point`, `nearest_chargers` (the charger index), `routing` and
+//! `get_osrm_distance` (routing lookups) and `gap_hulls`. `PhaseTimer` is a
+//! `tracing` subscriber that adds up how long each chunk spent in each of
+//! them, e.g. to see whether a run is waiting on OSRM or on the hulls.
+//!
+//! Any other subscriber works too, the spans are ordinary `tracing` spans.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use log::info;
+use tracing::{
+    field::{Field, Visit},
+    span,
+    subscriber::Interest,
+    Event, Metadata, Subscriber,
+};
+
+/// Name of the span around the search of one chunk. Time in the spans
+/// inside it is added up per chunk; spans outside of one aren't timed.
+pub const CHUNK_SPAN: &str = "find_gaps";
+
+/// How long one chunk spent in each span, see `PhaseTimer`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChunkTimes {
+    /// Fields of the chunk's span, e.g. `lat_min=40 lon_min=-100`.
+    pub chunk: String,
+    /// Time spent in each span, by span name, longest first. Spans entered
+    /// on several threads at once (all but `find_gaps` and `generate_grid`)
+    /// add up the time on each thread, so they can take longer than the
+    /// chunk itself.
+    pub phases: Vec<(&'static str, Duration)>,
+}
+
+impl ChunkTimes {
+    /// Time spent in the span called `name`, if it was entered at all.
+    pub fn phase(&self, name: &str) -> Option<Duration> {
+        self.phases
+            .iter()
+            .find(|(phase, _)| *phase == name)
+            .map(|(_, time)| *time)
+    }
+}
+
+impl fmt::Display for ChunkTimes {
+    /// `find_gaps 1.20s, routing 3.40s, ...`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (name, time)) in self.phases.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} {:.2}s", name, time.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
+
+/// A `tracing` subscriber timing the spans of each chunk, see the module
+/// docs. Each chunk's times are logged at info level when it's done, and
+/// kept for `finished` and `total`. Clones share their times.
+#[derive(Clone, Debug, Default)]
+pub struct PhaseTimer {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, SpanState>>,
+    finished: Mutex<Vec<ChunkTimes>>,
+}
+
+#[derive(Debug)]
+struct SpanState {
+    name: &'static str,
+    /// The `CHUNK_SPAN` this span is in, or its own id if it is one.
+    chunk: u64,
+    refs: usize,
+    /// Only filled in for chunks.
+    fields: String,
+    phases: HashMap<&'static str, Duration>,
+}
+
+thread_local! {
+    /// Spans entered on this thread, innermost last, with when.
+    static ENTERED: RefCell<Vec<(u64, Instant)>> = const { RefCell::new(Vec::new()) };
+}
+
+impl PhaseTimer {
+    pub fn new() -> PhaseTimer {
+        PhaseTimer::default()
+    }
+
+    /// Times of the chunks done so far, in the order they finished.
+    pub fn finished(&self) -> Vec<ChunkTimes> {
+        self.inner.finished.lock().unwrap().clone()
+    }
+
+    /// Times of all of the chunks done so far added up.
+    pub fn total(&self) -> ChunkTimes {
+        let mut phases: HashMap<&'static str, Duration> = HashMap::new();
+        for chunk in self.inner.finished.lock().unwrap().iter() {
+            for (name, time) in &chunk.phases {
+                *phases.entry(name).or_default() += *time;
+            }
+        }
+        ChunkTimes {
+            chunk: "all chunks".to_string(),
+            phases: sorted(phases),
+        }
+    }
+}
+
+/// Longest first, then by name so that the order is stable.
+fn sorted(phases: HashMap<&'static str, Duration>) -> Vec<(&'static str, Duration)> {
+    let mut phases: Vec<_> = phases.into_iter().collect();
+    phases.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    phases
+}
+
+/// Formats span fields as `name=value`, separated by spaces.
+struct FieldWriter<'a>(&'a mut String);
+
+impl Visit for FieldWriter<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        self.0.push_str(&format!("{}={:?}", field.name(), value));
+    }
+}
+
+impl Subscriber for PhaseTimer {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        match self.enabled(metadata) {
+            true => Interest::always(),
+            false => Interest::never(),
+        }
+    }
+
+    /// Only this crate's spans: the HTTP client has spans of its own.
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.is_span() && metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+    }
+
+    fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let parent = match attrs.is_contextual() {
+            true => ENTERED.with(|entered| entered.borrow().last().map(|(id, _)| *id)),
+            false => attrs.parent().map(span::Id::into_u64),
+        };
+        let name = attrs.metadata().name();
+        let mut spans = self.inner.spans.lock().unwrap();
+        let chunk = match parent.and_then(|parent| spans.get(&parent)) {
+            Some(parent) => Some(parent.chunk),
+            None if name == CHUNK_SPAN => Some(id),
+            None => None,
+        };
+        if let Some(chunk) = chunk {
+            let mut fields = String::new();
+            if chunk == id {
+                attrs.record(&mut FieldWriter(&mut fields));
+            }
+            spans.insert(
+                id,
+                SpanState {
+                    name,
+                    chunk,
+                    refs: 1,
+                    fields,
+                    phases: HashMap::new(),
+                },
+            );
+        }
+        span::Id::from_u64(id)
+    }
+
+    fn record(&self, _span: &span::Id, _values: &span::Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+    fn event(&self, _event: &Event<'_>) {}
+
+    fn enter(&self, span: &span::Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push((span.into_u64(), Instant::now())));
+    }
+
+    fn exit(&self, span: &span::Id) {
+        let id = span.into_u64();
+        let entered = ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            let at = entered.iter().rposition(|(entered, _)| *entered == id)?;
+            Some(entered.remove(at).1)
+        });
+        let Some(entered) = entered else {
+            return;
+        };
+        let mut spans = self.inner.spans.lock().unwrap();
+        let Some((name, chunk)) = spans.get(&id).map(|span| (span.name, span.chunk)) else {
+            return;
+        };
+        if let Some(chunk) = spans.get_mut(&chunk) {
+            *chunk.phases.entry(name).or_default() += entered.elapsed();
+        }
+    }
+
+    fn clone_span(&self, span: &span::Id) -> span::Id {
+        if let Some(state) = self.inner.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            state.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: span::Id) -> bool {
+        let id = span.into_u64();
+        let mut spans = self.inner.spans.lock().unwrap();
+        let Some(state) = spans.get_mut(&id) else {
+            return false;
+        };
+        state.refs -= 1;
+        if state.refs > 0 {
+            return false;
+        }
+        let state = spans.remove(&id).unwrap();
+        drop(spans);
+        if state.chunk == id {
+            let times = ChunkTimes {
+                chunk: state.fields,
+                phases: sorted(state.phases),
+            };
+            info!("Chunk {}: {}", times.chunk, times);
+            self.inner.finished.lock().unwrap().push(times);
+        }
+        true
+    }
+}
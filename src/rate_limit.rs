@@ -0,0 +1,42 @@
+//! Keeps the requests to a routing server under a fixed rate, across all of
+//! the threads sending them. The public OSRM server in particular only allows
+//! about one request per second per user.
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A token bucket holding a single token, refilled every `1 / per_second`
+/// seconds: requests are spaced out evenly, with no bursts. Share one (in an
+/// `Arc`) between all the clients that talk to the same server.
+#[derive(Debug)]
+pub struct RateLimiter {
+    interval: Duration,
+    /// When the next request may be sent. Each caller reserves a slot by
+    /// moving this forward, then waits for its slot outside the lock.
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    /// Limiter for at most `per_second` requests per second, which must be
+    /// positive.
+    pub fn new(per_second: f64) -> RateLimiter {
+        assert!(per_second > 0.0, "rate limit must be positive");
+        RateLimiter {
+            interval: Duration::from_secs_f64(1.0 / per_second),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Blocks until another request can be sent.
+    pub fn acquire(&self) {
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            // Time spent idle doesn't build up into a burst
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        thread::sleep(slot.saturating_duration_since(Instant::now()));
+    }
+}
@@ -0,0 +1,320 @@
+//! Driving distances from a routing engine, behind a trait so that the
+//! reachability logic doesn't depend on a particular server (or on a server
+//! at all, in tests).
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed},
+        Mutex,
+    },
+    thread,
+};
+
+use crate::{ChargerLocation, RangeConfig, RangeMode, TrialPoint, MAX_OSRM_CANDIDATES};
+
+#[cfg(feature = "network")]
+mod clients;
+#[cfg(feature = "network")]
+pub use clients::{GraphHopperClient, ValhallaClient};
+
+/// Default number of chargers looked up at the same time for one point, by
+/// engines that look up one charger per request.
+pub const CONCURRENT_LOOKUPS: usize = 4;
+
+/// What looking up the route between two points found out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RouteLookup {
+    /// There is a route, this long: meters for a distance, seconds for a
+    /// time.
+    Reachable(f64),
+    /// The routing engine answered that there is no route, e.g. to an island.
+    /// Asking again won't change that.
+    NoRoute,
+    /// The lookup failed (after any retries), so whether there is a route
+    /// isn't known.
+    Error,
+}
+
+impl RouteLookup {
+    /// How long the route is, if there is one.
+    pub fn length(self) -> Option<f64> {
+        match self {
+            RouteLookup::Reachable(length) => Some(length),
+            RouteLookup::NoRoute | RouteLookup::Error => None,
+        }
+    }
+}
+
+/// Something that can tell how far it is to drive from a point to a charger.
+///
+/// Only the single route lookups are required; the batch versions default to
+/// looking up each charger in turn, and engines with a batch API (like the
+/// OSRM table service) can override them.
+pub trait RouteProvider: Sync {
+    /// Driving distance in meters from `src` to `dst`, or `None` if there is
+    /// no route or the lookup failed.
+    fn driving_distance(&self, src: &TrialPoint, dst: &ChargerLocation) -> Option<f64>;
+
+    /// Driving time in seconds from `src` to `dst`, or `None` if there is no
+    /// route or the lookup failed.
+    fn driving_duration(&self, src: &TrialPoint, dst: &ChargerLocation) -> Option<f64>;
+
+    /// Driving distance in meters from `src` to each of `dsts`, in the same
+    /// order.
+    fn driving_distances(&self, src: &TrialPoint, dsts: &[ChargerLocation]) -> Vec<Option<f64>> {
+        dsts.iter()
+            .map(|dst| self.driving_distance(src, dst))
+            .collect()
+    }
+
+    /// Driving time in seconds from `src` to each of `dsts`, in the same
+    /// order.
+    fn driving_durations(&self, src: &TrialPoint, dsts: &[ChargerLocation]) -> Vec<Option<f64>> {
+        dsts.iter()
+            .map(|dst| self.driving_duration(src, dst))
+            .collect()
+    }
+
+    /// Like `driving_distances`, but telling apart the engine answering that
+    /// there is no route from the lookup failing. By default every `None` is
+    /// taken to be a failure, since that's all that is known.
+    fn driving_distance_lookups(
+        &self,
+        src: &TrialPoint,
+        dsts: &[ChargerLocation],
+    ) -> Vec<RouteLookup> {
+        self.driving_distances(src, dsts)
+            .into_iter()
+            .map(|distance| distance.map_or(RouteLookup::Error, RouteLookup::Reachable))
+            .collect()
+    }
+
+    /// Like `driving_durations`, telling apart no route from a failed lookup
+    /// like `driving_distance_lookups`.
+    fn driving_duration_lookups(
+        &self,
+        src: &TrialPoint,
+        dsts: &[ChargerLocation],
+    ) -> Vec<RouteLookup> {
+        self.driving_durations(src, dsts)
+            .into_iter()
+            .map(|duration| duration.map_or(RouteLookup::Error, RouteLookup::Reachable))
+            .collect()
+    }
+
+    /// Maximum number of closest chargers to look up per point, see
+    /// `MAX_OSRM_CANDIDATES`.
+    fn max_candidates(&self) -> usize {
+        MAX_OSRM_CANDIDATES
+    }
+
+    /// Number of chargers `any_in_range` looks up at the same time.
+    fn concurrent_lookups(&self) -> usize {
+        CONCURRENT_LOOKUPS
+    }
+
+    /// Whether any of `dsts` is in range of `src` by road, according to
+    /// `config.mode`.
+    ///
+    /// Looks up `concurrent_lookups` chargers at a time, closest first, and
+    /// stops starting new lookups once one is in range. Which lookups get
+    /// made depends on timing, but the answer doesn't: it is only false if
+    /// every charger was looked up and none was in range.
+    fn any_in_range(
+        &self,
+        src: &TrialPoint,
+        dsts: &[ChargerLocation],
+        config: &RangeConfig,
+    ) -> bool {
+        let in_range = |dst| match config.mode {
+            RangeMode::Distance => self
+                .driving_distance(src, dst)
+                .is_some_and(|distance| distance as u64 <= config.max_range_meters),
+            RangeMode::Duration => self
+                .driving_duration(src, dst)
+                .is_some_and(|duration| duration as u64 <= config.max_duration_secs),
+        };
+        let workers = self.concurrent_lookups().min(dsts.len());
+        // Without the network feature there may not be threads to use
+        if workers <= 1 || cfg!(not(feature = "network")) {
+            return dsts.iter().any(in_range);
+        }
+        let next = AtomicUsize::new(0);
+        let found = AtomicBool::new(false);
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| {
+                    while !found.load(Relaxed) {
+                        let Some(dst) = dsts.get(next.fetch_add(1, Relaxed)) else {
+                            break;
+                        };
+                        if in_range(dst) {
+                            found.store(true, Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+        found.into_inner()
+    }
+}
+
+/// `(latitude, longitude, charger id)`, with the coordinates as bits: the
+/// same grid point always has exactly the same coordinates.
+type Pair = (u64, u64, u64);
+
+/// A `RouteProvider` that remembers every distance and time it has looked
+/// up, so that searching the same grid again, e.g. for a shorter range, only
+/// asks `routes` about pairs of points and chargers it hasn't seen yet.
+/// Everything is kept in memory until it's dropped. Failed lookups aren't
+/// remembered, so they're tried again the next time.
+pub struct MemoizedRoutes<'a> {
+    routes: &'a dyn RouteProvider,
+    distances: Mutex<HashMap<Pair, Option<f64>>>,
+    durations: Mutex<HashMap<Pair, Option<f64>>>,
+}
+
+impl<'a> MemoizedRoutes<'a> {
+    pub fn new(routes: &'a dyn RouteProvider) -> MemoizedRoutes<'a> {
+        MemoizedRoutes {
+            routes,
+            distances: Mutex::new(HashMap::new()),
+            durations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Number of distances and times remembered.
+    pub fn len(&self) -> usize {
+        self.distances.lock().unwrap().len() + self.durations.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn pair(src: &TrialPoint, dst: &ChargerLocation) -> Pair {
+    (src.latitude.to_bits(), src.longitude.to_bits(), dst.id())
+}
+
+/// The values of `memo` for `src` and each of `dsts`, looking up the ones
+/// it doesn't have yet all at once with `lookup`. Only routes and answers
+/// that there is no route are remembered, not errors.
+fn memoized(
+    memo: &Mutex<HashMap<Pair, Option<f64>>>,
+    src: &TrialPoint,
+    dsts: &[ChargerLocation],
+    lookup: impl FnOnce(&[ChargerLocation]) -> Vec<RouteLookup>,
+) -> Vec<RouteLookup> {
+    let known: Vec<_> = {
+        let memo = memo.lock().unwrap();
+        dsts.iter()
+            .map(|dst| {
+                memo.get(&pair(src, dst))
+                    .map(|length| length.map_or(RouteLookup::NoRoute, RouteLookup::Reachable))
+            })
+            .collect()
+    };
+    let missing: Vec<_> = dsts
+        .iter()
+        .zip(&known)
+        .filter(|(_, known)| known.is_none())
+        .map(|(dst, _)| dst.clone())
+        .collect();
+    if missing.is_empty() {
+        return known.into_iter().flatten().collect();
+    }
+    let mut looked_up = lookup(&missing).into_iter();
+    let mut memo = memo.lock().unwrap();
+    dsts.iter()
+        .zip(known)
+        .map(|(dst, known)| {
+            known.unwrap_or_else(|| {
+                let lookup = looked_up.next().unwrap_or(RouteLookup::Error);
+                if lookup != RouteLookup::Error {
+                    memo.insert(pair(src, dst), lookup.length());
+                }
+                lookup
+            })
+        })
+        .collect()
+}
+
+impl RouteProvider for MemoizedRoutes<'_> {
+    fn driving_distance(&self, src: &TrialPoint, dst: &ChargerLocation) -> Option<f64> {
+        self.driving_distances(src, std::slice::from_ref(dst))
+            .pop()
+            .flatten()
+    }
+
+    fn driving_duration(&self, src: &TrialPoint, dst: &ChargerLocation) -> Option<f64> {
+        self.driving_durations(src, std::slice::from_ref(dst))
+            .pop()
+            .flatten()
+    }
+
+    fn driving_distances(&self, src: &TrialPoint, dsts: &[ChargerLocation]) -> Vec<Option<f64>> {
+        self.driving_distance_lookups(src, dsts)
+            .into_iter()
+            .map(RouteLookup::length)
+            .collect()
+    }
+
+    fn driving_durations(&self, src: &TrialPoint, dsts: &[ChargerLocation]) -> Vec<Option<f64>> {
+        self.driving_duration_lookups(src, dsts)
+            .into_iter()
+            .map(RouteLookup::length)
+            .collect()
+    }
+
+    fn driving_distance_lookups(
+        &self,
+        src: &TrialPoint,
+        dsts: &[ChargerLocation],
+    ) -> Vec<RouteLookup> {
+        memoized(&self.distances, src, dsts, |dsts| {
+            self.routes.driving_distance_lookups(src, dsts)
+        })
+    }
+
+    fn driving_duration_lookups(
+        &self,
+        src: &TrialPoint,
+        dsts: &[ChargerLocation],
+    ) -> Vec<RouteLookup> {
+        memoized(&self.durations, src, dsts, |dsts| {
+            self.routes.driving_duration_lookups(src, dsts)
+        })
+    }
+
+    fn max_candidates(&self) -> usize {
+        self.routes.max_candidates()
+    }
+
+    fn concurrent_lookups(&self) -> usize {
+        self.routes.concurrent_lookups()
+    }
+
+    /// Looks up every one of `dsts` rather than stopping at the first in
+    /// range, since a shorter range may need the others.
+    fn any_in_range(
+        &self,
+        src: &TrialPoint,
+        dsts: &[ChargerLocation],
+        config: &RangeConfig,
+    ) -> bool {
+        match config.mode {
+            RangeMode::Distance => self
+                .driving_distances(src, dsts)
+                .into_iter()
+                .flatten()
+                .any(|distance| distance as u64 <= config.max_range_meters),
+            RangeMode::Duration => self
+                .driving_durations(src, dsts)
+                .into_iter()
+                .flatten()
+                .any(|duration| duration as u64 <= config.max_duration_secs),
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! The routing engines that can be used over HTTP.
+
+use std::sync::Arc;
+
+use reqwest::blocking::Client;
+use serde::Deserialize;
+
+use super::{RouteLookup, RouteProvider};
+use crate::rate_limit::RateLimiter;
+use crate::{
+    request_json, ChargerLocation, OsrmClient, RangeConfig, RangeMode, RetryPolicy, TrialPoint,
+};
+
+impl RouteProvider for OsrmClient {
+    fn driving_distance(&self, src: &TrialPoint, dst: &ChargerLocation) -> Option<f64> {
+        src.get_osrm_distance(self, dst).length()
+    }
+
+    fn driving_duration(&self, src: &TrialPoint, dst: &ChargerLocation) -> Option<f64> {
+        src.get_osrm_table_durations(self, std::slice::from_ref(dst))
+            .pop()
+            .and_then(RouteLookup::length)
+    }
+
+    fn driving_distances(&self, src: &TrialPoint, dsts: &[ChargerLocation]) -> Vec<Option<f64>> {
+        self.driving_distance_lookups(src, dsts)
+            .into_iter()
+            .map(RouteLookup::length)
+            .collect()
+    }
+
+    fn driving_durations(&self, src: &TrialPoint, dsts: &[ChargerLocation]) -> Vec<Option<f64>> {
+        self.driving_duration_lookups(src, dsts)
+            .into_iter()
+            .map(RouteLookup::length)
+            .collect()
+    }
+
+    fn driving_distance_lookups(
+        &self,
+        src: &TrialPoint,
+        dsts: &[ChargerLocation],
+    ) -> Vec<RouteLookup> {
+        src.get_osrm_table_distances(self, dsts)
+    }
+
+    fn driving_duration_lookups(
+        &self,
+        src: &TrialPoint,
+        dsts: &[ChargerLocation],
+    ) -> Vec<RouteLookup> {
+        src.get_osrm_table_durations(self, dsts)
+    }
+
+    fn max_candidates(&self) -> usize {
+        self.max_candidates
+    }
+
+    /// All of the candidates go in a single table request, so there is
+    /// nothing to do concurrently.
+    fn any_in_range(
+        &self,
+        src: &TrialPoint,
+        dsts: &[ChargerLocation],
+        config: &RangeConfig,
+    ) -> bool {
+        match config.mode {
+            RangeMode::Distance => self
+                .driving_distances(src, dsts)
+                .into_iter()
+                .flatten()
+                .any(|distance| distance as u64 <= config.max_range_meters),
+            RangeMode::Duration => self
+                .driving_durations(src, dsts)
+                .into_iter()
+                .flatten()
+                .any(|duration| duration as u64 <= config.max_duration_secs),
+        }
+    }
+}
+
+/// Client for a Valhalla server's route service.
+///
+/// Valhalla reports lengths in kilometers (we ask for them explicitly, since
+/// the units can be configured) and times in seconds; distances are converted
+/// to meters like everywhere else.
+#[derive(Clone, Debug)]
+pub struct ValhallaClient {
+    /// Base url of the Valhalla server, without a trailing slash.
+    pub url: String,
+    pub client: Client,
+    /// How failed requests are retried.
+    pub retry: RetryPolicy,
+    /// Maximum number of closest chargers to look up per point. Each one is a
+    /// separate request.
+    pub max_candidates: usize,
+    /// Number of those requests made at the same time.
+    pub concurrent_lookups: usize,
+    /// Limit on the rate of requests to the server, if any.
+    pub rate_limit: Option<Arc<RateLimiter>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ValhallaJson {
+    trip: ValhallaTrip,
+}
+
+#[derive(Deserialize, Debug)]
+struct ValhallaTrip {
+    summary: ValhallaSummary,
+}
+
+#[derive(Deserialize, Debug)]
+struct ValhallaSummary {
+    /// Kilometers
+    length: f64,
+    /// Seconds
+    time: f64,
+}
+
+impl ValhallaClient {
+    fn summary(&self, src: &TrialPoint, dst: &ChargerLocation) -> Option<ValhallaSummary> {
+        let body = serde_json::json!({
+            "locations": [
+                {"lat": src.latitude, "lon": src.longitude},
+                {"lat": dst.latitude, "lon": dst.longitude},
+            ],
+            "costing": "auto",
+            "units": "kilometers",
+        });
+        let url = format!("{}/route", self.url);
+        let json: ValhallaJson = request_json(
+            || self.client.post(&url).json(&body),
+            &self.retry,
+            self.rate_limit.as_deref(),
+        )?;
+        Some(json.trip.summary)
+    }
+}
+
+impl RouteProvider for ValhallaClient {
+    fn driving_distance(&self, src: &TrialPoint, dst: &ChargerLocation) -> Option<f64> {
+        Some(self.summary(src, dst)?.length * 1000.0)
+    }
+
+    fn driving_duration(&self, src: &TrialPoint, dst: &ChargerLocation) -> Option<f64> {
+        Some(self.summary(src, dst)?.time)
+    }
+
+    fn max_candidates(&self) -> usize {
+        self.max_candidates
+    }
+
+    fn concurrent_lookups(&self) -> usize {
+        self.concurrent_lookups
+    }
+}
+
+/// Client for a GraphHopper server's route service.
+///
+/// GraphHopper reports distances in meters, but times in milliseconds, which
+/// are converted to seconds.
+#[derive(Clone, Debug)]
+pub struct GraphHopperClient {
+    /// Base url of the GraphHopper server, without a trailing slash.
+    pub url: String,
+    pub client: Client,
+    /// How failed requests are retried.
+    pub retry: RetryPolicy,
+    /// Maximum number of closest chargers to look up per point. Each one is a
+    /// separate request.
+    pub max_candidates: usize,
+    /// Number of those requests made at the same time.
+    pub concurrent_lookups: usize,
+    /// Limit on the rate of requests to the server, if any.
+    pub rate_limit: Option<Arc<RateLimiter>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GraphHopperJson {
+    paths: Vec<GraphHopperPath>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GraphHopperPath {
+    /// Meters
+    distance: f64,
+    /// Milliseconds
+    time: f64,
+}
+
+impl GraphHopperClient {
+    fn path(&self, src: &TrialPoint, dst: &ChargerLocation) -> Option<GraphHopperPath> {
+        // Unlike OSRM, GraphHopper points are latitude first
+        let url = format!(
+            "{}/route?point={},{}&point={},{}&profile=car&calc_points=false",
+            self.url, src.latitude, src.longitude, dst.latitude, dst.longitude
+        );
+        let json: GraphHopperJson = request_json(
+            || self.client.get(&url),
+            &self.retry,
+            self.rate_limit.as_deref(),
+        )?;
+        json.paths.into_iter().next()
+    }
+}
+
+impl RouteProvider for GraphHopperClient {
+    fn driving_distance(&self, src: &TrialPoint, dst: &ChargerLocation) -> Option<f64> {
+        Some(self.path(src, dst)?.distance)
+    }
+
+    fn driving_duration(&self, src: &TrialPoint, dst: &ChargerLocation) -> Option<f64> {
+        Some(self.path(src, dst)?.time / 1000.0)
+    }
+
+    fn max_candidates(&self) -> usize {
+        self.max_candidates
+    }
+
+    fn concurrent_lookups(&self) -> usize {
+        self.concurrent_lookups
+    }
+}
@@ -0,0 +1,151 @@
+//! Shapefile output: one polygon record per chunk with gaps, with its
+//! attributes in the dBase table alongside, and the chargers as points.
+
+use std::{error::Error, path::Path};
+
+use chrono::Datelike;
+use shapefile::dbase;
+
+use crate::{atomic::write_atomically, AllChargerLocations, ChunkGap};
+
+/// Longest network name kept in the dBase table, in bytes.
+const NETWORK_FIELD_LENGTH: u8 = 80;
+/// Longest station id kept in the dBase table, in bytes.
+const STATION_ID_FIELD_LENGTH: u8 = 40;
+
+/// Files written along with the `.shp`.
+const SIDECARS: &[&str] = &["shx", "dbf"];
+
+/// Writes `gaps` to the shapefile at `path` (plus the `.shx` and `.dbf`
+/// files next to it), one record per chunk. Each record has `has_charger`
+/// (always false), `chunk_id`, `point_count` and `area_km2` attributes, and
+/// `priority` if the gaps have a priority score.
+pub fn write_gaps(path: &str, gaps: &[ChunkGap]) -> Result<(), Box<dyn Error>> {
+    let mut table_info = dbase::TableWriterBuilder::new()
+        .add_logical_field(dbase::FieldName::try_from("has_charger").unwrap())
+        .add_integer_field(dbase::FieldName::try_from("chunk_id").unwrap())
+        .add_integer_field(dbase::FieldName::try_from("point_count").unwrap())
+        .add_double_field(dbase::FieldName::try_from("area_km2").unwrap());
+    // dBase field names can't be longer than 11 bytes, so this is the
+    // priority score
+    let with_priority = gaps.iter().any(|gap| gap.priority_score.is_some());
+    if with_priority {
+        table_info = table_info.add_double_field(dbase::FieldName::try_from("priority").unwrap());
+    }
+    write_atomically(Path::new(path), SIDECARS, |partial| {
+        write_gap_records(partial, table_info, gaps, with_priority)
+    })
+}
+
+fn write_gap_records(
+    path: &Path,
+    table_info: dbase::TableWriterBuilder,
+    gaps: &[ChunkGap],
+    with_priority: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = shapefile::Writer::from_path(path, table_info)?;
+    for gap in gaps {
+        let mut record = dbase::Record::default();
+        record.insert(
+            "has_charger".to_owned(),
+            dbase::FieldValue::Logical(Some(false)),
+        );
+        record.insert(
+            "chunk_id".to_owned(),
+            dbase::FieldValue::Integer(gap.chunk_id as i32),
+        );
+        record.insert(
+            "point_count".to_owned(),
+            dbase::FieldValue::Integer(gap.point_count as i32),
+        );
+        record.insert(
+            "area_km2".to_owned(),
+            dbase::FieldValue::Double(gap.area_km2),
+        );
+        if with_priority {
+            record.insert(
+                "priority".to_owned(),
+                dbase::FieldValue::Double(gap.priority_score.unwrap_or_default()),
+            );
+        }
+        writer.write_shape_and_record(&shapefile::Polygon::from(gap.polygons.clone()), &record)?;
+    }
+    Ok(())
+}
+
+/// Writes every charger in `chargers` to the shapefile at `path` as a point,
+/// in id order. Each record has `id` (the station id, as text), `network`,
+/// `level2`, `dc_fast` and `opened` attributes; station ids longer than 40
+/// bytes and network names longer than 80 bytes are cut short.
+pub fn write_chargers(path: &str, chargers: &AllChargerLocations) -> Result<(), Box<dyn Error>> {
+    let table_info = dbase::TableWriterBuilder::new()
+        .add_character_field(
+            dbase::FieldName::try_from("id").unwrap(),
+            STATION_ID_FIELD_LENGTH,
+        )
+        .add_character_field(
+            dbase::FieldName::try_from("network").unwrap(),
+            NETWORK_FIELD_LENGTH,
+        )
+        .add_logical_field(dbase::FieldName::try_from("level2").unwrap())
+        .add_logical_field(dbase::FieldName::try_from("dc_fast").unwrap())
+        .add_date_field(dbase::FieldName::try_from("opened").unwrap());
+    write_atomically(Path::new(path), SIDECARS, |partial| {
+        write_charger_records(partial, table_info, chargers)
+    })
+}
+
+fn write_charger_records(
+    path: &Path,
+    table_info: dbase::TableWriterBuilder,
+    chargers: &AllChargerLocations,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = shapefile::Writer::from_path(path, table_info)?;
+    let mut ids: Vec<_> = chargers.chargers_by_id.keys().copied().collect();
+    ids.sort_unstable();
+    for id in ids {
+        let charger = &chargers.chargers_by_id[&id];
+        let mut record = dbase::Record::default();
+        record.insert(
+            "id".to_owned(),
+            dbase::FieldValue::Character(Some(
+                truncated(charger.station_id(), STATION_ID_FIELD_LENGTH).to_owned(),
+            )),
+        );
+        record.insert(
+            "network".to_owned(),
+            dbase::FieldValue::Character(Some(
+                truncated(charger.network(), NETWORK_FIELD_LENGTH).to_owned(),
+            )),
+        );
+        record.insert(
+            "level2".to_owned(),
+            dbase::FieldValue::Logical(Some(charger.levels().level2)),
+        );
+        record.insert(
+            "dc_fast".to_owned(),
+            dbase::FieldValue::Logical(Some(charger.levels().dc_fast)),
+        );
+        record.insert(
+            "opened".to_owned(),
+            dbase::FieldValue::Date(
+                charger
+                    .open_date()
+                    .map(|date| dbase::Date::new(date.day(), date.month(), date.year() as u32)),
+            ),
+        );
+        let point = shapefile::Point::new(charger.longitude(), charger.latitude());
+        writer.write_shape_and_record(&point, &record)?;
+    }
+    Ok(())
+}
+
+/// The start of `text` that fits in a field of `length` bytes, without
+/// splitting a character.
+fn truncated(text: &str, length: u8) -> &str {
+    let mut end = text.len().min(length as usize);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
@@ -0,0 +1,242 @@
+//! Keeping unreachable points in a temporary file instead of in memory, for
+//! runs so large that they wouldn't fit (`--spill-dir`).
+//!
+//! Points are written as pairs of little-endian `f64`s (x, y), in grid
+//! order. Once a chunk is searched, its hulls are drawn from the file a batch
+//! of rows at a time (see `spilled_hulls`), and the file is removed, so
+//! neither the search nor the report ever holds all of the points.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use geo::MultiPolygon;
+use log::debug;
+
+use crate::{gap_hulls, HullConfig};
+
+/// Default number of unreachable points a chunk keeps in memory before they
+/// are written out to its spill file. At 16 bytes a point this is 16 MB per
+/// chunk being searched.
+pub const DEFAULT_SPILL_THRESHOLD: usize = 1_000_000;
+
+/// Where and when to spill points to disk.
+#[derive(Clone, Debug)]
+pub struct SpillConfig {
+    /// Directory for the spill files, which are removed once the hulls are
+    /// drawn. It has to exist already.
+    pub dir: PathBuf,
+    /// Points are written out whenever this many have built up in memory,
+    /// and read back this many (rounded up to whole grid rows) at a time.
+    /// Chunks with fewer unreachable points than this never touch the disk.
+    pub threshold: usize,
+}
+
+impl SpillConfig {
+    pub fn new(dir: impl Into<PathBuf>) -> SpillConfig {
+        SpillConfig {
+            dir: dir.into(),
+            threshold: DEFAULT_SPILL_THRESHOLD,
+        }
+    }
+}
+
+/// Tells spill files of the same process apart.
+static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+/// A list of points that moves to disk once it grows past the threshold of
+/// its `SpillConfig`. Without one, it's just a `Vec`.
+pub struct PointBuffer {
+    points: Vec<geo::Point<f64>>,
+    config: Option<SpillConfig>,
+    file: Option<(SpillFile, BufWriter<File>)>,
+}
+
+/// The points of a `PointBuffer` once they're all known.
+pub enum Points {
+    /// There were too few to spill.
+    InMemory(Vec<geo::Point<f64>>),
+    /// All of them are in this file.
+    Spilled(SpillFile),
+}
+
+impl PointBuffer {
+    pub fn new(config: Option<SpillConfig>) -> PointBuffer {
+        PointBuffer {
+            points: Vec::new(),
+            config,
+            file: None,
+        }
+    }
+
+    pub fn push(&mut self, point: geo::Point<f64>) -> io::Result<()> {
+        self.points.push(point);
+        match &self.config {
+            Some(config) if self.points.len() >= config.threshold.max(1) => self.spill(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Number of points pushed so far.
+    pub fn len(&self) -> usize {
+        self.spilled() + self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of points that have been written to disk.
+    pub fn spilled(&self) -> usize {
+        self.file.as_ref().map_or(0, |(file, _)| file.len)
+    }
+
+    /// All of the points, in the order they were pushed: in memory if none
+    /// were spilled, otherwise all in the spill file.
+    pub fn finish(mut self) -> io::Result<Points> {
+        if self.file.is_none() {
+            return Ok(Points::InMemory(self.points));
+        }
+        self.spill()?;
+        let (file, writer) = self.file.take().unwrap();
+        writer.into_inner().map_err(|error| error.into_error())?;
+        Ok(Points::Spilled(file))
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        if self.file.is_none() {
+            let dir = &self.config.as_ref().expect("spilling without a config").dir;
+            let path = dir.join(format!(
+                "ev-charging-gaps-{}-{}.points",
+                process::id(),
+                NEXT_FILE.fetch_add(1, Ordering::Relaxed)
+            ));
+            debug!("spilling unreachable points to {}", path.display());
+            let writer = BufWriter::new(File::create(&path)?);
+            self.file = Some((SpillFile { path, len: 0 }, writer));
+        }
+        let (file, writer) = self.file.as_mut().unwrap();
+        for point in self.points.drain(..) {
+            writer.write_all(&point.x().to_le_bytes())?;
+            writer.write_all(&point.y().to_le_bytes())?;
+            file.len += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Points written to disk by a `PointBuffer`. The file is removed when this
+/// is dropped.
+#[derive(Debug)]
+pub struct SpillFile {
+    path: PathBuf,
+    len: usize,
+}
+
+impl SpillFile {
+    /// Number of points in the file.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the points back in batches of at least `size` points, in the
+    /// order they were written. A batch only ends where the latitude (y)
+    /// changes, so that with points in grid order every batch is whole rows
+    /// of the grid.
+    pub fn batches(&self, size: usize) -> io::Result<Batches> {
+        Ok(Batches {
+            reader: BufReader::new(File::open(&self.path)?),
+            left: self.len,
+            size: size.max(1),
+            next: None,
+        })
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Iterator over the batches of a `SpillFile`, see `SpillFile::batches`.
+pub struct Batches {
+    reader: BufReader<File>,
+    left: usize,
+    size: usize,
+    /// The first point of the next batch, read to find where this one ends.
+    next: Option<geo::Point<f64>>,
+}
+
+impl Batches {
+    fn read_point(&mut self) -> io::Result<Option<geo::Point<f64>>> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        let mut bytes = [0; 16];
+        self.reader.read_exact(&mut bytes)?;
+        self.left -= 1;
+        let (x, y) = bytes.split_at(8);
+        Ok(Some(geo::Point::new(
+            f64::from_le_bytes(x.try_into().unwrap()),
+            f64::from_le_bytes(y.try_into().unwrap()),
+        )))
+    }
+
+    fn read_batch(&mut self) -> io::Result<Vec<geo::Point<f64>>> {
+        let mut batch: Vec<_> = self.next.take().into_iter().collect();
+        while let Some(point) = self.read_point()? {
+            let row_ended = batch.last().is_some_and(|last| last.y() != point.y());
+            if batch.len() >= self.size && row_ended {
+                self.next = Some(point);
+                break;
+            }
+            batch.push(point);
+        }
+        Ok(batch)
+    }
+}
+
+impl Iterator for Batches {
+    type Item = io::Result<Vec<geo::Point<f64>>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_batch() {
+            Ok(batch) if batch.is_empty() => None,
+            result => {
+                if result.is_err() {
+                    self.left = 0;
+                }
+                Some(result)
+            }
+        }
+    }
+}
+
+/// The hulls around the points in `file`, drawn like `gap_hulls` but a
+/// batch of `batch_size` points at a time, so that only one batch is in
+/// memory. A gap that spans batches gets a hull in each of them, cut off
+/// between two rows of the grid, the same as gaps that span chunks.
+pub fn spilled_hulls(
+    file: &SpillFile,
+    hull: &HullConfig,
+    batch_size: usize,
+) -> io::Result<MultiPolygon<f64>> {
+    let mut polygons = Vec::new();
+    for batch in file.batches(batch_size)? {
+        polygons.extend(gap_hulls(&batch?, hull));
+    }
+    Ok(MultiPolygon(polygons))
+}
@@ -1,4 +1,45 @@
 use super::*;
+use geo::algorithm::area::Area;
+use nrel_cache::NrelCache;
+use osrm_cache::OsrmCache;
+use routing::{GraphHopperClient, MemoizedRoutes, ValhallaClient, CONCURRENT_LOOKUPS};
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+/// A charger with no particular network or charging level.
+fn charger(latitude: f64, longitude: f64, id: u64) -> ChargerLocation {
+    ChargerLocation {
+        latitude,
+        longitude,
+        id,
+        station_id: id.to_string(),
+        network: "".into(),
+        levels: ChargingLevels::default(),
+        open_date: None,
+        public: true,
+    }
+}
+
+#[test]
+fn points_display_as_lat_lon() {
+    let point = TrialPoint {
+        latitude: 40.5,
+        longitude: -100.25,
+    };
+    assert_eq!(point.to_string(), "40.5, -100.25");
+    assert_eq!(point.to_geo_point(), geo::Point::new(-100.25, 40.5));
+
+    let networked = ChargerLocation {
+        network: "Electrify America".into(),
+        ..charger(40.5, -100.25, 7)
+    };
+    assert_eq!(
+        networked.to_string(),
+        "40.5, -100.25 (id 7, Electrify America)"
+    );
+    assert_eq!(networked.to_geo_point(), point.to_geo_point());
+    assert_eq!(charger(1.0, 2.0, 3).to_string(), "1, 2 (id 3)");
+}
 
 #[test]
 fn distance_ny_to_la() {
@@ -6,25 +47,149 @@ fn distance_ny_to_la() {
         latitude: 40.730610,
         longitude: -73.935242,
     };
-    let la = ChargerLocation {
-        latitude: 34.052235,
-        longitude: -118.243683,
-        id: 1,
-        // network: "Electrify America".to_string(),
-    };
+    let la = charger(34.052235, -118.243683, 1);
     let distance = ny.distance_to(&la);
-    let error = (3950_000. - distance).abs();
+    let error = (3_950_000. - distance).abs();
     // Assert that we're within 50km as a sanity check
     assert!(error < 50_000.);
 }
+
+#[test]
+fn from_chargers_builds_an_index_without_a_csv() {
+    let chargers = AllChargerLocations::from_chargers([
+        ChargerLocation::new("ny-1", 40.7, -74.0).with_network("EVgo Network"),
+        ChargerLocation::new("la-1", 34.1, -118.2)
+            .with_levels(ChargingLevels {
+                level2: false,
+                dc_fast: true,
+            })
+            .with_open_date(NaiveDate::from_ymd_opt(2020, 1, 1).unwrap())
+            .with_public(false),
+        // Replaces the first one
+        ChargerLocation::new("ny-1", 40.8, -73.9),
+    ]);
+    assert_eq!(chargers.chargers_by_id.len(), 2);
+    assert_eq!(chargers.index.size(), 2);
+    let la = chargers.by_station_id("la-1").unwrap();
+    assert!(la.levels().dc_fast && !la.is_public());
+    assert_eq!(la.open_date(), NaiveDate::from_ymd_opt(2020, 1, 1));
+
+    let point = TrialPoint {
+        latitude: 40.75,
+        longitude: -73.95,
+    };
+    let nearest = point.nearest_chargers(&chargers, 50_000, DistanceMetric::Haversine);
+    assert_eq!(nearest.len(), 1);
+    assert_eq!(nearest[0].0.station_id(), "ny-1");
+    assert_eq!(nearest[0].0.latitude(), 40.8);
+    assert_eq!(nearest[0].0.network(), "");
+}
+
+#[test]
+fn geodesic_distance_close_to_haversine() {
+    let ny = TrialPoint {
+        latitude: 40.730610,
+        longitude: -73.935242,
+    };
+    let la = charger(34.052235, -118.243683, 1);
+    let haversine = ny.distance_to_with(&la, DistanceMetric::Haversine);
+    let geodesic = ny.distance_to_with(&la, DistanceMetric::Geodesic);
+    assert_eq!(haversine, ny.distance_to(&la));
+    assert_ne!(haversine, geodesic);
+    // The two agree to within the earth's flattening
+    assert!((haversine - geodesic).abs() / geodesic < 0.005);
+    assert_eq!("Geodesic".parse(), Ok(DistanceMetric::Geodesic));
+    assert!("manhattan".parse::<DistanceMetric>().is_err());
+}
+/// A few dozen real-looking chargers around the northeastern US, from a few
+/// km to a few hundred km from New York, so some are just inside and some
+/// just outside of `MAX_RANGE_METERS`.
+fn fixture_chargers() -> AllChargerLocations {
+    read_from_file("testdata/chargers_northeast.csv", &ChargerFilter::default()).unwrap()
+}
+
 #[test]
 fn quadtree_include_relevant_points() {
-    // Test that if we call our nearest_chargers function, test
-    // that everything we get back is in our list of real chargers
-    // If there is an error, we just want the test to fail
+    for backend in [IndexBackend::Envelope, IndexBackend::Sphere] {
+        let charger_locations = AllChargerLocations::with_backend(
+            fixture_chargers().chargers_by_id.into_values(),
+            backend,
+        );
+        assert_eq!(charger_locations.backend(), backend);
+        let in_range = check_nearest_chargers_near_ny(&charger_locations);
+        assert!(in_range > 0);
+        assert!(in_range < charger_locations.chargers_by_id.len());
+    }
+}
+
+#[test]
+fn index_backends_find_the_same_nearest_chargers() {
+    let envelope = fixture_chargers();
+    let sphere = AllChargerLocations::with_backend(
+        envelope.chargers_by_id.values().cloned(),
+        IndexBackend::Sphere,
+    );
+    let ids = |nearest: Vec<(ChargerLocation, u64)>| -> Vec<_> {
+        nearest
+            .iter()
+            .map(|(charger, distance)| (*distance, charger.id))
+            .collect()
+    };
+    for (latitude, longitude) in [(40.73, -73.94), (42.36, -71.06), (44.0, -69.0)] {
+        let point = TrialPoint {
+            latitude,
+            longitude,
+        };
+        for metric in [DistanceMetric::Haversine, DistanceMetric::Geodesic] {
+            for range in [50_000, MAX_RANGE_METERS] {
+                // The sphere index doesn't have the corners of the box, but
+                // has everything in range
+                let in_range = |nearest: Vec<(ChargerLocation, u64)>| -> Vec<_> {
+                    let mut nearest = ids(nearest);
+                    nearest.retain(|(distance, _)| *distance <= range);
+                    nearest.sort_unstable();
+                    nearest
+                };
+                assert_eq!(
+                    in_range(point.nearest_chargers(&sphere, range, metric)),
+                    in_range(point.nearest_chargers(&envelope, range, metric))
+                );
+                for k in [1, 5] {
+                    let distances = |nearest: Vec<(ChargerLocation, u64)>| -> Vec<_> {
+                        nearest.iter().map(|(_, distance)| *distance).collect()
+                    };
+                    assert_eq!(
+                        distances(point.nearest_chargers_k(&sphere, range, metric, k)),
+                        distances(point.nearest_chargers_k(&envelope, range, metric, k))
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Same as `quadtree_include_relevant_points`, with every charger in the
+/// country. Needs `NREL_API_KEY` and network access, so run it with
+/// `cargo test -- --ignored`.
+#[test]
+#[ignore]
+fn quadtree_include_relevant_points_nrel() {
     let nrel_api_key =
         std::env::var("NREL_API_KEY").expect("NREL_API_KEY environment variable is not set");
-    let charger_locations = download_source_data(&nrel_api_key).unwrap();
+    let charger_locations = download_source_data(
+        &nrel_api_key,
+        &NrelQuery::default(),
+        &ChargerFilter::default(),
+        None,
+    )
+    .unwrap();
+    check_nearest_chargers_near_ny(&charger_locations);
+}
+
+/// Checks that `nearest_chargers` finds every charger in `charger_locations`
+/// within `MAX_RANGE_METERS` of New York, sorted by distance, by comparing it
+/// with a search through all of them. Returns how many there were.
+fn check_nearest_chargers_near_ny(charger_locations: &AllChargerLocations) -> usize {
     let ny = TrialPoint {
         latitude: 40.730610,
         longitude: -73.935242,
@@ -38,7 +203,11 @@ fn quadtree_include_relevant_points() {
         }
     }
     slow_check.sort_by_key(|(_, distance)| *distance);
-    let test_chargers = ny.nearest_chargers(&charger_locations);
+    let test_chargers = ny.nearest_chargers(
+        charger_locations,
+        MAX_RANGE_METERS,
+        DistanceMetric::Haversine,
+    );
     assert!(
         test_chargers.len() >= slow_check.len(),
         "nearest chargers must be at least as long as the expected nearest chargers"
@@ -49,24 +218,59 @@ fn quadtree_include_relevant_points() {
         test_chargers.len()
     );
     for (i, expected) in slow_check.iter().enumerate() {
-        assert_eq!(expected.1, (&test_chargers[i]).1);
+        assert_eq!(expected.1, test_chargers[i].1);
     }
+    slow_check.len()
 }
 
 #[test]
-fn osrm_api_works() {
+fn nearest_chargers_sorted_by_distance() {
+    // A few meters apart, which is only a few f32 steps at this longitude
+    let chargers = chargers_at(&[(40.0, -120.000_2), (40.0, -120.000_05), (40.0, -119.999_9)]);
+    let point = TrialPoint {
+        latitude: 40.0,
+        longitude: -120.0,
+    };
+    let nearest = point.nearest_chargers(&chargers, 1_000, DistanceMetric::Haversine);
+    let ids: Vec<_> = nearest.iter().map(|(charger, _)| charger.id).collect();
+    let distances: Vec<_> = nearest.iter().map(|(_, distance)| *distance).collect();
+    assert_eq!(ids, [1, 2, 0]);
+    assert_eq!(distances, [4, 8, 17]);
+    assert_eq!(chargers.index.size(), chargers.chargers_by_id.len());
+}
+
+#[test]
+fn nearest_chargers_k_is_start_of_nearest_chargers() {
+    let chargers = fixture_chargers();
     let ny = TrialPoint {
         latitude: 40.730610,
         longitude: -73.935242,
     };
-    let test_atlanta_charger = ChargerLocation {
-        latitude: 33.75,
-        longitude: -84.4,
-        id: 666,
+    let all = ny.nearest_chargers(&chargers, MAX_RANGE_METERS, DistanceMetric::Haversine);
+    let distances = |nearest: &[(ChargerLocation, u64)]| -> Vec<u64> {
+        nearest.iter().map(|(_, distance)| *distance).collect()
+    };
+    for k in [0, 1, 2, 5, all.len(), all.len() + 10] {
+        let nearest =
+            ny.nearest_chargers_k(&chargers, MAX_RANGE_METERS, DistanceMetric::Haversine, k);
+        assert_eq!(nearest.len(), k.min(all.len()));
+        assert_eq!(distances(&nearest), distances(&all[..nearest.len()]));
+        for (charger, distance) in &nearest {
+            assert_eq!(ny.distance_to(charger) as u64, *distance);
+        }
+    }
+}
+
+#[test]
+fn osrm_api_works() {
+    let ny = TrialPoint {
+        latitude: 40.730610,
+        longitude: -73.935242,
     };
-    let client = Client::new();
-    let distance = ny.get_osrm_distance(DEFAULT_OSRM_URL, &client, &test_atlanta_charger);
-    println!("distance: {:?}", distance);
+    let test_atlanta_charger = charger(33.75, -84.4, 666);
+    let osrm = OsrmClient::new(&serve_osrm(OSRM_ROUTE_JSON));
+    let distance = ny.get_osrm_distance(&osrm, &test_atlanta_charger);
+    assert_eq!(distance, RouteLookup::Reachable(1387012.4));
 }
 
 const US_BOUNDING_BOX: BoundingBox = BoundingBox {
@@ -97,23 +301,51 @@ fn chunkify_correct_number_of_chunks() {
 }
 
 #[test]
-fn chunkify_correct_width() {
+fn check_grid_points_limits_the_grid_size() {
+    let points = US_BOUNDING_BOX.grid_point_count(0.01);
+    assert_eq!(points, US_BOUNDING_BOX.generate_grid(0.01).len() as u64);
+    assert_eq!(US_BOUNDING_BOX.check_grid_points(0.01, points), Ok(points));
+    assert!(US_BOUNDING_BOX.check_grid_points(0.01, points - 1).is_err());
+    // Far too fine for the default limit, without making the grid
+    assert!(US_BOUNDING_BOX
+        .check_grid_points(0.0001, MAX_GRID_POINTS)
+        .is_err());
+}
+
+#[test]
+fn check_grid_points_allows_coarse_grids() {
+    // Only warned about
+    assert_eq!(
+        US_BOUNDING_BOX.check_grid_points(10.0, MAX_GRID_POINTS),
+        Ok(2 * 5)
+    );
+    let points = US_BOUNDING_BOX.check_grid_points(20.0, MAX_GRID_POINTS);
+    assert_eq!(points, Ok(2));
+    assert!(points.unwrap() < MIN_GRID_POINTS);
+    // Coarser than the whole box
+    assert!(US_BOUNDING_BOX
+        .check_grid_points(100.0, MAX_GRID_POINTS)
+        .is_err());
+}
+
+#[test]
+fn chunkify_correct_lat_span() {
     for n_chunks in [4, 6, 8, 10, 12] {
-        let width = US_BOUNDING_BOX.width() / (n_chunks / 2) as f64;
+        let lat_span = US_BOUNDING_BOX.lat_span() / (n_chunks / 2) as f64;
         let chunks = US_BOUNDING_BOX.chunkify(n_chunks);
         for chunk in chunks {
-            assert_float_eq!(chunk.width(), width);
+            assert_float_eq!(chunk.lat_span(), lat_span);
         }
     }
 }
 
 #[test]
-fn chunkify_correct_height() {
+fn chunkify_correct_lon_span() {
     for n_chunks in [4, 6, 8, 10, 12] {
-        let height = US_BOUNDING_BOX.height();
+        let lon_span = US_BOUNDING_BOX.lon_span();
         let chunks = US_BOUNDING_BOX.chunkify(n_chunks);
         for chunk in chunks {
-            assert_float_eq!(chunk.height(), height);
+            assert_float_eq!(chunk.lon_span(), lon_span);
         }
     }
 }
@@ -134,3 +366,3923 @@ fn chunks_end_in_correct_places() {
         assert_float_eq!(last.lon_max, US_BOUNDING_BOX.lon_max);
     }
 }
+
+const SQUARE_REGION_GEOJSON: &str = r#"{
+    "type": "FeatureCollection",
+    "features": [{
+        "type": "Feature",
+        "properties": {"name": "square"},
+        "geometry": {
+            "type": "Polygon",
+            "coordinates": [[[-100.0, 30.0], [-98.0, 30.0], [-98.0, 32.0], [-100.0, 32.0], [-100.0, 30.0]]]
+        }
+    }]
+}"#;
+
+#[test]
+fn region_geojson_derives_bounding_box() {
+    let region = geojson::parse_region_geojson(SQUARE_REGION_GEOJSON).unwrap();
+    let bbox = BoundingBox::from_region(&region).unwrap();
+    assert_float_eq!(bbox.lat_min, 30.0);
+    assert_float_eq!(bbox.lat_max, 32.0);
+    assert_float_eq!(bbox.lon_min, -100.0);
+    assert_float_eq!(bbox.lon_max, -98.0);
+}
+
+#[test]
+fn region_geojson_without_polygon_is_an_error() {
+    let point =
+        r#"{"type": "Feature", "geometry": {"type": "Point", "coordinates": [-100.0, 30.0]}}"#;
+    assert!(geojson::parse_region_geojson(point).is_err());
+}
+
+#[test]
+fn wkt_round_trips_gaps() {
+    let square = |x: f64, y: f64, size: f64| {
+        geo::LineString::from(vec![
+            (x, y),
+            (x + size, y),
+            (x + size, y + size),
+            (x, y + size),
+            (x, y),
+        ])
+    };
+    let one = geo::MultiPolygon(vec![geo::Polygon::new(
+        square(-100.125, 30.1, 2.0),
+        vec![square(-99.5, 30.5, 0.3)],
+    )]);
+    let two = geo::MultiPolygon(vec![
+        geo::Polygon::new(square(-90.0, 40.0, 1.0), vec![]),
+        geo::Polygon::new(square(-88.0, 40.0, 1.0 / 3.0), vec![]),
+    ]);
+    assert!(wkt::multi_polygon_wkt(&one).starts_with("POLYGON(("));
+    assert!(wkt::multi_polygon_wkt(&two).starts_with("MULTIPOLYGON((("));
+    let empty = geo::MultiPolygon(vec![]);
+    assert_eq!(wkt::multi_polygon_wkt(&empty), "MULTIPOLYGON EMPTY");
+
+    let path = temp_path("gaps.wkt");
+    let gaps = [(0, one), (3, two), (7, empty)];
+    wkt::write_gaps_wkt(path.to_str().unwrap(), &gaps).unwrap();
+    let contents = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let lines: Vec<_> = contents.lines().collect();
+    assert_eq!(lines.len(), gaps.len());
+    for (line, (chunk_id, polygons)) in lines.iter().zip(&gaps) {
+        let (id, text) = line.split_once('\t').unwrap();
+        assert_eq!(id.parse::<usize>().unwrap(), *chunk_id);
+        assert_eq!(&wkt::parse_polygons_wkt(text).unwrap(), polygons);
+    }
+    assert!(wkt::parse_polygons_wkt("POINT(1 2)").is_err());
+}
+
+#[test]
+fn failed_write_leaves_the_old_output() {
+    let path = temp_path("atomic.txt");
+    std::fs::write(&path, "old output").unwrap();
+    let result = atomic::write_file_atomically(&path, |writer| {
+        writer.write_all(b"half of the new")?;
+        Err("interrupted".into())
+    });
+    assert!(result.is_err());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "old output");
+    let dir = path.parent().unwrap();
+    let leftovers = std::fs::read_dir(dir)
+        .unwrap()
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .contains("atomic-partial")
+        })
+        .count();
+    assert_eq!(leftovers, 0);
+
+    atomic::write_file_atomically(&path, |writer| Ok(writer.write_all(b"new output")?)).unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "new output");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn writing_output_creates_its_directory() {
+    let dir = temp_path("output-dir");
+    let _ = std::fs::remove_dir_all(&dir);
+    let path = dir.join("nested").join("gaps.wkt");
+    wkt::write_gaps_wkt(path.to_str().unwrap(), &[]).unwrap();
+    assert!(path.exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn kml_has_a_placemark_per_gap() {
+    let square = |x: f64, y: f64| {
+        geo::Polygon::new(
+            geo::LineString::from(vec![(x, y), (x + 1.0, y), (x + 1.0, y + 1.0), (x, y)]),
+            vec![],
+        )
+    };
+    let gaps = [
+        ChunkGap {
+            chunk_id: 2,
+            point_count: 10,
+            polygons: geo::MultiPolygon(vec![square(-100.0, 40.0), square(-98.0, 40.0)]),
+            area_km2: 0.0,
+            priority_score: None,
+        },
+        ChunkGap {
+            chunk_id: 5,
+            point_count: 4,
+            polygons: geo::MultiPolygon(vec![square(-90.0, 35.0)]),
+            area_km2: 0.0,
+            priority_score: Some(1234.0),
+        },
+    ];
+    let path = temp_path("gaps.kml");
+    kml::write_gaps_kml(path.to_str().unwrap(), &gaps).unwrap();
+    let contents = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // Well-formed: every tag is closed, in order
+    let mut open = Vec::new();
+    for tag in contents.split('<').skip(1) {
+        let tag = &tag[..tag.find('>').unwrap()];
+        if tag.starts_with('?') || tag.ends_with('/') {
+            continue;
+        }
+        match tag.strip_prefix('/') {
+            Some(name) => assert_eq!(open.pop(), Some(name)),
+            None => open.push(tag.split_whitespace().next().unwrap()),
+        }
+    }
+    assert!(open.is_empty());
+    assert_eq!(contents.matches("<Placemark>").count(), 3);
+    let area = gap_area_km2(&square(-90.0, 35.0));
+    assert!(contents.contains(&format!("<name>Gap chunk 5 ({:.1} km²)</name>", area)));
+    assert_eq!(contents.matches("<description>").count(), 1);
+    // Longitude first
+    assert!(contents.contains("<coordinates>-100,40 -99,40 -99,41 -100,40</coordinates>"));
+}
+
+#[test]
+fn write_gaps_writes_a_shapefile() {
+    use shapefile::dbase::{FieldValue, Record};
+    let square = |x: f64, y: f64, size: f64| {
+        geo::LineString::from(vec![
+            (x, y),
+            (x, y + size),
+            (x + size, y + size),
+            (x + size, y),
+            (x, y),
+        ])
+    };
+    let gaps = [
+        ChunkGap {
+            chunk_id: 2,
+            point_count: 40,
+            polygons: geo::MultiPolygon(vec![geo::Polygon::new(
+                square(-100.0, 30.0, 2.0),
+                vec![square(-99.5, 30.5, 0.5)],
+            )]),
+            area_km2: 1234.5,
+            priority_score: Some(900.0),
+        },
+        ChunkGap {
+            chunk_id: 5,
+            point_count: 7,
+            polygons: geo::MultiPolygon(vec![
+                geo::Polygon::new(square(-90.0, 40.0, 1.0), vec![]),
+                geo::Polygon::new(square(-88.0, 40.0, 0.5), vec![]),
+            ]),
+            area_km2: 99.0,
+            priority_score: Some(0.0),
+        },
+    ];
+    let path = temp_path("gaps.shp");
+    shapefile_output::write_gaps(path.to_str().unwrap(), &gaps).unwrap();
+    let shapes: Vec<(shapefile::Polygon, Record)> = shapefile::read_as(&path).unwrap();
+    for extension in ["shp", "shx", "dbf"] {
+        std::fs::remove_file(path.with_extension(extension)).unwrap();
+    }
+    assert_eq!(shapes.len(), gaps.len());
+    for ((shape, record), gap) in shapes.into_iter().zip(&gaps) {
+        let polygons = geo::MultiPolygon::from(shape);
+        assert_eq!(polygons.0.len(), gap.polygons.0.len());
+        assert!((polygons.unsigned_area() - gap.polygons.unsigned_area()).abs() < 1e-9);
+        assert_eq!(
+            record.get("has_charger"),
+            Some(&FieldValue::Logical(Some(false)))
+        );
+        assert_eq!(
+            record.get("chunk_id"),
+            Some(&FieldValue::Integer(gap.chunk_id as i32))
+        );
+        assert_eq!(
+            record.get("point_count"),
+            Some(&FieldValue::Integer(gap.point_count as i32))
+        );
+        assert_eq!(
+            record.get("area_km2"),
+            Some(&FieldValue::Double(gap.area_km2))
+        );
+        assert_eq!(
+            record.get("priority"),
+            gap.priority_score.map(FieldValue::Double).as_ref()
+        );
+    }
+}
+
+#[test]
+fn write_chargers_writes_points() {
+    use shapefile::dbase::{Date, FieldValue, Record};
+    let chargers = AllChargerLocations::new([
+        ChargerLocation {
+            network: "Electrify America".into(),
+            levels: ChargingLevels {
+                level2: false,
+                dc_fast: true,
+            },
+            open_date: NaiveDate::from_ymd_opt(2021, 3, 14),
+            ..charger(40.0, -100.0, 123_456)
+        },
+        charger(41.0, -101.0, 7),
+    ]);
+
+    let path = temp_path("chargers.shp");
+    write_chargers(path.to_str().unwrap(), &chargers).unwrap();
+    let shapes: Vec<(shapefile::Point, Record)> = shapefile::read_as(&path).unwrap();
+    for extension in ["shp", "shx", "dbf"] {
+        std::fs::remove_file(path.with_extension(extension)).unwrap();
+    }
+    assert_eq!(shapes.len(), 2);
+    // In id order, with x = longitude
+    let (point, record) = &shapes[1];
+    assert_eq!((point.x, point.y), (-100.0, 40.0));
+    assert_eq!(
+        record.get("id"),
+        Some(&FieldValue::Character(Some("123456".to_string())))
+    );
+    assert_eq!(
+        record.get("network"),
+        Some(&FieldValue::Character(Some(
+            "Electrify America".to_string()
+        )))
+    );
+    assert_eq!(
+        record.get("level2"),
+        Some(&FieldValue::Logical(Some(false)))
+    );
+    assert_eq!(
+        record.get("dc_fast"),
+        Some(&FieldValue::Logical(Some(true)))
+    );
+    assert_eq!(
+        record.get("opened"),
+        Some(&FieldValue::Date(Some(Date::new(14, 3, 2021))))
+    );
+    assert_eq!(shapes[0].1.get("opened"), Some(&FieldValue::Date(None)));
+
+    let path = temp_path("chargers.geojson");
+    write_chargers(path.to_str().unwrap(), &chargers).unwrap();
+    let json: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let features = json["features"].as_array().unwrap();
+    assert_eq!(features.len(), 2);
+    assert_eq!(features[1]["properties"]["id"], "123456");
+    assert_eq!(features[1]["properties"]["network"], "Electrify America");
+    assert_eq!(features[1]["properties"]["dc_fast"], true);
+    assert_eq!(features[1]["properties"]["opened"], "2021-03-14");
+    assert!(features[0]["properties"]["opened"].is_null());
+    assert_eq!(
+        features[1]["geometry"]["coordinates"],
+        serde_json::json!([-100.0, 40.0])
+    );
+}
+
+#[test]
+fn find_gaps_clips_to_region() {
+    // A triangle covering the lower-right half of the square, with no
+    // chargers anywhere, so every point that survives clipping is a gap.
+    let triangle = r#"{"type": "Polygon", "coordinates": [[[-100.0, 30.0], [-98.0, 30.0], [-98.0, 32.0], [-100.0, 30.0]]]}"#;
+    let region = geojson::parse_region_geojson(triangle).unwrap();
+    let bbox = BoundingBox::from_region(&region).unwrap();
+    let chargers = chargers_at(&[]);
+    let unclipped = chargers.find_gaps(
+        0.5,
+        BoundingBox::from_region(&region).unwrap(),
+        &OsrmClient::new(DEFAULT_OSRM_URL),
+        None,
+        &RangeConfig::default(),
+        &HullConfig::default(),
+    );
+    let clipped = chargers.find_gaps(
+        0.5,
+        bbox,
+        &OsrmClient::new(DEFAULT_OSRM_URL),
+        Some(&region),
+        &RangeConfig::default(),
+        &HullConfig::default(),
+    );
+    assert!(clipped.unreachable < unclipped.unreachable);
+    for point in clipped.unreachable_points {
+        assert!(region.contains(&point));
+    }
+}
+
+#[test]
+fn grid_spacing_can_differ_by_direction() {
+    let bbox = BoundingBox {
+        lat_min: 40.0,
+        lat_max: 42.0,
+        lon_min: -101.0,
+        lon_max: -99.0,
+    };
+    let spacing = |grid: &[TrialPoint]| {
+        let lat = grid[1..]
+            .iter()
+            .map(|p| p.latitude - grid[0].latitude)
+            .find(|d| *d > 0.0)
+            .unwrap();
+        let lon = grid[1].longitude - grid[0].longitude;
+        (lat, lon)
+    };
+    let grid = bbox.generate_grid(Resolution {
+        lat: 0.5,
+        lon: 0.25,
+    });
+    assert_eq!(grid.len(), 4 * 8);
+    assert_eq!(spacing(&grid), (0.5, 0.25));
+    // A plain number is the same both ways
+    let coords = |grid: Vec<TrialPoint>| {
+        grid.iter()
+            .map(|p| (p.latitude, p.longitude))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        coords(bbox.generate_grid(0.5)),
+        coords(bbox.generate_grid(Resolution::square(0.5)))
+    );
+
+    // Square on the ground in the middle of the box: neighbours are as far
+    // apart east to west as north to south
+    let resolution = Resolution::ground_square(0.1, 41.0);
+    assert!(!resolution.is_square());
+    let grid = bbox.generate_grid(resolution);
+    let (lat, lon) = spacing(&grid);
+    assert!((lat - 0.1).abs() < 1e-9);
+    let middle = grid
+        .iter()
+        .find(|p| (p.latitude - 41.0).abs() < 1e-6)
+        .copied()
+        .unwrap();
+    let north = charger(middle.latitude + lat, middle.longitude, 0);
+    let east = charger(middle.latitude, middle.longitude + lon, 1);
+    let (north, east) = (middle.distance_to(&north), middle.distance_to(&east));
+    assert!(
+        (north - east).abs() / north < 0.001,
+        "{} vs {}",
+        north,
+        east
+    );
+
+    // Closing gaps works on the same lattice
+    let points: Vec<_> = grid
+        .iter()
+        .take(3)
+        .map(|p| geo::Point::new(p.longitude, p.latitude))
+        .collect();
+    let closed = close_gaps(vec![points[0], points[2]], resolution, 2.0 * lon);
+    assert_eq!(closed.len(), 3);
+    assert!(closed[2].euclidean_distance(&points[1]) < 1e-9);
+}
+
+#[test]
+fn mask_drops_points_outside() {
+    let mask = geojson::parse_region_geojson(SQUARE_REGION_GEOJSON).unwrap();
+    let square = BoundingBox::from_region(&mask).unwrap();
+    // A box twice as wide as the mask, so half the grid is outside it
+    let bbox = BoundingBox {
+        lon_max: square.lon_max + (square.lon_max - square.lon_min),
+        ..square
+    };
+    let grid = bbox.generate_grid(0.25);
+    let masked = bbox.generate_grid_within(0.25, Some(&mask));
+    assert!(!masked.is_empty());
+    assert!(masked.len() < grid.len());
+    for point in &masked {
+        assert!(
+            square.contains_point(*point),
+            "{:?}",
+            (point.latitude, point.longitude)
+        );
+    }
+    // Points on the edge of the mask don't count as inside it
+    let inside = |p: &&TrialPoint| {
+        square.lat_min < p.latitude
+            && p.latitude < square.lat_max
+            && square.lon_min < p.longitude
+            && p.longitude < square.lon_max
+    };
+    assert_eq!(masked.len(), grid.iter().filter(inside).count());
+}
+
+#[test]
+fn read_region_from_shapefile() {
+    let path = temp_path("mask.shp");
+    let square = geo::Polygon::new(
+        geo::LineString::from(vec![
+            (-100.0, 30.0),
+            (-98.0, 30.0),
+            (-98.0, 32.0),
+            (-100.0, 30.0),
+        ]),
+        vec![],
+    );
+    let mut writer = shapefile::ShapeWriter::from_path(&path).unwrap();
+    writer
+        .write_shape(&shapefile::Polygon::from(square))
+        .unwrap();
+    drop(writer);
+    let region = read_region(path.to_str().unwrap()).unwrap();
+    assert_eq!(region.0.len(), 1);
+    assert!(region.contains(&geo::Point::new(-98.5, 30.5)));
+    assert!(!region.contains(&geo::Point::new(-99.5, 31.5)));
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(path.with_extension("shx"));
+}
+
+#[test]
+fn nrel_query_url() {
+    let url = NrelQuery::default().url("my-key");
+    let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+    assert_eq!(params["api_key"], "my-key");
+    assert_eq!(params["status"], "E");
+    assert_eq!(params["state"], "all");
+    assert_eq!(params["ev_charging_level"], "2,dc_fast");
+    assert_eq!(params["fuel_type"], "ELEC");
+
+    let query = NrelQuery {
+        status: "E,P,T".to_string(),
+        state: "CA".to_string(),
+        level: ChargingLevel::DcFast,
+        ..NrelQuery::default()
+    };
+    let params: HashMap<_, _> = query.url("my-key").query_pairs().into_owned().collect();
+    assert_eq!(params["status"], "E,P,T");
+    assert_eq!(params["state"], "CA");
+    assert_eq!(params["ev_charging_level"], "dc_fast");
+    assert_eq!(
+        NrelCache::new("cache").path(&query),
+        std::path::Path::new("cache/nrel-dcfast-status_e_p_t-state_ca.csv")
+    );
+
+    let json = NrelQuery {
+        format: NrelFormat::Json,
+        ..NrelQuery::default()
+    };
+    let url = json.url("my-key");
+    assert!(url.path().ends_with("/v1.json"));
+    let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+    assert_eq!(params["api_key"], "my-key");
+    assert!(!params.contains_key("download") && !params.contains_key("utf8_bom"));
+    assert_eq!(
+        NrelCache::new("cache").path(&json),
+        std::path::Path::new("cache/nrel.json")
+    );
+}
+
+#[test]
+fn read_nrel_json_maps_stations() {
+    let body = r#"{
+        "station_counts": {"total": 4},
+        "fuel_stations": [
+            {"id": 1, "latitude": 40.0, "longitude": -100.0,
+             "ev_network": "ChargePoint Network", "ev_level2_evse_num": 2,
+             "ev_dc_fast_num": null, "open_date": "2019-05-01",
+             "access_code": "public", "ev_pricing": "Free"},
+            {"id": 2, "latitude": 41.0, "longitude": -101.0,
+             "ev_network": "Tesla", "ev_dc_fast_num": 8},
+            {"id": 3, "latitude": 42.0, "longitude": -102.0,
+             "ev_network": null, "ev_dc_fast_num": 4},
+            {"id": 4, "latitude": null, "longitude": -103.0}
+        ]
+    }"#;
+    let chargers = read_nrel_json(body, &ChargerFilter::default()).unwrap();
+    // Tesla is excluded by default, and 4 has no coordinates
+    assert_eq!(station_ids(&chargers), [1, 3]);
+    let charger = &chargers.by_station_id("1").unwrap();
+    assert_eq!(charger.network(), "ChargePoint Network");
+    assert!(charger.levels().level2 && !charger.levels().dc_fast);
+    assert_eq!(charger.open_date(), NaiveDate::from_ymd_opt(2019, 5, 1));
+    assert!(chargers.by_station_id("3").unwrap().levels().dc_fast);
+
+    let station: NrelStation = serde_json::from_str(
+        r#"{"id": 1, "latitude": 40.0, "longitude": -100.0, "access_code": "public", "ev_pricing": "Free"}"#,
+    )
+    .unwrap();
+    assert_eq!(station.access_code.as_deref(), Some("public"));
+    assert_eq!(station.ev_pricing.as_deref(), Some("Free"));
+
+    let error =
+        r#"{"error":{"code":"OVER_RATE_LIMIT","message":"You have exceeded your rate limit."}}"#;
+    assert!(matches!(
+        read_nrel_json(error, &ChargerFilter::default()),
+        Err(GapError::NrelApi { message, .. }) if message.starts_with("OVER_RATE_LIMIT")
+    ));
+    assert!(matches!(
+        read_nrel_json("not json", &ChargerFilter::default()),
+        Err(GapError::Json(_))
+    ));
+}
+
+#[test]
+fn nrel_download_uses_fresh_cache() {
+    let dir = temp_path("nrel_cache");
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut cache = NrelCache::new(&dir);
+    let query = NrelQuery::default();
+    assert_eq!(cache.load(&query), None);
+    cache.store(&query, NETWORKS_CSV).unwrap();
+    assert_eq!(cache.path(&query), dir.join("nrel.csv"));
+    // Different queries are different downloads
+    let l2 = NrelQuery {
+        level: ChargingLevel::L2,
+        ..NrelQuery::default()
+    };
+    assert_eq!(cache.load(&l2), None);
+    // Doesn't touch the network, so a bogus API key is fine
+    let chargers =
+        download_source_data("not-a-key", &query, &ChargerFilter::default(), Some(&cache)).unwrap();
+    assert!(!chargers.chargers_by_id.is_empty());
+    cache.refresh = true;
+    assert_eq!(cache.load(&query), None);
+    cache.refresh = false;
+    cache.max_age = Duration::ZERO;
+    std::thread::sleep(Duration::from_millis(10));
+    assert_eq!(cache.load(&query), None);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn read_csv_drops_invalid_coordinates() {
+    let csv = "\
+ID,Latitude,Longitude,EV Network
+1,40.0,-100.0,ChargePoint Network
+2,0.0,0.0,ChargePoint Network
+3,40.0,-999.0,ChargePoint Network
+4,91.0,-100.0,ChargePoint Network
+5,-33.9,151.2,ChargePoint Network
+";
+    let chargers = read_csv(
+        csv::Reader::from_reader(csv.as_bytes()),
+        &ChargerFilter::default(),
+    )
+    .unwrap();
+    assert_eq!(station_ids(&chargers), [1, 5]);
+}
+
+#[test]
+fn read_gzipped_csv() {
+    let plain = read_from_file("testdata/chargers.csv", &ChargerFilter::default()).unwrap();
+    let gzipped = read_from_file("testdata/chargers.csv.gz", &ChargerFilter::default()).unwrap();
+    assert_eq!(gzipped.chargers_by_id.len(), plain.chargers_by_id.len());
+    // Detected from the contents, not the name
+    let path = temp_path("chargers.csv");
+    std::fs::copy("testdata/chargers.csv.gz", &path).unwrap();
+    let renamed = read_from_file(path.to_str().unwrap(), &ChargerFilter::default()).unwrap();
+    assert_eq!(renamed.chargers_by_id.len(), plain.chargers_by_id.len());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn gzip_decompresses_all_block_types() {
+    let compressed = include_bytes!("../testdata/chargers.csv.gz");
+    let plain = include_bytes!("../testdata/chargers.csv");
+    assert_eq!(gzip::decompress(compressed).unwrap(), plain);
+    // Fixed Huffman codes, from `printf 'hello hello hello\n' | gzip -n`
+    let fixed = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0xcb, 0x48, 0xcd, 0xc9, 0xc9,
+        0x57, 0xc8, 0x40, 0x90, 0x5c, 0x00, 0x3b, 0x7c, 0x8a, 0xdf, 0x12, 0x00, 0x00, 0x00,
+    ];
+    // Stored (uncompressed) block, followed by a second gzip member
+    let mut stored = vec![
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x01, 0x03, 0x00, 0xfc, 0xff,
+        b'a', b'b', b'c', 0xc2, 0x41, 0x24, 0x35, 0x03, 0x00, 0x00, 0x00,
+    ];
+    stored.extend_from_slice(&fixed);
+    assert_eq!(gzip::decompress(&fixed).unwrap(), b"hello hello hello\n");
+    assert_eq!(
+        gzip::decompress(&stored).unwrap(),
+        b"abchello hello hello\n"
+    );
+    // Corruption is caught by the checksum
+    let mut corrupt = fixed;
+    corrupt[21] ^= 1;
+    assert!(gzip::decompress(&corrupt).is_err());
+}
+
+/// Two 3x5 blocks of gap points on a unit grid, with a two-column reachable
+/// sliver between them at x = 3 and x = 4.
+fn two_separated_gaps() -> Vec<geo::Point<f64>> {
+    let mut points = Vec::new();
+    for x in [0, 1, 2, 5, 6, 7] {
+        for y in 0..5 {
+            points.push(geo::Point::new(x as f64, y as f64));
+        }
+    }
+    points
+}
+
+#[test]
+fn separate_gaps_get_separate_hulls() {
+    let points = two_separated_gaps();
+    let hulls = |cluster_eps| {
+        gap_hulls(
+            &points,
+            &HullConfig {
+                cluster_eps,
+                ..HullConfig::default()
+            },
+        )
+    };
+    let separate = hulls(1.5);
+    assert_eq!(separate.0.len(), 2);
+    // Neither hull covers the reachable columns in between
+    for polygon in &separate {
+        assert!(!polygon.contains(&geo::Point::new(3.5, 2.0)));
+    }
+    assert!(separate.0[0].contains(&geo::Point::new(1.0, 2.0)));
+    assert!(separate.0[1].contains(&geo::Point::new(6.0, 2.0)));
+    // Close enough to be one gap, or clustering turned off
+    assert_eq!(hulls(3.0).0.len(), 1);
+    assert_eq!(hulls(0.0).0.len(), 1);
+    assert!(hulls(0.0).0[0].contains(&geo::Point::new(3.5, 2.0)));
+
+    assert_eq!(cluster_points(&points, 1.5)[0], points[..15]);
+    assert!(cluster_points(&[], 1.5).is_empty());
+}
+
+#[test]
+fn close_gaps_keeps_gaps_separate_at_zero_distance() {
+    let points = two_separated_gaps();
+    let closed = close_gaps(points.clone(), 1.0, 0.0);
+    assert_eq!(closed, points);
+}
+
+#[test]
+fn close_gaps_merges_nearby_gaps() {
+    let points = two_separated_gaps();
+    let closed = close_gaps(points.clone(), 1.0, 2.0);
+    // Nothing is lost
+    for point in &points {
+        assert!(closed.contains(point));
+    }
+    // The sliver between the two gaps is filled in along the middle row
+    assert!(closed.contains(&geo::Point::new(3.0, 2.0)));
+    assert!(closed.contains(&geo::Point::new(4.0, 2.0)));
+    // but the close doesn't grow the gaps outwards
+    assert!(closed.iter().all(|p| (0.0..=7.0).contains(&p.x())));
+    assert!(closed.iter().all(|p| (0.0..=4.0).contains(&p.y())));
+    // The filled in points come west to east, south to north, every time
+    let filled = &closed[points.len()..];
+    assert!(filled
+        .windows(2)
+        .all(|pair| (pair[0].x(), pair[0].y()) < (pair[1].x(), pair[1].y())));
+    assert_eq!(close_gaps(points, 1.0, 2.0), closed);
+}
+
+#[test]
+fn parse_base_url_normalizes_trailing_slash() {
+    assert_eq!(
+        parse_base_url("http://localhost:5000/").unwrap(),
+        "http://localhost:5000"
+    );
+    assert_eq!(
+        parse_base_url("https://example.com/osrm/").unwrap(),
+        "https://example.com/osrm"
+    );
+    assert_eq!(parse_base_url(DEFAULT_OSRM_URL).unwrap(), DEFAULT_OSRM_URL);
+}
+
+#[test]
+fn parse_base_url_rejects_malformed_urls() {
+    for url in [
+        "router.project-osrm.org",
+        "localhost:5000",
+        "ftp://example.com",
+        "http://",
+        "https://example.com/?foo=bar",
+        "",
+    ] {
+        assert!(parse_base_url(url).is_err(), "{:?} should be rejected", url);
+    }
+}
+
+/// Builds an `AllChargerLocations` with a charger at each `(lat, lon)`.
+/// The NREL ids of `chargers`, in order.
+fn station_ids(chargers: &AllChargerLocations) -> Vec<u64> {
+    let mut ids: Vec<u64> = chargers
+        .chargers_by_id
+        .values()
+        .map(|charger| charger.station_id().parse().unwrap())
+        .collect();
+    ids.sort_unstable();
+    ids
+}
+
+fn chargers_at(locations: &[(f64, f64)]) -> AllChargerLocations {
+    AllChargerLocations::new(
+        locations
+            .iter()
+            .enumerate()
+            .map(|(i, &(latitude, longitude))| charger(latitude, longitude, i as u64)),
+    )
+}
+
+fn is_yes(result: CheckResult) -> bool {
+    match result {
+        CheckResult::Yes => true,
+        CheckResult::Maybe { .. } => false,
+        CheckResult::No => panic!("expected Yes or Maybe, got No"),
+    }
+}
+
+#[test]
+fn crow_flies_ratio_decides_yes_vs_maybe() {
+    let chargers = chargers_at(&[(40.0, -100.0)]);
+    // ~33km north of the charger
+    let near = TrialPoint {
+        latitude: 40.3,
+        longitude: -100.0,
+    };
+    // ~300km north of the charger
+    let far = TrialPoint {
+        latitude: 42.7,
+        longitude: -100.0,
+    };
+    let config = |crow_flies_ratio| RangeConfig {
+        crow_flies_ratio,
+        ..RangeConfig::default()
+    };
+    // 0.1 => 40km shortcut
+    assert!(is_yes(near.check_charger(&chargers, &config(0.1))));
+    assert!(!is_yes(far.check_charger(&chargers, &config(0.1))));
+    // 0.05 => 20km shortcut
+    assert!(!is_yes(near.check_charger(&chargers, &config(0.05))));
+    // 0.0 always needs a lookup
+    assert!(!is_yes(near.check_charger(&chargers, &config(0.0))));
+    // 1.0 never needs a lookup within range
+    assert!(is_yes(far.check_charger(&chargers, &config(1.0))));
+    // 0.5 => 200km shortcut
+    assert!(!is_yes(far.check_charger(&chargers, &config(0.5))));
+}
+
+const NETWORKS_CSV: &str = "\
+ID,Latitude,Longitude,EV Network
+1,40.0,-100.0,Tesla Destination
+2,40.1,-100.1,Tesla
+3,40.2,-100.2,Electrify America
+4,40.3,-100.3,ChargePoint Network
+5,40.4,-100.4,EVgo Network
+";
+
+fn read_networks_csv(networks: &NetworkFilter) -> AllChargerLocations {
+    let filter = ChargerFilter {
+        networks: networks.clone(),
+        ..ChargerFilter::default()
+    };
+    read_csv(csv::Reader::from_reader(NETWORKS_CSV.as_bytes()), &filter).unwrap()
+}
+
+#[test]
+fn summary_has_the_count_and_extent() {
+    let chargers = read_networks_csv(&NetworkFilter {
+        include: vec![],
+        exclude: vec![],
+    });
+    let summary = chargers.summary();
+    assert_eq!(summary.charger_count, chargers.chargers_by_id.len());
+    assert_eq!(
+        summary.bbox,
+        Some(BoundingBox {
+            lat_min: 40.0,
+            lat_max: 40.4,
+            lon_min: -100.4,
+            lon_max: -100.0,
+        })
+    );
+    assert_eq!(AllChargerLocations::new([]).summary().bbox, None);
+}
+
+#[test]
+fn read_csv_excludes_tesla_by_default() {
+    let chargers = read_networks_csv(&NetworkFilter::default());
+    assert_eq!(chargers.chargers_by_id.len(), 3);
+}
+
+#[test]
+fn read_csv_exclude_networks_case_insensitively() {
+    let networks = NetworkFilter {
+        include: Vec::new(),
+        exclude: vec!["tesla".to_string(), "EVGO".to_string()],
+    };
+    assert_eq!(read_networks_csv(&networks).chargers_by_id.len(), 2);
+
+    let networks = NetworkFilter {
+        include: Vec::new(),
+        exclude: Vec::new(),
+    };
+    assert_eq!(read_networks_csv(&networks).chargers_by_id.len(), 5);
+}
+
+#[test]
+fn including_tesla_overrides_the_default_exclude() {
+    let networks = NetworkFilter::new(vec!["Tesla".to_string()], None);
+    assert_eq!(read_networks_csv(&networks).chargers_by_id.len(), 2);
+    // Excluding by hand still applies
+    let networks = NetworkFilter::new(
+        vec!["Tesla".to_string()],
+        Some(vec!["destination".to_string()]),
+    );
+    assert_eq!(read_networks_csv(&networks).chargers_by_id.len(), 1);
+
+    let networks = NetworkFilter::new(Vec::new(), None);
+    assert_eq!(read_networks_csv(&networks).chargers_by_id.len(), 3);
+    // An empty name clears the default rather than excluding everything
+    let networks = NetworkFilter::new(Vec::new(), Some(vec![String::new()]));
+    assert_eq!(read_networks_csv(&networks).chargers_by_id.len(), 5);
+}
+
+#[test]
+fn read_csv_keeps_last_of_duplicate_ids() {
+    let csv = "\
+ID,Latitude,Longitude,EV Network
+1,40.0,-100.0,EVgo Network
+2,41.0,-100.0,EVgo Network
+1,42.0,-100.0,ChargePoint Network
+";
+    let chargers = read_csv(
+        csv::Reader::from_reader(csv.as_bytes()),
+        &ChargerFilter::default(),
+    )
+    .unwrap();
+    assert_eq!(chargers.chargers_by_id.len(), 2);
+    assert_eq!(chargers.by_station_id("1").unwrap().latitude(), 42.0);
+    assert_eq!(
+        chargers.by_station_id("1").unwrap().network(),
+        "ChargePoint Network"
+    );
+    // The replaced charger isn't left behind in the index
+    assert_eq!(chargers.index.size(), 2);
+    let old_location = TrialPoint {
+        latitude: 40.0,
+        longitude: -100.0,
+    };
+    let nearest = old_location.nearest_chargers(&chargers, 50_000, DistanceMetric::Haversine);
+    assert!(nearest.is_empty(), "{:?}", nearest);
+}
+
+#[test]
+fn read_csv_keeps_alphanumeric_ids() {
+    let csv = "\
+ID,Latitude,Longitude,EV Network
+EA-0042,40.0,-100.0,Electrify America
+17,41.0,-101.0,ChargePoint Network
+EA-0042,42.0,-102.0,Electrify America
+";
+    let chargers = read_csv(
+        csv::Reader::from_reader(csv.as_bytes()),
+        &ChargerFilter::default(),
+    )
+    .unwrap();
+    assert_eq!(chargers.chargers_by_id.len(), 2);
+    let charger = chargers.by_station_id("EA-0042").unwrap();
+    assert_eq!(charger.latitude(), 42.0);
+    assert_eq!(
+        charger.to_string(),
+        "42, -102 (id EA-0042, Electrify America)"
+    );
+    assert!(chargers.by_station_id("17").is_some());
+
+    let body = r#"{"fuel_stations": [
+        {"id": "FED-7", "latitude": 40.0, "longitude": -100.0},
+        {"id": 8, "latitude": 41.0, "longitude": -101.0}
+    ]}"#;
+    let chargers = read_nrel_json(body, &ChargerFilter::default()).unwrap();
+    assert!(chargers.by_station_id("FED-7").is_some());
+    assert!(chargers.by_station_id("8").is_some());
+}
+
+#[test]
+fn read_csv_keeps_shared_network_names() {
+    let csv = "\
+ID,Latitude,Longitude,EV Network
+1,40.0,-100.0,Electrify America
+2,40.1,-100.1,EVgo Network
+3,40.2,-100.2,Electrify America
+";
+    let chargers = read_csv(
+        csv::Reader::from_reader(csv.as_bytes()),
+        &ChargerFilter::default(),
+    )
+    .unwrap();
+    let by_id = |id| chargers.by_station_id(id).unwrap();
+    assert_eq!(by_id("1").network(), "Electrify America");
+    assert_eq!(by_id("2").network(), "EVgo Network");
+    assert!(Arc::ptr_eq(&by_id("1").network, &by_id("3").network));
+
+    let point = TrialPoint {
+        latitude: 40.1,
+        longitude: -100.1,
+    };
+    let nearest = point.nearest_chargers(&chargers, 50_000, DistanceMetric::Haversine);
+    assert_eq!(nearest[0].0.network(), "EVgo Network");
+}
+
+#[test]
+fn read_csv_include_networks() {
+    let networks = NetworkFilter {
+        include: vec!["electrify".to_string(), "chargepoint".to_string()],
+        exclude: Vec::new(),
+    };
+    let chargers = read_networks_csv(&networks);
+    assert_eq!(station_ids(&chargers), [3, 4]);
+
+    // Excludes still apply on top of includes
+    let networks = NetworkFilter {
+        include: vec!["tesla".to_string()],
+        exclude: vec!["destination".to_string()],
+    };
+    assert_eq!(read_networks_csv(&networks).chargers_by_id.len(), 1);
+}
+
+#[test]
+fn gaps_feature_collection_has_feature_per_chunk() {
+    let square = |x: f64, y: f64| {
+        geo::Polygon::new(
+            geo::LineString::from(vec![(x, y), (x + 1.0, y), (x + 1.0, y + 1.0), (x, y)]),
+            vec![],
+        )
+    };
+    let gaps = [
+        ChunkGap {
+            chunk_id: 0,
+            point_count: 2,
+            polygons: geo::MultiPolygon(vec![square(-100.0, 30.0), square(-98.0, 30.0)]),
+            area_km2: 20.0,
+            priority_score: None,
+        },
+        ChunkGap {
+            chunk_id: 2,
+            point_count: 1,
+            polygons: geo::MultiPolygon(vec![square(-90.0, 40.0)]),
+            area_km2: 10.0,
+            priority_score: Some(500.0),
+        },
+    ];
+    let collection = geojson::gaps_feature_collection(&gaps, &[]);
+    assert_eq!(collection["type"], "FeatureCollection");
+    let features = collection["features"].as_array().unwrap();
+    assert_eq!(features.len(), 2);
+    assert_eq!(features[0]["properties"]["reachable"], false);
+    assert_eq!(features[0]["properties"]["chunk"], 0);
+    assert_eq!(features[0]["properties"]["point_count"], 2);
+    assert_eq!(features[0]["properties"]["area_km2"], 20.0);
+    assert!(features[0]["properties"]["priority_score"].is_null());
+    assert_eq!(features[0]["geometry"]["type"], "MultiPolygon");
+    // GeoJSON positions are [longitude, latitude]
+    assert_eq!(
+        features[0]["geometry"]["coordinates"][1][0][1],
+        serde_json::json!([-97.0, 30.0])
+    );
+    assert_eq!(features[1]["properties"]["chunk"], 2);
+    assert_eq!(features[1]["properties"]["priority_score"], 500.0);
+
+    let reachable = [geo::Point::new(-80.0, 45.0)];
+    let collection = geojson::gaps_feature_collection(&gaps, &reachable);
+    let features = collection["features"].as_array().unwrap();
+    assert_eq!(features.len(), 3);
+    assert_eq!(features[2]["properties"]["reachable"], true);
+    assert!(features[2]["properties"]["chunk"].is_null());
+    assert_eq!(features[2]["geometry"]["type"], "MultiPoint");
+    assert_eq!(
+        features[2]["geometry"]["coordinates"][0],
+        serde_json::json!([-80.0, 45.0])
+    );
+}
+
+/// Starts a local HTTP server that answers every request with `body`, and
+/// returns its base URL. The server lives until the test process exits.
+fn serve_osrm(body: &'static str) -> String {
+    serve_osrm_with(move |_| body.to_string())
+}
+
+/// Like `serve_osrm`, but the body is built by `respond` from the requested
+/// path (including the query string).
+fn serve_osrm_with(respond: impl Fn(&str) -> String + Send + 'static) -> String {
+    serve_http_with(move |path, _| respond(path))
+}
+
+/// Serves HTTP on a local port, responding with the body built by `respond`
+/// from the requested path and the request body.
+fn serve_http_with(respond: impl Fn(&str, &str) -> String + Send + 'static) -> String {
+    serve_http_status_with(move |path, body| ("200 OK", respond(path, body)))
+}
+
+/// Like `serve_http_with`, but `respond` also picks the status, e.g.
+/// "403 Forbidden", which can be followed by more header lines, e.g.
+/// "429 Too Many Requests\r\nRetry-After: 5".
+fn serve_http_status_with(
+    respond: impl Fn(&str, &str) -> (&'static str, String) + Send + 'static,
+) -> String {
+    use std::io::{Read, Write};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            let header_end = loop {
+                if let Some(i) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break i + 4;
+                }
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break request.len(),
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            };
+            let headers = String::from_utf8_lossy(&request[..header_end]).to_string();
+            let content_length = headers
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, value)| value.trim().parse().ok())
+                .unwrap_or(0);
+            while request.len() < header_end + content_length {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            // "GET /path HTTP/1.1"
+            let path = headers.split(' ').nth(1).unwrap_or_default();
+            let (status, body) = respond(path, &String::from_utf8_lossy(&request[header_end..]));
+            let _ = write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+        }
+    });
+    url
+}
+
+/// Trimmed down response from a real OSRM server, for
+/// `/route/v1/driving/-73.9,40.7;-84.4,33.7`.
+const OSRM_ROUTE_JSON: &str = r#"{
+    "code": "Ok",
+    "routes": [{
+        "geometry": "m~dwFnmwbMnzJr|@",
+        "legs": [{"steps": [], "summary": "", "weight": 44122.5, "duration": 43987.3, "distance": 1387012.4}],
+        "weight_name": "routability",
+        "weight": 44122.5,
+        "duration": 43987.3,
+        "distance": 1387012.4
+    }],
+    "waypoints": [
+        {"hint": "", "distance": 12.3, "name": "Broadway", "location": [-73.900123, 40.700456]},
+        {"hint": "", "distance": 4.5, "name": "Peachtree Street", "location": [-84.399876, 33.699543]}
+    ]
+}"#;
+
+#[test]
+fn osrm_route_request_end_to_end() {
+    let (requests, paths) = std::sync::mpsc::channel();
+    let osrm_url = serve_osrm_with(move |path| {
+        requests.send(path.to_string()).unwrap();
+        OSRM_ROUTE_JSON.to_string()
+    });
+    let osrm = OsrmClient::new(&osrm_url);
+    let ny = TrialPoint {
+        latitude: 40.7,
+        longitude: -73.9,
+    };
+    let atlanta = charger(33.7, -84.4, 0);
+    assert_eq!(
+        ny.get_osrm_distance(&osrm, &atlanta),
+        RouteLookup::Reachable(1387012.4)
+    );
+    // OSRM wants longitude first, source then destination
+    assert_eq!(
+        paths.recv().unwrap(),
+        "/route/v1/driving/-73.9,40.7;-84.4,33.7"
+    );
+
+    let body: Json = serde_json::from_str(OSRM_ROUTE_JSON).unwrap();
+    assert_eq!(body.routes.len(), 1);
+    assert_eq!(body.routes[0].distance, 1387012.4);
+    assert_eq!(body.routes[0].duration, 43987.3);
+}
+
+#[test]
+fn osrm_points_snapped_too_far_have_no_route() {
+    let (requests, paths) = std::sync::mpsc::channel();
+    let osrm_url = serve_osrm_with(move |path| {
+        requests.send(path.to_string()).unwrap();
+        OSRM_ROUTE_JSON.to_string()
+    });
+    let ny = TrialPoint {
+        latitude: 40.7,
+        longitude: -73.9,
+    };
+    let atlanta = charger(33.7, -84.4, 0);
+    // The point was moved 12.3 m onto Broadway
+    let osrm = OsrmClient {
+        max_snap_meters: Some(10.0),
+        ..OsrmClient::new(&osrm_url)
+    };
+    assert_eq!(ny.get_osrm_distance(&osrm, &atlanta), RouteLookup::NoRoute);
+    assert_eq!(
+        paths.recv().unwrap(),
+        "/route/v1/driving/-73.9,40.7;-84.4,33.7?radiuses=10;unlimited"
+    );
+    let osrm = OsrmClient {
+        max_snap_meters: Some(20.0),
+        ..osrm
+    };
+    assert_eq!(
+        ny.get_osrm_distance(&osrm, &atlanta),
+        RouteLookup::Reachable(1387012.4)
+    );
+    paths.recv().unwrap();
+
+    // Same for tables, where the source is snapped once for every charger
+    let table_url = serve_osrm(
+        r#"{"code": "Ok", "distances": [[1000.0, 2000.0]], "sources": [{"distance": 250.0}]}"#,
+    );
+    let chargers = [charger(40.8, -73.9, 1), charger(40.9, -73.9, 2)];
+    let osrm = OsrmClient {
+        max_snap_meters: Some(100.0),
+        ..OsrmClient::new(&table_url)
+    };
+    assert_eq!(
+        ny.get_osrm_table_distances(&osrm, &chargers),
+        vec![RouteLookup::NoRoute; 2]
+    );
+    let osrm = OsrmClient {
+        max_snap_meters: None,
+        ..osrm
+    };
+    assert_eq!(
+        ny.get_osrm_table_distances(&osrm, &chargers),
+        vec![
+            RouteLookup::Reachable(1000.0),
+            RouteLookup::Reachable(2000.0)
+        ]
+    );
+}
+
+#[test]
+fn osrm_profile_goes_in_the_path() {
+    let (requests, paths) = std::sync::mpsc::channel();
+    let osrm_url = serve_osrm_with(move |path| {
+        requests.send(path.to_string()).unwrap();
+        OSRM_ROUTE_JSON.to_string()
+    });
+    let osrm = OsrmClient {
+        profile: "ev_car".to_string(),
+        ..OsrmClient::new(&osrm_url)
+    };
+    let ny = TrialPoint {
+        latitude: 40.7,
+        longitude: -73.9,
+    };
+    let atlanta = charger(33.7, -84.4, 0);
+    ny.get_osrm_distance(&osrm, &atlanta);
+    assert_eq!(
+        paths.recv().unwrap(),
+        "/route/v1/ev_car/-73.9,40.7;-84.4,33.7"
+    );
+    ny.get_osrm_table_durations(&osrm, &[atlanta]);
+    assert!(paths.recv().unwrap().starts_with("/table/v1/ev_car/"));
+
+    assert!(is_valid_osrm_profile(DEFAULT_OSRM_PROFILE));
+    assert!(is_valid_osrm_profile("bike-2"));
+    for profile in ["", "car/../../x", "car?x=1", "car#", "car bike", "café"] {
+        assert!(!is_valid_osrm_profile(profile), "{:?}", profile);
+    }
+}
+
+#[test]
+fn rate_limit_is_shared_between_clients() {
+    let (requests, times) = std::sync::mpsc::channel();
+    let osrm_url = serve_osrm_with(move |_| {
+        requests.send(Instant::now()).unwrap();
+        OSRM_ROUTE_JSON.to_string()
+    });
+    let osrm = OsrmClient {
+        rate_limit: Some(Arc::new(rate_limit::RateLimiter::new(20.0))),
+        ..OsrmClient::new(&osrm_url)
+    };
+    let ny = TrialPoint {
+        latitude: 40.7,
+        longitude: -73.9,
+    };
+    // Each thread has its own clone of the client, like the rayon workers
+    std::thread::scope(|scope| {
+        for i in 0..3 {
+            let osrm = osrm.clone();
+            scope.spawn(move || {
+                for j in 0..2 {
+                    let charger = charger(33.7, -84.4, i * 10 + j);
+                    assert!(ny.get_osrm_distance(&osrm, &charger).length().is_some());
+                }
+            });
+        }
+    });
+    let mut times: Vec<_> = times.try_iter().collect();
+    assert_eq!(times.len(), 6);
+    times.sort();
+    for pair in times.windows(2) {
+        // 50ms apart, less some slack for when the server gets to them
+        assert!(
+            pair[1] - pair[0] >= Duration::from_millis(40),
+            "{:?}",
+            pair[1] - pair[0]
+        );
+    }
+}
+
+#[test]
+fn valhalla_distances_are_in_meters() {
+    let (requests, bodies) = std::sync::mpsc::channel();
+    let url = serve_http_with(move |path, body| {
+        assert_eq!(path, "/route");
+        requests.send(body.to_string()).unwrap();
+        r#"{"trip":{"summary":{"length":123.4,"time":4567.0},"legs":[]}}"#.to_string()
+    });
+    let valhalla = ValhallaClient {
+        url,
+        client: http_client(Duration::from_secs(5)).unwrap(),
+        retry: RetryPolicy::default(),
+        max_candidates: MAX_OSRM_CANDIDATES,
+        concurrent_lookups: 1,
+        rate_limit: None,
+    };
+    let ny = TrialPoint {
+        latitude: 40.7,
+        longitude: -73.9,
+    };
+    let charger = charger(33.7, -84.4, 0);
+    assert_eq!(valhalla.driving_distance(&ny, &charger), Some(123_400.0));
+    let body: serde_json::Value = serde_json::from_str(&bodies.recv().unwrap()).unwrap();
+    assert_eq!(body["locations"][0]["lat"], 40.7);
+    assert_eq!(body["locations"][1]["lon"], -84.4);
+    assert_eq!(body["units"], "kilometers");
+    assert_eq!(valhalla.driving_duration(&ny, &charger), Some(4567.0));
+}
+
+#[test]
+fn graphhopper_durations_are_in_seconds() {
+    let (requests, paths) = std::sync::mpsc::channel();
+    let url = serve_osrm_with(move |path| {
+        requests.send(path.to_string()).unwrap();
+        r#"{"paths":[{"distance":123400.5,"time":4567000}]}"#.to_string()
+    });
+    let graphhopper = GraphHopperClient {
+        url,
+        client: http_client(Duration::from_secs(5)).unwrap(),
+        retry: RetryPolicy::default(),
+        max_candidates: MAX_OSRM_CANDIDATES,
+        concurrent_lookups: 1,
+        rate_limit: None,
+    };
+    let ny = TrialPoint {
+        latitude: 40.7,
+        longitude: -73.9,
+    };
+    let charger = charger(33.7, -84.4, 0);
+    assert_eq!(graphhopper.driving_distance(&ny, &charger), Some(123_400.5));
+    assert!(paths
+        .recv()
+        .unwrap()
+        .starts_with("/route?point=40.7,-73.9&point=33.7,-84.4&"));
+    assert_eq!(graphhopper.driving_duration(&ny, &charger), Some(4567.0));
+}
+
+#[test]
+fn find_gaps_points_are_lon_lat() {
+    // One charger in the south-west corner of a tall, narrow box: points
+    // next to it are trivially reachable, the middle of the box is only
+    // maybe reachable (and OSRM finds no route), and the north end is out of
+    // range entirely.
+    let chargers = chargers_at(&[(40.0, -100.0)]);
+    let bbox = BoundingBox {
+        lat_min: 40.0,
+        lat_max: 45.0,
+        lon_min: -100.0,
+        lon_max: -99.0,
+    };
+    let osrm_url = serve_osrm("{}");
+    let points = chargers
+        .find_gaps(
+            0.5,
+            bbox,
+            &OsrmClient::new(&osrm_url),
+            None,
+            &RangeConfig::default(),
+            &HullConfig::default(),
+        )
+        .unreachable_points;
+    for point in &points {
+        assert!((-100.0..=-99.0).contains(&point.x()), "{:?}", point);
+        assert!((40.0..=45.0).contains(&point.y()), "{:?}", point);
+    }
+    // Make sure both the No and the Maybe branches contributed
+    assert!(points.iter().any(|p| p.y() >= 44.0));
+    assert!(points.iter().any(|p| p.y() <= 42.0));
+}
+
+#[test]
+fn osrm_table_distances_are_parsed_in_order() {
+    let osrm_url = serve_osrm(r#"{"code":"Ok","distances":[[1000.5,null,500000]]}"#);
+    let ny = TrialPoint {
+        latitude: 40.730610,
+        longitude: -73.935242,
+    };
+    let candidates = [(40.0, -74.0), (41.0, -70.0), (33.75, -84.4)]
+        .map(|(latitude, longitude)| charger(latitude, longitude, 0));
+    let osrm = OsrmClient::new(&osrm_url);
+    let distances = ny.get_osrm_table_distances(&osrm, &candidates);
+    assert_eq!(
+        distances,
+        [
+            RouteLookup::Reachable(1000.5),
+            RouteLookup::NoRoute,
+            RouteLookup::Reachable(500000.0)
+        ]
+    );
+    // A response that doesn't match the number of candidates is a failed
+    // lookup, not an answer that there are no routes.
+    let distances = ny.get_osrm_table_distances(&osrm, &candidates[..2]);
+    assert_eq!(distances, [RouteLookup::Error; 2]);
+}
+
+#[test]
+fn find_gaps_uses_osrm_table_for_maybe_points() {
+    // Same layout as `find_gaps_points_are_lon_lat`, but OSRM says the
+    // charger is close enough by road, so only the out-of-range points are
+    // left as gaps.
+    let chargers = chargers_at(&[(40.0, -100.0)]);
+    let bbox = BoundingBox {
+        lat_min: 40.0,
+        lat_max: 45.0,
+        lon_min: -100.0,
+        lon_max: -99.0,
+    };
+    let osrm_url = serve_osrm(r#"{"code":"Ok","distances":[[100000]]}"#);
+    let points = chargers
+        .find_gaps(
+            0.5,
+            bbox,
+            &OsrmClient::new(&osrm_url),
+            None,
+            &RangeConfig::default(),
+            &HullConfig::default(),
+        )
+        .unreachable_points;
+    assert!(!points.is_empty());
+    assert!(points.iter().all(|p| p.y() >= 44.0), "{:?}", points);
+}
+
+#[test]
+fn osrm_candidate_limit() {
+    // Four chargers 111-144km north of the only grid point, so all of them
+    // need an OSRM lookup, but only the furthest one is in range by road.
+    let chargers = chargers_at(&[
+        (41.0, -100.0),
+        (41.1, -100.0),
+        (41.2, -100.0),
+        (41.3, -100.0),
+    ]);
+    let osrm_url = serve_osrm_with(|path| {
+        let coordinates = path
+            .trim_start_matches("/table/v1/driving/")
+            .split('?')
+            .next()
+            .unwrap();
+        let distances: Vec<_> = coordinates
+            .split(';')
+            .skip(1)
+            .map(|c| if c == "-100,41.3" { 150_000 } else { 900_000 })
+            .collect();
+        serde_json::json!({ "code": "Ok", "distances": [distances] }).to_string()
+    });
+    // Just wide enough for one grid point
+    let bbox = BoundingBox {
+        lat_min: 40.0,
+        lat_max: 40.015,
+        lon_min: -100.0,
+        lon_max: -99.985,
+    };
+    assert_eq!(bbox.generate_grid(0.01).len(), 1);
+    let unreachable = |max_candidates| {
+        let osrm = OsrmClient {
+            max_candidates,
+            ..OsrmClient::new(&osrm_url)
+        };
+        chargers
+            .find_gaps(
+                0.01,
+                bbox,
+                &osrm,
+                None,
+                &RangeConfig::default(),
+                &HullConfig::default(),
+            )
+            .unreachable
+    };
+    // The reachable charger is 4th closest, so a limit of 3 misses it
+    assert_eq!(unreachable(3), 1);
+    assert_eq!(unreachable(4), 0);
+    assert_eq!(unreachable(MAX_OSRM_CANDIDATES), 0);
+}
+
+#[test]
+fn duration_mode_always_asks_osrm() {
+    // A charger right next to the only grid point, which distance mode would
+    // assume is reachable, but is a 2 hour drive away.
+    let chargers = chargers_at(&[(40.0, -100.0)]);
+    let osrm_url = serve_osrm_with(|path| {
+        assert!(path.ends_with("annotations=duration"), "{}", path);
+        r#"{"code":"Ok","durations":[[7200]]}"#.to_string()
+    });
+    let bbox = BoundingBox {
+        lat_min: 40.0,
+        lat_max: 40.015,
+        lon_min: -100.0,
+        lon_max: -99.985,
+    };
+    let report = |max_duration_secs| {
+        let config = RangeConfig {
+            mode: RangeMode::Duration,
+            max_duration_secs,
+            ..RangeConfig::default()
+        };
+        chargers.find_gaps(
+            0.01,
+            bbox,
+            &OsrmClient::new(&osrm_url),
+            None,
+            &config,
+            &HullConfig::default(),
+        )
+    };
+    let one_hour = report(3600);
+    assert_eq!((one_hour.maybe, one_hour.unreachable), (1, 1));
+    let three_hours = report(3 * 3600);
+    assert_eq!((three_hours.maybe, three_hours.unreachable), (1, 0));
+}
+
+/// Canned driving distances by charger id, instead of asking a server.
+struct MockRoutes {
+    distances: HashMap<u64, f64>,
+    lookups: AtomicUsize,
+    /// How long each lookup takes
+    delay: Duration,
+}
+
+impl MockRoutes {
+    fn new(distances: &[(u64, f64)]) -> MockRoutes {
+        MockRoutes {
+            distances: distances.iter().copied().collect(),
+            lookups: AtomicUsize::new(0),
+            delay: Duration::ZERO,
+        }
+    }
+}
+
+impl RouteProvider for MockRoutes {
+    fn driving_distance(&self, _src: &TrialPoint, dst: &ChargerLocation) -> Option<f64> {
+        self.lookups.fetch_add(1, Relaxed);
+        std::thread::sleep(self.delay);
+        self.distances.get(&dst.id()).copied()
+    }
+
+    fn driving_duration(&self, _src: &TrialPoint, _dst: &ChargerLocation) -> Option<f64> {
+        None
+    }
+}
+
+#[test]
+fn adaptive_grid_finds_same_gaps_with_fewer_lookups() {
+    // Everything within 50km of the charger is reachable, but only after
+    // asking the routing engine, so every checked point in range is a lookup.
+    let chargers = chargers_at(&[(40.0, -100.0)]);
+    let routes = MockRoutes::new(&[(0, 0.0)]);
+    let config = RangeConfig {
+        max_range_meters: 50_000,
+        crow_flies_ratio: 0.0,
+        ..RangeConfig::default()
+    };
+    let bbox = BoundingBox {
+        lat_min: 39.0,
+        lat_max: 41.0,
+        lon_min: -101.0,
+        lon_max: -99.0,
+    };
+    let hull = HullConfig::default();
+    let uniform = chargers.find_gaps(0.02, bbox, &routes, None, &config, &hull);
+    let adaptive = chargers.adaptive_find_gaps(
+        adaptive::AdaptiveResolution {
+            min: 0.02,
+            max: 0.16,
+        },
+        bbox,
+        &routes,
+        None,
+        &config,
+        &hull,
+        &ReportConfig::default(),
+        None,
+    );
+    assert_eq!(adaptive.total(), uniform.total());
+    assert!(
+        adaptive.routed_points * 2 < uniform.routed_points,
+        "{} vs {}",
+        adaptive.routed_points,
+        uniform.routed_points
+    );
+    // The edge of the circle can cut across a cell between two of its
+    // corners, so a few points along it can differ
+    let key = |p: &geo::Point<f64>| ((p.y() * 1e6) as i64, (p.x() * 1e6) as i64);
+    let uniform_gaps: HashSet<_> = uniform.unreachable_points.iter().map(key).collect();
+    let adaptive_gaps: HashSet<_> = adaptive.unreachable_points.iter().map(key).collect();
+    let differences = uniform_gaps.symmetric_difference(&adaptive_gaps).count();
+    assert!(
+        differences * 100 <= uniform.total(),
+        "{} of {} points differ",
+        differences,
+        uniform.total()
+    );
+    assert!((adaptive.area_km2 - uniform.area_km2).abs() < 0.01 * uniform.area_km2);
+}
+
+#[test]
+fn adaptive_grid_without_edges_checks_only_corners() {
+    // The charger is in range of every point, but too far by road, so every
+    // checked point is a lookup and none of them are reachable.
+    let chargers = chargers_at(&[(40.05, -99.95)]);
+    let routes = MockRoutes::new(&[(0, 1e9)]);
+    let config = RangeConfig {
+        crow_flies_ratio: 0.0,
+        ..RangeConfig::default()
+    };
+    let bbox = BoundingBox {
+        lat_min: 40.0,
+        lat_max: 40.1,
+        lon_min: -100.0,
+        lon_max: -99.9,
+    };
+    let report = chargers.adaptive_find_gaps(
+        adaptive::AdaptiveResolution {
+            min: 0.01,
+            max: 0.1,
+        },
+        bbox,
+        &routes,
+        None,
+        &config,
+        &HullConfig::default(),
+        &ReportConfig::default(),
+        None,
+    );
+    assert_eq!(report.unreachable, bbox.generate_grid(0.01).len());
+    assert_eq!(report.reachable, 0);
+    // The 9x9 grid is 2x2 cells of 8x8 points, whose corners are on a 3x3
+    // grid
+    assert_eq!(report.routed_points, 9);
+}
+
+#[test]
+fn estimate_gaps_counts_points_without_lookups() {
+    let chargers = chargers_at(&[(40.0, -100.0)]);
+    let bbox = BoundingBox {
+        lat_min: 39.0,
+        lat_max: 41.0,
+        lon_min: -101.0,
+        lon_max: -99.0,
+    };
+    let config = RangeConfig {
+        max_range_meters: 50_000,
+        crow_flies_ratio: 0.5,
+        ..RangeConfig::default()
+    };
+    let estimate = chargers.estimate_gaps(0.1, bbox, None, &config);
+    assert_eq!(estimate.points, bbox.generate_grid(0.1).len());
+    assert_eq!(
+        estimate.reachable + estimate.unreachable + estimate.maybe,
+        estimate.points
+    );
+    assert!(estimate.reachable > 0);
+    assert!(estimate.maybe > 0);
+    // The same points need lookups in a real run, which fails them all here
+    let routes = MockRoutes::new(&[]);
+    let report = chargers.find_gaps(0.1, bbox, &routes, None, &config, &HullConfig::default());
+    assert_eq!(report.maybe, estimate.maybe);
+    assert_eq!(report.reachable, estimate.reachable);
+    assert_eq!(routes.lookups.load(Relaxed), estimate.maybe);
+    // and chunks add up to the whole
+    let chunked = bbox
+        .chunkify_grid(2, 3)
+        .into_iter()
+        .map(|chunk| chargers.estimate_gaps(0.1, chunk, None, &config))
+        .fold(GridEstimate::default(), |a, b| a + b);
+    assert_eq!(
+        chunked.points,
+        chunked.reachable + chunked.unreachable + chunked.maybe
+    );
+}
+
+#[test]
+fn maybe_reachable_points_use_route_provider() {
+    // Two chargers ~300km and ~330km north of the only grid point: too far to
+    // assume they're reachable, so both are looked up.
+    let chargers = chargers_at(&[(42.7, -100.0), (43.0, -100.0)]);
+    let bbox = BoundingBox {
+        lat_min: 40.0,
+        lat_max: 40.015,
+        lon_min: -100.0,
+        lon_max: -99.985,
+    };
+    let unreachable = |routes: &MockRoutes| {
+        let report = chargers.find_gaps(
+            0.01,
+            bbox,
+            routes,
+            None,
+            &RangeConfig::default(),
+            &HullConfig::default(),
+        );
+        assert_eq!(report.maybe, 1);
+        report.unreachable
+    };
+
+    let in_range = MockRoutes::new(&[(0, 450_000.0), (1, 390_000.0)]);
+    assert_eq!(unreachable(&in_range), 0);
+    assert!(in_range.lookups.load(Relaxed) <= 2);
+
+    let out_of_range = MockRoutes::new(&[(0, 450_000.0), (1, 410_000.0)]);
+    assert_eq!(unreachable(&out_of_range), 1);
+
+    // No route to either
+    assert_eq!(unreachable(&MockRoutes::new(&[])), 1);
+}
+
+#[test]
+fn memoized_routes_are_looked_up_once_for_every_range() {
+    let chargers = chargers_at(&[(40.0, -100.0), (40.0, -99.0)]);
+    let routes = MockRoutes::new(&[(0, 40_000.0), (1, 80_000.0)]);
+    let memoized = MemoizedRoutes::new(&routes);
+    let bbox = BoundingBox {
+        lat_min: 39.0,
+        lat_max: 41.0,
+        lon_min: -101.0,
+        lon_max: -98.0,
+    };
+    let hull = HullConfig::default();
+    let search = |routes: &dyn RouteProvider, max_range_meters| {
+        let config = RangeConfig {
+            max_range_meters,
+            crow_flies_ratio: 0.0,
+            ..RangeConfig::default()
+        };
+        chargers.find_gaps(0.1, bbox, routes, None, &config, &hull)
+    };
+
+    // Longest first: every route the shorter range needs is looked up then
+    let long = search(&memoized, 100_000);
+    let lookups = routes.lookups.load(Relaxed);
+    assert!(lookups > 0);
+    let short = search(&memoized, 60_000);
+    assert_eq!(routes.lookups.load(Relaxed), lookups);
+    assert!(short.unreachable > long.unreachable);
+
+    let unmemoized = MockRoutes::new(&[(0, 40_000.0), (1, 80_000.0)]);
+    assert_eq!(
+        search(&unmemoized, 60_000).unreachable_points,
+        short.unreachable_points
+    );
+    assert_eq!(
+        search(&unmemoized, 100_000).unreachable_points,
+        long.unreachable_points
+    );
+}
+
+#[test]
+fn memoized_routes_ask_again_after_a_failed_lookup() {
+    // The first request fails, the rest find a route to one charger only
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counted = requests.clone();
+    let osrm_url = serve_http_status_with(move |_, _| match counted.fetch_add(1, Relaxed) {
+        0 => ("500 Internal Server Error", String::new()),
+        _ => (
+            "200 OK",
+            r#"{"code":"Ok","distances":[[1000.0,null]]}"#.to_string(),
+        ),
+    });
+    let osrm = OsrmClient {
+        retry: RetryPolicy {
+            max_retries: 0,
+            ..RetryPolicy::default()
+        },
+        ..OsrmClient::new(&osrm_url)
+    };
+    let memoized = MemoizedRoutes::new(&osrm);
+    let ny = TrialPoint {
+        latitude: 40.7,
+        longitude: -73.9,
+    };
+    let chargers = [charger(40.8, -73.9, 1), charger(40.9, -73.9, 2)];
+
+    assert_eq!(
+        memoized.driving_distance_lookups(&ny, &chargers),
+        [RouteLookup::Error; 2]
+    );
+    assert!(memoized.is_empty());
+    let found = [RouteLookup::Reachable(1000.0), RouteLookup::NoRoute];
+    assert_eq!(memoized.driving_distance_lookups(&ny, &chargers), found);
+    assert_eq!(requests.load(Relaxed), 2);
+    // Both the route and the answer that there is none are remembered
+    assert_eq!(memoized.driving_distance_lookups(&ny, &chargers), found);
+    assert_eq!(requests.load(Relaxed), 2);
+}
+
+#[test]
+fn unreachable_points_keep_their_nearest_charger() {
+    // Charger 0 is 80km away by road, beyond the 60km range
+    let chargers = chargers_at(&[(40.0, -100.0), (40.0, -98.0)]);
+    let bbox = BoundingBox {
+        lat_min: 39.0,
+        lat_max: 41.0,
+        lon_min: -101.0,
+        lon_max: -98.0,
+    };
+    let config = RangeConfig {
+        max_range_meters: 60_000,
+        crow_flies_ratio: 0.0,
+        ..RangeConfig::default()
+    };
+    let search = |memoize: bool, report: &ReportConfig| {
+        let routes = MockRoutes::new(&[(0, 80_000.0), (1, 40_000.0)]);
+        let memoized = MemoizedRoutes::new(&routes);
+        let provider: &dyn RouteProvider = if memoize { &memoized } else { &routes };
+        let report = chargers
+            .find_gaps_with_progress(
+                0.1,
+                bbox,
+                provider,
+                None,
+                None,
+                &config,
+                &HullConfig::default(),
+                report,
+                None,
+                None,
+                &|_| {},
+            )
+            .unwrap();
+        (report, routes.lookups.load(Relaxed))
+    };
+    let (plain, plain_lookups) = search(true, &ReportConfig::default());
+    assert_eq!(plain.unreachable_details, None);
+    let (report, lookups) = search(
+        false,
+        &ReportConfig {
+            emit_unreachable_details: true,
+            ..ReportConfig::default()
+        },
+    );
+    // The details reuse the lookups made to decide the points
+    assert_eq!(lookups, plain_lookups);
+    assert_eq!(report.unreachable, plain.unreachable);
+
+    let details = report.unreachable_details.unwrap();
+    assert_eq!(details.len(), report.unreachable);
+    assert!(details
+        .iter()
+        .all(|point| point.nearest_charger.is_some() && point.driving_secs.is_none()));
+    let at = |lat: f64, lon: f64| {
+        details
+            .iter()
+            .find(|point| {
+                (point.point.latitude - lat).abs() < 1e-9
+                    && (point.point.longitude - lon).abs() < 1e-9
+            })
+            .unwrap()
+    };
+    let near_charger_0 = at(40.0, -100.0);
+    assert_eq!(near_charger_0.nearest_charger.as_deref(), Some("0"));
+    assert_eq!(near_charger_0.straight_line_meters, Some(0));
+    assert_eq!(near_charger_0.driving_meters, Some(80_000.0));
+    // Out of range of both, so never looked up
+    let corner = at(39.0, -101.0);
+    assert_eq!(corner.nearest_charger.as_deref(), Some("0"));
+    assert!(corner.straight_line_meters.unwrap() > 60_000);
+    assert_eq!(corner.driving_meters, None);
+
+    let path = temp_path("unreachable.csv");
+    csv_output::write_unreachable_csv(path.to_str().unwrap(), &details).unwrap();
+    let mut reader = csv::Reader::from_path(&path).unwrap();
+    assert_eq!(
+        reader.headers().unwrap(),
+        csv_output::UNREACHABLE_CSV_HEADER.as_slice()
+    );
+    let rows: Vec<_> = reader.records().map(Result::unwrap).collect();
+    assert_eq!(rows.len(), details.len());
+    let lat = near_charger_0.point.latitude.to_string();
+    let lon = near_charger_0.point.longitude.to_string();
+    assert!(rows
+        .iter()
+        .any(|row| row == vec![&lat, &lon, "0", "0", "80000", ""].as_slice()));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn candidate_lookups_run_concurrently() {
+    // Eight candidates that each take 100ms to look up, and only the last
+    // (furthest) one is in range.
+    let chargers: Vec<_> = (0..8)
+        .map(|id| charger(42.7 + id as f64 * 0.01, -100.0, id))
+        .collect();
+    let mut routes = MockRoutes::new(&[(7, 390_000.0)]);
+    routes.delay = Duration::from_millis(100);
+    let point = TrialPoint {
+        latitude: 40.0,
+        longitude: -100.0,
+    };
+    let config = RangeConfig::default();
+    let start = Instant::now();
+    assert!(routes.any_in_range(&point, &chargers, &config));
+    // Four at a time, so two rounds rather than eight
+    assert!(
+        start.elapsed() < Duration::from_millis(600),
+        "{:?}",
+        start.elapsed()
+    );
+    assert_eq!(routes.lookups.load(Relaxed), 8);
+    assert!(!routes.any_in_range(&point, &chargers[..7], &config));
+
+    // Once one is found, no more lookups are started
+    let routes = MockRoutes::new(&[(0, 390_000.0)]);
+    assert!(routes.any_in_range(&point, &chargers, &config));
+    assert!(routes.lookups.load(Relaxed) <= CONCURRENT_LOOKUPS);
+}
+
+#[test]
+fn reachable_points_are_only_kept_on_request() {
+    let chargers = chargers_at(&[(40.0, -100.0)]);
+    let bbox = BoundingBox {
+        lat_min: 40.0,
+        lat_max: 45.0,
+        lon_min: -100.0,
+        lon_max: -99.0,
+    };
+    let osrm_url = serve_osrm(r#"{"code":"Ok","distances":[[100000]]}"#);
+    let report = |emit_reachable| {
+        chargers
+            .find_gaps_with_progress(
+                0.5,
+                bbox,
+                &OsrmClient::new(&osrm_url),
+                None,
+                None,
+                &RangeConfig::default(),
+                &HullConfig::default(),
+                &ReportConfig {
+                    emit_reachable,
+                    ..ReportConfig::default()
+                },
+                None,
+                None,
+                &|_| {},
+            )
+            .unwrap()
+    };
+    assert_eq!(report(false).reachable_points, None);
+    let report = report(true);
+    let reachable = report.reachable_points.unwrap();
+    assert_eq!(reachable.len(), report.reachable);
+    // x = longitude, y = latitude, like the unreachable points
+    assert!(reachable.iter().all(|p| p.x() <= -99.0 && p.y() >= 40.0));
+    assert!(reachable
+        .iter()
+        .all(|p| !report.unreachable_points.contains(p)));
+}
+
+#[test]
+fn gap_report_counts_add_up_to_grid() {
+    let chargers = chargers_at(&[(40.0, -100.0)]);
+    let bbox = BoundingBox {
+        lat_min: 40.0,
+        lat_max: 45.0,
+        lon_min: -100.0,
+        lon_max: -99.0,
+    };
+    let osrm_url = serve_osrm(r#"{"code":"Ok","distances":[[100000]]}"#);
+    let report = chargers.find_gaps(
+        0.5,
+        bbox,
+        &OsrmClient::new(&osrm_url),
+        None,
+        &RangeConfig::default(),
+        &HullConfig::default(),
+    );
+    let grid_len = bbox.generate_grid(0.5).len();
+    assert_eq!(report.reachable + report.unreachable, grid_len);
+    assert_eq!(report.total(), grid_len);
+    assert_eq!(report.unreachable, report.unreachable_points.len());
+    // Every maybe point is one table request, and OSRM says they're all
+    // reachable.
+    assert!(report.maybe > 0);
+    assert_eq!(report.routed_points, report.maybe);
+    assert!(report.maybe <= report.reachable);
+    assert!(report.unreachable > 0);
+}
+
+#[test]
+fn osrm_retries_give_up_on_unreachable_server() {
+    // Grab a free port, then close it so nothing is listening there
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let osrm = OsrmClient {
+        retry: RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(20),
+        },
+        ..OsrmClient::new(&format!("http://127.0.0.1:{}", port))
+    };
+    let ny = TrialPoint {
+        latitude: 40.730610,
+        longitude: -73.935242,
+    };
+    let start = std::time::Instant::now();
+    assert_eq!(
+        ny.get_osrm_distance(&osrm, &charger(40.0, -74.0, 0)),
+        RouteLookup::Error
+    );
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn osrm_no_route_is_not_an_error() {
+    let (requests, count) = std::sync::mpsc::channel();
+    let osrm_url = serve_osrm_with(move |_| {
+        requests.send(()).unwrap();
+        r#"{"code":"NoRoute","message":"Impossible route between points","routes":[]}"#.to_string()
+    });
+    let osrm = OsrmClient {
+        retry: RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(20),
+        },
+        ..OsrmClient::new(&osrm_url)
+    };
+    let ny = TrialPoint {
+        latitude: 40.730610,
+        longitude: -73.935242,
+    };
+    let island = charger(21.3, -157.8, 0);
+    assert_eq!(ny.get_osrm_distance(&osrm, &island), RouteLookup::NoRoute);
+    // A definite answer isn't retried
+    assert_eq!(count.try_iter().count(), 1);
+    let body: Json = serde_json::from_str(
+        r#"{"code":"NoSegment","message":"Could not find a matching segment"}"#,
+    )
+    .unwrap();
+    assert!(body.routes.is_empty());
+    assert!(OSRM_NO_ROUTE_CODES.contains(&body.code.as_str()));
+}
+
+#[test]
+fn osrm_server_errors_are_retried() {
+    use std::io::{Read, Write};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (requests, count) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let _ = stream.read(&mut [0; 1024]);
+            requests.send(()).unwrap();
+            let body = "<html>502 Bad Gateway</html>";
+            let _ = write!(
+                stream,
+                "HTTP/1.1 502 Bad Gateway\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+        }
+    });
+    let osrm = OsrmClient {
+        retry: RetryPolicy {
+            max_retries: 2,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(20),
+        },
+        ..OsrmClient::new(&url)
+    };
+    let ny = TrialPoint {
+        latitude: 40.730610,
+        longitude: -73.935242,
+    };
+    assert_eq!(
+        ny.get_osrm_distance(&osrm, &charger(40.0, -74.0, 0)),
+        RouteLookup::Error
+    );
+    assert_eq!(count.try_iter().count(), 3);
+}
+
+#[test]
+fn osrm_requests_time_out_on_stalled_server() {
+    // Accepts connections but never answers
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        let _streams: Vec<_> = listener.incoming().collect();
+    });
+    let osrm = OsrmClient {
+        client: http_client(Duration::from_millis(100)).unwrap(),
+        retry: RetryPolicy {
+            max_retries: 0,
+            ..RetryPolicy::default()
+        },
+        ..OsrmClient::new(&url)
+    };
+    let ny = TrialPoint {
+        latitude: 40.730610,
+        longitude: -73.935242,
+    };
+    let start = std::time::Instant::now();
+    assert_eq!(
+        ny.get_osrm_distance(&osrm, &charger(40.0, -74.0, 0)),
+        RouteLookup::Error
+    );
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn retry_delay_is_capped_exponential() {
+    let retry = RetryPolicy::default();
+    assert_eq!(retry.delay(0), Duration::from_secs(1));
+    assert_eq!(retry.delay(1), Duration::from_secs(2));
+    assert_eq!(retry.delay(5), Duration::from_secs(32));
+    assert_eq!(retry.delay(6), Duration::from_secs(60));
+    assert_eq!(retry.delay(100), Duration::from_secs(60));
+}
+
+#[test]
+fn retry_delay_is_jittered_or_what_the_server_asks() {
+    let retry = RetryPolicy::default();
+    // Up to half of the delay comes off, at random
+    assert_eq!(retry.jittered_delay(2, None, 0.0), Duration::from_secs(4));
+    assert_eq!(retry.jittered_delay(2, None, 0.5), Duration::from_secs(3));
+    assert_eq!(retry.jittered_delay(6, None, 1.0), Duration::from_secs(30));
+    let random: Vec<_> = (0..100).map(|_| network::random_fraction()).collect();
+    assert!(random.iter().all(|random| (0.0..1.0).contains(random)));
+    assert!(random.windows(2).any(|pair| pair[0] != pair[1]));
+
+    // A Retry-After is waited out, with a little jitter on top
+    let now = std::time::UNIX_EPOCH + Duration::from_secs(1_445_412_400);
+    let retry_after = network::parse_retry_after("45", now);
+    assert_eq!(retry_after, Some(Duration::from_secs(45)));
+    assert_eq!(
+        retry.jittered_delay(0, retry_after, 0.0),
+        Duration::from_secs(45)
+    );
+    assert_eq!(
+        retry.jittered_delay(5, retry_after, 1.0),
+        Duration::from_millis(45_500)
+    );
+    // 2015-10-21 07:28:00 UTC, 80 seconds after `now`
+    assert_eq!(
+        network::parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT", now),
+        Some(Duration::from_secs(80))
+    );
+    assert_eq!(
+        network::parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+        Some(Duration::ZERO)
+    );
+    assert_eq!(network::parse_retry_after("soon", now), None);
+
+    // but only up to max_delay
+    let day = network::parse_retry_after("86400", now);
+    assert_eq!(retry.jittered_delay(0, day, 0.0), Duration::from_secs(60));
+}
+
+/// An OSRM server that answers the first request with 429 Too Many Requests
+/// and a Retry-After of a day, and then with `OSRM_ROUTE_JSON`.
+fn serve_osrm_throttled_once() -> String {
+    let requests = AtomicUsize::new(0);
+    serve_http_status_with(move |_, _| match requests.fetch_add(1, Relaxed) {
+        0 => (
+            "429 Too Many Requests\r\nRetry-After: 86400",
+            "<html><body>Slow down</body></html>".to_string(),
+        ),
+        _ => ("200 OK", OSRM_ROUTE_JSON.to_string()),
+    })
+}
+
+/// Retries that wait a little longer than `base_delay` if they honor a
+/// capped Retry-After, and much longer if they don't cap it.
+const THROTTLED_RETRY: RetryPolicy = RetryPolicy {
+    max_retries: 1,
+    base_delay: Duration::from_millis(1),
+    max_delay: Duration::from_millis(300),
+};
+
+#[test]
+fn retry_after_is_waited_out_up_to_max_delay() {
+    let osrm = OsrmClient {
+        retry: THROTTLED_RETRY,
+        ..OsrmClient::new(&serve_osrm_throttled_once())
+    };
+    let ny = TrialPoint {
+        latitude: 40.7,
+        longitude: -73.9,
+    };
+    let start = std::time::Instant::now();
+    assert_eq!(
+        ny.get_osrm_distance(&osrm, &charger(33.7, -84.4, 0)),
+        RouteLookup::Reachable(1387012.4)
+    );
+    assert!(start.elapsed() >= THROTTLED_RETRY.max_delay);
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_retry_after_is_waited_out_up_to_max_delay() {
+    let osrm = async_osrm::AsyncOsrmClient {
+        retry: THROTTLED_RETRY,
+        ..async_osrm::AsyncOsrmClient::new(&serve_osrm_throttled_once(), 1)
+    };
+    let ny = TrialPoint {
+        latitude: 40.7,
+        longitude: -73.9,
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let start = std::time::Instant::now();
+    let distance = runtime.block_on(ny.get_osrm_distance_async(&osrm, &charger(33.7, -84.4, 0)));
+    assert_eq!(distance, Some(1387012.4));
+    assert!(start.elapsed() >= THROTTLED_RETRY.max_delay);
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn gap_hull_of_no_points_is_empty() {
+    assert!(gap_hull(&[], &HullConfig::default())
+        .exterior()
+        .0
+        .is_empty());
+    // Everything is right next to the charger, so there are no gaps
+    let chargers = chargers_at(&[(40.0, -100.0)]);
+    let bbox = BoundingBox {
+        lat_min: 40.0,
+        lat_max: 40.1,
+        lon_min: -100.0,
+        lon_max: -99.9,
+    };
+    let report = chargers.find_gaps(
+        0.05,
+        bbox,
+        &OsrmClient::new("http://127.0.0.1:1"),
+        None,
+        &RangeConfig::default(),
+        &HullConfig::default(),
+    );
+    assert_eq!(report.unreachable, 0);
+    assert!(report.polygons.0.is_empty());
+}
+
+#[test]
+fn gap_hull_of_one_point_is_that_point() {
+    let point = geo::Point::new(-100.0, 40.0);
+    let hull = gap_hull(&[point], &HullConfig::default());
+    assert!(!hull.exterior().0.is_empty());
+    assert!(hull.exterior().points().all(|p| p == point));
+    assert!(hull.interiors().is_empty());
+}
+
+#[test]
+fn gap_hull_of_two_points_is_a_line() {
+    let a = geo::Point::new(-100.0, 40.0);
+    let b = geo::Point::new(-99.0, 41.0);
+    let hull = gap_hull(&[a, b], &HullConfig::default());
+    assert_eq!(hull.exterior().points().collect::<Vec<_>>(), [a, b, a]);
+}
+
+#[test]
+fn lower_concavity_gives_tighter_hull() {
+    // A C shape: a 10x10 block of points with the middle of the right side
+    // cut out, which a convex hull would cover up.
+    let mut points = Vec::new();
+    for x in 0..10 {
+        for y in 0..10 {
+            if !(x >= 3 && (3..7).contains(&y)) {
+                points.push(geo::Point::new(x as f64, y as f64));
+            }
+        }
+    }
+    let vertices = |concavity| {
+        gap_hull(
+            &points,
+            &HullConfig {
+                concavity,
+                ..HullConfig::default()
+            },
+        )
+        .exterior()
+        .0
+        .len()
+    };
+    assert!(vertices(1.0) >= vertices(2.0));
+    assert!(vertices(2.0) >= vertices(10.0));
+    assert!(vertices(1.0) > vertices(10.0));
+}
+
+/// A `size`x`size` block of grid points with the middle of the right side
+/// cut out.
+fn c_shape(size: i32) -> Vec<geo::Point<f64>> {
+    let mut points = Vec::new();
+    for x in 0..size {
+        for y in 0..size {
+            if !(x >= size / 3 && (size / 3..size - size / 3).contains(&y)) {
+                points.push(geo::Point::new(x as f64, y as f64));
+            }
+        }
+    }
+    points
+}
+
+#[test]
+fn convex_hull_of_square_is_its_corners() {
+    let mut points = vec![
+        geo::Point::new(0.0, 0.0),
+        geo::Point::new(4.0, 0.0),
+        geo::Point::new(4.0, 4.0),
+        geo::Point::new(0.0, 4.0),
+    ];
+    // Points inside and on the edges aren't vertices
+    points
+        .extend([(1.0, 1.0), (2.0, 3.0), (3.5, 0.5), (2.0, 0.0), (0.0, 2.0)].map(geo::Point::from));
+    let hull = gap_hull(
+        &points,
+        &HullConfig {
+            algorithm: HullAlgorithm::Convex,
+            ..HullConfig::default()
+        },
+    );
+    let mut vertices: Vec<(f64, f64)> = hull.exterior().points().map(|p| p.x_y()).collect();
+    assert_eq!(vertices.first(), vertices.last());
+    vertices.pop();
+    vertices.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(vertices, [(0.0, 0.0), (0.0, 4.0), (4.0, 0.0), (4.0, 4.0)]);
+    assert!(hull.interiors().is_empty());
+}
+
+#[test]
+fn alpha_shape_leaves_out_bays() {
+    let points = c_shape(9);
+    let hull = |algorithm| HullConfig {
+        algorithm,
+        alpha: 1.0,
+        ..HullConfig::default()
+    };
+    let notch = geo::Point::new(6.0, 4.0);
+    let alpha = gap_hulls(&points, &hull(HullAlgorithm::Alpha));
+    assert_eq!(alpha.0.len(), 1);
+    assert!(!alpha.contains(&notch));
+    let area = alpha.unsigned_area();
+    let convex = gap_hulls(&points, &hull(HullAlgorithm::Convex));
+    assert!(convex.contains(&notch));
+    assert!(area < convex.unsigned_area());
+}
+
+#[test]
+fn alpha_shape_can_have_holes() {
+    // A 7x7 block of points with a 3x3 hole in the middle
+    let points: Vec<_> = (0..7)
+        .flat_map(|x| (0..7).map(move |y| (x, y)))
+        .filter(|&(x, y)| !((2..5).contains(&x) && (2..5).contains(&y)))
+        .map(|(x, y)| geo::Point::new(x as f64, y as f64))
+        .collect();
+    let shape = alpha_shape::alpha_shape(&points, 1.0);
+    assert_eq!(shape.0.len(), 1);
+    assert_eq!(shape.0[0].interiors().len(), 1);
+    // 6x6 minus the 4x4 hole, whose corners are cut off by triangles with
+    // a circumradius of √2/2
+    assert!(
+        (shape.unsigned_area() - 22.0).abs() < 1e-3,
+        "{}",
+        shape.unsigned_area()
+    );
+    assert!(!shape.contains(&geo::Point::new(3.0, 3.0)));
+}
+
+#[test]
+fn alpha_shape_splits_separate_blobs() {
+    let blob = |x0: f64| {
+        (0..3).flat_map(move |x| (0..3).map(move |y| geo::Point::new(x0 + x as f64, y as f64)))
+    };
+    let points: Vec<_> = blob(0.0).chain(blob(10.0)).collect();
+    let shape = alpha_shape::alpha_shape(&points, 1.0);
+    assert_eq!(shape.0.len(), 2);
+    assert!((shape.unsigned_area() - 8.0).abs() < 1e-3);
+    // Too small to join neighbouring points
+    assert!(alpha_shape::alpha_shape(&points, 0.5).0.is_empty());
+}
+
+#[test]
+fn simplify_gaps_drops_zig_zags() {
+    // A 10x1 rectangle whose bottom edge zig-zags by 0.01
+    let mut exterior: Vec<(f64, f64)> = (0..=100)
+        .map(|i| (i as f64 / 10.0, if i % 2 == 0 { 0.0 } else { 0.01 }))
+        .collect();
+    exterior.extend([(10.0, 1.0), (0.0, 1.0), (0.0, 0.0)]);
+    let polygons = geo::MultiPolygon(vec![geo::Polygon::new(exterior.into(), vec![])]);
+    let simplified = simplify_gaps(&polygons, 0.05);
+    let vertices = |polygons: &geo::MultiPolygon<f64>| polygons.0[0].exterior().0.len();
+    assert_eq!(vertices(&polygons), 104);
+    assert!(vertices(&simplified) <= 6, "{}", vertices(&simplified));
+    let (before, after) = (polygons.unsigned_area(), simplified.unsigned_area());
+    assert!(
+        (before - after).abs() < 0.01 * before,
+        "{} {}",
+        before,
+        after
+    );
+    // 0 is off
+    assert_eq!(simplify_gaps(&polygons, 0.0), polygons);
+}
+
+#[test]
+fn hull_algorithm_from_str() {
+    assert_eq!("Alpha".parse(), Ok(HullAlgorithm::Alpha));
+    assert_eq!("convex".parse(), Ok(HullAlgorithm::Convex));
+    assert!("round".parse::<HullAlgorithm>().is_err());
+}
+
+#[test]
+fn gap_area_of_one_degree_square() {
+    // A one degree square on the equator is about 111.3km x 110.6km
+    let square = geo::Polygon::new(
+        geo::LineString::from(vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)]),
+        vec![],
+    );
+    let area = gap_area_km2(&square);
+    assert!((12_200.0..12_500.0).contains(&area), "{}", area);
+    // and shrinks further north
+    let northern = geo::Polygon::new(
+        geo::LineString::from(vec![(0.0, 60.0), (1.0, 60.0), (1.0, 61.0), (0.0, 61.0)]),
+        vec![],
+    );
+    assert!(gap_area_km2(&northern) < area * 0.55);
+    assert_eq!(gap_area_km2(&gap_hull(&[], &HullConfig::default())), 0.0);
+}
+
+#[test]
+fn run_summary_adds_up_chunks() {
+    let hull = HullConfig::default();
+    let square = [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)].map(geo::Point::from);
+    let reports = [
+        GapReport::new(square.to_vec(), &hull, 10, 3, 4, Duration::from_secs(5)),
+        GapReport::new(vec![], &hull, 20, 1, 1, Duration::from_secs(7)),
+    ];
+    let summary = RunSummary::new(&reports, 0.5, Duration::from_millis(7500));
+    assert_eq!(summary.total_points, 34);
+    assert_eq!(summary.reachable, 30);
+    assert_eq!(summary.unreachable, 4);
+    assert_eq!(summary.maybe, 4);
+    assert_eq!(summary.routed_points, 5);
+    assert_eq!(summary.elapsed_secs, 7.5);
+    assert_eq!(summary.gap_area_km2, reports[0].area_km2);
+    assert!(summary.gap_area_km2 > 12_000.0);
+
+    let path = temp_path("summary.json");
+    summary.write(path.to_str().unwrap()).unwrap();
+    let json: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(json["total_points"], 34);
+    assert_eq!(json["routed_points"], 5);
+    assert_eq!(json["resolution"], 0.5);
+    assert_eq!(json["elapsed_secs"], 7.5);
+}
+
+#[test]
+fn population_counts_people_inside_gaps() {
+    let csv = "lat,lon,population
+40.5,-99.5,1000
+40.5,-99.4,250
+41.5,-99.5,70000
+bad,row,1
+";
+    let population = population::Population::from_csv(csv::Reader::from_reader(csv.as_bytes()));
+    // A gap around the first two points, but not the town to the north
+    let gap = geo::MultiPolygon(vec![geo::Polygon::new(
+        geo::LineString::from(vec![
+            (-100.0, 40.0),
+            (-99.0, 40.0),
+            (-99.0, 41.0),
+            (-100.0, 41.0),
+            (-100.0, 40.0),
+        ]),
+        vec![],
+    )]);
+    assert_eq!(population.within(&gap), 1250.0);
+    assert_eq!(population.within(&geo::MultiPolygon(vec![])), 0.0);
+
+    let corners = [(-100.0, 40.0), (-99.0, 40.0), (-99.0, 41.0), (-100.0, 41.0)];
+    let report = GapReport::new(
+        corners.map(geo::Point::from).to_vec(),
+        &HullConfig::default(),
+        0,
+        0,
+        0,
+        Duration::ZERO,
+    );
+    assert_eq!(report.priority_score, None);
+    assert_eq!(
+        report.with_population(&population).priority_score,
+        Some(1250.0)
+    );
+}
+
+/// A path in the system temp dir that is unique to this test run.
+fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("ev-charging-gaps-{}-{}", std::process::id(), name))
+}
+
+#[test]
+fn osrm_cache_persists_distances() {
+    let path = temp_path("osrm_cache.json");
+    let _ = std::fs::remove_file(&path);
+    let osrm_url = serve_osrm(r#"{"code":"Ok","routes":[{"distance":1234.5,"duration":60.0}]}"#);
+    let ny = TrialPoint {
+        latitude: 40.730610,
+        longitude: -73.935242,
+    };
+    let charger = charger(33.75, -84.4, 666);
+    let mut osrm = OsrmClient::new(&osrm_url);
+    osrm.cache = Some(Arc::new(OsrmCache::load(&path).unwrap()));
+    assert_eq!(
+        ny.get_osrm_distance(&osrm, &charger),
+        RouteLookup::Reachable(1234.5)
+    );
+    osrm.cache.as_ref().unwrap().flush().unwrap();
+
+    // A fresh cache loaded from disk answers without a working server, even
+    // for a point that's only within rounding distance of the original.
+    let cache = OsrmCache::load(&path).unwrap();
+    assert_eq!(cache.len(), 1);
+    let osrm = OsrmClient {
+        cache: Some(Arc::new(cache)),
+        ..OsrmClient::new("http://127.0.0.1:1")
+    };
+    let nearby = TrialPoint {
+        latitude: ny.latitude + 0.000_001,
+        longitude: ny.longitude,
+    };
+    assert_eq!(
+        nearby.get_osrm_distance(&osrm, &charger),
+        RouteLookup::Reachable(1234.5)
+    );
+    assert_eq!(
+        nearby.get_osrm_table_distances(&osrm, &[charger]),
+        [RouteLookup::Reachable(1234.5)]
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn read_errors_are_distinguishable() {
+    let missing = read_from_file("/nonexistent/chargers.csv", &ChargerFilter::default());
+    assert!(
+        matches!(missing, Err(GapError::Io(_))),
+        "{:?}",
+        missing.err()
+    );
+
+    let only_tesla = "ID,Latitude,Longitude,EV Network\n1,40.0,-100.0,Tesla\n";
+    let empty = read_csv(
+        csv::Reader::from_reader(only_tesla.as_bytes()),
+        &ChargerFilter::default(),
+    );
+    assert!(
+        matches!(empty, Err(GapError::EmptyData)),
+        "{:?}",
+        empty.err()
+    );
+}
+
+#[test]
+fn nrel_errors_are_not_read_as_csv() {
+    let url = serve_http_status_with(|path, _| match path {
+        "/forbidden" => (
+            "403 Forbidden",
+            r#"{"error":{"code":"API_KEY_INVALID","message":"An invalid api_key was supplied."}}"#
+                .to_string(),
+        ),
+        "/throttled" => (
+            "429 Too Many Requests",
+            "<html><body>Slow down</body></html>".to_string(),
+        ),
+        "/ok-but-json" => ("200 OK", r#"{"errors":["state is invalid"]}"#.to_string()),
+        _ => (
+            "200 OK",
+            "\u{feff}ID,Latitude,Longitude,EV Network\n1,40.0,-100.0,ChargePoint\n".to_string(),
+        ),
+    });
+    let fetch = |path: &str| {
+        fetch_nrel(
+            url::Url::parse(&format!("{}{}", url, path)).unwrap(),
+            NrelFormat::Csv,
+        )
+    };
+
+    let error = fetch("/forbidden").unwrap_err();
+    assert!(
+        matches!(&error, GapError::NrelApi { status: 403, message }
+            if message == "API_KEY_INVALID: An invalid api_key was supplied."),
+        "{:?}",
+        error
+    );
+    assert!(error.to_string().contains("An invalid api_key"));
+    assert!(matches!(
+        fetch("/throttled"),
+        Err(GapError::NrelApi { status: 429, message }) if message.contains("Slow down")
+    ));
+    assert!(matches!(
+        fetch("/ok-but-json"),
+        Err(GapError::NrelApi { status: 200, message }) if message == "state is invalid"
+    ));
+    let body = fetch("/csv").unwrap();
+    let chargers = read_csv(
+        csv::Reader::from_reader(body.as_bytes()),
+        &ChargerFilter::default(),
+    )
+    .unwrap();
+    assert_eq!(chargers.chargers_by_id.len(), 1);
+}
+
+#[test]
+fn bounding_box_from_str() {
+    let bbox: BoundingBox = "32.5, -124.5, 42.0, -114.1".parse().unwrap();
+    assert_eq!(
+        bbox,
+        BoundingBox {
+            lat_min: 32.5,
+            lon_min: -124.5,
+            lat_max: 42.0,
+            lon_max: -114.1,
+        }
+    );
+    for invalid in [
+        "32.5,-124.5,42.0",
+        "32.5,-124.5,42.0,-114.1,0",
+        "a,b,c,d",
+        // min > max
+        "42.0,-124.5,32.5,-114.1",
+        "32.5,-114.1,42.0,-124.5",
+        // out of range
+        "32.5,-190.0,42.0,-114.1",
+        "-95.0,-124.5,42.0,-114.1",
+    ] {
+        assert!(
+            invalid.parse::<BoundingBox>().is_err(),
+            "{:?} should be rejected",
+            invalid
+        );
+    }
+}
+
+#[test]
+fn continental_us_is_valid() {
+    assert_eq!(CONTINENTAL_US, US_BOUNDING_BOX);
+    assert!(CONTINENTAL_US.validate().is_ok());
+}
+
+#[test]
+fn bounding_box_across_the_antimeridian() {
+    // The western Aleutians, from 172°E east to 165°W
+    let aleutians = BoundingBox {
+        lat_min: 51.0,
+        lon_min: 172.0,
+        lat_max: 55.0,
+        lon_max: -165.0,
+    };
+    assert!(aleutians.crosses_antimeridian());
+    assert!(!US_BOUNDING_BOX.crosses_antimeridian());
+    let error = aleutians.validate().unwrap_err();
+    assert!(error.contains("antimeridian"), "{}", error);
+    assert!("51,172,55,-165".parse::<BoundingBox>().is_err());
+}
+
+#[test]
+fn chunks_tile_bounding_box_exactly() {
+    for n_chunks in [1, 3, 4, 7, 8, 12, 128] {
+        let chunks = US_BOUNDING_BOX.chunkify(n_chunks);
+        assert_eq!(chunks.first().unwrap().lat_min, US_BOUNDING_BOX.lat_min);
+        assert_eq!(chunks.last().unwrap().lat_max, US_BOUNDING_BOX.lat_max);
+        for pair in chunks.windows(2) {
+            // Each band starts exactly where the previous one ends
+            assert_eq!(pair[0].lat_max, pair[1].lat_min);
+        }
+        for chunk in &chunks {
+            assert!(chunk.lat_min < chunk.lat_max);
+            assert_eq!(chunk.lon_min, US_BOUNDING_BOX.lon_min);
+            assert_eq!(chunk.lon_max, US_BOUNDING_BOX.lon_max);
+        }
+    }
+}
+
+#[test]
+fn grid_chunks_tile_bounding_box_exactly() {
+    for (rows, cols) in [(1, 1), (1, 5), (3, 1), (4, 7), (16, 16)] {
+        let chunks = US_BOUNDING_BOX.chunkify_grid(rows, cols);
+        assert_eq!(chunks.len(), rows * cols);
+        for (i, chunk) in chunks.iter().enumerate() {
+            let (row, col) = (i / cols, i % cols);
+            assert!(chunk.lat_min < chunk.lat_max);
+            assert!(chunk.lon_min < chunk.lon_max);
+            // Tiles on the edges are on the edges of the box, and the others
+            // start exactly where their neighbour to the south or west ends
+            if row == 0 {
+                assert_eq!(chunk.lat_min, US_BOUNDING_BOX.lat_min);
+            } else {
+                assert_eq!(chunk.lat_min, chunks[i - cols].lat_max);
+            }
+            if row == rows - 1 {
+                assert_eq!(chunk.lat_max, US_BOUNDING_BOX.lat_max);
+            }
+            if col == 0 {
+                assert_eq!(chunk.lon_min, US_BOUNDING_BOX.lon_min);
+            } else {
+                assert_eq!(chunk.lon_min, chunks[i - 1].lon_max);
+                assert_eq!(chunk.lat_min, chunks[i - 1].lat_min);
+                assert_eq!(chunk.lat_max, chunks[i - 1].lat_max);
+            }
+            if col == cols - 1 {
+                assert_eq!(chunk.lon_max, US_BOUNDING_BOX.lon_max);
+            }
+        }
+        // Same size, so the areas add up to the whole box
+        let area: f64 = chunks.iter().map(|c| c.lat_span() * c.lon_span()).sum();
+        let total = US_BOUNDING_BOX.lat_span() * US_BOUNDING_BOX.lon_span();
+        assert!((area - total).abs() < 1e-9 * total, "{} != {}", area, total);
+        for chunk in &chunks {
+            let (lat_span, lon_span) = (
+                US_BOUNDING_BOX.lat_span() / rows as f64,
+                US_BOUNDING_BOX.lon_span() / cols as f64,
+            );
+            assert!((chunk.lat_span() - lat_span).abs() < 1e-9 * lat_span);
+            assert!((chunk.lon_span() - lon_span).abs() < 1e-9 * lon_span);
+        }
+    }
+    assert_eq!(
+        US_BOUNDING_BOX.chunkify_grid(5, 1),
+        US_BOUNDING_BOX.chunkify(5)
+    );
+}
+
+#[test]
+fn grid_shape_gives_square_tiles() {
+    // Twice as wide (in longitude) as it is tall
+    let bbox = BoundingBox {
+        lat_min: 30.0,
+        lat_max: 40.0,
+        lon_min: -120.0,
+        lon_max: -100.0,
+    };
+    assert_eq!(bbox.grid_shape(32), (4, 8));
+    assert_eq!(bbox.grid_shape(1), (1, 1));
+    let (rows, cols) = bbox.grid_shape(30);
+    assert!(rows * cols >= 30);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn find_gaps_async_matches_blocking() {
+    use async_osrm::AsyncOsrmClient;
+    let chargers = chargers_at(&[(40.0, -100.0)]);
+    let bbox = BoundingBox {
+        lat_min: 40.0,
+        lat_max: 45.0,
+        lon_min: -100.0,
+        lon_max: -99.0,
+    };
+    // Everything within 50 candidates is 300km away by road
+    let osrm_url = serve_osrm(
+        r#"{"code":"Ok","routes":[{"distance":300000,"duration":10800}],"distances":[[300000]]}"#,
+    );
+    let config = RangeConfig::default();
+    let blocking = chargers
+        .find_gaps(
+            0.5,
+            bbox,
+            &OsrmClient::new(&osrm_url),
+            None,
+            &config,
+            &HullConfig::default(),
+        )
+        .unreachable_points;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let osrm = AsyncOsrmClient::new(&osrm_url, 8);
+    let report = runtime.block_on(chargers.find_gaps_async(
+        0.5,
+        bbox,
+        &osrm,
+        None,
+        &config,
+        &HullConfig::default(),
+        &ReportConfig::default(),
+    ));
+    assert!(!report.unreachable_points.is_empty());
+    assert_eq!(report.unreachable_points, blocking);
+    assert_eq!(report.total(), bbox.generate_grid(0.5).len());
+}
+
+const LEVELS_CSV: &str = "\
+ID,Latitude,Longitude,EV Network,EV Level2 EVSE Num,EV DC Fast Count
+1,40.0,-100.0,ChargePoint Network,2,
+2,40.1,-100.1,Electrify America,,4
+3,40.2,-100.2,EVgo Network,1,2
+4,40.3,-100.3,Non-Networked,0,0
+";
+
+#[test]
+fn read_csv_filters_by_charging_level() {
+    let read = |level| {
+        let filter = ChargerFilter {
+            level,
+            ..ChargerFilter::default()
+        };
+        let chargers = read_csv(csv::Reader::from_reader(LEVELS_CSV.as_bytes()), &filter).unwrap();
+        station_ids(&chargers)
+    };
+    assert_eq!(read(ChargingLevel::All), [1, 2, 3, 4]);
+    assert_eq!(read(ChargingLevel::L2), [1, 3]);
+    assert_eq!(read(ChargingLevel::DcFast), [2, 3]);
+
+    let chargers = read_csv(
+        csv::Reader::from_reader(LEVELS_CSV.as_bytes()),
+        &ChargerFilter::default(),
+    )
+    .unwrap();
+    let evgo = chargers
+        .chargers_by_id
+        .values()
+        .find(|c| c.station_id() == "3")
+        .unwrap();
+    assert_eq!(
+        evgo.levels,
+        ChargingLevels {
+            level2: true,
+            dc_fast: true
+        }
+    );
+    assert_eq!("dcfast".parse(), Ok(ChargingLevel::DcFast));
+    assert!("level3".parse::<ChargingLevel>().is_err());
+}
+
+#[test]
+fn parse_open_date_reads_nrel_dates() {
+    let date = |y, m, d| chrono::NaiveDate::from_ymd_opt(y, m, d);
+    assert_eq!(parse_open_date("2021-12-31"), date(2021, 12, 31));
+    assert_eq!(parse_open_date(" 2010-08-01 "), date(2010, 8, 1));
+    assert_eq!(parse_open_date("12/31/2021"), date(2021, 12, 31));
+    assert_eq!(parse_open_date(""), None);
+    assert_eq!(parse_open_date("2021-13-01"), None);
+    assert_eq!(parse_open_date("soon"), None);
+}
+
+#[test]
+fn read_csv_reads_access() {
+    let csv = "\
+ID,Latitude,Longitude,EV Network,Access Code,Groups With Access Code
+1,40.0,-100.0,ChargePoint Network,public,Public
+2,40.1,-100.1,ChargePoint Network,private,Private - Fleet use only
+3,40.2,-100.2,ChargePoint Network,,Private - Government only
+4,40.3,-100.3,ChargePoint Network,PUBLIC,
+5,40.4,-100.4,ChargePoint Network,,
+";
+    let read = |public_only| {
+        let filter = ChargerFilter {
+            public_only,
+            ..ChargerFilter::default()
+        };
+        let chargers = read_csv(csv::Reader::from_reader(csv.as_bytes()), &filter).unwrap();
+        let mut public: Vec<(u64, bool)> = chargers
+            .chargers_by_id
+            .values()
+            .map(|c| (c.station_id().parse().unwrap(), c.is_public()))
+            .collect();
+        public.sort();
+        public
+    };
+    assert_eq!(
+        read(false),
+        vec![(1, true), (2, false), (3, false), (4, true), (5, true)]
+    );
+    assert_eq!(read(true), vec![(1, true), (4, true), (5, true)]);
+}
+
+#[test]
+fn read_csv_filters_by_open_date() {
+    let csv = "\
+ID,Latitude,Longitude,EV Network,Open Date
+1,40.0,-100.0,ChargePoint Network,2015-06-01
+2,40.1,-100.1,ChargePoint Network,2021-12-31
+3,40.2,-100.2,ChargePoint Network,2022-01-01
+4,40.3,-100.3,ChargePoint Network,
+5,40.4,-100.4,ChargePoint Network,not a date
+";
+    let read = |as_of| {
+        let filter = ChargerFilter {
+            as_of,
+            ..ChargerFilter::default()
+        };
+        let chargers = read_csv(csv::Reader::from_reader(csv.as_bytes()), &filter).unwrap();
+        station_ids(&chargers)
+    };
+    assert_eq!(read(None), [1, 2, 3, 4, 5]);
+    // Undated chargers are kept
+    assert_eq!(read(parse_open_date("2021-12-31")), [1, 2, 4, 5]);
+    assert_eq!(read(parse_open_date("2000-01-01")), [4, 5]);
+
+    let chargers = read_csv(
+        csv::Reader::from_reader(csv.as_bytes()),
+        &ChargerFilter::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        chargers.by_station_id("3").unwrap().open_date(),
+        parse_open_date("2022-01-01")
+    );
+    assert_eq!(chargers.by_station_id("5").unwrap().open_date(), None);
+}
+
+#[test]
+fn multi_hop_keeps_chargers_connected_to_origin() {
+    // A chain of chargers ~333km apart going north, and a separate pair far
+    // to the east.
+    let chargers = chargers_at(&[
+        (40.0, -100.0),
+        (43.0, -100.0),
+        (46.0, -100.0),
+        (40.0, -80.0),
+        (40.5, -80.0),
+    ]);
+    let config = RangeConfig::default();
+    assert_eq!(
+        chargers.charger_components(&config),
+        [vec![0, 1, 2], vec![3, 4]]
+    );
+    let ids = |chargers: AllChargerLocations| {
+        let mut ids: Vec<_> = chargers.chargers_by_id.into_keys().collect();
+        ids.sort_unstable();
+        ids
+    };
+    let around = |lat: f64, lon: f64| {
+        MultiPolygon(vec![geo::Rect::new(
+            geo::Coordinate {
+                x: lon - 0.5,
+                y: lat - 0.5,
+            },
+            geo::Coordinate {
+                x: lon + 0.5,
+                y: lat + 0.5,
+            },
+        )
+        .to_polygon()])
+    };
+    assert_eq!(
+        ids(chargers.reachable_from(Some(&around(40.0, -80.0)), &config)),
+        [3, 4]
+    );
+    // The far end of the chain is out of range of the origin, but reachable
+    // with a stop on the way.
+    let from_west = chargers.reachable_from(Some(&around(40.0, -100.0)), &config);
+    let north = TrialPoint {
+        latitude: 49.0,
+        longitude: -100.0,
+    };
+    assert!(is_yes(north.check_charger(
+        &from_west,
+        &RangeConfig {
+            crow_flies_ratio: 1.0,
+            ..config
+        }
+    )));
+    assert_eq!(ids(from_west), [0, 1, 2]);
+    // Without an origin, the largest group is used
+    assert_eq!(ids(chargers.reachable_from(None, &config)), [0, 1, 2]);
+    assert!(chargers
+        .reachable_from(Some(&around(0.0, 0.0)), &config)
+        .chargers_by_id
+        .is_empty());
+}
+
+#[test]
+fn multi_hop_from_anchors() {
+    // The same chain going north and pair to the east as above
+    let chargers = chargers_at(&[
+        (40.0, -100.0),
+        (43.0, -100.0),
+        (46.0, -100.0),
+        (40.0, -80.0),
+        (40.5, -80.0),
+    ]);
+    let config = RangeConfig {
+        crow_flies_ratio: 1.0,
+        ..RangeConfig::default()
+    };
+    let anchors = multi_hop::parse_anchors("38.0,-100.0; 30,-60").unwrap();
+    let reachable = chargers.reachable_from_anchors(&anchors, &config);
+    let mut ids: Vec<_> = reachable.chargers_by_id.keys().copied().collect();
+    ids.sort_unstable();
+    // The chain is in range of the first anchor, the pair of neither, and
+    // the anchors count as chargers themselves
+    assert_eq!(
+        ids,
+        [
+            0,
+            1,
+            2,
+            multi_hop::ANCHOR_ID_MAX - 1,
+            multi_hop::ANCHOR_ID_MAX
+        ]
+    );
+    let point = |latitude, longitude| TrialPoint {
+        latitude,
+        longitude,
+    };
+    // North of the chain, through the chargers
+    assert!(is_yes(
+        point(49.0, -100.0).check_charger(&reachable, &config)
+    ));
+    // In range of the second anchor, with no chargers near it
+    assert!(is_yes(
+        point(31.0, -62.0).check_charger(&reachable, &config)
+    ));
+    // Next to the pair, which no anchor can reach
+    assert!(matches!(
+        point(41.0, -80.0).check_charger(&reachable, &config),
+        CheckResult::No
+    ));
+
+    assert_eq!(
+        multi_hop::parse_anchors("# cities\n40.7,-74.0\n\n41.9,-87.6\n")
+            .unwrap()
+            .len(),
+        2
+    );
+    assert!(multi_hop::parse_anchors("40.7").is_err());
+    assert!(multi_hop::parse_anchors("95,-74").is_err());
+}
+
+#[test]
+fn config_file_parses_options() {
+    use config_file::{parse_config, ConfigValue};
+    let text = r#"
+# Northeast, L2 only
+resolution = 0.05
+level = "l2"  # not "all"
+output = 'output/ne #1.shp'
+refresh = true
+verbose = 2
+exclude_network = [
+    "Tesla",   # superchargers
+    "Non-Networked",
+]
+"#;
+    let pairs = parse_config(text).unwrap();
+    assert_eq!(
+        pairs,
+        [
+            (
+                "resolution".to_string(),
+                ConfigValue::Number("0.05".to_string())
+            ),
+            ("level".to_string(), ConfigValue::String("l2".to_string())),
+            (
+                "output".to_string(),
+                ConfigValue::String("output/ne #1.shp".to_string())
+            ),
+            ("refresh".to_string(), ConfigValue::Bool(true)),
+            ("verbose".to_string(), ConfigValue::Number("2".to_string())),
+            (
+                "exclude_network".to_string(),
+                ConfigValue::Array(vec![
+                    ConfigValue::String("Tesla".to_string()),
+                    ConfigValue::String("Non-Networked".to_string()),
+                ])
+            ),
+        ]
+    );
+    assert!(parse_config("[run]\nresolution = 0.05").is_err());
+    assert!(parse_config("resolution = 0.05\nresolution = 0.1").is_err());
+    assert!(parse_config("level = l2").is_err());
+    assert!(parse_config("exclude_network = [\"Tesla\"").is_err());
+}
+
+#[test]
+fn config_file_becomes_arguments() {
+    use config_file::{config_args, parse_config, OptionKind};
+    let kind = |long: &str| match long {
+        "refresh" | "clip" | "verbose" => Some(OptionKind::Flag),
+        "lat-min" | "exclude-network" => Some(OptionKind::Value),
+        _ => None,
+    };
+    let pairs = parse_config(
+        "refresh = true\nclip = false\nverbose = 2\nlat_min = -10.5\nexclude-network = [\"Tesla\", \"EVgo\"]",
+    )
+    .unwrap();
+    assert_eq!(
+        config_args(&pairs, kind).unwrap(),
+        [
+            "--refresh",
+            "--verbose",
+            "--verbose",
+            "--lat-min=-10.5",
+            "--exclude-network=Tesla",
+            "--exclude-network=EVgo",
+        ]
+    );
+    assert!(config_args(&parse_config("resolutoin = 0.05").unwrap(), kind).is_err());
+    assert!(config_args(&parse_config("refresh = \"yes\"").unwrap(), kind).is_err());
+}
+
+#[test]
+fn distance_units_convert_to_meters() {
+    assert_eq!(DistanceUnit::Meters.to_meters(400_000.0), 400_000.0);
+    assert_eq!(DistanceUnit::Kilometers.to_meters(400.0), 400_000.0);
+    assert_eq!(DistanceUnit::Miles.to_meters(250.0), 402_336.0);
+    assert_eq!("miles".parse(), Ok(DistanceUnit::Miles));
+    assert_eq!("KM".parse(), Ok(DistanceUnit::Kilometers));
+    assert!("furlongs".parse::<DistanceUnit>().is_err());
+}
+
+#[test]
+fn resolution_from_km_matches_distance_on_the_ground() {
+    let degrees = resolution_from_km(10.0);
+    assert!((degrees - 0.0899).abs() < 0.0001);
+    // North to south, grid points are 10 km apart
+    let south = TrialPoint {
+        latitude: 40.0,
+        longitude: -100.0,
+    };
+    let north = charger(40.0 + degrees, -100.0, 1);
+    assert!((south.distance_to(&north) - 10_000.0).abs() < 1.0);
+    // Round trip through add_meters_to_coords
+    let (lat, _) = add_meters_to_coords(10_000.0, (40.0, -100.0));
+    assert!((lat - 40.0 - degrees).abs() < 1e-12);
+}
+
+#[test]
+fn search_envelope_covers_geodesic_range() {
+    use geographiclib_rs::{DirectGeodesic, Geodesic};
+    let wgs84 = Geodesic::wgs84();
+    let range = 400_000.0;
+    for lat in [0.0, 25.0, 45.0, 60.0, 70.0, -65.0] {
+        let point = TrialPoint {
+            latitude: lat,
+            longitude: -150.0,
+        };
+        let envelope = point.search_envelope(range as u64);
+        let ([lat_min, lon_min], [lat_max, lon_max]) = (envelope.lower(), envelope.upper());
+        // Every point `range` away on the ellipsoid is inside the box
+        let mut widest: f64 = 0.0;
+        for azimuth in (0..360).step_by(5) {
+            let (lat2, lon2): (f64, f64) = wgs84.direct(lat, -150.0, azimuth as f64, range);
+            assert!((lat_min..=lat_max).contains(&lat2), "{} {}", lat, azimuth);
+            assert!((lon_min..=lon_max).contains(&lon2), "{} {}", lat, azimuth);
+            widest = widest.max((lon2 + 150.0).abs());
+        }
+        // and the box isn't much bigger than it needs to be
+        let (north, _): (f64, f64) = wgs84.direct(lat, -150.0, 0.0, range);
+        assert!(lat_max - north < 0.5, "{}: {} vs {}", lat, lat_max, north);
+        assert!(
+            lon_max + 150.0 < 1.5 * widest,
+            "{}: {} vs {}",
+            lat,
+            lon_max,
+            widest
+        );
+    }
+    // Near a pole the box takes in every longitude rather than blowing up
+    let envelope = TrialPoint {
+        latitude: 87.0,
+        longitude: 10.0,
+    }
+    .search_envelope(range as u64);
+    assert_eq!(envelope.lower()[1], -180.0);
+    assert_eq!(envelope.upper(), [90.0, 180.0]);
+}
+
+#[test]
+fn find_gaps_reports_progress() {
+    let chargers = chargers_at(&[(40.0, -100.0)]);
+    let bbox = BoundingBox {
+        lat_min: 39.0,
+        lat_max: 41.0,
+        lon_min: -101.0,
+        lon_max: -99.0,
+    };
+    let config = RangeConfig {
+        max_range_meters: 50_000,
+        crow_flies_ratio: 0.5,
+        ..RangeConfig::default()
+    };
+    let routes = MockRoutes::new(&[]);
+    let events = std::sync::Mutex::new(Vec::new());
+    let report = chargers
+        .find_gaps_with_progress(
+            0.05,
+            bbox,
+            &routes,
+            None,
+            None,
+            &config,
+            &HullConfig::default(),
+            &ReportConfig::default(),
+            None,
+            None,
+            &|progress| events.lock().unwrap().push(*progress),
+        )
+        .unwrap();
+    let events = events.into_inner().unwrap();
+    let total = bbox.generate_grid(0.05).len();
+    assert_eq!(events.len(), total.div_ceil(PROGRESS_INTERVAL));
+    for (i, progress) in events.iter().enumerate() {
+        assert_eq!(progress.processed, i * PROGRESS_INTERVAL + 1);
+        assert_eq!(progress.total, total);
+        assert_eq!(
+            progress.reachable + progress.unreachable,
+            progress.processed
+        );
+        assert!(progress.maybe <= report.maybe);
+    }
+    assert_eq!(report.total(), total);
+    let json = serde_json::to_value(events[0]).unwrap();
+    assert_eq!(json["processed"], 1);
+    assert_eq!(json["total"], total);
+}
+
+#[test]
+fn geojson_gaps_can_be_queried() {
+    // What `--format geojson` writes is read back by `query --gaps`
+    let chargers = chargers_at(&[(40.0, -100.0)]);
+    let bbox = BoundingBox {
+        lat_min: 38.0,
+        lat_max: 42.0,
+        lon_min: -102.0,
+        lon_max: -98.0,
+    };
+    let config = RangeConfig {
+        max_range_meters: 100_000,
+        crow_flies_ratio: 1.0,
+        ..RangeConfig::default()
+    };
+    let hull = HullConfig {
+        algorithm: HullAlgorithm::Convex,
+        cluster_eps: 0.5,
+        ..HullConfig::default()
+    };
+    let report = chargers
+        .find_gaps_with_progress(
+            0.25,
+            bbox,
+            &MockRoutes::new(&[]),
+            None,
+            None,
+            &config,
+            &hull,
+            &ReportConfig {
+                emit_reachable: true,
+                ..ReportConfig::default()
+            },
+            None,
+            None,
+            &|_| {},
+        )
+        .unwrap();
+    let gap = ChunkGap {
+        chunk_id: 0,
+        point_count: report.unreachable,
+        polygons: report.polygons.clone(),
+        area_km2: report.area_km2,
+        priority_score: None,
+    };
+    let path = temp_path("query.geojson");
+    geojson::write_gaps_geojson(
+        path.to_str().unwrap(),
+        &[gap],
+        report.reachable_points.as_deref().unwrap(),
+    )
+    .unwrap();
+    let gaps = read_region(path.to_str().unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(gaps, report.polygons);
+    let at = |latitude, longitude| TrialPoint {
+        latitude,
+        longitude,
+    };
+    assert!(at(38.1, -101.9)
+        .locate_in_gaps(&gaps)
+        .unwrap()
+        .inside
+        .is_some());
+    assert_eq!(at(45.0, -100.0).locate_in_gaps(&gaps).unwrap().inside, None);
+}
+
+#[test]
+fn locate_in_gaps_finds_the_containing_gap() {
+    let square = |lat: f64, lon: f64, half: f64| {
+        geo::Polygon::new(
+            geo::LineString::from(vec![
+                (lon - half, lat - half),
+                (lon + half, lat - half),
+                (lon + half, lat + half),
+                (lon - half, lat + half),
+                (lon - half, lat - half),
+            ]),
+            vec![],
+        )
+    };
+    let gaps = geo::MultiPolygon(vec![square(30.0, -90.0, 0.5), square(40.0, -100.0, 0.5)]);
+    let at = |latitude, longitude| TrialPoint {
+        latitude,
+        longitude,
+    };
+    let close = |a: f64, b: f64| (a - b).abs() < 0.01 * b;
+
+    // East and west edges are closer than north and south ones at 40°
+    let middle = at(40.0, -100.0).locate_in_gaps(&gaps).unwrap();
+    assert_eq!(middle.inside, Some(1));
+    assert_eq!(middle.nearest, 1);
+    let east = at(40.0, -99.5);
+    let east = at(40.0, -100.0).distance_to(&charger(east.latitude, east.longitude, 0));
+    assert!(
+        close(middle.boundary_meters, east),
+        "{}",
+        middle.boundary_meters
+    );
+
+    // A degree north of the second gap is nearer it than the first
+    let north = at(41.5, -100.0).locate_in_gaps(&gaps).unwrap();
+    assert_eq!(north.inside, None);
+    assert_eq!(north.nearest, 1);
+    let edge = at(41.5, -100.0).distance_to(&charger(40.5, -100.0, 0));
+    assert!(
+        close(north.boundary_meters, edge),
+        "{}",
+        north.boundary_meters
+    );
+
+    assert_eq!(
+        at(40.0, -100.0).locate_in_gaps(&geo::MultiPolygon(vec![])),
+        None
+    );
+}
+
+#[test]
+fn diff_gaps_finds_closed_and_new_gaps() {
+    use std::time::Duration;
+    // Two 10x10 blocks of unreachable points on the same 0.01 degree grid,
+    // sharing four columns
+    let block = |first_column: usize| -> Vec<geo::Point<f64>> {
+        (0..10)
+            .flat_map(|lat| {
+                (first_column..first_column + 10).map(move |lon| {
+                    geo::Point::new(-100.0 + lon as f64 * 0.01, 40.0 + lat as f64 * 0.01)
+                })
+            })
+            .collect()
+    };
+    let hull = HullConfig {
+        cluster_eps: 0.015,
+        ..HullConfig::default()
+    };
+    let report = |points| GapReport::new(points, &hull, 0, 0, 0, Duration::ZERO);
+    let old = [report(block(0))];
+    // Split into chunks differently, which doesn't matter
+    let new_points = block(6);
+    let new = [
+        report(new_points[..50].to_vec()),
+        report(new_points[50..].to_vec()),
+    ];
+    let diff = diff::diff_gaps(&old, &new, &hull);
+    assert_eq!(diff.closed_points.len(), 60);
+    assert_eq!(diff.opened_points.len(), 60);
+    assert!(diff
+        .closed_points
+        .iter()
+        .all(|point| point.x() < -99.94 - 1e-9));
+    assert!(diff
+        .opened_points
+        .iter()
+        .all(|point| point.x() > -99.91 + 1e-9));
+    // One block each, 0.05 x 0.09 degrees around the points
+    assert_eq!(diff.closed.0.len(), 1);
+    assert_eq!(diff.opened.0.len(), 1);
+    assert!((diff.closed.unsigned_area() - 0.05 * 0.09).abs() < 1e-9);
+    assert!((diff.closed_area_km2() - diff.opened_area_km2()).abs() < 0.01);
+
+    let collection = geojson::gap_diff_feature_collection(&diff);
+    let features = collection["features"].as_array().unwrap();
+    assert_eq!(features[0]["properties"]["change"], "closed");
+    assert_eq!(features[0]["properties"]["point_count"], 60);
+    assert_eq!(features[1]["properties"]["change"], "new");
+    assert_eq!(features[1]["geometry"]["type"], "MultiPolygon");
+
+    let unchanged = diff::diff_gaps(&old, &old, &hull);
+    assert!(unchanged.closed_points.is_empty() && unchanged.opened_points.is_empty());
+    assert!(unchanged.closed.0.is_empty());
+}
+
+#[test]
+fn partial_results_are_written_per_chunk() {
+    let chargers = chargers_at(&[(40.0, -100.0)]);
+    let bbox = BoundingBox {
+        lat_min: 39.0,
+        lat_max: 41.0,
+        lon_min: -101.0,
+        lon_max: -99.0,
+    };
+    let routes = MockRoutes::new(&[]);
+    let dir = temp_path("partial");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let reports = run_gap_analysis(GapConfig {
+        resolution: Resolution::square(0.05),
+        range: RangeConfig {
+            max_range_meters: 50_000,
+            crow_flies_ratio: 0.5,
+            ..RangeConfig::default()
+        },
+        chunks: 4,
+        partial_dir: Some(&dir),
+        ..GapConfig::new(&chargers, &routes, bbox)
+    })
+    .unwrap();
+    let mut files: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    let expected: Vec<_> = (0..reports.len())
+        .map(|chunk| format!("chunk_{}.geojson", chunk))
+        .collect();
+    assert_eq!(files, expected);
+    for (chunk, report) in reports.iter().enumerate() {
+        let contents = std::fs::read_to_string(dir.join(&expected[chunk])).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&contents).unwrap();
+        let properties = &json["features"][0]["properties"];
+        assert_eq!(properties["chunk"], chunk);
+        assert_eq!(properties["point_count"], report.unreachable);
+        assert!(properties["priority_score"].is_null());
+        let polygons = geojson::parse_region_geojson(&contents).unwrap();
+        assert_eq!(polygons, report.polygons);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn spilled_points_are_read_back_a_row_at_a_time() {
+    let dir = temp_path("spill");
+    std::fs::create_dir_all(&dir).unwrap();
+    // 3 rows of 4 points, in grid order
+    let points: Vec<_> = (0..12)
+        .map(|i| geo::Point::new(-100.0 + (i % 4) as f64, 40.0 + (i / 4) as f64))
+        .collect();
+    let mut buffer = spill::PointBuffer::new(Some(spill::SpillConfig {
+        dir: dir.clone(),
+        threshold: 3,
+    }));
+    for &point in &points {
+        buffer.push(point).unwrap();
+    }
+    assert_eq!(buffer.len(), 12);
+    assert_eq!(buffer.spilled(), 12);
+    let spill::Points::Spilled(file) = buffer.finish().unwrap() else {
+        panic!("nothing was spilled");
+    };
+    assert_eq!(file.len(), 12);
+    let path = file.path().to_path_buf();
+    assert!(path.starts_with(&dir));
+    // Batches of at least 5 points end with a whole row
+    let batches: Vec<_> = file.batches(5).unwrap().map(Result::unwrap).collect();
+    assert_eq!(batches, [points[..8].to_vec(), points[8..].to_vec()]);
+    drop(file);
+    assert!(!path.exists());
+
+    // Too few to spill stay in memory
+    let mut buffer = spill::PointBuffer::new(Some(spill::SpillConfig::new(&dir)));
+    buffer.push(points[0]).unwrap();
+    assert!(matches!(
+        buffer.finish().unwrap(),
+        spill::Points::InMemory(kept) if kept == points[..1]
+    ));
+    std::fs::remove_dir(&dir).unwrap();
+}
+
+#[test]
+fn spilled_reports_keep_the_gaps_but_not_the_points() {
+    use geo::algorithm::intersects::Intersects;
+    let chargers = chargers_at(&[(40.0, -100.0)]);
+    let bbox = BoundingBox {
+        lat_min: 38.0,
+        lat_max: 42.0,
+        lon_min: -102.0,
+        lon_max: -98.0,
+    };
+    let config = RangeConfig {
+        max_range_meters: 100_000,
+        crow_flies_ratio: 1.0,
+        ..RangeConfig::default()
+    };
+    let hull = HullConfig {
+        algorithm: HullAlgorithm::Convex,
+        ..HullConfig::default()
+    };
+    let dir = temp_path("spill-gaps");
+    std::fs::create_dir_all(&dir).unwrap();
+    let search = |spill| {
+        chargers
+            .find_gaps_with_progress(
+                0.25,
+                bbox,
+                &MockRoutes::new(&[]),
+                None,
+                None,
+                &config,
+                &hull,
+                &ReportConfig {
+                    spill,
+                    ..ReportConfig::default()
+                },
+                None,
+                None,
+                &|_| {},
+            )
+            .unwrap()
+    };
+    let in_memory = search(None);
+    let spilled = search(Some(spill::SpillConfig {
+        dir: dir.clone(),
+        threshold: 100,
+    }));
+    assert!(in_memory.unreachable > 100);
+    assert!(!in_memory.spilled);
+    assert!(spilled.spilled);
+    assert!(spilled.unreachable_points.is_empty());
+    assert_eq!(spilled.unreachable, in_memory.unreachable);
+    assert_eq!(spilled.total(), in_memory.total());
+    // One hull per batch of rows, which still covers every point
+    assert!(spilled.polygons.0.len() > 1);
+    assert!(in_memory.unreachable_points.iter().all(|point| spilled
+        .polygons
+        .iter()
+        .any(|polygon| polygon.intersects(point))));
+    // and the spill files are cleaned up
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+    let merged = merge_reports(vec![spilled.clone(), in_memory.clone()], &hull);
+    assert!(merged.spilled);
+    assert_eq!(merged.unreachable, 2 * in_memory.unreachable);
+    assert_eq!(
+        merged.polygons.0.len(),
+        spilled.polygons.0.len() + in_memory.polygons.0.len()
+    );
+
+    // Below the threshold nothing is spilled
+    let kept = search(Some(spill::SpillConfig::new(&dir)));
+    assert!(!kept.spilled);
+    assert_eq!(kept.unreachable_points, in_memory.unreachable_points);
+    assert_eq!(kept.polygons, in_memory.polygons);
+    std::fs::remove_dir(&dir).unwrap();
+}
+
+#[test]
+fn point_budget_is_shared_by_chunks() {
+    // Every point near the charger needs a lookup, and is reachable by road
+    let chargers = chargers_at(&[(40.0, -100.0)]);
+    let bbox = BoundingBox {
+        lat_min: 39.5,
+        lat_max: 40.5,
+        lon_min: -100.5,
+        lon_max: -99.5,
+    };
+    let routes = MockRoutes::new(&[(0, 0.0)]);
+    let config = GapConfig {
+        resolution: Resolution::square(0.05),
+        range: RangeConfig {
+            max_range_meters: 50_000,
+            crow_flies_ratio: 0.0,
+            ..RangeConfig::default()
+        },
+        chunks: 4,
+        ..GapConfig::new(&chargers, &routes, bbox)
+    };
+    let unlimited = run_gap_analysis(config.clone()).unwrap();
+    let lookups: usize = unlimited.iter().map(|report| report.routed_points).sum();
+    assert!(lookups > 100, "{}", lookups);
+
+    let budget = PointBudget::new(100);
+    let limited = run_gap_analysis(GapConfig {
+        budget: Some(&budget),
+        ..config.clone()
+    })
+    .unwrap();
+    assert!(budget.exhausted());
+    assert_eq!(budget.used(), 100);
+    let routed_points: usize = limited.iter().map(|report| report.routed_points).sum();
+    assert_eq!(routed_points, 100);
+    // Points that didn't get a lookup are gaps, so there are more of them
+    let unreachable =
+        |reports: &[GapReport]| -> usize { reports.iter().map(|report| report.unreachable).sum() };
+    assert_eq!(
+        unreachable(&limited),
+        unreachable(&unlimited) + lookups - 100
+    );
+
+    let budget = PointBudget::new(lookups);
+    run_gap_analysis(GapConfig {
+        budget: Some(&budget),
+        ..config
+    })
+    .unwrap();
+    assert!(!budget.exhausted());
+}
+
+#[test]
+fn point_budget_caps_osrm_requests() {
+    // Every point near the charger needs a lookup, which OSRM answers with
+    // one table request
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counted = requests.clone();
+    let osrm_url = serve_osrm_with(move |_| {
+        counted.fetch_add(1, Relaxed);
+        r#"{"code":"NoTable"}"#.to_string()
+    });
+    let osrm = OsrmClient::new(&osrm_url);
+    let chargers = chargers_at(&[(40.0, -100.0), (40.1, -100.1)]);
+    let bbox = BoundingBox {
+        lat_min: 39.5,
+        lat_max: 40.5,
+        lon_min: -100.5,
+        lon_max: -99.5,
+    };
+    let budget = PointBudget::new(20);
+    let reports = run_gap_analysis(GapConfig {
+        resolution: Resolution::square(0.1),
+        range: RangeConfig {
+            max_range_meters: 50_000,
+            crow_flies_ratio: 0.0,
+            ..RangeConfig::default()
+        },
+        chunks: 4,
+        budget: Some(&budget),
+        ..GapConfig::new(&chargers, &osrm, bbox)
+    })
+    .unwrap();
+    assert!(budget.exhausted());
+    let routed_points: usize = reports.iter().map(|report| report.routed_points).sum();
+    assert_eq!(routed_points, 20);
+    assert_eq!(requests.load(Relaxed), 20);
+}
+
+#[test]
+fn cancelled_search_keeps_the_points_checked() {
+    use std::sync::atomic::AtomicBool;
+    let chargers = chargers_at(&[(40.0, -100.0)]);
+    let bbox = BoundingBox {
+        lat_min: 39.0,
+        lat_max: 41.0,
+        lon_min: -101.0,
+        lon_max: -99.0,
+    };
+    let config = RangeConfig {
+        max_range_meters: 50_000,
+        ..RangeConfig::default()
+    };
+    let routes = MockRoutes::new(&[]);
+    let cancel = AtomicBool::new(false);
+    // Cancelled from the second progress report, after 1001 points
+    let report = chargers
+        .find_gaps_with_progress(
+            0.02,
+            bbox,
+            &routes,
+            None,
+            None,
+            &config,
+            &HullConfig::default(),
+            &ReportConfig::default(),
+            None,
+            Some(&cancel),
+            &|progress| {
+                if progress.processed > 1 {
+                    cancel.store(true, Relaxed);
+                }
+            },
+        )
+        .unwrap();
+    assert!(bbox.generate_grid(0.02).len() > 2 * PROGRESS_INTERVAL);
+    assert_eq!(report.total(), PROGRESS_INTERVAL + 1);
+    assert_eq!(report.unreachable_points.len(), report.unreachable);
+
+    // Chunks that haven't started find nothing
+    let reports = run_gap_analysis(GapConfig {
+        resolution: Resolution::square(0.05),
+        range: config,
+        chunks: 4,
+        cancel: Some(&cancel),
+        ..GapConfig::new(&chargers, &routes, bbox)
+    })
+    .unwrap();
+    assert!(reports.iter().all(|report| report.total() == 0));
+}
+
+#[test]
+fn parallel_search_keeps_grid_order() {
+    // No route to the charger, so every point in range is a lookup
+    let chargers = chargers_at(&[(40.0, -100.0)]);
+    let bbox = BoundingBox {
+        lat_min: 39.0,
+        lat_max: 41.0,
+        lon_min: -101.0,
+        lon_max: -99.0,
+    };
+    let config = RangeConfig {
+        max_range_meters: 50_000,
+        ..RangeConfig::default()
+    };
+    let routes = MockRoutes::new(&[]);
+    let report = chargers.find_gaps(0.05, bbox, &routes, None, &config, &HullConfig::default());
+    let grid: Vec<_> = bbox
+        .generate_grid(0.05)
+        .iter()
+        .map(TrialPoint::to_geo_point)
+        .collect();
+    assert!(grid.len() > PROGRESS_INTERVAL);
+    let in_grid_order: Vec<_> = grid
+        .into_iter()
+        .filter(|point| report.unreachable_points.contains(point))
+        .collect();
+    assert_eq!(report.unreachable_points, in_grid_order);
+    assert_eq!(routes.lookups.load(Relaxed), report.maybe);
+}
+
+#[test]
+fn sample_keeps_about_the_fraction_of_the_grid() {
+    let grid = US_BOUNDING_BOX.generate_grid(0.1);
+    let sample = Sample {
+        fraction: 0.1,
+        seed: 7,
+    };
+    let sampled: Vec<_> = grid.iter().filter(|point| sample.keeps(point)).collect();
+    let expected = 0.1 * grid.len() as f64;
+    assert!((sampled.len() as f64 - expected).abs() < 0.05 * expected);
+
+    // The same seed picks the same points, another one different ones
+    let again: Vec<_> = grid.iter().filter(|point| sample.keeps(point)).collect();
+    assert_eq!(
+        sampled
+            .iter()
+            .map(|p| (p.latitude, p.longitude))
+            .collect::<Vec<_>>(),
+        again
+            .iter()
+            .map(|p| (p.latitude, p.longitude))
+            .collect::<Vec<_>>()
+    );
+    let other = Sample { seed: 8, ..sample };
+    assert!(sampled.iter().any(|point| !other.keeps(point)));
+
+    assert!(grid.iter().all(|point| Sample {
+        fraction: 1.0,
+        seed: 7
+    }
+    .keeps(point)));
+    assert!(!grid.iter().any(|point| Sample {
+        fraction: 0.0,
+        seed: 7
+    }
+    .keeps(point)));
+}
+
+#[test]
+fn merge_reports_joins_gaps_across_chunks() {
+    // One block of unreachable points, cut in two by a chunk edge
+    let block = |lon_min: f64, lon_max: f64| BoundingBox {
+        lat_min: 40.0,
+        lat_max: 41.0,
+        lon_min,
+        lon_max,
+    };
+    let hull = HullConfig {
+        algorithm: HullAlgorithm::Convex,
+        cluster_eps: 0.15,
+        ..HullConfig::default()
+    };
+    let report = |bbox: BoundingBox| {
+        let points: Vec<_> = bbox
+            .generate_grid(0.1)
+            .iter()
+            .map(TrialPoint::to_geo_point)
+            .collect();
+        GapReport::new(points, &hull, 5, 2, 2, Duration::from_secs(1))
+    };
+    let chunks = vec![report(block(-101.0, -100.0)), report(block(-100.0, -99.0))];
+    assert_eq!(chunks.iter().map(|r| r.polygons.0.len()).sum::<usize>(), 2);
+
+    let merged = merge_reports(chunks, &hull);
+    assert_eq!(merged.polygons.0.len(), 1);
+    assert_eq!(merged.unreachable, 200);
+    assert_eq!(merged.reachable, 10);
+    assert_eq!(merged.routed_points, 4);
+    assert_eq!(merged.elapsed, Duration::from_secs(1));
+    // One rectangle across both chunks, without the gap along their edge
+    assert!((merged.polygons.unsigned_area() - 0.9 * 1.9).abs() < 1e-9);
+}
+
+#[test]
+fn phase_timer_times_each_chunk() {
+    use profile::PhaseTimer;
+    let chargers = chargers_at(&[(40.0, -100.0)]);
+    let bbox = BoundingBox {
+        lat_min: 39.0,
+        lat_max: 41.0,
+        lon_min: -101.0,
+        lon_max: -99.0,
+    };
+    let config = RangeConfig {
+        max_range_meters: 50_000,
+        ..RangeConfig::default()
+    };
+    let mut routes = MockRoutes::new(&[]);
+    routes.delay = Duration::from_millis(1);
+    let timer = PhaseTimer::new();
+    tracing::subscriber::with_default(timer.clone(), || {
+        chargers.find_gaps(0.1, bbox, &routes, None, &config, &HullConfig::default())
+    });
+
+    let chunks = timer.finished();
+    assert_eq!(chunks.len(), 1);
+    let chunk = &chunks[0];
+    assert_eq!(chunk.chunk, "lat_min=39.0 lon_min=-101.0");
+    for phase in [
+        "find_gaps",
+        "generate_grid",
+        "check_point",
+        "nearest_chargers",
+        "routing",
+        "gap_hulls",
+    ] {
+        assert!(chunk.phase(phase).is_some(), "no {} in {}", phase, chunk);
+    }
+    // Every lookup took at least a millisecond
+    let lookups = routes.lookups.load(Relaxed) as u32;
+    assert!(lookups > 0);
+    assert!(chunk.phase("routing").unwrap() >= Duration::from_millis(1) * lookups);
+    assert_eq!(timer.total().phases, chunk.phases);
+}
+
+#[test]
+fn derated_range_leaves_more_points_unreachable() {
+    let chargers = chargers_at(&[(40.0, -100.0), (40.5, -99.5)]);
+    let bbox = BoundingBox {
+        lat_min: 39.0,
+        lat_max: 41.0,
+        lon_min: -101.0,
+        lon_max: -99.0,
+    };
+    // Chargers are 1 km away by road, so only the straight line range
+    // matters for the lookups
+    let routes = MockRoutes::new(&[(0, 1000.0), (1, 1000.0)]);
+    let config = RangeConfig {
+        max_range_meters: 50_000,
+        crow_flies_ratio: 0.5,
+        ..RangeConfig::default()
+    };
+    let hull = HullConfig::default();
+    let full = chargers.find_gaps(0.05, bbox, &routes, None, &config, &hull);
+    let derated = config.derated(0.7);
+    assert_eq!(derated.max_range_meters, 35_000);
+    let cold = chargers.find_gaps(0.05, bbox, &routes, None, &derated, &hull);
+    assert_eq!(cold.total(), full.total());
+    assert!(cold.unreachable > full.unreachable);
+    // Every point out of reach in full range is out of reach in the cold too
+    let cold_points: HashSet<_> = cold
+        .unreachable_points
+        .iter()
+        .map(|p| (p.x().to_bits(), p.y().to_bits()))
+        .collect();
+    assert!(full
+        .unreachable_points
+        .iter()
+        .all(|p| cold_points.contains(&(p.x().to_bits(), p.y().to_bits()))));
+
+    // Routes that are too long for the derated range aren't in range either
+    let far = MockRoutes::new(&[(0, 40_000.0), (1, 40_000.0)]);
+    let config = RangeConfig {
+        crow_flies_ratio: 0.0,
+        ..config
+    };
+    let full = chargers.find_gaps(0.05, bbox, &far, None, &config, &hull);
+    let cold = chargers.find_gaps(0.05, bbox, &far, None, &config.derated(0.7), &hull);
+    assert!(full.reachable > 0);
+    assert_eq!(cold.reachable, 0);
+}
+
+#[test]
+fn run_gap_analysis_searches_every_chunk() {
+    let chargers = chargers_at(&[(40.0, -100.0)]);
+    let bbox = BoundingBox {
+        lat_min: 39.0,
+        lat_max: 41.0,
+        lon_min: -101.0,
+        lon_max: -99.0,
+    };
+    let routes = MockRoutes::new(&[]);
+    let finished = std::sync::Mutex::new(Vec::new());
+    let progress = |chunk, progress: &Progress| {
+        if progress.processed == progress.total {
+            finished.lock().unwrap().push((chunk, progress.total));
+        }
+    };
+    let config = GapConfig {
+        resolution: Resolution::square(0.05),
+        range: RangeConfig {
+            max_range_meters: 50_000,
+            crow_flies_ratio: 0.5,
+            ..RangeConfig::default()
+        },
+        chunks: 4,
+        ..GapConfig::new(&chargers, &routes, bbox)
+    };
+    let reports = run_gap_analysis(GapConfig {
+        progress: Some(&progress),
+        ..config.clone()
+    })
+    .unwrap();
+    let (rows, cols) = bbox.grid_shape(4);
+    let chunks = bbox.chunkify_grid(rows, cols);
+    assert_eq!(reports.len(), chunks.len());
+    // Reports are in chunk order, each the same as searching the chunk alone
+    for (report, chunk) in reports.iter().zip(&chunks) {
+        let alone = chargers.find_gaps(0.05, *chunk, &routes, None, &config.range, &config.hull);
+        assert_eq!(report.unreachable_points, alone.unreachable_points);
+        assert_eq!(report.reachable, alone.reachable);
+    }
+    assert!(reports.iter().any(|report| report.reachable > 0));
+    assert!(reports.iter().any(|report| report.unreachable > 0));
+    // Every chunk says when it's done
+    let mut finished = finished.lock().unwrap().clone();
+    finished.sort_unstable();
+    let expected: Vec<_> = reports.iter().map(GapReport::total).enumerate().collect();
+    assert_eq!(finished, expected);
+
+    let invalid = GapConfig {
+        bbox: BoundingBox {
+            lat_min: 41.0,
+            ..bbox
+        },
+        ..config.clone()
+    };
+    assert!(matches!(
+        run_gap_analysis(invalid),
+        Err(GapError::InvalidConfig(_))
+    ));
+    assert!(run_gap_analysis(GapConfig {
+        resolution: Resolution::square(0.0),
+        ..config
+    })
+    .is_err());
+}
@@ -0,0 +1,131 @@
+//! Well-known text (WKT) output, e.g. for loading gaps into PostGIS with
+//! `COPY`. Only polygons are needed, so this writes and parses those rather
+//! than pulling in the `wkt` crate.
+
+use std::{error::Error, fmt::Write as _, io::Write, path::Path};
+
+use geo::{Coordinate, LineString, MultiPolygon, Polygon};
+
+use crate::atomic::write_file_atomically;
+
+/// WKT for `polygons`: a `POLYGON` if there's exactly one, otherwise a
+/// `MULTIPOLYGON` (`MULTIPOLYGON EMPTY` if there are none).
+pub fn multi_polygon_wkt(polygons: &MultiPolygon<f64>) -> String {
+    match polygons.0.as_slice() {
+        [polygon] => format!("POLYGON{}", polygon_text(polygon)),
+        [] => "MULTIPOLYGON EMPTY".to_owned(),
+        polygons => {
+            let parts: Vec<_> = polygons.iter().map(polygon_text).collect();
+            format!("MULTIPOLYGON({})", parts.join(","))
+        }
+    }
+}
+
+/// The parenthesized rings of a polygon, exterior first.
+fn polygon_text(polygon: &Polygon<f64>) -> String {
+    let rings: Vec<_> = std::iter::once(polygon.exterior())
+        .chain(polygon.interiors())
+        .map(|ring| {
+            let mut text = String::from("(");
+            for (i, c) in ring.0.iter().enumerate() {
+                if i > 0 {
+                    text.push(',');
+                }
+                // f64's Display is the shortest string that parses back to
+                // the same value, so nothing is lost
+                write!(text, "{} {}", c.x, c.y).unwrap();
+            }
+            text.push(')');
+            text
+        })
+        .collect();
+    format!("({})", rings.join(","))
+}
+
+/// Writes one line per chunk to `path`: the chunk id, a tab and the WKT of
+/// its gaps. This is PostgreSQL's default `COPY` text format, e.g. for
+/// `COPY gaps (chunk_id, geom) FROM 'gaps.wkt'`.
+pub fn write_gaps_wkt(
+    path: &str,
+    gaps: &[(usize, MultiPolygon<f64>)],
+) -> Result<(), Box<dyn Error>> {
+    write_file_atomically(Path::new(path), |writer| {
+        for (chunk_id, polygons) in gaps {
+            writeln!(writer, "{}\t{}", chunk_id, multi_polygon_wkt(polygons))?;
+        }
+        Ok(())
+    })
+}
+
+/// Parses a `POLYGON` or `MULTIPOLYGON` as written by `multi_polygon_wkt`.
+/// Other geometry types, and Z or M coordinates, are an error.
+pub fn parse_polygons_wkt(text: &str) -> Result<MultiPolygon<f64>, Box<dyn Error>> {
+    let text = text.trim();
+    let (kind, body) = text
+        .find('(')
+        .map(|i| text.split_at(i))
+        .unwrap_or((text, ""));
+    match (kind.trim().to_uppercase().as_str(), body) {
+        ("POLYGON EMPTY" | "MULTIPOLYGON EMPTY", "") => Ok(MultiPolygon(vec![])),
+        ("POLYGON", body) => Ok(MultiPolygon(vec![parse_polygon(unwrap_parens(body)?)?])),
+        ("MULTIPOLYGON", body) => split_top_level(unwrap_parens(body)?)
+            .into_iter()
+            .map(|polygon| parse_polygon(unwrap_parens(polygon)?))
+            .collect::<Result<_, _>>()
+            .map(MultiPolygon),
+        _ => Err(format!("not a WKT polygon: {:?}", text).into()),
+    }
+}
+
+fn parse_polygon(rings: &str) -> Result<Polygon<f64>, Box<dyn Error>> {
+    let mut rings = split_top_level(rings)
+        .into_iter()
+        .map(|ring| parse_ring(unwrap_parens(ring)?))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter();
+    let exterior = rings.next().ok_or("WKT polygon has no rings")?;
+    Ok(Polygon::new(exterior, rings.collect()))
+}
+
+fn parse_ring(coordinates: &str) -> Result<LineString<f64>, Box<dyn Error>> {
+    coordinates
+        .split(',')
+        .map(
+            |position| match position.split_whitespace().collect::<Vec<_>>()[..] {
+                [x, y] => Ok(Coordinate {
+                    x: x.parse()?,
+                    y: y.parse()?,
+                }),
+                _ => Err(format!("WKT position must be \"x y\", got {:?}", position).into()),
+            },
+        )
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()
+        .map(LineString)
+}
+
+/// The inside of `(...)`.
+fn unwrap_parens(text: &str) -> Result<&str, Box<dyn Error>> {
+    text.trim()
+        .strip_prefix('(')
+        .and_then(|text| text.strip_suffix(')'))
+        .ok_or_else(|| format!("expected parentheses around {:?}", text).into())
+}
+
+/// Splits on the commas that aren't inside parentheses.
+fn split_top_level(text: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(&text[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}